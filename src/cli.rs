@@ -0,0 +1,674 @@
+use std::{iter, net::SocketAddr, path::PathBuf, thread, time::Duration};
+
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand, ValueEnum};
+use event_viewer::etw_constructs::{
+    bits, browser, clr,
+    controller::{BufferConfig, BufferMemory, LogFile, LogFileMode, ProviderConfig, SequenceMode},
+    crash,
+    event_filter::EventFilter,
+    filter::{FilterSet, PROCESS_GUID},
+    latency::LatencySlo,
+    mapped_file::MappedFileConfig,
+    named_pipe, pnp, print_service, privilege,
+    process_graph::GraphFormat,
+    rdp, schemas,
+    stack_walk::StackTracedEvent,
+    taxonomy, win32k, windows_update,
+};
+use windows::{
+    core::GUID,
+    Win32::System::Diagnostics::Etw::{
+        EVENT_TRACE_FLAG, EVENT_TRACE_FLAG_ALPC, EVENT_TRACE_FLAG_CSWITCH,
+        EVENT_TRACE_FLAG_DISK_FILE_IO, EVENT_TRACE_FLAG_DISK_IO, EVENT_TRACE_FLAG_DISK_IO_INIT,
+        EVENT_TRACE_FLAG_DISPATCHER, EVENT_TRACE_FLAG_DPC, EVENT_TRACE_FLAG_FILE_IO,
+        EVENT_TRACE_FLAG_FILE_IO_INIT, EVENT_TRACE_FLAG_IMAGE_LOAD, EVENT_TRACE_FLAG_INTERRUPT,
+        EVENT_TRACE_FLAG_MEMORY_HARD_FAULTS, EVENT_TRACE_FLAG_MEMORY_PAGE_FAULTS,
+        EVENT_TRACE_FLAG_NETWORK_TCPIP, EVENT_TRACE_FLAG_PROCESS, EVENT_TRACE_FLAG_PROFILE,
+        EVENT_TRACE_FLAG_REGISTRY, EVENT_TRACE_FLAG_SYSTEMCALL, EVENT_TRACE_FLAG_THREAD,
+        TRACE_LEVEL_CRITICAL, TRACE_LEVEL_ERROR, TRACE_LEVEL_INFORMATION, TRACE_LEVEL_VERBOSE,
+        TRACE_LEVEL_WARNING,
+    },
+};
+
+/// Traces process creation (or anything else) with Event Tracing for Windows.
+/// With no arguments, process start events are traced in real-time until Ctrl-C is pressed
+#[derive(Debug, Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Replay a recorded .etl file instead of tracing in real-time
+    pub trace: Option<PathBuf>,
+
+    /// Threads decoding the events of a replayed .etl file, including for summarize and diff. Defaults to one per
+    /// CPU, 1 decodes them on the thread reading the file
+    #[arg(long)]
+    pub decode_threads: Option<usize>,
+
+    /// Comma separated kernel event classes to enable, or none
+    #[arg(long, value_delimiter = ',', value_parser = parse_kernel_flag, default_value = "process")]
+    pub kernel_flags: Vec<EVENT_TRACE_FLAG>,
+
+    /// User-mode provider to enable, by GUID or by name: win32k, print, bits, windows-update, pnp, usbport, ucx,
+    /// rdp-sessions, rdp-auth, rdp-core, wer, kernel-general, kernel-power, kernel-file, kernel-process, dotnet,
+    /// security-auditing, jscript, chrome or edge. Can be repeated
+    #[arg(long = "provider", value_parser = parse_provider)]
+    pub providers: Vec<GUID>,
+
+    /// Most verbose level logged by the providers: critical, error, warning, information, verbose or a number.
+    /// Defaults to what the events of a named provider need, e.g. verbose for the GC events of dotnet, and information
+    /// otherwise
+    #[arg(long, value_parser = parse_level)]
+    pub level: Option<u8>,
+
+    /// Only log provider events with any of these keywords, e.g. 0x10. Every event is logged if this is 0. Defaults to
+    /// the keywords of the events a named provider is analyzed with, e.g. GC and exceptions for dotnet, and 0 otherwise
+    #[arg(long, value_parser = parse_number)]
+    pub keywords: Option<u64>,
+
+    /// Only keep events of this process id. Can be repeated
+    #[arg(long = "filter-pid")]
+    pub filter_pids: Vec<u32>,
+
+    /// Write decoded events to another output with its own filter, as format,path[,filter], e.g.
+    /// json,net.jsonl,provider=tcpip|provider=process. The format is json or csv and the path - for stdout, in double
+    /// quotes if it has a comma in it. Can be repeated, and replaces --output and --out. See EventFilter for the filter
+    /// syntax
+    #[arg(long = "sink", value_parser = parse_sink)]
+    pub sinks: Vec<SinkConfig>,
+
+    /// Directory of instrumentation manifests (.man or .xml) for providers not installed on this machine. Events whose
+    /// schema is in neither are written with their payload as hex
+    #[arg(long)]
+    pub manifest_dir: Option<PathBuf>,
+
+    /// JSON file of output filters, projections, privilege rules and session process ids, e.g.
+    /// {"filters": {"net.jsonl": "provider=tcpip"}}. Applied at startup, and edits to it are picked up while the
+    /// session runs, replacing what it names
+    #[arg(long)]
+    pub filter_file: Option<PathBuf>,
+
+    /// How decoded events are printed
+    #[arg(long, value_enum, default_value_t = OutputFormat::Pretty)]
+    pub output: OutputFormat,
+
+    /// Where json and csv output is written. Defaults to stdout
+    #[arg(long, default_value = "-")]
+    pub out: PathBuf,
+
+    /// Write event counts per provider, event id, opcode and process in buckets this long instead of every event,
+    /// e.g. 1m, for charting a long capture. Applies to json and csv outputs, where each bucket is written a couple of
+    /// seconds after it ends. Needs --output json or csv, or a --sink
+    #[arg(long, value_parser = parse_duration)]
+    pub bucket: Option<Duration>,
+
+    /// Also write the session to this .etl file. Once the session stops, a WPA regions of interest file named after it
+    /// marks the anomalies the analyzers find in it
+    #[arg(long)]
+    pub etl_out: Option<PathBuf>,
+
+    /// Stop the session after this long, e.g. 30s, 5m or 500ms
+    #[arg(long, value_parser = parse_duration)]
+    pub duration: Option<Duration>,
+
+    /// Size of each session buffer in KB, up to 1024
+    #[arg(long, default_value = "0")]
+    pub buffer_size: u32,
+
+    /// Buffers allocated when the session starts
+    #[arg(long, default_value = "0")]
+    pub min_buffers: u32,
+
+    /// Most buffers the session grows to under load
+    #[arg(long, default_value = "0")]
+    pub max_buffers: u32,
+
+    /// How often partially filled buffers are flushed, e.g. 1s
+    #[arg(long, value_parser = parse_duration)]
+    pub flush_timer: Option<Duration>,
+
+    /// Kernel events to capture the call stack of, as class:opcode, e.g. process:1 for process starts. The class is
+    /// process, thread, image, tcpip, udpip, registry, fileio or a GUID. Stacks are only included in json and csv output
+    #[arg(long = "stack", value_delimiter = ',', value_parser = parse_stack_traced_event)]
+    pub stacks: Vec<StackTracedEvent>,
+
+    /// Log the working set and commit charge of every process about every half second. Combine with
+    /// --kernel-flags page-faults,hard-faults for a per-process memory summary at the end of json and csv output
+    #[arg(long)]
+    pub mem_info: bool,
+
+    /// Have ETW number the events logged with TraceMessage: none, global or local
+    #[arg(long, value_parser = parse_sequence_mode, default_value = "none")]
+    pub sequence: SequenceMode,
+
+    /// Print the tree of every process seen at the end of json and csv output. Needs the process kernel flag
+    #[arg(long)]
+    pub process_tree: bool,
+
+    /// Write the process tree as a spawn graph to this file at the end of json and csv output, as Graphviz for .dot or
+    /// .gv and Mermaid for .mmd or .mermaid. Exited processes and processes whose id was reused are kept. Processes
+    /// that matched a privilege rule of --provider security-auditing are highlighted. Needs the process kernel flag
+    #[arg(long, value_parser = parse_graph)]
+    pub process_graph: Option<GraphConfig>,
+
+    /// Delivery latency objective for json and csv output of a live session, as percent@age, e.g. 99%@2s for 99% of
+    /// events written within 2s of being logged. Every 10s window that misses it is reported, and the capture as a
+    /// whole at the end
+    #[arg(long, value_parser = parse_latency_slo)]
+    pub latency_slo: Option<LatencySlo>,
+
+    /// Serve the delivery latency quantiles and objective breaches of a live session over HTTP on this address, in
+    /// the Prometheus text format, e.g. 127.0.0.1:9184. Only for json and csv output
+    #[arg(long, conflicts_with = "trace")]
+    pub metrics: Option<SocketAddr>,
+
+    /// Write process starts and stops as one canonical event whichever of the kernel logger, kernel-process or
+    /// security-auditing logged them, merging the copies when several of them are enabled
+    #[arg(long)]
+    pub normalize: bool,
+
+    /// Attach the image name and command line of up to this many ancestors (4 if no number is given) to every process
+    /// Start event, parent first. Only in json and csv output, and ancestors must have been seen starting or in the
+    /// rundown
+    #[arg(long, value_name = "LEVELS", num_args = 0..=1, default_missing_value = "4")]
+    pub ancestry: Option<usize>,
+
+    /// Allocate the session buffers from paged pool, so a long capture does not pin memory. Only for user-mode
+    /// providers, so it needs --kernel-flags none
+    #[arg(long)]
+    pub paged_memory: bool,
+
+    /// Start the session in secure mode, so only accounts allowed to log to it can, and list who can consume it
+    #[arg(long)]
+    pub secure: bool,
+
+    /// Stop a session of the same name that is already running, e.g. one left behind by a crashed run, and start a new
+    /// one in its place. Without it the capture fails if the session is already running
+    #[arg(long, conflicts_with = "trace")]
+    pub stop_existing: bool,
+
+    /// Leave the session running when this process exits, so the kernel keeps logging while the collector is upgraded
+    /// or restarted. Stop it with a run with --stop-existing, or `logman stop`
+    #[arg(long)]
+    pub keep_alive: bool,
+
+    /// Consume the session a run with --keep-alive left running instead of starting a new one. It must be given the
+    /// same kernel flags and providers. Starts a new session if none is running. A session that was reattached to is
+    /// never stopped by this run
+    #[arg(long, conflicts_with_all = ["trace", "stop_existing"])]
+    pub reattach: bool,
+
+    /// Comma separated environment variables to read out of every started process and attach to its Start event,
+    /// along with its current directory, e.g. PATH,USERNAME. Best-effort, only in json and csv output. Not available
+    /// with --trace, since the processes of a recorded trace are long gone
+    #[arg(long = "capture-env", value_delimiter = ',', conflicts_with = "trace")]
+    pub capture_env: Vec<String>,
+
+    /// Write json output and raw captures through a memory-mapped file preallocated to this many MB (64 if no size
+    /// is given), for high event rates. Only applies when writing to a file
+    #[arg(long, value_name = "MB", num_args = 0..=1, default_missing_value = "64")]
+    pub mmap: Option<u64>,
+}
+
+/// A spawn graph given with --process-graph
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphConfig {
+    pub format: GraphFormat,
+    pub path: PathBuf,
+}
+
+impl Cli {
+    /// How json output and raw captures are memory-mapped, if --mmap was given
+    pub fn mapped_file(&self) -> Option<MappedFileConfig> {
+        self.mmap.map(|size_mb| MappedFileConfig {
+            preallocate_bytes: size_mb * 1024 * 1024,
+            ..Default::default()
+        })
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Capture undecoded events to a raw capture file, for when CPU is tight
+    Raw { out: PathBuf },
+    /// Decode a raw capture file written by `raw`
+    Decode { input: PathBuf },
+    /// List the providers registered on this machine, optionally only those whose name contains `name`
+    Providers { name: Option<String> },
+    /// List the trace sessions running on this machine
+    Sessions,
+    /// Summarize the processes, network destinations and autostart registry writes of an .etl file as JSON, for use
+    /// as a `diff` baseline
+    Summarize { trace: PathBuf },
+    /// Report the processes, network destinations and autostart registry writes in `after` but not in `before`. Each
+    /// is an .etl file or a .json summary written by `summarize`
+    Diff { before: PathBuf, after: PathBuf },
+    /// Wait for a coordinator on this address, e.g. 0.0.0.0:9185, and run the live captures it asks for, streaming
+    /// their events back to it in place of the outputs the capture names. Anyone who can connect can start a capture,
+    /// so only listen on a network the coordinator is trusted on
+    Agent { listen: SocketAddr },
+    /// Run a live capture on every agent at once, e.g. `coordinate host1:9185,host2:9185 -- --kernel-flags process`,
+    /// and write their events to --out as JSON Lines, each with the host it came from. Stops the agents after
+    /// --duration or on Ctrl-C
+    Coordinate {
+        /// Comma separated agents, as host:port
+        #[arg(value_delimiter = ',', required = true)]
+        agents: Vec<String>,
+        /// Options of the capture, after --
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Pretty,
+    Json,
+    Csv,
+}
+
+/// An output given with --sink
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SinkConfig {
+    pub format: OutputFormat,
+    pub path: PathBuf,
+    pub filter: EventFilter,
+}
+
+impl Cli {
+    /// Every provider to enable, with the level and keywords given on the command line
+    pub fn provider_configs(&self) -> Vec<ProviderConfig> {
+        self.providers
+            .iter()
+            // Enabling it on a session delivers nothing, its events are read from the Security log instead
+            .filter(|guid| **guid != privilege::SECURITY_AUDITING_GUID || self.trace.is_some())
+            .map(|guid| {
+                let defaults = match *guid {
+                    clr::DOTNET_RUNTIME_GUID => {
+                        self.keywords.map_or_else(clr::provider, clr::provider_for)
+                    }
+                    win32k::WIN32K_GUID => win32k::provider(),
+                    print_service::PRINT_SERVICE_GUID => print_service::provider(),
+                    bits::BITS_CLIENT_GUID => bits::provider(),
+                    windows_update::WINDOWS_UPDATE_CLIENT_GUID => windows_update::provider(),
+                    pnp::KERNEL_PNP_GUID => pnp::provider(),
+                    guid => ProviderConfig {
+                        guid,
+                        level: TRACE_LEVEL_INFORMATION as u8,
+                        match_any_keyword: 0,
+                    },
+                };
+                ProviderConfig {
+                    level: self.level.unwrap_or(defaults.level),
+                    match_any_keyword: self.keywords.unwrap_or(defaults.match_any_keyword),
+                    ..defaults
+                }
+            })
+            .collect()
+    }
+
+    /// Parses the command line, then checks the combinations of options clap cannot check on its own. Exits with a
+    /// usage error like clap's own if they do not go together
+    pub fn parse_checked() -> Self {
+        let cli = Self::parse();
+        if let Err(message) = cli._check() {
+            Self::command()
+                .error(ErrorKind::ArgumentConflict, message)
+                .exit();
+        }
+        cli
+    }
+
+    /// Parses the options of a capture a coordinator asked an agent for. Only live captures are run that way
+    pub fn parse_capture_args(args: &[String]) -> Result<Self, String> {
+        let program = env!("CARGO_PKG_NAME").to_string();
+        let cli = Self::try_parse_from(iter::once(program).chain(args.iter().cloned()))
+            .map_err(|err| err.to_string())?;
+        if cli.command.is_some() || cli.trace.is_some() {
+            return Err(
+                "an agent only runs live captures, without a subcommand or trace".to_string(),
+            );
+        }
+        cli._check()?;
+        Ok(cli)
+    }
+
+    fn _check(&self) -> Result<(), String> {
+        if self.bucket.is_some() && self.output == OutputFormat::Pretty && self.sinks.is_empty() {
+            return Err("--bucket needs --output json or csv, or a --sink".to_string());
+        }
+        Ok(())
+    }
+
+    /// Whether security-auditing events are read from the Security event log, see `SecurityLog`
+    pub fn security_log(&self) -> bool {
+        self.trace.is_none() && self.providers.contains(&privilege::SECURITY_AUDITING_GUID)
+    }
+
+    /// The kernel flags combined into one value
+    pub fn enable_flags(&self) -> EVENT_TRACE_FLAG {
+        self.kernel_flags
+            .iter()
+            .fold(EVENT_TRACE_FLAG(0), |flags, flag| flags | *flag)
+    }
+
+    /// Anything not given is left for ETW to pick
+    pub fn buffers(&self) -> BufferConfig {
+        BufferConfig {
+            buffer_size_kb: self.buffer_size,
+            minimum_buffers: self.min_buffers,
+            maximum_buffers: self.max_buffers,
+            flush_timer_secs: self
+                .flush_timer
+                .map_or(0, |timer| timer.as_secs().max(1) as u32),
+        }
+    }
+
+    pub fn decode_threads(&self) -> usize {
+        self.decode_threads
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, |threads| threads.get()))
+    }
+
+    pub fn buffer_memory(&self) -> BufferMemory {
+        if self.paged_memory {
+            BufferMemory::Paged
+        } else {
+            BufferMemory::NonPaged
+        }
+    }
+
+    pub fn log_file(&self) -> Option<LogFile> {
+        self.etl_out.as_ref().map(|path| LogFile {
+            path: path.clone(),
+            mode: LogFileMode::Sequential { max_size_mb: None },
+            real_time: true,
+        })
+    }
+
+    /// None if no filter was asked for
+    pub fn filter(&self) -> Option<FilterSet> {
+        if self.filter_pids.is_empty() {
+            return None;
+        }
+
+        Some(
+            self.filter_pids
+                .iter()
+                .fold(FilterSet::new(), |filter, pid| filter.process_id(*pid)),
+        )
+    }
+}
+
+fn parse_kernel_flag(name: &str) -> Result<EVENT_TRACE_FLAG, String> {
+    Ok(match name.trim() {
+        "process" => EVENT_TRACE_FLAG_PROCESS,
+        "thread" => EVENT_TRACE_FLAG_THREAD,
+        "image" => EVENT_TRACE_FLAG_IMAGE_LOAD,
+        "disk-io" => EVENT_TRACE_FLAG_DISK_IO,
+        "disk-io-init" => EVENT_TRACE_FLAG_DISK_IO_INIT,
+        "disk-file-io" => EVENT_TRACE_FLAG_DISK_FILE_IO,
+        "file-io" => EVENT_TRACE_FLAG_FILE_IO | EVENT_TRACE_FLAG_FILE_IO_INIT,
+        "network" => EVENT_TRACE_FLAG_NETWORK_TCPIP,
+        "registry" => EVENT_TRACE_FLAG_REGISTRY,
+        "cswitch" => EVENT_TRACE_FLAG_CSWITCH,
+        "dispatcher" => EVENT_TRACE_FLAG_DISPATCHER,
+        "profile" => EVENT_TRACE_FLAG_PROFILE,
+        "page-faults" => EVENT_TRACE_FLAG_MEMORY_PAGE_FAULTS,
+        "hard-faults" => EVENT_TRACE_FLAG_MEMORY_HARD_FAULTS,
+        "syscall" => EVENT_TRACE_FLAG_SYSTEMCALL,
+        "dpc" => EVENT_TRACE_FLAG_DPC,
+        "interrupt" => EVENT_TRACE_FLAG_INTERRUPT,
+        "alpc" => EVENT_TRACE_FLAG_ALPC,
+        "none" => EVENT_TRACE_FLAG(0),
+        name => return Err(format!("Unknown kernel flag {name:?}")),
+    })
+}
+
+fn parse_provider(value: &str) -> Result<GUID, String> {
+    Ok(match value {
+        "win32k" => win32k::WIN32K_GUID,
+        "print" => print_service::PRINT_SERVICE_GUID,
+        "bits" => bits::BITS_CLIENT_GUID,
+        "windows-update" => windows_update::WINDOWS_UPDATE_CLIENT_GUID,
+        "pnp" => pnp::KERNEL_PNP_GUID,
+        "usbport" => pnp::USB_PORT_GUID,
+        "ucx" => pnp::USB_UCX_GUID,
+        "rdp-sessions" => rdp::LOCAL_SESSION_MANAGER_GUID,
+        "rdp-auth" => rdp::REMOTE_CONNECTION_MANAGER_GUID,
+        "rdp-core" => rdp::RDP_CORE_GUID,
+        "wer" => crash::WER_GUID,
+        "kernel-general" => crash::KERNEL_GENERAL_GUID,
+        "kernel-power" => crash::KERNEL_POWER_GUID,
+        "kernel-file" => named_pipe::KERNEL_FILE_GUID,
+        "kernel-process" => taxonomy::KERNEL_PROCESS_GUID,
+        "dotnet" => clr::DOTNET_RUNTIME_GUID,
+        "security-auditing" => privilege::SECURITY_AUDITING_GUID,
+        "jscript" => browser::JSCRIPT_GUID,
+        "chrome" => browser::CHROME_GUID,
+        "edge" => browser::EDGE_GUID,
+        guid => GUID::try_from(guid.trim_start_matches('{').trim_end_matches('}'))
+            .map_err(|_| format!("{guid:?} is not a GUID or known provider name"))?,
+    })
+}
+
+fn parse_stack_traced_event(value: &str) -> Result<StackTracedEvent, String> {
+    let (class, opcode) = value
+        .rsplit_once(':')
+        .ok_or_else(|| format!("{value:?} is not class:opcode"))?;
+
+    let provider = parse_kernel_class(class)?;
+    let opcode = opcode
+        .parse()
+        .map_err(|_| format!("{opcode:?} is not an opcode"))?;
+
+    Ok(StackTracedEvent { provider, opcode })
+}
+
+fn parse_kernel_class(class: &str) -> Result<GUID, String> {
+    Ok(match class {
+        "process" => PROCESS_GUID,
+        "thread" => schemas::THREAD_GUID,
+        "image" => schemas::IMAGE_LOAD_GUID,
+        "tcpip" => schemas::TCPIP_GUID,
+        "udpip" => schemas::UDPIP_GUID,
+        "registry" => schemas::REGISTRY_GUID,
+        "fileio" => schemas::FILEIO_GUID,
+        guid => GUID::try_from(guid.trim_start_matches('{').trim_end_matches('}'))
+            .map_err(|_| format!("{guid:?} is not a GUID or known kernel class"))?,
+    })
+}
+
+/// Kernel classes and user-mode provider names are both accepted as providers
+pub fn compile_filter(expression: &str) -> Result<EventFilter, String> {
+    EventFilter::compile(expression, |provider| {
+        parse_kernel_class(provider).or_else(|_| parse_provider(provider))
+    })
+}
+
+/// Accepts format,path[,filter]. The filter goes last so it may hold commas, and a path that holds them is quoted, e.g.
+/// csv,"C:\logs\a,b.csv",pid=4
+fn parse_sink(value: &str) -> Result<SinkConfig, String> {
+    let usage = || format!("{value:?} is not format,path[,filter]");
+    let (format, rest) = value.split_once(',').ok_or_else(usage)?;
+    let format = match format.trim() {
+        "json" => OutputFormat::Json,
+        "csv" => OutputFormat::Csv,
+        format => return Err(format!("{format:?} is not json or csv")),
+    };
+    let rest = rest.trim_start();
+    let (path, filter) = match rest.strip_prefix('"') {
+        Some(quoted) => {
+            let (path, rest) = quoted
+                .split_once('"')
+                .ok_or_else(|| format!("{value:?} does not close the quotes around its path"))?;
+            let filter = match rest.trim() {
+                "" => "",
+                rest => rest.strip_prefix(',').ok_or_else(usage)?,
+            };
+            (path, filter)
+        }
+        None => {
+            let (path, filter) = rest.split_once(',').unwrap_or((rest, ""));
+            (path.trim(), filter)
+        }
+    };
+    if path.is_empty() {
+        return Err(usage());
+    }
+
+    Ok(SinkConfig {
+        format,
+        path: PathBuf::from(path),
+        filter: compile_filter(filter)?,
+    })
+}
+
+fn parse_graph(value: &str) -> Result<GraphConfig, String> {
+    let path = PathBuf::from(value);
+    let format = GraphFormat::from_path(&path)
+        .ok_or_else(|| format!("{value:?} does not end in .dot, .gv, .mmd or .mermaid"))?;
+    Ok(GraphConfig { format, path })
+}
+
+/// Accepts percent@duration, e.g. 99.9%@500ms
+fn parse_latency_slo(value: &str) -> Result<LatencySlo, String> {
+    let (percent, target) = value
+        .split_once('@')
+        .ok_or_else(|| format!("{value:?} is not percent@duration"))?;
+    let percent: f64 = percent
+        .trim_end_matches('%')
+        .parse()
+        .ok()
+        .filter(|percent| (0.0..=100.0).contains(percent))
+        .ok_or_else(|| format!("{percent:?} is not a percentage"))?;
+
+    Ok(LatencySlo {
+        quantile: percent / 100.0,
+        target: parse_duration(target)?,
+    })
+}
+
+fn parse_level(value: &str) -> Result<u8, String> {
+    Ok(match value {
+        "critical" => TRACE_LEVEL_CRITICAL as u8,
+        "error" => TRACE_LEVEL_ERROR as u8,
+        "warning" => TRACE_LEVEL_WARNING as u8,
+        "information" => TRACE_LEVEL_INFORMATION as u8,
+        "verbose" => TRACE_LEVEL_VERBOSE as u8,
+        level => level
+            .parse()
+            .map_err(|_| format!("{level:?} is not a trace level"))?,
+    })
+}
+
+fn parse_sequence_mode(value: &str) -> Result<SequenceMode, String> {
+    match value {
+        "none" => Ok(SequenceMode::None),
+        "global" => Ok(SequenceMode::Global),
+        "local" => Ok(SequenceMode::Local),
+        mode => Err(format!("Unknown sequence mode {mode:?}")),
+    }
+}
+
+/// Accepts decimal or 0x prefixed hex
+fn parse_number(value: &str) -> Result<u64, String> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|_| format!("{value:?} is not a number"))
+}
+
+/// Accepts a number followed by ms, s, m or h
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount
+        .parse()
+        .map_err(|_| format!("{value:?} does not start with a number"))?;
+
+    match unit {
+        "ms" => Ok(Duration::from_millis(amount)),
+        "s" | "" => Ok(Duration::from_secs(amount)),
+        "m" => Ok(Duration::from_secs(amount * 60)),
+        "h" => Ok(Duration::from_secs(amount * 60 * 60)),
+        unit => Err(format!("Unknown duration unit {unit:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sink(value: &str) -> (OutputFormat, PathBuf, String) {
+        let config = parse_sink(value).unwrap();
+        (
+            config.format,
+            config.path,
+            config.filter.expression().to_string(),
+        )
+    }
+
+    #[test]
+    fn sinks_take_a_format_path_and_filter() {
+        assert_eq!(
+            sink("json,out.jsonl"),
+            (
+                OutputFormat::Json,
+                PathBuf::from("out.jsonl"),
+                String::new()
+            )
+        );
+        assert_eq!(
+            sink("csv, all.csv ,pid=4|pid=8"),
+            (
+                OutputFormat::Csv,
+                PathBuf::from("all.csv"),
+                "pid=4|pid=8".to_string()
+            )
+        );
+        // Only the path ends at a comma, the filter may hold more
+        assert_eq!(
+            sink(r#"json,-,CommandLine="a.exe, b.exe""#).2,
+            r#"CommandLine="a.exe, b.exe""#
+        );
+    }
+
+    #[test]
+    fn quoted_sink_paths_may_hold_commas() {
+        assert_eq!(
+            sink(r#"csv,"C:\logs\a,b.csv",pid=4"#),
+            (
+                OutputFormat::Csv,
+                PathBuf::from(r"C:\logs\a,b.csv"),
+                "pid=4".to_string()
+            )
+        );
+        assert_eq!(
+            sink(r#"json, "a,b.jsonl" "#),
+            (
+                OutputFormat::Json,
+                PathBuf::from("a,b.jsonl"),
+                String::new()
+            )
+        );
+    }
+
+    #[test]
+    fn bad_sinks_are_rejected() {
+        for value in [
+            "json",
+            "json,",
+            "xml,out.xml",
+            r#"json,"a,b.jsonl"#,
+            r#"json,"a.jsonl"x"#,
+            r#"json,"",pid=4"#,
+            "json,out.jsonl,pid=four",
+        ] {
+            assert!(parse_sink(value).is_err(), "{value:?} parsed");
+        }
+    }
+}
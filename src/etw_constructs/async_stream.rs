@@ -0,0 +1,206 @@
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+    thread,
+};
+
+use futures_core::Stream;
+
+use super::{
+    error::{EtwError, EtwResult},
+    parsed_event::ParsedEvent,
+    pipeline_error::PipelineErrorCounters,
+    stop_handle::StopHandle,
+    stream::EventStream,
+};
+
+/// What happens when events arrive faster than the stream is polled and its buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backpressure {
+    /// Wait for room. ProcessTrace stalls behind the stream, so ETW loses events once its own buffers fill
+    #[default]
+    Block,
+    /// Drop the oldest buffered event to make room, see [`AsyncEventStream::dropped`]
+    DropOldest,
+}
+
+/// Options of an [`AsyncEventStream`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AsyncStreamConfig {
+    /// Most events buffered before [`AsyncStreamConfig::backpressure`] applies
+    pub capacity: usize,
+    pub backpressure: Backpressure,
+}
+
+impl Default for AsyncStreamConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            backpressure: Backpressure::default(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ChannelState {
+    events: VecDeque<ParsedEvent>,
+    waker: Option<Waker>,
+    dropped: u64,
+    finished: bool,                // The session stopped and every event was forwarded
+    receiver_dropped: bool,        // Nothing will poll the stream again
+    result: Option<EtwResult<()>>, // What ProcessTrace returned, once finished
+}
+
+/// Bounded queue between the forwarding thread and whatever task polls the stream
+struct Channel {
+    state: Mutex<ChannelState>,
+    not_full: Condvar,
+    config: AsyncStreamConfig,
+}
+
+impl Channel {
+    /// Queues `event` as the backpressure policy says. Returns false once the stream has been dropped
+    fn push(&self, event: ParsedEvent) -> bool {
+        let mut state = self._state();
+        if state.events.len() >= self.config.capacity.max(1) {
+            match self.config.backpressure {
+                Backpressure::Block => {
+                    state = self
+                        .not_full
+                        .wait_while(state, |state| {
+                            state.events.len() >= self.config.capacity.max(1)
+                                && !state.receiver_dropped
+                        })
+                        .expect("Async stream lock was poisoned");
+                }
+                Backpressure::DropOldest => {
+                    state.events.pop_front();
+                    state.dropped += 1;
+                }
+            }
+        }
+        if state.receiver_dropped {
+            return false;
+        }
+
+        state.events.push_back(event);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        true
+    }
+
+    fn finish(&self, result: EtwResult<()>) {
+        let mut state = self._state();
+        state.finished = true;
+        state.result = Some(result);
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn _state(&self) -> MutexGuard<'_, ChannelState> {
+        self.state.lock().expect("Async stream lock was poisoned")
+    }
+}
+
+/// Events of a session as a [`Stream`], for services that are already async. `ProcessTrace` still runs on a dedicated
+/// thread, and a second thread hands its events one at a time to a bounded buffer that the stream is polled from, the
+/// only place events are queued. Works with any executor
+pub struct AsyncEventStream {
+    channel: Arc<Channel>,
+    stop_handle: StopHandle,
+    pipeline_errors: Arc<PipelineErrorCounters>,
+}
+
+impl AsyncEventStream {
+    pub(crate) fn new(mut events: EventStream, config: AsyncStreamConfig) -> EtwResult<Self> {
+        let channel = Arc::new(Channel {
+            state: Mutex::default(),
+            not_full: Condvar::new(),
+            config,
+        });
+        let stop_handle = events.stop_handle();
+        let pipeline_errors = events.pipeline_errors();
+
+        // Detached, it winds the session down on its own once the stream is dropped
+        {
+            let channel = Arc::clone(&channel);
+            thread::Builder::new()
+                .name("etw-async-forwarder".to_string())
+                .spawn(move || {
+                    for event in events.by_ref() {
+                        if !channel.push(event) {
+                            // Draining lets a ProcessTrace blocked on a full channel finish
+                            events.stop();
+                            events.by_ref().for_each(drop);
+                            break;
+                        }
+                    }
+                    channel.finish(events.join());
+                })
+                .map_err(|err| EtwError::from_io(&err, "Could not spawn the forwarding thread"))?;
+        }
+
+        Ok(Self {
+            channel,
+            stop_handle,
+            pipeline_errors,
+        })
+    }
+
+    /// A handle that stops the session from any thread
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop_handle.clone()
+    }
+
+    /// Stops the session. Events already buffered are still yielded before the stream ends
+    pub fn stop(&self) {
+        self.stop_handle.stop();
+    }
+
+    /// Same as [`EventStream::pipeline_errors`]
+    pub fn pipeline_errors(&self) -> Arc<PipelineErrorCounters> {
+        Arc::clone(&self.pipeline_errors)
+    }
+
+    /// How many events [`Backpressure::DropOldest`] has dropped so far
+    pub fn dropped(&self) -> u64 {
+        self.channel._state().dropped
+    }
+
+    /// What `ProcessTrace` returned, once the stream has ended. Can only be taken once
+    pub fn take_result(&self) -> Option<EtwResult<()>> {
+        self.channel._state().result.take()
+    }
+}
+
+impl Stream for AsyncEventStream {
+    type Item = ParsedEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut state = self.channel._state();
+        if let Some(event) = state.events.pop_front() {
+            self.channel.not_full.notify_one();
+            return Poll::Ready(Some(event));
+        }
+        if state.finished {
+            return Poll::Ready(None);
+        }
+
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Stops the session without waiting for it to wind down, so dropping the stream never blocks the task that owned
+/// it. The forwarding thread drains what is left and joins the session thread on its own
+impl Drop for AsyncEventStream {
+    fn drop(&mut self) {
+        self.stop_handle.stop();
+        self.channel._state().receiver_dropped = true;
+        self.channel.not_full.notify_all();
+    }
+}
@@ -0,0 +1,153 @@
+use std::{collections::BTreeMap, time::SystemTime};
+
+use windows::{
+    core::GUID,
+    Win32::System::Diagnostics::Etw::{CONTROLTRACE_HANDLE, EVENT_RECORD},
+};
+
+use super::{
+    bookmark::{log_strings, read_strings},
+    parsed_event::PropertyValue,
+};
+
+/// Provider GUID of the meta-events the controller logs into its own session for every change it makes, so a trace
+/// records what was being collected when
+pub const AUDIT_GUID: GUID = GUID::from_u128(0x7c2e9a41_5b3d_4f60_8a1e_2d9f6b0c4e57);
+
+/// A change the controller made to its session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlAction {
+    /// The session was started
+    Start {
+        enable_flags: u32,
+        log_file_mode: u32,
+    },
+    EnableProvider {
+        provider: GUID,
+        level: u8,
+        match_any_keyword: u64,
+    },
+    DisableProvider {
+        provider: GUID,
+    },
+    UpdateFlags {
+        enable_flags: u32,
+    },
+    UpdateBuffers {
+        maximum_buffers: u32,
+        flush_timer_secs: u32,
+    },
+    /// Stack tracing was turned on for this many kernel events
+    StackWalk {
+        events: usize,
+    },
+    MemInfo,
+}
+
+impl ControlAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControlAction::Start { .. } => "Start",
+            ControlAction::EnableProvider { .. } => "EnableProvider",
+            ControlAction::DisableProvider { .. } => "DisableProvider",
+            ControlAction::UpdateFlags { .. } => "UpdateFlags",
+            ControlAction::UpdateBuffers { .. } => "UpdateBuffers",
+            ControlAction::StackWalk { .. } => "StackWalk",
+            ControlAction::MemInfo => "MemInfo",
+        }
+    }
+
+    /// The parameters of the action as names and display strings, in the order they are logged
+    pub fn parameters(&self) -> Vec<(&'static str, String)> {
+        let guid = |guid: &GUID| PropertyValue::Guid(*guid).to_string();
+
+        match self {
+            ControlAction::Start {
+                enable_flags,
+                log_file_mode,
+            } => vec![
+                ("EnableFlags", format!("{enable_flags:#x}")),
+                ("LogFileMode", format!("{log_file_mode:#x}")),
+            ],
+            ControlAction::EnableProvider {
+                provider,
+                level,
+                match_any_keyword,
+            } => vec![
+                ("Provider", guid(provider)),
+                ("Level", level.to_string()),
+                ("MatchAnyKeyword", format!("{match_any_keyword:#x}")),
+            ],
+            ControlAction::DisableProvider { provider } => vec![("Provider", guid(provider))],
+            ControlAction::UpdateFlags { enable_flags } => {
+                vec![("EnableFlags", format!("{enable_flags:#x}"))]
+            }
+            ControlAction::UpdateBuffers {
+                maximum_buffers,
+                flush_timer_secs,
+            } => vec![
+                ("MaximumBuffers", maximum_buffers.to_string()),
+                ("FlushTimer", flush_timer_secs.to_string()),
+            ],
+            ControlAction::StackWalk { events } => vec![("Events", events.to_string())],
+            ControlAction::MemInfo => Vec::new(),
+        }
+    }
+
+    /// The strings logged for the action: its name, then each parameter name followed by its value
+    pub(crate) fn to_strings(&self) -> Vec<String> {
+        let mut strings = vec![self.name().to_string()];
+        for (name, value) in self.parameters() {
+            strings.push(name.to_string());
+            strings.push(value);
+        }
+        strings
+    }
+}
+
+/// Logs `action` into the session as an [`AUDIT_GUID`] event. Returns whether it was logged
+pub(crate) fn log(trace_handle: CONTROLTRACE_HANDLE, action: &ControlAction) -> bool {
+    let strings = action.to_strings();
+    let strings: Vec<&str> = strings.iter().map(String::as_str).collect();
+    log_strings(trace_handle, AUDIT_GUID, &strings).is_ok()
+}
+
+/// An action the controller took, kept in memory alongside the meta-event logged for it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub action: ControlAction,
+    pub time: SystemTime,
+    /// Whether the meta-event made it into the session. Auditing never fails the action itself
+    pub logged: bool,
+}
+
+/// A meta-event read back from a trace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEvent {
+    /// e.g. EnableProvider
+    pub action: String,
+    pub parameters: BTreeMap<String, String>,
+    pub timestamp: i64,
+}
+
+impl AuditEvent {
+    /// Returns the meta-event if `record` was logged by a controller for one of its actions
+    pub fn from_record(record: &EVENT_RECORD) -> Option<Self> {
+        if record.EventHeader.ProviderId != AUDIT_GUID {
+            return None;
+        }
+
+        let mut strings = read_strings(record).into_iter();
+        let action = strings.next().unwrap_or_default();
+        let mut parameters = BTreeMap::new();
+        while let (Some(name), Some(value)) = (strings.next(), strings.next()) {
+            parameters.insert(name, value);
+        }
+
+        Some(Self {
+            action,
+            parameters,
+            timestamp: record.EventHeader.TimeStamp,
+        })
+    }
+}
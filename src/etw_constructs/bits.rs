@@ -0,0 +1,110 @@
+use windows::{core::GUID, Win32::System::Diagnostics::Etw::TRACE_LEVEL_INFORMATION};
+
+use super::{controller::ProviderConfig, parsed_event::ParsedEvent, schemas::Fields};
+
+/// Microsoft-Windows-Bits-Client. Logs background transfer jobs and the URLs they download from
+pub const BITS_CLIENT_GUID: GUID = GUID::from_u128(0xef1cc15b_46c1_414e_bb95_e76b077bd51e);
+
+// Events of the Operational channel
+const EVENT_JOB_CREATED: u16 = 3;
+const EVENT_JOB_COMPLETED: u16 = 4;
+const EVENT_TRANSFER_STARTED: u16 = 59;
+const EVENT_TRANSFER_STOPPED: u16 = 60;
+
+/// Enables every BITS client event at information level
+pub fn provider() -> ProviderConfig {
+    ProviderConfig {
+        guid: BITS_CLIENT_GUID,
+        level: TRACE_LEVEL_INFORMATION as u8,
+        match_any_keyword: 0,
+    }
+}
+
+/// What happened to a BITS job
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BitsActivity {
+    /// `process_path` and `process_id` are the process that created the job, not the BITS service logging the event
+    Created {
+        owner: String,
+        process_path: String,
+        process_id: u32,
+    },
+    TransferStarted {
+        url: String,
+        bytes_total: u64,
+    },
+    /// `hresult` is 0 if the transfer succeeded
+    TransferStopped {
+        url: String,
+        hresult: u32,
+        bytes_transferred: u64,
+    },
+    Completed {
+        owner: String,
+        file_count: u32,
+        bytes_transferred: u64,
+    },
+}
+
+/// A BITS job event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitsEvent {
+    pub job_id: String,
+    pub job_title: String,
+    pub activity: BitsActivity,
+    pub timestamp: i64,
+}
+
+impl BitsEvent {
+    /// Returns the event if it is a BITS job creation, transfer or completion event
+    pub fn from_event(event: &ParsedEvent) -> Option<Self> {
+        let fields = Fields::new(event, BITS_CLIENT_GUID).ok()?;
+
+        // Transfer events name the job `name` and `Id`, the others `jobTitle` and `jobId`
+        let (job_title, job_id, activity) = match event.event_id {
+            EVENT_JOB_CREATED => (
+                fields.string("jobTitle"),
+                fields.string("jobId"),
+                BitsActivity::Created {
+                    owner: fields.string("jobOwner"),
+                    process_path: fields.string("processPath"),
+                    process_id: fields.optional("processId") as u32,
+                },
+            ),
+            EVENT_JOB_COMPLETED => (
+                fields.string("jobTitle"),
+                fields.string("jobId"),
+                BitsActivity::Completed {
+                    owner: fields.string("jobOwner"),
+                    file_count: fields.optional("fileCount") as u32,
+                    bytes_transferred: fields.optional("bytesTransferred"),
+                },
+            ),
+            EVENT_TRANSFER_STARTED => (
+                fields.string("name"),
+                fields.string("Id"),
+                BitsActivity::TransferStarted {
+                    url: fields.string("url"),
+                    bytes_total: fields.optional("bytesTotal"),
+                },
+            ),
+            EVENT_TRANSFER_STOPPED => (
+                fields.string("name"),
+                fields.string("Id"),
+                BitsActivity::TransferStopped {
+                    url: fields.string("url"),
+                    hresult: fields.optional("hr") as u32,
+                    bytes_transferred: fields.optional("bytesTransferred"),
+                },
+            ),
+            _ => return None,
+        };
+
+        Some(Self {
+            job_id,
+            job_title,
+            activity,
+            timestamp: event.timestamp,
+        })
+    }
+}
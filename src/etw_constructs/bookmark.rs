@@ -0,0 +1,151 @@
+use core::slice;
+use std::mem;
+
+use windows::{
+    core::GUID,
+    Win32::{
+        Foundation::{ERROR_SUCCESS, WIN32_ERROR},
+        System::Diagnostics::Etw::{
+            TraceEvent, CONTROLTRACE_HANDLE, EVENT_RECORD, EVENT_TRACE_HEADER,
+            EVENT_TRACE_HEADER_2, EVENT_TRACE_HEADER_3, EVENT_TRACE_HEADER_3_1,
+            WNODE_FLAG_TRACED_GUID,
+        },
+    },
+};
+
+use super::error::{EtwError, EtwResult};
+
+/// Provider GUID of the marker events written by [`Bookmarker::mark`]
+pub const BOOKMARK_GUID: GUID = GUID::from_u128(0x5b1c6f4e_2d7a_4c39_9e0b_8f3a6d2e7c11);
+
+/// Writes labelled marker events into a running session, so moments like "repro happened here" can be found later.
+/// Cheap to copy, so it can be handed to a hotkey or input thread while the session is being consumed
+#[derive(Debug, Clone, Copy)]
+pub struct Bookmarker {
+    trace_handle: CONTROLTRACE_HANDLE,
+}
+
+impl Bookmarker {
+    pub(crate) fn new(trace_handle: CONTROLTRACE_HANDLE) -> Self {
+        Self { trace_handle }
+    }
+
+    /// Logs a marker event with `label` to the session with [`TraceEvent`]
+    pub fn mark(&self, label: &str) -> EtwResult<()> {
+        log_strings(self.trace_handle, BOOKMARK_GUID, &[label]).map_err(|status| EtwError::Win32 {
+            status,
+            context: "TraceEvent could not log the bookmark".to_string(),
+        })
+    }
+}
+
+/// Logs an event of `provider` to the session with [`TraceEvent`]. The strings are stored one after another as nul
+/// terminated UTF-16 after the header, and read back with [`read_strings`]. The header's Size field limits an event to
+/// 64KB, so the string that would go past it is cut short and those after it are left empty
+pub(crate) fn log_strings(
+    trace_handle: CONTROLTRACE_HANDLE,
+    provider: GUID,
+    strings: &[&str],
+) -> Result<(), WIN32_ERROR> {
+    let header_size = mem::size_of::<EVENT_TRACE_HEADER>();
+    let strings = fit_strings(
+        strings,
+        (u16::MAX as usize - header_size) / mem::size_of::<u16>(),
+    );
+    let strings_size = strings.len() * mem::size_of::<u16>();
+
+    let header = EVENT_TRACE_HEADER {
+        Size: (header_size + strings_size) as u16,
+        Anonymous3: EVENT_TRACE_HEADER_2 { Guid: provider },
+        Anonymous4: EVENT_TRACE_HEADER_3 {
+            Anonymous2: EVENT_TRACE_HEADER_3_1 {
+                ClientContext: 0,
+                Flags: WNODE_FLAG_TRACED_GUID,
+            },
+        },
+        ..Default::default()
+    };
+
+    // The event is laid out as [EVENT_TRACE_HEADER][string\0][string\0]...
+    let mut event_buf: Vec<u8> = Vec::with_capacity(header_size + strings_size);
+    event_buf.extend_from_slice(unsafe {
+        slice::from_raw_parts(
+            &header as *const EVENT_TRACE_HEADER as *const u8,
+            header_size,
+        )
+    });
+    event_buf.extend(strings.iter().flat_map(|x| x.to_le_bytes()));
+
+    match unsafe {
+        TraceEvent(
+            trace_handle,
+            event_buf.as_ptr() as *const EVENT_TRACE_HEADER,
+        )
+    } {
+        ERROR_SUCCESS => Ok(()),
+        status => Err(status),
+    }
+}
+
+/// Encodes `strings` as nul terminated UTF-16 in at most `max_units` code units, keeping room for every terminator.
+/// A string is never cut between the two halves of a surrogate pair
+fn fit_strings(strings: &[&str], max_units: usize) -> Vec<u16> {
+    let mut units = Vec::new();
+    for (index, string) in strings.iter().enumerate() {
+        let room = max_units.saturating_sub(units.len() + strings.len() - index);
+        let start = units.len();
+        units.extend(string.encode_utf16().take(room));
+        if units.len() - start == room
+            && units
+                .last()
+                .is_some_and(|unit| (0xd800..0xdc00).contains(unit))
+        {
+            units.pop();
+        }
+        units.push(0);
+    }
+    units
+}
+
+/// Reads back the nul terminated UTF-16 strings of an event written by [`log_strings`]
+pub(crate) fn read_strings(record: &EVENT_RECORD) -> Vec<String> {
+    let userdata: &[u8] = if record.UserData.is_null() {
+        &[]
+    } else {
+        unsafe {
+            slice::from_raw_parts(record.UserData as *const u8, record.UserDataLength as usize)
+        }
+    };
+
+    let units: Vec<u16> = userdata
+        .chunks_exact(2)
+        .map(|x| u16::from_le_bytes([x[0], x[1]]))
+        .collect();
+    let units = units.strip_suffix(&[0]).unwrap_or(&units);
+
+    units
+        .split(|x| *x == 0)
+        .map(String::from_utf16_lossy)
+        .collect()
+}
+
+/// A marker event read back from a trace
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bookmark {
+    pub label: String,
+    pub timestamp: i64,
+}
+
+impl Bookmark {
+    /// Returns the bookmark if `record` was written by [`Bookmarker::mark`]
+    pub fn from_record(record: &EVENT_RECORD) -> Option<Self> {
+        if record.EventHeader.ProviderId != BOOKMARK_GUID {
+            return None;
+        }
+
+        Some(Self {
+            label: read_strings(record).into_iter().next().unwrap_or_default(),
+            timestamp: record.EventHeader.TimeStamp,
+        })
+    }
+}
@@ -0,0 +1,131 @@
+use windows::{core::GUID, Win32::System::Diagnostics::Etw::TRACE_LEVEL_VERBOSE};
+
+use super::{
+    controller::ProviderConfig,
+    parsed_event::{ParsedEvent, PropertyValue},
+    schemas::Fields,
+};
+
+/// Microsoft-JScript. Logs script sources and JIT compiled functions of the Chakra engines used by IE and legacy Edge
+pub const JSCRIPT_GUID: GUID = GUID::from_u128(0x57277741_3638_4a4b_bdba_0ac6e45da56c);
+
+/// Chrome. Chrome forwards its trace events to ETW through this TraceLogging provider when it is enabled
+pub const CHROME_GUID: GUID = GUID::from_u128(0xd2d578d9_2936_45b6_a09f_30e32715f42d);
+
+/// Microsoft.MSEdgeStable. The same trace events as [`CHROME_GUID`], logged by Chromium based Edge
+pub const EDGE_GUID: GUID = GUID::from_u128(0x3a5f2396_5c8f_4f1f_9b67_6cca6c990e61);
+
+// Chromium trace event phases, https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+const PHASE_BEGIN: &str = "Begin";
+const PHASE_END: &str = "End";
+const PHASE_COMPLETE: &str = "Complete";
+
+/// Enables the script engine and Chromium providers at verbose level. With no keywords set, Chromium logs its default
+/// trace categories
+pub fn providers() -> [ProviderConfig; 3] {
+    [JSCRIPT_GUID, CHROME_GUID, EDGE_GUID].map(|guid| ProviderConfig {
+        guid,
+        level: TRACE_LEVEL_VERBOSE as u8,
+        match_any_keyword: 0,
+    })
+}
+
+/// A Microsoft-JScript event about a script or the functions compiled from it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScriptEvent {
+    /// A script was loaded into a script context
+    SourceLoad { source_id: u64, url: String },
+    /// A function was JIT compiled. `source_id` is the [`ScriptEvent::SourceLoad`] it came from
+    MethodLoad {
+        source_id: u64,
+        method_name: String,
+        start_address: u64,
+        size: u64,
+        line: u32,
+        column: u32,
+    },
+}
+
+impl ScriptEvent {
+    /// Returns the event if it is a script source or method load. They are told apart by their fields, since the
+    /// event ids differ between versions of the engine
+    pub fn from_event(event: &ParsedEvent) -> Option<Self> {
+        let fields = Fields::new(event, JSCRIPT_GUID).ok()?;
+
+        if let Some(method_name) = event.get("MethodName") {
+            return Some(Self::MethodLoad {
+                source_id: fields.optional("SourceID"),
+                method_name: method_name.to_string(),
+                start_address: fields.optional("MethodStartAddress"),
+                size: fields.optional("MethodSize"),
+                line: fields.optional("Line") as u32,
+                column: fields.optional("Column") as u32,
+            });
+        }
+
+        event.get("Url").map(|url| Self::SourceLoad {
+            source_id: fields.optional("SourceID"),
+            url: url.to_string(),
+        })
+    }
+}
+
+/// A Chromium trace event, such as a navigation, a task on the main thread or a V8 garbage collection
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChromiumEvent {
+    pub name: String,
+    /// e.g. Begin, End, Complete or Instant
+    pub phase: String,
+    /// Every argument of the trace event, in the order of their names
+    pub args: Vec<(String, PropertyValue)>,
+    pub process_id: u32,
+    pub thread_id: u32,
+    pub timestamp: i64,
+}
+
+impl ChromiumEvent {
+    /// Returns the event if it was logged by Chrome or Edge. Older versions log the name and phase as fields,
+    /// newer ones use the TraceLogging event name
+    pub fn from_event(event: &ParsedEvent) -> Option<Self> {
+        if event.provider != CHROME_GUID && event.provider != EDGE_GUID {
+            return None;
+        }
+
+        let name = event
+            .get("Name")
+            .map(ToString::to_string)
+            .or_else(|| event.event_name.clone())
+            .or_else(|| event.task_name.clone())?;
+
+        Some(Self {
+            name,
+            phase: event
+                .get("Phase")
+                .map(ToString::to_string)
+                .unwrap_or_default(),
+            args: event
+                .properties
+                .iter()
+                .filter(|(name, _)| !matches!(name.as_str(), "Name" | "Phase"))
+                .map(|(name, value)| (name.clone(), value.clone()))
+                .collect(),
+            process_id: event.process_id,
+            thread_id: event.thread_id,
+            timestamp: event.timestamp,
+        })
+    }
+
+    /// Whether this event opens a span that a later [`ChromiumEvent::is_end`] event with the same name closes
+    pub fn is_begin(&self) -> bool {
+        self.phase == PHASE_BEGIN
+    }
+
+    pub fn is_end(&self) -> bool {
+        self.phase == PHASE_END
+    }
+
+    /// Whether this event is a whole span on its own
+    pub fn is_complete(&self) -> bool {
+        self.phase == PHASE_COMPLETE
+    }
+}
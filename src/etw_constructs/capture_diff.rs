@@ -0,0 +1,160 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    net::SocketAddr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    parsed_event::ParsedEvent,
+    schemas::{
+        NetworkEvent, NetworkOpcode, ProcessEvent, ProcessOpcode, RegistryEvent, RegistryOpcode,
+    },
+};
+
+/// Registry keys that start programs on boot or logon, as lowercase substrings of the kernel's key paths
+const AUTOSTART_KEYS: [&str; 7] = [
+    r"\microsoft\windows\currentversion\run", // Also RunOnce, RunOnceEx and RunServices
+    r"\microsoft\windows\currentversion\policies\explorer\run",
+    r"\microsoft\windows nt\currentversion\winlogon",
+    r"\microsoft\windows nt\currentversion\windows", // AppInit_DLLs
+    r"\microsoft\windows nt\currentversion\image file execution options",
+    r"\microsoft\active setup\installed components",
+    r"\currentcontrolset\services\",
+];
+
+/// What a capture did, reduced to what is worth comparing between two captures. Built from a trace with
+/// [`CaptureSummary::add`], and saved as JSON so a baseline does not have to be parsed again
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct CaptureSummary {
+    /// Image names of every process started or running, lowercase
+    pub processes: BTreeSet<String>,
+    /// Addresses TCP connections were made and UDP datagrams were sent to, as `address:port/protocol`
+    pub network_destinations: BTreeSet<String>,
+    /// Values set under autostart keys such as Run and Services, as the full key path and value name, lowercase
+    pub autostart_writes: BTreeSet<String>,
+    /// Key paths by key control block, learned from KCB events so value writes can be given their full path
+    #[serde(skip)]
+    key_names: HashMap<u64, String>,
+}
+
+impl CaptureSummary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes in one event. Needs the process, network and registry kernel flags for each part of the summary
+    pub fn add(&mut self, event: &ParsedEvent) {
+        if let Ok(process) = ProcessEvent::try_from(event) {
+            if matches!(
+                process.opcode,
+                ProcessOpcode::Start | ProcessOpcode::DcStart
+            ) {
+                self.processes
+                    .insert(process.image_file_name.to_lowercase());
+            }
+        } else if let Ok(network) = NetworkEvent::try_from(event) {
+            let outbound = match network.opcode {
+                NetworkOpcode::Connect => !network.udp,
+                NetworkOpcode::Send => network.udp,
+                _ => false,
+            };
+            if outbound {
+                self.network_destinations.insert(format!(
+                    "{}/{}",
+                    SocketAddr::new(network.destination, network.destination_port),
+                    if network.udp { "udp" } else { "tcp" }
+                ));
+            }
+        } else if let Ok(registry) = RegistryEvent::try_from(event) {
+            self._add_registry(registry);
+        }
+    }
+
+    fn _add_registry(&mut self, registry: RegistryEvent) {
+        match registry.opcode {
+            RegistryOpcode::KcbCreate | RegistryOpcode::KcbRundownBegin => {
+                self.key_names
+                    .insert(registry.key_handle, registry.key_name.to_lowercase());
+            }
+            RegistryOpcode::KcbDelete | RegistryOpcode::KcbRundownEnd => {
+                self.key_names.remove(&registry.key_handle);
+            }
+            // Value events name the value, relative to the key their handle points at
+            RegistryOpcode::SetValue if registry.status == 0 => {
+                let value = registry.key_name.to_lowercase();
+                let path = match self.key_names.get(&registry.key_handle) {
+                    Some(key) => format!(r"{key}\{value}"),
+                    None => value,
+                };
+                if Self::_is_autostart(&path) {
+                    self.autostart_writes.insert(path);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether `path`, lowercase, is under one of the [`AUTOSTART_KEYS`] or the services key of a control set. The
+    /// kernel names keys by their real path, so services show up under `\controlset001\services\` and the other
+    /// numbered control sets rather than under CurrentControlSet, which only links to one of them
+    fn _is_autostart(path: &str) -> bool {
+        AUTOSTART_KEYS.iter().any(|key| path.contains(key))
+            || path.match_indices(r"\controlset").any(|(start, found)| {
+                let rest = &path[start + found.len()..];
+                let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+                digits > 0 && rest[digits..].starts_with(r"\services\")
+            })
+    }
+
+    /// What `after` has that this summary does not
+    pub fn diff(&self, after: &CaptureSummary) -> CaptureDiff {
+        let new = |before: &BTreeSet<String>, after: &BTreeSet<String>| {
+            after.difference(before).cloned().collect()
+        };
+
+        CaptureDiff {
+            new_processes: new(&self.processes, &after.processes),
+            new_network_destinations: new(&self.network_destinations, &after.network_destinations),
+            new_autostart_writes: new(&self.autostart_writes, &after.autostart_writes),
+        }
+    }
+}
+
+/// What changed between two captures, such as before and after installing something. Only additions are reported,
+/// a process that did not happen to run in the second capture is not a change
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize)]
+pub struct CaptureDiff {
+    pub new_processes: Vec<String>,
+    pub new_network_destinations: Vec<String>,
+    pub new_autostart_writes: Vec<String>,
+}
+
+impl CaptureDiff {
+    pub fn is_empty(&self) -> bool {
+        self.new_processes.is_empty()
+            && self.new_network_destinations.is_empty()
+            && self.new_autostart_writes.is_empty()
+    }
+}
+
+/// Lists each kind of change under its own heading, skipping kinds with no changes
+impl fmt::Display for CaptureDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (heading, changes) in [
+            ("New processes", &self.new_processes),
+            ("New network destinations", &self.new_network_destinations),
+            ("New autostart registry writes", &self.new_autostart_writes),
+        ] {
+            if changes.is_empty() {
+                continue;
+            }
+            writeln!(f, "{heading}:")?;
+            for change in changes {
+                writeln!(f, "    {change}")?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use std::{path::Path, time::SystemTime};
+
+use serde::Serialize;
+use windows::{
+    core::{GUID, PWSTR},
+    Win32::System::SystemInformation::{ComputerNamePhysicalDnsHostname, GetComputerNameExW},
+};
+
+use super::{
+    clock,
+    consumer::TraceHeaderInfo,
+    controller::{ControllerConfig, ProviderConfig},
+    parsed_event::serialize_guid,
+    system_config::MachineProfile,
+    validation::SystemCapabilities,
+};
+
+/// A provider enabled in the session, as listed in a [`CaptureHeader`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HeaderProvider {
+    #[serde(serialize_with = "serialize_guid")]
+    pub guid: GUID,
+    pub level: u8,
+    /// As hex, e.g. `0x10`
+    pub match_any_keyword: String,
+}
+
+impl From<&ProviderConfig> for HeaderProvider {
+    fn from(provider: &ProviderConfig) -> Self {
+        Self {
+            guid: provider.guid,
+            level: provider.level,
+            match_any_keyword: format!("{:#x}", provider.match_any_keyword),
+        }
+    }
+}
+
+/// What a capture was taken with and where, written at the start of every file output so an archived capture can be
+/// interpreted without the command line it was taken with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CaptureHeader {
+    pub tool_version: &'static str,
+    /// The machine the capture was taken on. None for a replay, since .etl files do not record it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_name: Option<String>,
+    /// Windows build number, e.g. 19045. None if it could not be read
+    pub os_build: Option<u32>,
+    /// None when replaying a recorded .etl file
+    pub session_name: Option<String>,
+    /// The .etl file the events were replayed from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trace: Option<String>,
+    /// Kernel event classes enabled, as hex
+    pub enable_flags: String,
+    pub providers: Vec<HeaderProvider>,
+    pub buffer_size_kb: u32,
+    pub maximum_buffers: u32,
+    /// Processes the session kept events of. Empty if it kept every process
+    pub process_ids: Vec<u32>,
+    /// Expression of the filter in front of this output. None if every event was written
+    pub filter: Option<String>,
+    /// RFC 3339. For a replay, when the trace was started
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<String>,
+    /// Hardware and OS of the machine the events were logged on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub machine: Option<MachineProfile>,
+}
+
+impl CaptureHeader {
+    /// The header of a live session started with `config`
+    pub fn for_session(session_name: &str, config: &ControllerConfig) -> Self {
+        Self {
+            session_name: Some(session_name.to_string()),
+            enable_flags: format!("{:#x}", config.effective_flags().0),
+            providers: config.providers.iter().map(HeaderProvider::from).collect(),
+            buffer_size_kb: config.buffers.buffer_size_kb,
+            maximum_buffers: config.buffers.maximum_buffers,
+            host_name: Self::_host_name(),
+            os_build: SystemCapabilities::detect()
+                .ok()
+                .map(|capabilities| capabilities.build_number),
+            start_time: Some(clock::rfc3339_from_ticks(clock::ticks_from_system_time(
+                SystemTime::now(),
+            ))),
+            machine: Some(MachineProfile::detect()),
+            ..Self::_empty()
+        }
+    }
+
+    /// The header of a replay of the .etl file at `path`, with the build and start time from the file's `header`. The
+    /// session it was recorded with and the host are not known
+    pub fn for_trace(path: &Path, header: Option<&TraceHeaderInfo>) -> Self {
+        Self {
+            trace: Some(path.display().to_string()),
+            // The high bits of the logged build number are flags, as in NtBuildNumber
+            os_build: header
+                .map(|header| header.provider_version & 0xffff)
+                .filter(|build| *build != 0),
+            start_time: header
+                .map(|header| header.start_time)
+                .filter(|start| *start != 0)
+                .map(clock::rfc3339_from_ticks),
+            machine: header.map(MachineProfile::from_trace_header),
+            ..Self::_empty()
+        }
+    }
+
+    pub fn process_ids(mut self, process_ids: Vec<u32>) -> Self {
+        self.process_ids = process_ids;
+        self
+    }
+
+    /// The same header for an output behind `filter`. An empty expression lets every event through
+    pub fn with_filter(&self, filter: &str) -> Self {
+        Self {
+            filter: Some(filter.to_string()).filter(|filter| !filter.is_empty()),
+            ..self.clone()
+        }
+    }
+
+    fn _empty() -> Self {
+        Self {
+            tool_version: env!("CARGO_PKG_VERSION"),
+            host_name: None,
+            os_build: None,
+            session_name: None,
+            trace: None,
+            enable_flags: "0x0".to_string(),
+            providers: Vec::new(),
+            buffer_size_kb: 0,
+            maximum_buffers: 0,
+            process_ids: Vec::new(),
+            filter: None,
+            start_time: None,
+            machine: None,
+        }
+    }
+
+    /// None if the name could not be read
+    fn _host_name() -> Option<String> {
+        let mut buf = [0u16; 256];
+        let mut size = buf.len() as u32;
+        match unsafe {
+            GetComputerNameExW(
+                ComputerNamePhysicalDnsHostname,
+                PWSTR(buf.as_mut_ptr()),
+                &mut size,
+            )
+        } {
+            Ok(()) => Some(String::from_utf16_lossy(&buf[..size as usize])),
+            Err(_) => None,
+        }
+    }
+}
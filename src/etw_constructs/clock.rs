@@ -0,0 +1,310 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use windows::Win32::{
+    Foundation::{FILETIME, WIN32_ERROR},
+    System::{
+        Performance::QueryPerformanceCounter,
+        SystemInformation::{GetSystemTimeAsFileTime, GetSystemTimePreciseAsFileTime},
+    },
+};
+
+use super::{
+    consumer::TraceHeaderInfo,
+    error::{EtwError, EtwResult},
+};
+
+/// Where a consumer gets the time that `ProcessTrace` starts delivering events from
+pub trait Clock {
+    fn now(&self) -> EtwResult<FILETIME>;
+}
+
+/// The real clock, read with [`GetSystemTimeAsFileTime`]. Used by real-time sessions unless another clock is given.
+/// It is in UTC like event timestamps, whatever the time zone of the machine
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalClock;
+
+impl Clock for LocalClock {
+    fn now(&self) -> EtwResult<FILETIME> {
+        Ok(unsafe { GetSystemTimeAsFileTime() })
+    }
+}
+
+/// Always returns the same time, given in FILETIME ticks. Lets tests run against a known start time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FixedClock(pub i64);
+
+impl Clock for FixedClock {
+    fn now(&self) -> EtwResult<FILETIME> {
+        Ok(filetime_from_ticks(self.0))
+    }
+}
+
+/// The start time recorded in a trace's logfile header, so a recorded .etl file is replayed from its own beginning
+/// rather than from whenever it happens to be opened
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TraceClock {
+    start_time: i64,
+}
+
+impl From<&TraceHeaderInfo> for TraceClock {
+    fn from(header: &TraceHeaderInfo) -> Self {
+        Self {
+            start_time: header.start_time,
+        }
+    }
+}
+
+impl Clock for TraceClock {
+    fn now(&self) -> EtwResult<FILETIME> {
+        Ok(filetime_from_ticks(self.start_time))
+    }
+}
+
+/// Converts raw timestamps of the session clock to FILETIME ticks. Some kernel events carry a raw timestamp in their
+/// payload, such as the `InitialTime` of minifilter and registry events, which ProcessTrace leaves as it was logged
+/// while it converts the event timestamp
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SessionClock {
+    /// Raw ticks per second, 0 when the session clock is the system time and raw timestamps already are FILETIME ticks
+    frequency: i64,
+    /// A raw timestamp and the FILETIME ticks it converts to
+    sync_raw: i64,
+    sync_ticks: i64,
+}
+
+impl SessionClock {
+    /// Pairs the current QPC value with the current system time, for the QPC clock real-time sessions are started with
+    pub fn now(header: &TraceHeaderInfo) -> EtwResult<Self> {
+        let mut qpc = 0;
+        unsafe { QueryPerformanceCounter(&mut qpc) }.map_err(|err| EtwError::Win32 {
+            status: WIN32_ERROR::from_error(&err).unwrap_or_default(),
+            context: "Could not read the performance counter".to_string(),
+        })?;
+        let now = unsafe { GetSystemTimePreciseAsFileTime() };
+
+        Ok(Self {
+            frequency: header.perf_freq,
+            sync_raw: qpc,
+            sync_ticks: ((now.dwHighDateTime as i64) << 32) | now.dwLowDateTime as i64,
+        })
+    }
+
+    /// Converts a raw timestamp of the session clock to FILETIME ticks
+    pub fn ticks(&self, raw: i64) -> i64 {
+        if self.frequency <= 0 {
+            return raw;
+        }
+
+        let elapsed =
+            (raw - self.sync_raw) as i128 * TICKS_PER_SECOND as i128 / self.frequency as i128;
+        self.sync_ticks + elapsed as i64
+    }
+}
+
+/// The clock a recorded trace was logged with, from the `ReservedFlags` of its logfile header. Both the QPC and the
+/// CPU cycle counter start counting at boot, so the boot time of the trace pairs with a raw timestamp of 0
+impl From<&TraceHeaderInfo> for SessionClock {
+    fn from(header: &TraceHeaderInfo) -> Self {
+        let frequency = match header.clock_type {
+            2 => 0,
+            3 => header.cpu_speed_mhz as i64 * 1_000_000,
+            _ => header.perf_freq,
+        };
+
+        Self {
+            frequency,
+            sync_raw: 0,
+            sync_ticks: header.boot_time,
+        }
+    }
+}
+
+/// Splits FILETIME ticks (100ns intervals since January 1, 1601) into a [`FILETIME`]
+pub fn filetime_from_ticks(ticks: i64) -> FILETIME {
+    FILETIME {
+        dwLowDateTime: ticks as u32,
+        dwHighDateTime: (ticks >> 32) as u32,
+    }
+}
+
+/// FILETIME ticks between January 1, 1601 and the UNIX epoch
+const UNIX_EPOCH_TICKS: i64 = 116_444_736_000_000_000;
+const TICKS_PER_SECOND: i64 = 10_000_000;
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// Converts FILETIME ticks to a [`SystemTime`]. Event timestamps are already in system time, since ProcessTrace converts
+/// the QPC or CPU cycle timestamps of the trace using its logfile header unless `PROCESS_TRACE_MODE_RAW_TIMESTAMP` is set
+pub fn system_time_from_ticks(ticks: i64) -> SystemTime {
+    let since_unix = ticks - UNIX_EPOCH_TICKS;
+    let duration = Duration::new(
+        (since_unix / TICKS_PER_SECOND).unsigned_abs(),
+        (since_unix % TICKS_PER_SECOND).unsigned_abs() as u32 * 100,
+    );
+
+    if since_unix >= 0 {
+        UNIX_EPOCH + duration
+    } else {
+        UNIX_EPOCH - duration
+    }
+}
+
+/// Converts a [`SystemTime`] back to FILETIME ticks
+pub fn ticks_from_system_time(time: SystemTime) -> i64 {
+    let ticks = |duration: Duration| {
+        duration.as_secs() as i64 * TICKS_PER_SECOND + duration.subsec_nanos() as i64 / 100
+    };
+
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => UNIX_EPOCH_TICKS + ticks(after),
+        Err(before) => UNIX_EPOCH_TICKS - ticks(before.duration()),
+    }
+}
+
+/// Formats FILETIME ticks as an RFC 3339 UTC time with 100ns precision, e.g. `2024-05-01T12:34:56.1234567Z`
+pub fn rfc3339_from_ticks(ticks: i64) -> String {
+    let since_unix = ticks - UNIX_EPOCH_TICKS;
+    let seconds = since_unix.div_euclid(TICKS_PER_SECOND);
+    let fraction = since_unix.rem_euclid(TICKS_PER_SECOND);
+    let days = seconds.div_euclid(SECONDS_PER_DAY);
+    let second_of_day = seconds.rem_euclid(SECONDS_PER_DAY);
+
+    // civil_from_days from https://howardhinnant.github.io/date_algorithms.html
+    let shifted = days + 719_468;
+    let era = shifted.div_euclid(146_097);
+    let day_of_era = shifted.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153; // March is 0
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{fraction:07}Z",
+        second_of_day / 3600,
+        second_of_day % 3600 / 60,
+        second_of_day % 60
+    )
+}
+
+/// Parses an RFC 3339 UTC time, such as the `SystemTime` of an event log record, into FILETIME ticks. Digits of the
+/// fraction past 100ns are dropped. None if it is not a UTC time in that format
+pub fn ticks_from_rfc3339(time: &str) -> Option<i64> {
+    let (date, time) = time.strip_suffix('Z')?.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let (time, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut time = time.splitn(3, ':').map(str::parse::<i64>);
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let fraction: i64 = format!("{:0<7}", &fraction[..fraction.len().min(7)])
+        .parse()
+        .ok()?;
+
+    // days_from_civil from https://howardhinnant.github.io/date_algorithms.html
+    let year = year - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let seconds = days * SECONDS_PER_DAY + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH_TICKS + seconds * TICKS_PER_SECOND + fraction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-05-01T12:34:56.1234567Z
+    const START: i64 = 133_590_404_961_234_567;
+
+    fn ticks(filetime: FILETIME) -> i64 {
+        ((filetime.dwHighDateTime as i64) << 32) | filetime.dwLowDateTime as i64
+    }
+
+    #[test]
+    fn fixed_clock_returns_its_time() {
+        let now = FixedClock(START).now().unwrap();
+
+        assert_eq!(ticks(now), START);
+        assert_eq!(
+            rfc3339_from_ticks(ticks(now)),
+            "2024-05-01T12:34:56.1234567Z"
+        );
+    }
+
+    #[test]
+    fn local_clock_is_in_utc() {
+        let before = ticks_from_system_time(SystemTime::now());
+        let now = ticks(LocalClock.now().unwrap());
+        let after = ticks_from_system_time(SystemTime::now());
+
+        // A local time would be off by the time zone offset, which is whole quarter hours
+        assert!(before - TICKS_PER_SECOND <= now && now <= after + TICKS_PER_SECOND);
+    }
+
+    #[test]
+    fn trace_clock_starts_at_the_trace_start() {
+        let header = TraceHeaderInfo {
+            start_time: START,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            ticks(TraceClock::from(&header).now().unwrap()),
+            ticks(FixedClock(START).now().unwrap())
+        );
+    }
+
+    #[test]
+    fn rfc3339_round_trips() {
+        let now = ticks(FixedClock(START).now().unwrap());
+
+        assert_eq!(ticks_from_rfc3339(&rfc3339_from_ticks(now)), Some(now));
+        assert_eq!(
+            ticks_from_rfc3339("2024-05-01T12:34:56.12345678Z"),
+            Some(now)
+        );
+        assert_eq!(ticks_from_rfc3339("2024-05-01 12:34:56Z"), None);
+        assert_eq!(rfc3339_from_ticks(0), "1601-01-01T00:00:00.0000000Z");
+    }
+
+    #[test]
+    fn system_time_round_trips() {
+        let now = ticks(FixedClock(START).now().unwrap());
+
+        assert_eq!(ticks_from_system_time(system_time_from_ticks(now)), now);
+        assert_eq!(
+            ticks_from_system_time(system_time_from_ticks(UNIX_EPOCH_TICKS - 1)),
+            UNIX_EPOCH_TICKS - 1
+        );
+    }
+
+    #[test]
+    fn session_clock_converts_raw_timestamps_from_boot() {
+        let header = TraceHeaderInfo {
+            boot_time: START,
+            perf_freq: 3_000_000,
+            ..Default::default()
+        };
+        let clock = SessionClock::from(&header);
+
+        assert_eq!(clock.ticks(0), START);
+        assert_eq!(clock.ticks(3_000_000), START + TICKS_PER_SECOND);
+        // The system time clock logs FILETIME ticks already
+        let system = SessionClock::from(&TraceHeaderInfo {
+            clock_type: 2,
+            ..header
+        });
+        assert_eq!(system.ticks(START), START);
+    }
+}
@@ -0,0 +1,160 @@
+use std::{collections::BTreeMap, fmt, time::Duration};
+
+use windows::{
+    core::GUID,
+    Win32::System::Diagnostics::Etw::{TRACE_LEVEL_INFORMATION, TRACE_LEVEL_VERBOSE},
+};
+
+use super::{
+    controller::ProviderConfig,
+    parsed_event::{ParsedEvent, PropertyValue},
+};
+
+/// Microsoft-Windows-DotNETRuntime. Logs garbage collections, allocations, exceptions and more of every .NET process
+pub const DOTNET_RUNTIME_GUID: GUID = GUID::from_u128(0xe13c0d23_ccbc_4e12_931b_d9cc2eee27e4);
+
+pub const KEYWORD_GC: u64 = 0x1;
+pub const KEYWORD_EXCEPTION: u64 = 0x8000;
+
+pub(crate) const EVENT_GC_RESTART_EE_END: u16 = 3;
+pub(crate) const EVENT_GC_SUSPEND_EE_BEGIN: u16 = 9;
+const EVENT_GC_ALLOCATION_TICK: u16 = 10;
+const EVENT_EXCEPTION_THROWN: u16 = 80;
+
+/// Enables the GC and exception events
+pub fn provider() -> ProviderConfig {
+    provider_for(KEYWORD_GC | KEYWORD_EXCEPTION)
+}
+
+/// Enables the events of `match_any_keyword`. GCAllocationTick is logged at verbose level, so the GC keyword is
+/// enabled at verbose and anything else at information
+pub fn provider_for(match_any_keyword: u64) -> ProviderConfig {
+    let level = if match_any_keyword & KEYWORD_GC != 0 {
+        TRACE_LEVEL_VERBOSE
+    } else {
+        TRACE_LEVEL_INFORMATION
+    };
+
+    ProviderConfig {
+        guid: DOTNET_RUNTIME_GUID,
+        level: level as u8,
+        match_any_keyword,
+    }
+}
+
+/// GC and exception statistics of one managed process
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ClrProcessStats {
+    /// Number of times the runtime suspended the process for a GC
+    pub gc_pauses: u64,
+    pub total_pause: Duration,
+    pub max_pause: Duration,
+    /// Bytes allocated, sampled by the runtime every ~100KB
+    pub bytes_allocated: u64,
+    /// First-chance exceptions thrown, keyed on exception type
+    pub exceptions: BTreeMap<String, u64>,
+    first_timestamp: i64,
+    last_timestamp: i64,
+    suspended_at: Option<i64>,
+}
+
+impl ClrProcessStats {
+    /// Bytes allocated per second over the time the process was seen
+    pub fn allocation_rate(&self) -> f64 {
+        let elapsed = ClrAnalyzer::_duration(self.last_timestamp - self.first_timestamp);
+        if elapsed.is_zero() {
+            0.0
+        } else {
+            self.bytes_allocated as f64 / elapsed.as_secs_f64()
+        }
+    }
+
+    pub fn exception_count(&self) -> u64 {
+        self.exceptions.values().sum()
+    }
+}
+
+/// Computes GC pause durations, allocation rates and first-chance exception counts per process from DotNETRuntime events.
+/// Events are fed in with [`ClrAnalyzer::add`] in the order they were logged
+#[derive(Debug, Default)]
+pub struct ClrAnalyzer {
+    processes: BTreeMap<u32, ClrProcessStats>,
+}
+
+impl ClrAnalyzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes in one event. Anything other than a GC or exception event of the runtime is ignored
+    pub fn add(&mut self, event: &ParsedEvent) {
+        if event.provider != DOTNET_RUNTIME_GUID {
+            return;
+        }
+
+        let stats = self.processes.entry(event.process_id).or_default();
+        if stats.first_timestamp == 0 {
+            stats.first_timestamp = event.timestamp;
+        }
+        stats.last_timestamp = event.timestamp;
+
+        match event.event_id {
+            // A pause lasts from the runtime suspending managed threads to it resuming them
+            EVENT_GC_SUSPEND_EE_BEGIN => stats.suspended_at = Some(event.timestamp),
+            EVENT_GC_RESTART_EE_END => {
+                if let Some(suspended_at) = stats.suspended_at.take() {
+                    let pause = Self::_duration(event.timestamp - suspended_at);
+                    stats.gc_pauses += 1;
+                    stats.total_pause += pause;
+                    stats.max_pause = stats.max_pause.max(pause);
+                }
+            }
+            EVENT_GC_ALLOCATION_TICK => {
+                // AllocationAmount64 was added in version 2, older runtimes only log the 32 bit amount
+                stats.bytes_allocated += event
+                    .get("AllocationAmount64")
+                    .or_else(|| event.get("AllocationAmount"))
+                    .and_then(PropertyValue::as_u64)
+                    .unwrap_or_default();
+            }
+            EVENT_EXCEPTION_THROWN => {
+                let exception_type = event
+                    .get("ExceptionType")
+                    .map(ToString::to_string)
+                    .unwrap_or_default();
+                *stats.exceptions.entry(exception_type).or_default() += 1;
+            }
+            _ => {}
+        }
+    }
+
+    /// Statistics of every managed process seen, keyed on process id
+    pub fn processes(&self) -> &BTreeMap<u32, ClrProcessStats> {
+        &self.processes
+    }
+
+    /// Event timestamps are in 100ns ticks
+    fn _duration(ticks: i64) -> Duration {
+        Duration::from_nanos(ticks.max(0) as u64 * 100)
+    }
+}
+
+impl fmt::Display for ClrAnalyzer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (process_id, stats) in &self.processes {
+            writeln!(
+                f,
+                "Process {process_id}: {} GC pauses ({:?} total, {:?} max), {:.0} bytes/s allocated, {} exceptions",
+                stats.gc_pauses,
+                stats.total_pause,
+                stats.max_pause,
+                stats.allocation_rate(),
+                stats.exception_count()
+            )?;
+            for (exception_type, count) in &stats.exceptions {
+                writeln!(f, "    {count} x {exception_type}")?;
+            }
+        }
+        Ok(())
+    }
+}
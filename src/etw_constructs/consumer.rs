@@ -1,125 +1,601 @@
-use std::{ffi::CStr, sync::OnceLock};
+use core::slice;
+use std::{
+    ffi::{c_void, CStr, CString},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{Sender, SyncSender},
+        Arc, OnceLock,
+    },
+};
 
 use windows::{
     core::PSTR,
     Win32::{
         Foundation::{
-            ERROR_BAD_LENGTH, ERROR_CANCELLED, ERROR_INVALID_HANDLE, ERROR_INVALID_PARAMETER,
-            ERROR_INVALID_TIME, ERROR_NOACCESS, ERROR_SUCCESS, ERROR_WMI_INSTANCE_NOT_FOUND,
-            FILETIME,
+            GetLastError, ERROR_BAD_LENGTH, ERROR_BAD_PATHNAME, ERROR_CANCELLED,
+            ERROR_INVALID_PARAMETER, ERROR_NOT_FOUND, ERROR_SUCCESS, FILETIME,
         },
-        System::{
-            Diagnostics::Etw::{
-                CloseTrace, OpenTraceA, ProcessTrace, EVENT_RECORD, EVENT_TRACE_LOGFILEA,
-                EVENT_TRACE_LOGFILEA_0, EVENT_TRACE_LOGFILEA_1, PROCESSTRACE_HANDLE,
-                PROCESS_TRACE_MODE_EVENT_RECORD, PROCESS_TRACE_MODE_REAL_TIME,
-            },
-            SystemInformation::GetLocalTime,
-            Time::SystemTimeToFileTime,
+        System::Diagnostics::Etw::{
+            OpenTraceA, ProcessTrace, EVENT_RECORD, EVENT_TRACE_LOGFILEA, EVENT_TRACE_LOGFILEA_0,
+            EVENT_TRACE_LOGFILEA_1, PROCESSTRACE_HANDLE, PROCESS_TRACE_MODE_EVENT_RECORD,
+            PROCESS_TRACE_MODE_REAL_TIME, TRACE_LOGFILE_HEADER,
         },
     },
 };
 
-pub(crate) static SIGINT: OnceLock<()> = OnceLock::new();
+use super::{
+    clock::{Clock, LocalClock, SessionClock, TraceClock},
+    error::{EtwError, EtwResult},
+    extended::ExtendedData,
+    filter::{FilterSet, SessionFilter},
+    parallel_decode::DecoderPool,
+    parsed_event::ParsedEvent,
+    pipeline_error::{PipelineError, PipelineErrorCounters},
+    router::Router,
+    schema_cache::SchemaCache,
+    session_stats::LostEvent,
+    stack_walk::{StackCorrelator, StackTracedEvent, StackWalk, STACK_WALK_GUID},
+    stop_handle::{StopHandle, StopState},
+    tdh_wrapper::Tdh,
+};
+
+/// Details of the trace taken from the [`TRACE_LOGFILE_HEADER`] that [`OpenTraceA`] fills in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TraceHeaderInfo {
+    /// Pointer size of the machine that recorded the trace
+    pub pointer_size: u32,
+    /// Build number of the OS that recorded the trace
+    pub provider_version: u32,
+    pub number_of_processors: u32,
+    pub cpu_speed_mhz: u32,
+    pub timer_resolution: u32,
+    pub boot_time: i64,
+    pub perf_freq: i64,
+    pub start_time: i64,
+    pub end_time: i64,
+    pub events_lost: u32,
+    /// The clock the session was started with: 1 for QPC, 2 for system time and 3 for the CPU cycle counter
+    pub clock_type: u32,
+}
+
+impl From<&TRACE_LOGFILE_HEADER> for TraceHeaderInfo {
+    fn from(header: &TRACE_LOGFILE_HEADER) -> Self {
+        let (pointer_size, cpu_speed_mhz, events_lost) = unsafe {
+            (
+                header.Anonymous2.Anonymous.PointerSize,
+                header.Anonymous2.Anonymous.CpuSpeedInMHz,
+                header.Anonymous2.Anonymous.EventsLost,
+            )
+        };
+
+        Self {
+            pointer_size,
+            provider_version: header.ProviderVersion,
+            number_of_processors: header.NumberOfProcessors,
+            cpu_speed_mhz,
+            timer_resolution: header.TimerResolution,
+            boot_time: header.BootTime,
+            perf_freq: header.PerfFreq,
+            start_time: header.StartTime,
+            end_time: header.EndTime,
+            events_lost,
+            clock_type: header.ReservedFlags,
+        }
+    }
+}
+
+/// Where a consumer sends the events it decodes
+#[derive(Clone)]
+pub enum EventSender {
+    Unbounded(Sender<ParsedEvent>),
+    /// ProcessTrace waits for room in the channel, so ETW loses events once its own buffers fill
+    Bounded(SyncSender<ParsedEvent>),
+}
+
+impl EventSender {
+    /// Sends `event`, waiting for room in a bounded channel. Events are dropped once the receiver is gone
+    pub(crate) fn send(&self, event: ParsedEvent) {
+        let _ = match self {
+            EventSender::Unbounded(sender) => sender.send(event),
+            EventSender::Bounded(sender) => sender.send(event),
+        };
+    }
+}
+
+impl From<Sender<ParsedEvent>> for EventSender {
+    fn from(sender: Sender<ParsedEvent>) -> Self {
+        EventSender::Unbounded(sender)
+    }
+}
+
+impl From<SyncSender<ParsedEvent>> for EventSender {
+    fn from(sender: SyncSender<ParsedEvent>) -> Self {
+        EventSender::Bounded(sender)
+    }
+}
+
+/// State handed to ETW as the `Context` of the trace, and given back to us in [`EVENT_RECORD::UserContext`]
+#[derive(Default)]
+struct ConsumerContext {
+    process_evt_handler: Option<unsafe extern "system" fn(*mut EVENT_RECORD)>,
+    events_consumed: Arc<AtomicU64>,
+    events_emitted: AtomicU64, // Events that passed the filter, other than stacks sent with their event
+    event_sender: OnceLock<EventSender>, // Set when events are streamed over a channel
+    trace_header: OnceLock<TraceHeaderInfo>,
+    filter: SessionFilter, // Events it rejects never reach the handler or the channel
+    router: OnceLock<Router>,
+    stop_state: Arc<StopState>,
+    schema_cache: SchemaCache, // Shared by every event decoded for the channel
+    lost_notifications: Arc<AtomicU64>,
+    stacks: OnceLock<StackCorrelator>, // Set when stack traced events are streamed
+    pipeline_errors: OnceLock<Arc<PipelineErrorCounters>>, // Set when events are streamed over a channel
+    decode_threads: OnceLock<usize>, // Set when the events of a file are decoded on several threads
+    decoders: OnceLock<Option<DecoderPool>>, // Started on the first event, None if its threads could not be spawned
+}
+
+impl ConsumerContext {
+    /// The pool decoding the events of this trace, if they are decoded on several threads
+    fn decoders(&self, sender: &EventSender) -> Option<&DecoderPool> {
+        let threads = *self.decode_threads.get()?;
+        self.decoders
+            .get_or_init(|| {
+                DecoderPool::new(
+                    threads,
+                    sender.clone(),
+                    self.trace_header.get().copied(),
+                    self.schema_cache.manifest_dir().map(Path::to_path_buf),
+                    self.pipeline_errors.get().cloned(),
+                )
+                .ok()
+            })
+            .as_ref()
+    }
+}
+
+/// Decodes `record`, with its payload left undecoded if its schema cannot be found. Returns the event the error takes
+/// the place of in the stream if it cannot be decoded at all, so the output records what it is missing
+pub(crate) fn decode_record(
+    record: &EVENT_RECORD,
+    sequence: u64,
+    trace_header: Option<&TraceHeaderInfo>,
+    schema_cache: &SchemaCache,
+    pipeline_errors: Option<&PipelineErrorCounters>,
+) -> Result<ParsedEvent, ParsedEvent> {
+    let decoded =
+        ParsedEvent::from_record_cached(record, trace_header, schema_cache).or_else(|err| {
+            // Providers without a schema on this machine still have their events in the stream, undecoded. Other
+            // failures are counted as decode errors below
+            match err.status() {
+                ERROR_NOT_FOUND => {
+                    if let Some(pipeline_errors) = pipeline_errors {
+                        pipeline_errors.record_undecodable(record.EventHeader.ProviderId);
+                    }
+                    Ok(ParsedEvent::raw(record, trace_header))
+                }
+                _ => Err(err),
+            }
+        });
+
+    match decoded {
+        Ok(mut event) => {
+            event.sequence = sequence;
+            Ok(event)
+        }
+        Err(err) => {
+            let error = PipelineError::decode(record, sequence, err);
+            if let Some(pipeline_errors) = pipeline_errors {
+                pipeline_errors.record_error(&error);
+            }
+            Err(error.to_event())
+        }
+    }
+}
 
 #[derive(Default)]
 pub struct Consumer {
     reghandle: PROCESSTRACE_HANDLE,
-    current_time: FILETIME,
+    current_time: Option<FILETIME>, // Where ProcessTrace starts delivering events from
+    context: Box<ConsumerContext>, // Boxed so its address stays the same while ProcessTrace is running
+    real_time: bool,
+}
+
+/// Returning false from the buffer callback makes ProcessTrace return, so keep going until the consumer's session is stopped
+unsafe extern "system" fn on_termination(logfile: *mut EVENT_TRACE_LOGFILEA) -> u32 {
+    logfile
+        .as_ref()
+        .and_then(|logfile| (logfile.Context as *const ConsumerContext).as_ref())
+        .map_or(0, |context| !context.stop_state.is_stopped() as u32)
 }
 
-unsafe extern "system" fn on_termination(_logfile: *mut EVENT_TRACE_LOGFILEA) -> u32 {
-    SIGINT.get().is_none() as u32
+/// Gets the logfile header details of the trace `record` was delivered from.
+///
+/// # Safety
+/// `record` must have been handed to an event callback by a [`Consumer`], or have a null `UserContext`
+pub unsafe fn trace_header_of(record: &EVENT_RECORD) -> Option<TraceHeaderInfo> {
+    (record.UserContext as *const ConsumerContext)
+        .as_ref()
+        .and_then(|context| context.trace_header.get().copied())
 }
 
-/// An EWT consumer. Consumes events from an existing controller session. Stops its trace session when dropped.
+/// Counts the event, then forwards it to the handler the consumer was created with, the routed handlers and the event channel, if there is one.
+/// Events rejected by the consumer's filter are only counted. Events sent on the channel are numbered with their event
+/// key if they have one, otherwise with their position among the events that passed the filter
+unsafe extern "system" fn on_event(eventrecord: *mut EVENT_RECORD) {
+    let Some((record, context)) = eventrecord.as_ref().and_then(|record| {
+        (record.UserContext as *const ConsumerContext)
+            .as_ref()
+            .map(|context| (record, context))
+    }) else {
+        return;
+    };
+
+    context.events_consumed.fetch_add(1, Ordering::Relaxed);
+
+    // Lost event notifications skip the filter, so the handler always learns the capture is incomplete
+    let lost = LostEvent::from_record(record).is_some();
+    if lost {
+        context.lost_notifications.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Stacks are logged by the kernel rather than the process, so they are matched to their event instead of filtered
+    let is_stack =
+        context.stacks.get().is_some() && record.EventHeader.ProviderId == STACK_WALK_GUID;
+
+    if !lost && !is_stack && !context.filter.matches(record, context.trace_header.get()) {
+        return;
+    }
+
+    if let Some(process_evt_handler) = context.process_evt_handler {
+        process_evt_handler(eventrecord);
+    }
+
+    if let Some(router) = context.router.get() {
+        router.dispatch(record, context.trace_header.get());
+    }
+
+    if let Some(sender) = context.event_sender.get() {
+        let stacks = context.stacks.get();
+
+        // Stacks are sent as part of the event they belong to rather than on their own
+        if let Some(stacks) = stacks {
+            let pointer_size = Tdh::pointer_size(
+                record,
+                context.trace_header.get().map(|header| header.pointer_size),
+            );
+            if let Some(stack) = StackWalk::from_record(record, pointer_size) {
+                // Stacks of events that were filtered out are dropped along with them
+                if let Some(event) = stacks.attach(stack) {
+                    sender.send(event);
+                }
+                return;
+            }
+        }
+
+        let sequence = ExtendedData::event_key(record)
+            .unwrap_or_else(|| context.events_emitted.fetch_add(1, Ordering::Relaxed) + 1);
+
+        if let Some(decoders) = context.decoders(sender) {
+            decoders.push(record, sequence);
+            return;
+        }
+
+        match decode_record(
+            record,
+            sequence,
+            context.trace_header.get(),
+            &context.schema_cache,
+            context.pipeline_errors.get().map(Arc::as_ref),
+        ) {
+            // The receiver going away just means nobody is listening anymore
+            Ok(event) => match stacks {
+                Some(stacks) => stacks.hold(event, |event| sender.send(event)),
+                None => sender.send(event),
+            },
+            Err(error_event) => sender.send(error_event),
+        }
+    }
+}
+
+/// An EWT consumer. Consumes events from an existing controller session or a recorded .etl file. Closes its trace when dropped.
 impl Consumer {
     /// Creates a consumer set to trace `session_name` and calls [`OpenTraceA`] to start an existing trace session
     /// Accepts an optional callback function that is invoked every time an event is recorded
+    /// Returns an [`EtwError::OpenTrace`] if the trace could not be opened
     pub fn new(
         session_name: &'static CStr,
         process_evt_handler: Option<unsafe extern "system" fn(*mut EVENT_RECORD)>,
-    ) -> Self {
-        Self {
-            current_time: Self::_get_current_time_as_filetime(),
-            reghandle: {
-                let mut event_consume_properties = EVENT_TRACE_LOGFILEA {
-                    LoggerName: Self::_session_name_pstr(session_name),
-                    BufferCallback: Some(on_termination),
-                    Anonymous1: EVENT_TRACE_LOGFILEA_0 {
-                        ProcessTraceMode: PROCESS_TRACE_MODE_REAL_TIME
-                            | PROCESS_TRACE_MODE_EVENT_RECORD,
-                    },
-                    Anonymous2: EVENT_TRACE_LOGFILEA_1 {
-                        EventRecordCallback: process_evt_handler,
-                    },
-                    ..Default::default()
-                };
-                unsafe { OpenTraceA(&mut event_consume_properties) }
+    ) -> EtwResult<Self> {
+        Self::with_clock(session_name, process_evt_handler, &LocalClock)
+    }
+
+    /// Same as [`Consumer::new`], but only events from the time given by `clock` onwards are processed
+    pub fn with_clock(
+        session_name: &'static CStr,
+        process_evt_handler: Option<unsafe extern "system" fn(*mut EVENT_RECORD)>,
+        clock: &impl Clock,
+    ) -> EtwResult<Self> {
+        let context = Box::new(ConsumerContext {
+            process_evt_handler,
+            ..Default::default()
+        });
+
+        let event_consume_properties = EVENT_TRACE_LOGFILEA {
+            LoggerName: Self::_session_name_pstr(session_name),
+            Anonymous1: EVENT_TRACE_LOGFILEA_0 {
+                ProcessTraceMode: PROCESS_TRACE_MODE_REAL_TIME | PROCESS_TRACE_MODE_EVENT_RECORD,
+            },
+            ..Default::default()
+        };
+
+        Ok(Self {
+            reghandle: Self::_open_trace(event_consume_properties, &context, || {
+                format!("Could not open real-time session {:?}", session_name)
+            })?,
+            current_time: Some(clock.now()?),
+            context,
+            real_time: true,
+        })
+    }
+
+    /// Creates a consumer that replays the events recorded in the .etl file at `path` through `process_evt_handler`.
+    /// `LogFileName` is populated instead of `LoggerName`, so no controller session is needed. Events are replayed
+    /// from the start time in the file's logfile header, see [`TraceClock`]
+    /// Returns an [`EtwError::OpenTrace`] if the file could not be opened
+    pub fn from_file(
+        path: &Path,
+        process_evt_handler: Option<unsafe extern "system" fn(*mut EVENT_RECORD)>,
+    ) -> EtwResult<Self> {
+        let log_file_name = path
+            .to_str()
+            .and_then(|path| CString::new(path).ok())
+            .ok_or_else(|| EtwError::OpenTrace {
+                status: ERROR_BAD_PATHNAME,
+                context: format!("{:?} is not a valid ANSI path", path),
+            })?;
+
+        let context = Box::new(ConsumerContext {
+            process_evt_handler,
+            ..Default::default()
+        });
+
+        let event_consume_properties = EVENT_TRACE_LOGFILEA {
+            LogFileName: Self::_session_name_pstr(&log_file_name),
+            Anonymous1: EVENT_TRACE_LOGFILEA_0 {
+                ProcessTraceMode: PROCESS_TRACE_MODE_EVENT_RECORD,
             },
+            ..Default::default()
+        };
+
+        let reghandle = Self::_open_trace(event_consume_properties, &context, || {
+            format!("Could not open trace file {:?}", path)
+        })?;
+        let current_time = match context.trace_header.get() {
+            Some(header) => Some(TraceClock::from(header).now()?),
+            None => None,
+        };
+
+        Ok(Self {
+            reghandle,
+            current_time,
+            context,
+            real_time: false,
+        })
+    }
+
+    /// Wrapper for ProcessTrace, returns an [`EtwError::ProcessTrace`] if the status is not success
+    pub fn start_listening(&self) -> EtwResult<()> {
+        Self::_process_trace(slice::from_ref(self))
+    }
+
+    /// A handle that stops this consumer from another thread. `session_name` is also stopped with `ControlTrace(STOP)` if given
+    pub fn stop_handle(&self, session_name: Option<&'static CStr>) -> StopHandle {
+        StopHandle::new(
+            session_name,
+            Some(self.reghandle),
+            Arc::clone(&self.context.stop_state),
+        )
+    }
+
+    /// Decodes every event into a [`ParsedEvent`] and sends it to `sender`. Can only be set once
+    pub fn set_event_sender(&self, sender: impl Into<EventSender>) {
+        let _ = self.context.event_sender.set(sender.into());
+    }
+
+    /// Counts the events that could not be decoded into `counters`. Can only be set once
+    pub fn set_pipeline_errors(&self, counters: Arc<PipelineErrorCounters>) {
+        let _ = self.context.pipeline_errors.set(counters);
+    }
+
+    /// Looks for the schemas of events TDH cannot decode in the manifests under `dir`, see
+    /// [`SchemaCache::set_manifest_dir`]. Can only be set once
+    pub fn set_manifest_dir(&self, dir: PathBuf) {
+        self.context.schema_cache.set_manifest_dir(dir);
+    }
+
+    /// Decodes the events of a recorded .etl file on `threads` threads instead of the ProcessTrace thread, keeping their
+    /// order. Live sessions ignore it, since batching events for the threads would delay them. Can only be set once
+    pub fn set_decode_threads(&self, threads: usize) {
+        if !self.real_time && threads > 1 {
+            let _ = self.context.decode_threads.set(threads);
         }
     }
 
-    /// Wrapper for ProcessTraceA, panics if the error is not success
-    pub fn start_listening(&self) {
-        let status_code =
-            unsafe { ProcessTrace(&[self.reghandle], Some(&self.current_time), None) };
+    /// Drops events that do not match `filter` before they are decoded. Replaces the filter set before, if any
+    pub fn set_filter(&self, filter: FilterSet) {
+        self.context.filter.set(Some(filter));
+    }
 
-        match status_code {
-            ERROR_SUCCESS => {}
-            ERROR_BAD_LENGTH => {
-                panic!("HandleCount is not valid or the number of handles is greater than 64.")
-            }
-            ERROR_INVALID_HANDLE => {
-                panic!("An element of HandleArray is not a valid event tracing session handle.")
-            }
-            ERROR_INVALID_TIME => {
-                panic!("EndTime is less than StartTime.")
-            }
-            ERROR_INVALID_PARAMETER => {
-                panic!("HandleArray is NULL, contains both file processing sessions and real-time processing sessions, or contains more than one real-time processing session.")
-            }
-            ERROR_NOACCESS => {
-                panic!(
-                "An exception occurred in one of the callback functions that receives the events."
-            )
+    /// A handle that swaps the filter of [`Consumer::set_filter`] while the trace is being processed
+    pub fn session_filter(&self) -> SessionFilter {
+        self.context.filter.clone()
+    }
+
+    /// Hands every event to the handlers `router` has for it, in addition to the handler the consumer was created with. Can only be set once
+    pub fn set_router(&self, router: Router) {
+        let _ = self.context.router.set(router);
+    }
+
+    /// Attaches the call stacks of `events` to the streamed events they belong to. Each of them is held back until its
+    /// stack arrives. Can only be set once
+    pub fn set_stack_walk(&self, events: Vec<StackTracedEvent>) {
+        let _ = self.context.stacks.set(StackCorrelator::new(events));
+    }
+
+    /// Details of the trace from its logfile header, such as the pointer size and OS build of the machine that recorded it
+    pub fn trace_header(&self) -> Option<TraceHeaderInfo> {
+        self.context.trace_header.get().copied()
+    }
+
+    /// Converts raw timestamps of the session clock found in event payloads to FILETIME ticks, like the event
+    /// timestamps. None until the trace is opened
+    pub fn session_clock(&self) -> EtwResult<Option<SessionClock>> {
+        let Some(header) = self.trace_header() else {
+            return Ok(None);
+        };
+
+        if self.real_time {
+            SessionClock::now(&header).map(Some)
+        } else {
+            Ok(Some(SessionClock::from(&header)))
+        }
+    }
+
+    /// A counter of every event this consumer has received, shared so it can be read while [`Consumer::start_listening`] is blocking
+    pub fn events_consumed(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.context.events_consumed)
+    }
+
+    /// Whether this consumer processes a live session rather than a recorded .etl file
+    pub fn is_real_time(&self) -> bool {
+        self.real_time
+    }
+
+    /// A counter of the [`LostEvent`] notifications this consumer has received
+    pub fn lost_notifications(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.context.lost_notifications)
+    }
+
+    /// Calls [`OpenTraceA`] with the given logfile properties, routing events through [`on_event`] with `consumer_context`.
+    /// `context` describes the trace being opened if the call fails
+    fn _open_trace(
+        mut event_consume_properties: EVENT_TRACE_LOGFILEA,
+        consumer_context: &ConsumerContext,
+        context: impl FnOnce() -> String,
+    ) -> EtwResult<PROCESSTRACE_HANDLE> {
+        event_consume_properties.BufferCallback = Some(on_termination);
+        event_consume_properties.Anonymous2 = EVENT_TRACE_LOGFILEA_1 {
+            EventRecordCallback: Some(on_event),
+        };
+        event_consume_properties.Context =
+            consumer_context as *const ConsumerContext as *mut c_void;
+
+        let reghandle = unsafe { OpenTraceA(&mut event_consume_properties) };
+
+        // OpenTraceA returns INVALID_PROCESSTRACE_HANDLE (all bits set) on failure
+        if reghandle.Value == u64::MAX {
+            return Err(EtwError::OpenTrace {
+                status: unsafe { GetLastError() },
+                context: context(),
+            });
+        }
+
+        let _ = consumer_context.trace_header.set(TraceHeaderInfo::from(
+            &event_consume_properties.LogfileHeader,
+        ));
+
+        Ok(reghandle)
+    }
+
+    /// Calls [`ProcessTrace`] with the handles of every consumer in `consumers`, from the earliest of their start times
+    fn _process_trace(consumers: &[Consumer]) -> EtwResult<()> {
+        let handles: Vec<PROCESSTRACE_HANDLE> = consumers
+            .iter()
+            .map(|consumer| consumer.reghandle)
+            .collect();
+        let start_time = consumers
+            .iter()
+            .filter_map(|consumer| consumer.current_time)
+            .min_by_key(|time| ((time.dwHighDateTime as u64) << 32) | time.dwLowDateTime as u64);
+
+        let status_code = unsafe { ProcessTrace(&handles, start_time.as_ref(), None) };
+
+        // Events still waiting for their stack will not get one now, and those still being decoded are finished
+        for context in consumers.iter().map(|consumer| &consumer.context) {
+            if let Some(Some(decoders)) = context.decoders.get() {
+                decoders.finish();
             }
-            ERROR_CANCELLED => {
-                panic!(
-                "An exception occurred in one of the callback functions that receives the events."
-            )
+            if let (Some(stacks), Some(sender)) = (context.stacks.get(), context.event_sender.get())
+            {
+                for event in stacks.drain() {
+                    sender.send(event);
+                }
             }
-            ERROR_WMI_INSTANCE_NOT_FOUND => {
-                panic!("The trace collection session from which you are trying to consume events in real time is not running or does not have the real-time trace mode enabled.")
+        }
+
+        match status_code {
+            ERROR_SUCCESS => Ok(()),
+            // Closing the trace from a stop handle cancels ProcessTrace
+            ERROR_CANCELLED
+                if consumers
+                    .iter()
+                    .any(|consumer| consumer.context.stop_state.is_stopped()) =>
+            {
+                Ok(())
             }
-            status => panic!("Unspecified Error: {:?}", status),
+            status => Err(EtwError::ProcessTrace { status }),
         }
     }
 
     fn _session_name_pstr(str: &CStr) -> PSTR {
         PSTR::from_raw(str.as_ptr() as *mut u8)
     }
+}
+
+/// Several consumers processed by a single [`ProcessTrace`] call, so their events arrive on one thread in timestamp order.
+/// Each consumer keeps its own handler, filter, router and event sender.
+///
+/// `ProcessTrace` can merge up to 64 .etl files, but not real-time sessions: a real-time consumer can only be merged
+/// on its own. See [`MergedSession`](super::MergedSession) for processing several live sessions side by side
+pub struct MergedConsumer {
+    consumers: Vec<Consumer>,
+}
 
-    /// Gets the current time as a windows SYSTEMTIME object, then converts it to a FILETIME object
-    fn _get_current_time_as_filetime() -> FILETIME {
-        let systemtime = unsafe { GetLocalTime() };
+impl MergedConsumer {
+    /// Most traces [`ProcessTrace`] accepts in one call
+    pub const MAX_TRACES: usize = 64;
 
-        // to get local time and https://learn.microsoft.com/en-us/windows/win32/api/timezoneapi/nf-timezoneapi-systemtimetofiletime to convert to file time
-        let mut filetime: FILETIME = FILETIME::default();
-        unsafe { SystemTimeToFileTime(&systemtime, &mut filetime) }
-            .expect("Could not convert system timie to filetime!");
+    /// Returns an [`EtwError::ProcessTrace`] if `consumers` is empty, has more than [`MergedConsumer::MAX_TRACES`] or
+    /// has a real-time consumer alongside any other consumer, which `ProcessTrace` would reject
+    pub fn new(consumers: Vec<Consumer>) -> EtwResult<Self> {
+        if consumers.is_empty() || consumers.len() > Self::MAX_TRACES {
+            return Err(EtwError::ProcessTrace {
+                status: ERROR_BAD_LENGTH,
+            });
+        }
 
-        filetime
+        if consumers.len() > 1 && consumers.iter().any(Consumer::is_real_time) {
+            return Err(EtwError::ProcessTrace {
+                status: ERROR_INVALID_PARAMETER,
+            });
+        }
+
+        Ok(Self { consumers })
+    }
+
+    /// Processes every trace until all of them end or one is stopped. Blocks like [`Consumer::start_listening`]
+    pub fn start_listening(&self) -> EtwResult<()> {
+        Consumer::_process_trace(&self.consumers)
+    }
+
+    pub fn consumers(&self) -> &[Consumer] {
+        &self.consumers
     }
 }
 
 impl Drop for Consumer {
     fn drop(&mut self) {
-        println!("Consumer went out of scope, closing trace...");
-        if self.reghandle.Value != 0 {
-            unsafe {
-                let _ = CloseTrace(self.reghandle);
-            }
-        }
+        eprintln!("Consumer went out of scope, closing trace...");
+        self.context.stop_state.close_trace(self.reghandle);
     }
 }
@@ -1,56 +1,368 @@
 use core::slice;
 use std::{
-    ffi::{c_void, CStr},
+    ffi::{c_void, CStr, CString},
     mem,
+    path::PathBuf,
+    ptr,
+    sync::Mutex,
+    time::SystemTime,
 };
 
 use windows::{
-    core::PCSTR,
+    core::{GUID, PCSTR},
     Win32::{
         Foundation::{
-            ERROR_ACCESS_DENIED, ERROR_ALREADY_EXISTS, ERROR_BAD_LENGTH, ERROR_BAD_PATHNAME,
-            ERROR_INVALID_PARAMETER, ERROR_NO_SYSTEM_RESOURCES, ERROR_SUCCESS,
-            INVALID_HANDLE_VALUE,
+            ERROR_ALREADY_EXISTS, ERROR_BAD_PATHNAME, ERROR_SUCCESS, INVALID_HANDLE_VALUE,
         },
         System::Diagnostics::Etw::{
-            ControlTraceA, StartTraceA, SystemTraceControlGuid, CONTROLTRACE_HANDLE,
-            EVENT_TRACE_CONTROL_STOP, EVENT_TRACE_FLAG_PROCESS, EVENT_TRACE_PROPERTIES,
-            EVENT_TRACE_REAL_TIME_MODE, EVENT_TRACE_SYSTEM_LOGGER_MODE, WNODE_FLAG_TRACED_GUID,
+            ControlTraceA, EnableTraceEx2, StartTraceA, SystemTraceControlGuid,
+            CONTROLTRACE_HANDLE, ENABLE_TRACE_PARAMETERS, ENABLE_TRACE_PARAMETERS_VERSION_2,
+            EVENT_CONTROL_CODE_DISABLE_PROVIDER, EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+            EVENT_ENABLE_PROPERTY_EVENT_KEY, EVENT_FILTER_DESCRIPTOR, EVENT_FILTER_TYPE_EVENT_ID,
+            EVENT_FILTER_TYPE_PID, EVENT_TRACE_CONTROL, EVENT_TRACE_CONTROL_FLUSH,
+            EVENT_TRACE_CONTROL_QUERY, EVENT_TRACE_CONTROL_STOP, EVENT_TRACE_CONTROL_UPDATE,
+            EVENT_TRACE_FILE_MODE_CIRCULAR, EVENT_TRACE_FILE_MODE_NEWFILE,
+            EVENT_TRACE_FILE_MODE_SEQUENTIAL, EVENT_TRACE_FLAG, EVENT_TRACE_FLAG_NO_SYSCONFIG,
+            EVENT_TRACE_FLAG_PROCESS, EVENT_TRACE_PROPERTIES, EVENT_TRACE_REAL_TIME_MODE,
+            EVENT_TRACE_SECURE_MODE, EVENT_TRACE_SYSTEM_LOGGER_MODE,
+            EVENT_TRACE_USE_GLOBAL_SEQUENCE, EVENT_TRACE_USE_LOCAL_SEQUENCE,
+            EVENT_TRACE_USE_PAGED_MEMORY, KERNEL_LOGGER_NAMEA, WNODE_FLAG_TRACED_GUID,
             WNODE_HEADER,
         },
     },
 };
 
+use super::{
+    audit::{self, AuditRecord, ControlAction},
+    bookmark::Bookmarker,
+    error::{EtwError, EtwResult},
+    filter::ProviderFilter,
+    guardrails::Guardrails,
+    keep_alive::KeepAliveMarker,
+    memory,
+    security::{self, ConsumerAccess},
+    session_stats::SessionStats,
+    stack_walk::{self, StackTracedEvent},
+    validation::{SystemCapabilities, UnavailableFeature},
+};
+
+/// How the session writes to its .etl file, see https://learn.microsoft.com/en-us/windows/win32/etw/logging-mode-constants
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFileMode {
+    /// Writes events sequentially until the disk is full, or until `max_size_mb` is reached if one is given
+    Sequential { max_size_mb: Option<u32> },
+    /// Overwrites the oldest events once the file reaches `max_size_mb`
+    Circular { max_size_mb: u32 },
+    /// Starts a new file every time `max_size_mb` is reached. The path must contain a `%d` which is replaced by the file number
+    NewFile { max_size_mb: u32 },
+}
+
+impl LogFileMode {
+    /// The `LogFileMode` flag and `MaximumFileSize` written to [`EVENT_TRACE_PROPERTIES`]
+    fn mode_and_max_size(&self) -> (u32, u32) {
+        match *self {
+            LogFileMode::Sequential { max_size_mb } => {
+                (EVENT_TRACE_FILE_MODE_SEQUENTIAL, max_size_mb.unwrap_or(0))
+            }
+            LogFileMode::Circular { max_size_mb } => (EVENT_TRACE_FILE_MODE_CIRCULAR, max_size_mb),
+            LogFileMode::NewFile { max_size_mb } => (EVENT_TRACE_FILE_MODE_NEWFILE, max_size_mb),
+        }
+    }
+}
+
+/// Whether ETW numbers the events of the session, see https://learn.microsoft.com/en-us/windows/win32/etw/logging-mode-constants.
+/// ETW only numbers events logged with `TraceMessage`, such as WPP traces. Any other mode also enables the session's
+/// providers with `EVENT_ENABLE_PROPERTY_EVENT_KEY`, so their events carry the kernel's event key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SequenceMode {
+    #[default]
+    None,
+    /// Numbers are unique across every session using global sequence numbers, so their events can be merged in order
+    Global,
+    /// Numbers are unique within this session only. Cheaper than [`SequenceMode::Global`]
+    Local,
+}
+
+impl SequenceMode {
+    /// The flag added to `LogFileMode`
+    pub fn log_file_mode(&self) -> u32 {
+        match self {
+            SequenceMode::None => 0,
+            SequenceMode::Global => EVENT_TRACE_USE_GLOBAL_SEQUENCE,
+            SequenceMode::Local => EVENT_TRACE_USE_LOCAL_SEQUENCE,
+        }
+    }
+
+    /// The `EnableProperty` providers are enabled with
+    pub fn enable_property(&self) -> u32 {
+        match self {
+            SequenceMode::None => 0,
+            SequenceMode::Global | SequenceMode::Local => EVENT_ENABLE_PROPERTY_EVENT_KEY,
+        }
+    }
+}
+
+/// Writes the session to an .etl file on disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFile {
+    pub path: PathBuf,
+    pub mode: LogFileMode,
+    /// Keep delivering events in real-time in addition to writing them to the file
+    pub real_time: bool,
+}
+
+/// A manifest or TraceLogging provider enabled in the session with `EnableTraceEx2`, alongside the kernel flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProviderConfig {
+    pub guid: GUID,
+    /// Most verbose level logged, e.g. `TRACE_LEVEL_INFORMATION`
+    pub level: u8,
+    /// Events are logged if they have any of these keywords. 0 logs every event
+    pub match_any_keyword: u64,
+}
+
+/// Sizes the buffers events are collected in before they are written out or delivered. Every field left at 0 lets ETW pick.
+/// Heavy workloads need bigger or more buffers, otherwise events are lost while the buffers are full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BufferConfig {
+    /// Size of each buffer in KB, at most 1024
+    pub buffer_size_kb: u32,
+    /// Buffers allocated when the session starts
+    pub minimum_buffers: u32,
+    /// Most buffers the session grows to under load
+    pub maximum_buffers: u32,
+    /// How often partially filled buffers are flushed, in seconds. 0 only flushes buffers once they are full,
+    /// which can delay real-time events on a quiet session
+    pub flush_timer_secs: u32,
+}
+
+impl BufferConfig {
+    /// Most memory the buffers can take, or None if ETW picks the buffer size or count
+    pub fn max_memory_bytes(&self) -> Option<u64> {
+        (self.buffer_size_kb != 0 && self.maximum_buffers != 0)
+            .then(|| self.buffer_size_kb as u64 * self.maximum_buffers as u64 * 1024)
+    }
+}
+
+/// Which pool the session's buffers are allocated from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BufferMemory {
+    /// Nonpaged pool. Needed for kernel events, which can be logged at any IRQL, but every buffer stays in physical
+    /// memory for as long as the session runs, which adds up on small servers
+    #[default]
+    NonPaged,
+    /// Paged pool, see [`EVENT_TRACE_USE_PAGED_MEMORY`]. Buffers can be paged out, so a long-running, low-priority
+    /// capture does not pin memory, at the cost of logging being slower while they are paged back in. Only user-mode
+    /// providers can log to such a session: it cannot be the NT Kernel Logger or a system logger, so kernel flags,
+    /// stack walking and MemInfo are unavailable
+    Paged,
+}
+
+impl BufferMemory {
+    /// The flag added to `LogFileMode`
+    pub fn log_file_mode(&self) -> u32 {
+        match self {
+            BufferMemory::NonPaged => 0,
+            BufferMemory::Paged => EVENT_TRACE_USE_PAGED_MEMORY,
+        }
+    }
+}
+
+/// What to do when a session with the same name is already running, e.g. one left behind after a crash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExistingSessionPolicy {
+    /// Stop the running session with `ControlTrace(STOP)` and start a new one in its place
+    StopAndRestart,
+    /// Consume the running session as it is configured, without starting a new one. A session this controller did not
+    /// start is never stopped by it, it keeps running until stopped with `ControlTrace(STOP)`, e.g. by
+    /// [`ExistingSessionPolicy::StopAndRestart`] or `logman stop`
+    AttachExisting,
+    /// Fail with the [`ERROR_ALREADY_EXISTS`] returned by `StartTrace`
+    #[default]
+    Error,
+}
+
+/// Options used when starting a controller session
+#[derive(Debug, Clone)]
+pub struct ControllerConfig {
+    /// Kernel event classes to enable
+    pub enable_flags: EVENT_TRACE_FLAG,
+    /// Whether the kernel logs the SystemConfig rundown events (CPU, disks, NICs) when the session stops.
+    /// Setting this to false sets [`EVENT_TRACE_FLAG_NO_SYSCONFIG`]
+    pub system_config: bool,
+    /// Log the session to an .etl file. The session is real-time only if this is None
+    pub log_file: Option<LogFile>,
+    /// Resource limits checked while the session is running
+    pub guardrails: Option<Guardrails>,
+    /// What to do if the session is already running
+    pub existing_session: ExistingSessionPolicy,
+    /// User-mode providers to enable. The NT Kernel Logger cannot enable them, so the session needs a different name
+    pub providers: Vec<ProviderConfig>,
+    /// Processes and event ids the user-mode providers log, checked by the providers themselves
+    pub provider_filter: ProviderFilter,
+    /// Buffer sizes and counts of the session
+    pub buffers: BufferConfig,
+    /// Kernel events to capture the call stack of. Their stacks are attached to [`ParsedEvent::stack`](super::ParsedEvent::stack)
+    /// of streamed events
+    pub stack_walk: Vec<StackTracedEvent>,
+    /// Log the kernel's periodic MemInfo and working set rundowns, see [`memory::enable_mem_info`]
+    pub mem_info: bool,
+    /// Sequence numbers ETW adds to the events of the session
+    pub sequence: SequenceMode,
+    /// Only accounts granted TRACELOG_LOG_EVENT can log events to the session, see [`EVENT_TRACE_SECURE_MODE`].
+    /// Check who can read them with [`Controller::real_time_consumers`]
+    pub secure: bool,
+    /// Which pool the buffers come from. Check how much memory they take with [`BufferConfig::max_memory_bytes`] or,
+    /// once running, [`SessionStats::buffer_memory_bytes`]
+    pub buffer_memory: BufferMemory,
+    /// Leave the session running when the controller is dropped or its stop handle is used, so the kernel keeps logging
+    /// while the consumer is replaced, e.g. by an upgraded binary. A new consumer picks it up with
+    /// [`ExistingSessionPolicy::AttachExisting`]. Events are buffered while no consumer is attached, and lost once the
+    /// buffers are full. A [`KeepAliveMarker`] records the session while it is left running
+    pub keep_alive: bool,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        Self {
+            enable_flags: EVENT_TRACE_FLAG_PROCESS,
+            system_config: true,
+            log_file: None,
+            guardrails: None,
+            existing_session: ExistingSessionPolicy::default(),
+            providers: Vec::new(),
+            provider_filter: ProviderFilter::default(),
+            buffers: BufferConfig::default(),
+            stack_walk: Vec::new(),
+            mem_info: false,
+            sequence: SequenceMode::None,
+            secure: false,
+            buffer_memory: BufferMemory::default(),
+            keep_alive: false,
+        }
+    }
+}
+
+impl ControllerConfig {
+    /// Whether events are delivered to real-time consumers
+    pub fn is_real_time(&self) -> bool {
+        match &self.log_file {
+            Some(log_file) => log_file.real_time,
+            None => true,
+        }
+    }
+
+    /// The flags written to [`EVENT_TRACE_PROPERTIES::EnableFlags`]
+    pub fn effective_flags(&self) -> EVENT_TRACE_FLAG {
+        if self.system_config {
+            self.enable_flags
+        } else {
+            self.enable_flags | EVENT_TRACE_FLAG_NO_SYSCONFIG
+        }
+    }
+}
+
 pub struct Controller {
     trace_handle: CONTROLTRACE_HANDLE,
     session_name: &'static CStr, // This session name should be a global variable.
     event_prop_buf: Vec<u8>,
+    attached: bool,     // Whether the session was already running
+    keep_running: bool, // Whether the session is left running when dropped
+    audit_trail: Mutex<Vec<AuditRecord>>,
 }
 
 /// A Controller construct for windows ETW. Creates a controller and manages its session
 impl Controller {
-    /// Creates a new controller and starts a session with it. This will allocate a buffer holding an [`EVENT_TRACE_PROPERTIES``] structure along with space to store the session name after
+    /// Creates a new controller and starts a session with it. This will allocate a buffer holding an [`EVENT_TRACE_PROPERTIES``] structure along with space to store the session name (and log file name, if any) after
     /// For information as to why the session name needs to be stored after the properties structure, please consult https://learn.microsoft.com/en-us/windows/win32/api/evntrace/ns-evntrace-event_trace_properties
-    /// Panics if the session cannot be started
-    pub fn new(session_name: &'static CStr) -> Self {
+    /// Returns an [`EtwError`] if the session cannot be started
+    pub fn new(session_name: &'static CStr) -> EtwResult<Self> {
+        Self::with_config(session_name, ControllerConfig::default())
+    }
+
+    /// Same as [`Controller::new`], but starts the session with the options in `config`.
+    /// The config is validated first, so features this machine cannot provide are all reported before StartTrace is called
+    pub fn with_config(session_name: &'static CStr, config: ControllerConfig) -> EtwResult<Self> {
+        UnavailableFeature::into_result(
+            config.validate(session_name, &SystemCapabilities::detect()?),
+        )?;
+
         let mut handle: CONTROLTRACE_HANDLE = CONTROLTRACE_HANDLE::default();
-        let mut event_prop_buf: Vec<u8> = Vec::with_capacity(
-            mem::size_of::<EVENT_TRACE_PROPERTIES>() + session_name.to_bytes_with_nul().len(),
-        );
+
+        let log_file_name = config
+            .log_file
+            .as_ref()
+            .map(|log_file| {
+                log_file
+                    .path
+                    .to_str()
+                    .and_then(|path| CString::new(path).ok())
+                    .ok_or_else(|| EtwError::StartTrace {
+                        status: ERROR_BAD_PATHNAME,
+                        context: format!("{:?} is not a valid ANSI path", log_file.path),
+                    })
+            })
+            .transpose()?;
+
+        // The buffer is laid out as [EVENT_TRACE_PROPERTIES][session name\0][log file name\0]
+        let logger_name_offset = mem::size_of::<EVENT_TRACE_PROPERTIES>();
+        let log_file_name_offset = logger_name_offset + session_name.to_bytes_with_nul().len();
+        let buffer_size = log_file_name_offset
+            + log_file_name
+                .as_ref()
+                .map_or(0, |name| name.to_bytes_with_nul().len());
+
+        let (file_mode, maximum_file_size) = config
+            .log_file
+            .as_ref()
+            .map_or((0, 0), |log_file| log_file.mode.mode_and_max_size());
+
+        // A system logger always allocates its buffers from nonpaged pool
+        let logger_mode = match config.buffer_memory {
+            BufferMemory::NonPaged => EVENT_TRACE_SYSTEM_LOGGER_MODE,
+            BufferMemory::Paged => 0,
+        };
+        let mut log_file_mode = logger_mode
+            | file_mode
+            | config.sequence.log_file_mode()
+            | config.buffer_memory.log_file_mode();
+        if config.secure {
+            log_file_mode |= EVENT_TRACE_SECURE_MODE;
+        }
+        if config.is_real_time() {
+            log_file_mode |= EVENT_TRACE_REAL_TIME_MODE;
+        }
+
+        let mut event_prop_buf: Vec<u8> = Vec::with_capacity(buffer_size);
         // Set event properties in temp struct and copy everything over when complete
         {
             let temp_prop = EVENT_TRACE_PROPERTIES {
                 Wnode: WNODE_HEADER {
-                    BufferSize: event_prop_buf.capacity() as u32,
-                    Guid: SystemTraceControlGuid,
+                    BufferSize: buffer_size as u32,
+                    // Any other session is a system logger, and ETW generates a GUID for it
+                    Guid: if Self::is_kernel_logger(session_name) {
+                        SystemTraceControlGuid
+                    } else {
+                        GUID::zeroed()
+                    },
                     ClientContext: 1,
                     Flags: WNODE_FLAG_TRACED_GUID,
                     ..Default::default()
                 },
-                EnableFlags: EVENT_TRACE_FLAG_PROCESS,
-                LogFileMode: EVENT_TRACE_REAL_TIME_MODE | EVENT_TRACE_SYSTEM_LOGGER_MODE,
-                LogFileNameOffset: 0, // Sets realtime session
-                LoggerNameOffset: mem::size_of::<EVENT_TRACE_PROPERTIES>() as u32,
+                BufferSize: config.buffers.buffer_size_kb,
+                MinimumBuffers: config.buffers.minimum_buffers,
+                MaximumBuffers: config.buffers.maximum_buffers,
+                FlushTimer: config.buffers.flush_timer_secs,
+                EnableFlags: config.effective_flags(),
+                LogFileMode: log_file_mode,
+                MaximumFileSize: maximum_file_size,
+                // 0 sets a realtime only session
+                LogFileNameOffset: if log_file_name.is_some() {
+                    log_file_name_offset as u32
+                } else {
+                    0
+                },
+                LoggerNameOffset: logger_name_offset as u32,
                 ..Default::default()
             };
 
@@ -62,67 +374,398 @@ impl Controller {
             });
         }
 
-        Controller::_start_session(
+        // StartTraceA fills in the session name, the log file name has to be copied in by us
+        event_prop_buf.resize(log_file_name_offset, 0);
+        if let Some(log_file_name) = &log_file_name {
+            event_prop_buf.extend_from_slice(log_file_name.to_bytes_with_nul());
+        }
+
+        // StartTraceA writes back into the properties, so keep a copy in case the session has to be started again
+        let initial_prop_buf = event_prop_buf.clone();
+        let mut attached = false;
+
+        match Controller::_start_session(
             &mut handle,
             Self::_properties(&mut event_prop_buf),
             session_name,
-        );
+        ) {
+            Ok(()) => {}
+            Err(err) if err.status() == ERROR_ALREADY_EXISTS => match config.existing_session {
+                ExistingSessionPolicy::StopAndRestart => {
+                    Self::stop(session_name)?;
+                    event_prop_buf = initial_prop_buf;
+                    Controller::_start_session(
+                        &mut handle,
+                        Self::_properties(&mut event_prop_buf),
+                        session_name,
+                    )?;
+                }
+                ExistingSessionPolicy::AttachExisting => {
+                    let running = Self::query(session_name)?;
+                    UnavailableFeature::into_result(config.diff(&running))?;
 
-        Self {
+                    // A query returns the handle of the running session in Wnode.HistoricalContext
+                    handle = CONTROLTRACE_HANDLE {
+                        Value: running.Wnode.HistoricalContext,
+                    };
+                    attached = true;
+                }
+                ExistingSessionPolicy::Error => return Err(err),
+            },
+            Err(err) => return Err(err),
+        }
+
+        // A session that was attached to is left running, and a session that was just started makes any marker stale
+        let keep_running = config.keep_alive || attached;
+        if config.keep_alive {
+            KeepAliveMarker::new(session_name, &config).write()?;
+        } else if !attached {
+            KeepAliveMarker::remove(session_name);
+        }
+
+        let controller = Self {
             trace_handle: handle,
             session_name,
             event_prop_buf,
+            attached,
+            keep_running,
+            audit_trail: Mutex::default(),
+        };
+
+        // An attached session is left as it was configured
+        if !attached {
+            controller._audit(ControlAction::Start {
+                enable_flags: config.effective_flags().0,
+                log_file_mode,
+            });
+            for provider in &config.providers {
+                controller._enable_provider(
+                    provider,
+                    config.sequence.enable_property(),
+                    &config.provider_filter,
+                )?;
+            }
+            if !config.stack_walk.is_empty() {
+                stack_walk::enable(controller.trace_handle, &config.stack_walk)?;
+                controller._audit(ControlAction::StackWalk {
+                    events: config.stack_walk.len(),
+                });
+            }
+            if config.mem_info {
+                memory::enable_mem_info(controller.trace_handle, config.effective_flags())?;
+                controller._audit(ControlAction::MemInfo);
+            }
         }
+
+        Ok(controller)
+    }
+
+    /// Whether `session_name` is the NT Kernel Logger, which only logs kernel events
+    pub fn is_kernel_logger(session_name: &CStr) -> bool {
+        session_name.to_bytes() == unsafe { KERNEL_LOGGER_NAMEA.as_bytes() }
     }
 
-    /// Starts the Trace Session with the given session_name. Panics if it's not possible
+    /// Enables `provider` in the running session with [`EnableTraceEx2`]
+    pub fn enable_provider(&self, provider: &ProviderConfig) -> EtwResult<()> {
+        self._enable_provider(provider, 0, &ProviderFilter::default())
+    }
+
+    /// Enables `provider` with the `EVENT_ENABLE_PROPERTY_*` flags in `enable_property`. The provider only logs the
+    /// events `filter` lets through
+    fn _enable_provider(
+        &self,
+        provider: &ProviderConfig,
+        enable_property: u32,
+        filter: &ProviderFilter,
+    ) -> EtwResult<()> {
+        // An EVENT_FILTER_EVENT_ID is a BOOLEAN FilterIn, a reserved byte and a USHORT Count, followed by the ids.
+        // It is built from USHORTs so it is aligned like the struct: FilterIn in the low byte of the first one
+        let event_ids: Vec<u16> = [1, filter.event_ids.len() as u16]
+            .into_iter()
+            .chain(filter.event_ids.iter().copied())
+            .collect();
+        let mut descriptors = Vec::new();
+        if !filter.process_ids.is_empty() {
+            descriptors.push(EVENT_FILTER_DESCRIPTOR {
+                Ptr: filter.process_ids.as_ptr() as u64,
+                Size: mem::size_of_val(filter.process_ids.as_slice()) as u32,
+                Type: EVENT_FILTER_TYPE_PID,
+            });
+        }
+        if !filter.event_ids.is_empty() {
+            descriptors.push(EVENT_FILTER_DESCRIPTOR {
+                Ptr: event_ids.as_ptr() as u64,
+                Size: mem::size_of_val(event_ids.as_slice()) as u32,
+                Type: EVENT_FILTER_TYPE_EVENT_ID,
+            });
+        }
+
+        let parameters = ENABLE_TRACE_PARAMETERS {
+            Version: ENABLE_TRACE_PARAMETERS_VERSION_2,
+            EnableProperty: enable_property,
+            EnableFilterDesc: if descriptors.is_empty() {
+                ptr::null_mut()
+            } else {
+                descriptors.as_mut_ptr()
+            },
+            FilterDescCount: descriptors.len() as u32,
+            ..Default::default()
+        };
+        let status = unsafe {
+            EnableTraceEx2(
+                self.trace_handle,
+                &provider.guid,
+                EVENT_CONTROL_CODE_ENABLE_PROVIDER,
+                provider.level,
+                provider.match_any_keyword,
+                0,
+                0,
+                Some(&parameters),
+            )
+        };
+
+        match status {
+            ERROR_SUCCESS => {
+                self._audit(ControlAction::EnableProvider {
+                    provider: provider.guid,
+                    level: provider.level,
+                    match_any_keyword: provider.match_any_keyword,
+                });
+                Ok(())
+            }
+            status => Err(EtwError::Win32 {
+                status,
+                context: format!(
+                    "EnableTraceEx2 could not enable provider {:?} in session {:?}",
+                    provider.guid, self.session_name
+                ),
+            }),
+        }
+    }
+
+    /// Disables the provider with GUID `guid` in the running session with [`EnableTraceEx2`]
+    pub fn disable_provider(&self, guid: &GUID) -> EtwResult<()> {
+        let status = unsafe {
+            EnableTraceEx2(
+                self.trace_handle,
+                guid,
+                EVENT_CONTROL_CODE_DISABLE_PROVIDER,
+                0,
+                0,
+                0,
+                0,
+                None,
+            )
+        };
+
+        match status {
+            ERROR_SUCCESS => {
+                self._audit(ControlAction::DisableProvider { provider: *guid });
+                Ok(())
+            }
+            status => Err(EtwError::Win32 {
+                status,
+                context: format!(
+                    "EnableTraceEx2 could not disable provider {:?} in session {:?}",
+                    guid, self.session_name
+                ),
+            }),
+        }
+    }
+
+    /// Whether this controller attached to a session that was already running instead of starting its own
+    pub fn is_attached(&self) -> bool {
+        self.attached
+    }
+
+    /// Whether the session is left running when the controller is dropped, see [`ControllerConfig::keep_alive`]
+    pub fn keeps_running(&self) -> bool {
+        self.keep_running
+    }
+
+    /// Starts the Trace Session with the given session_name. Returns an [`EtwError::StartTrace`] if it's not possible
     fn _start_session(
         handle: &mut CONTROLTRACE_HANDLE,
         properties: &mut EVENT_TRACE_PROPERTIES,
         session_name: &CStr,
-    ) {
+    ) -> EtwResult<()> {
         let status =
             unsafe { StartTraceA(handle, Self::_session_name_ptr(session_name), properties) };
 
         match status {
-            ERROR_SUCCESS => {}
-            ERROR_BAD_LENGTH => {
-                panic!(
-                    "One of the following is true:
-                        The Wnode.Buffer size is incorrect: {:?}?
-                        The backing buffer to the event trace properties is not large enough
-                    ",
-                    properties.Wnode.BufferSize
-                );
-            }
-            ERROR_INVALID_PARAMETER => {
-                panic!(
-                    "One of the following is true:
-                        TraceHandle is null: {:?},
-                        LogFileNameOffset of Properties is {},
-                        LoggerNameOffset of Properties is {},
-                        LogFileMode of Properties is {},
-                        Wnode GUID is SystemTraceControl GUID, but the InstanceName parameter is {:?}
-                    ",
-                    handle,
+            ERROR_SUCCESS => Ok(()),
+            status => Err(EtwError::StartTrace {
+                status,
+                context: format!(
+                    "Session {:?}, GUID {:?}, Wnode.BufferSize {}, LogFileNameOffset {}, LoggerNameOffset {}, LogFileMode {}",
+                    session_name,
+                    properties.Wnode.Guid,
+                    properties.Wnode.BufferSize,
                     properties.LogFileNameOffset,
                     properties.LoggerNameOffset,
-                    properties.LogFileMode,
-                    session_name
-                );
-            }
-            ERROR_ALREADY_EXISTS => panic!(
-                "Error, session with name {:?} or GUID {:?} alreay exists!",
-                session_name, properties.Wnode.Guid
-            ),
-            ERROR_BAD_PATHNAME => {
-                panic!("This is supposed to be a realtime session")
-            }
-            ERROR_NO_SYSTEM_RESOURCES => panic!("Not enough system resources"),
-            ERROR_ACCESS_DENIED => {
-                panic!("Only users with administrative privileges can run this!")
-            }
-            status => panic!("Unspecified Error: {:?}", status),
+                    properties.LogFileMode
+                ),
+            }),
+        }
+    }
+
+    /// A handle that writes labelled marker events into this session
+    pub fn bookmarker(&self) -> Bookmarker {
+        Bookmarker::new(self.trace_handle)
+    }
+
+    /// Queries the running session named `session_name` with [`EVENT_TRACE_CONTROL_QUERY`]. The returned properties hold
+    /// the current buffer counts, buffer size and flags of the session
+    pub fn query(session_name: &CStr) -> EtwResult<EVENT_TRACE_PROPERTIES> {
+        Self::_control(session_name, EVENT_TRACE_CONTROL_QUERY, |_| {})
+    }
+
+    /// Every account that can or cannot consume this controller's session in real-time, for checking that a sensitive
+    /// event stream is only readable by who it should be
+    pub fn real_time_consumers(&self) -> EtwResult<Vec<ConsumerAccess>> {
+        let running = Self::query(self.session_name)?;
+        security::real_time_consumers(&running.Wnode.Guid)
+    }
+
+    /// Queries this controller's session for its buffer and lost event counters
+    pub fn query_stats(&self) -> EtwResult<SessionStats> {
+        Self::query(self.session_name).map(|properties| SessionStats::from(&properties))
+    }
+
+    /// Replaces the enabled kernel flags of the running session named `session_name` with [`EVENT_TRACE_CONTROL_UPDATE`].
+    /// The change is logged into the session, but only [`Controller::update_kernel_flags`] keeps it in an audit trail
+    pub fn update_flags(session_name: &CStr, flags: EVENT_TRACE_FLAG) -> EtwResult<()> {
+        let updated = Self::_control(session_name, EVENT_TRACE_CONTROL_UPDATE, |properties| {
+            properties.EnableFlags = flags;
+            properties.LogFileNameOffset = 0; // Keeps the current log file when updating
+        })?;
+
+        // The handle of the session comes back in Wnode.HistoricalContext, as with a query
+        audit::log(
+            CONTROLTRACE_HANDLE {
+                Value: updated.Wnode.HistoricalContext,
+            },
+            &ControlAction::UpdateFlags {
+                enable_flags: flags.0,
+            },
+        );
+        Ok(())
+    }
+
+    /// Same as [`Controller::update_flags`] for this controller's session, recording the change in its audit trail
+    pub fn update_kernel_flags(&self, flags: EVENT_TRACE_FLAG) -> EtwResult<()> {
+        Self::_control(
+            self.session_name,
+            EVENT_TRACE_CONTROL_UPDATE,
+            |properties| {
+                properties.EnableFlags = flags;
+                properties.LogFileNameOffset = 0;
+            },
+        )?;
+
+        self._audit(ControlAction::UpdateFlags {
+            enable_flags: flags.0,
+        });
+        Ok(())
+    }
+
+    /// Changes the buffers of this controller's live session with [`EVENT_TRACE_CONTROL_UPDATE`]. Only
+    /// `maximum_buffers` and `flush_timer_secs` can change once a session is running, a 0 leaves them as they are.
+    /// The enabled flags and logging mode are kept
+    pub fn update(&self, buffers: &BufferConfig) -> EtwResult<()> {
+        let running = Self::query(self.session_name)?;
+
+        Self::_control(
+            self.session_name,
+            EVENT_TRACE_CONTROL_UPDATE,
+            |properties| {
+                properties.EnableFlags = running.EnableFlags;
+                properties.LogFileMode = running.LogFileMode;
+                properties.MaximumBuffers = buffers.maximum_buffers;
+                properties.FlushTimer = buffers.flush_timer_secs;
+                properties.LogFileNameOffset = 0;
+            },
+        )?;
+
+        self._audit(ControlAction::UpdateBuffers {
+            maximum_buffers: buffers.maximum_buffers,
+            flush_timer_secs: buffers.flush_timer_secs,
+        });
+        Ok(())
+    }
+
+    /// Every change this controller made to its session, in the order it made them. Each one is also logged into the
+    /// session as an [`audit::AUDIT_GUID`] event, so an exported trace records what was being collected when
+    pub fn audit_trail(&self) -> Vec<AuditRecord> {
+        self.audit_trail
+            .lock()
+            .expect("Audit trail lock was poisoned")
+            .clone()
+    }
+
+    /// Logs `action` into the session and keeps it in the audit trail. A failure to log it does not fail the action
+    fn _audit(&self, action: ControlAction) {
+        let logged = audit::log(self.trace_handle, &action);
+        self.audit_trail
+            .lock()
+            .expect("Audit trail lock was poisoned")
+            .push(AuditRecord {
+                action,
+                time: SystemTime::now(),
+                logged,
+            });
+    }
+
+    /// Flushes this controller's session with [`EVENT_TRACE_CONTROL_FLUSH`], delivering every buffered event now instead of
+    /// waiting for its buffer to fill or the flush timer
+    pub fn flush(&self) -> EtwResult<()> {
+        Self::_control(self.session_name, EVENT_TRACE_CONTROL_FLUSH, |_| {}).map(|_| ())
+    }
+
+    /// Stops the running session named `session_name`. Can be called from any thread while a consumer is processing the session
+    pub fn stop(session_name: &CStr) -> EtwResult<()> {
+        Self::_control(session_name, EVENT_TRACE_CONTROL_STOP, |_| {}).map(|_| ())
+    }
+
+    /// Calls [`ControlTraceA`] by session name with a freshly allocated properties buffer, large enough for ETW to write back the
+    /// session and log file names. `configure` fills in whatever else the control code needs, such as the flags of an update
+    fn _control(
+        session_name: &CStr,
+        control_code: EVENT_TRACE_CONTROL,
+        configure: impl FnOnce(&mut EVENT_TRACE_PROPERTIES),
+    ) -> EtwResult<EVENT_TRACE_PROPERTIES> {
+        const MAX_NAME_LEN: usize = 1024;
+
+        let logger_name_offset = mem::size_of::<EVENT_TRACE_PROPERTIES>();
+        let log_file_name_offset = logger_name_offset + MAX_NAME_LEN;
+        let mut event_prop_buf = vec![0u8; log_file_name_offset + MAX_NAME_LEN];
+
+        let properties = Self::_properties(&mut event_prop_buf);
+        properties.Wnode.BufferSize = (log_file_name_offset + MAX_NAME_LEN) as u32;
+        properties.Wnode.Guid = SystemTraceControlGuid;
+        properties.Wnode.Flags = WNODE_FLAG_TRACED_GUID;
+        properties.LoggerNameOffset = logger_name_offset as u32;
+        properties.LogFileNameOffset = log_file_name_offset as u32;
+        configure(properties);
+
+        let status = unsafe {
+            ControlTraceA(
+                CONTROLTRACE_HANDLE::default(),
+                Self::_session_name_ptr(session_name),
+                properties,
+                control_code,
+            )
+        };
+
+        match status {
+            ERROR_SUCCESS => Ok(*properties),
+            status => Err(EtwError::ControlTrace {
+                status,
+                context: format!(
+                    "Control code {:?} failed for session {:?}",
+                    control_code, session_name
+                ),
+            }),
         }
     }
 
@@ -138,10 +781,16 @@ impl Controller {
     }
 }
 
-/// Stop the trace if the controller goes out of scope.
+/// Stop the trace if the controller goes out of scope, unless it was attached to a session it did not start or keeps it
+/// alive.
 impl Drop for Controller {
     fn drop(&mut self) {
-        println!("Controller went out of scope, dropping session...");
+        if self.keep_running {
+            return;
+        }
+        KeepAliveMarker::remove(self.session_name);
+
+        eprintln!("Controller went out of scope, dropping session...");
         // check to see if the trace handle is not invalid, this means we have a trace session
         if self.trace_handle.Value as *mut c_void != INVALID_HANDLE_VALUE.0 {
             unsafe {
@@ -0,0 +1,412 @@
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{
+    capture_header::CaptureHeader,
+    error::{EtwError, EtwResult},
+    parsed_event::ParsedEvent,
+    sink::EventSink,
+    stop_handle::StopHandle,
+};
+
+/// Sent by a coordinator to an agent, one JSON object per line
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlCommand {
+    /// Start a live capture configured by `args`, the command line options of a capture on this tool
+    Start { args: Vec<String> },
+    /// Stop the capture that is running
+    Stop,
+}
+
+/// Sent by an agent to its coordinator, one JSON object per line. `Finished` is always the last
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "message", rename_all = "snake_case")]
+pub enum AgentMessage {
+    Header {
+        header: Value,
+    },
+    Event {
+        event: Value,
+    },
+    /// The capture failed, or an event was lost on the way
+    Error {
+        message: String,
+    },
+    Finished,
+}
+
+/// Waits for coordinators to connect, for running the captures they ask for. There is no authentication, anyone who
+/// can connect can start a capture
+pub struct Agent {
+    listener: TcpListener,
+}
+
+impl Agent {
+    pub fn bind(address: SocketAddr) -> EtwResult<Self> {
+        TcpListener::bind(address)
+            .map(|listener| Self { listener })
+            .map_err(|err| EtwError::from_io(&err, format!("Could not listen on {address}")))
+    }
+
+    /// The address listened on, with the port picked if port 0 was asked for
+    pub fn address(&self) -> EtwResult<SocketAddr> {
+        self.listener
+            .local_addr()
+            .map_err(|err| EtwError::from_io(&err, "Could not read the agent address"))
+    }
+
+    /// Waits for the next coordinator
+    pub fn accept(&self) -> EtwResult<AgentConnection> {
+        let (stream, coordinator) = self
+            .listener
+            .accept()
+            .map_err(|err| EtwError::from_io(&err, "Could not accept a coordinator"))?;
+        let commands = stream
+            .try_clone()
+            .map_err(|err| EtwError::from_io(&err, format!("Could not read from {coordinator}")))?;
+        Ok(AgentConnection {
+            coordinator,
+            commands: Some(BufReader::new(commands)),
+            writer: Arc::new(Mutex::new(BufWriter::new(stream))),
+        })
+    }
+}
+
+/// One coordinator connected to an [`Agent`]
+pub struct AgentConnection {
+    coordinator: SocketAddr,
+    commands: Option<BufReader<TcpStream>>, // Taken by the thread waiting for Stop
+    writer: Arc<Mutex<BufWriter<TcpStream>>>,
+}
+
+impl AgentConnection {
+    pub fn coordinator(&self) -> SocketAddr {
+        self.coordinator
+    }
+
+    /// Waits for the coordinator to ask for a capture. None if it disconnects first
+    pub fn start_args(&mut self) -> EtwResult<Option<Vec<String>>> {
+        let Some(commands) = self.commands.as_mut() else {
+            return Ok(None);
+        };
+        loop {
+            match read_line::<ControlCommand>(commands)
+                .map_err(|err| EtwError::from_io(&err, "Could not read a command"))?
+            {
+                Some(ControlCommand::Start { args }) => return Ok(Some(args)),
+                // Nothing is running yet
+                Some(ControlCommand::Stop) => {}
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Stops the capture of `stop_handle` once the coordinator sends Stop or disconnects
+    pub fn stop_on_command(&mut self, stop_handle: StopHandle) -> EtwResult<()> {
+        let Some(mut commands) = self.commands.take() else {
+            stop_handle.stop();
+            return Ok(());
+        };
+        thread::Builder::new()
+            .name("etw-agent".to_string())
+            .spawn(move || {
+                // A command that does not parse does not stop the capture, only Stop or losing the coordinator does
+                loop {
+                    match read_line::<ControlCommand>(&mut commands) {
+                        Ok(Some(ControlCommand::Start { .. })) => {}
+                        Err(err) if err.kind() == io::ErrorKind::InvalidData => {}
+                        _ => break,
+                    }
+                }
+                stop_handle.stop();
+            })
+            .map(drop)
+            .map_err(|err| EtwError::from_io(&err, "Could not spawn the agent thread"))
+    }
+
+    /// Sends the events of the capture to the coordinator
+    pub fn sink(&self) -> AgentSink {
+        AgentSink {
+            writer: Arc::clone(&self.writer),
+        }
+    }
+
+    pub fn send(&self, message: &AgentMessage) -> EtwResult<()> {
+        write_line(
+            &mut *self.writer.lock().expect("Agent writer lock was poisoned"),
+            message,
+        )
+        .map_err(|err| EtwError::from_io(&err, format!("Could not write to {}", self.coordinator)))
+    }
+}
+
+/// Writes events to the coordinator of an [`AgentConnection`]
+pub struct AgentSink {
+    writer: Arc<Mutex<BufWriter<TcpStream>>>,
+}
+
+impl AgentSink {
+    fn _send(&mut self, message: &AgentMessage) -> EtwResult<()> {
+        write_line(
+            &mut *self.writer.lock().expect("Agent writer lock was poisoned"),
+            message,
+        )
+        .map_err(|err| EtwError::from_io(&err, "Could not send the event to the coordinator"))
+    }
+}
+
+impl EventSink for AgentSink {
+    fn write(&mut self, event: &ParsedEvent) -> EtwResult<()> {
+        let event = serde_json::to_value(event).map_err(|err| {
+            EtwError::from_io(&io::Error::from(err), "Could not write the event as JSON")
+        })?;
+        self._send(&AgentMessage::Event { event })
+    }
+
+    fn flush(&mut self) -> EtwResult<()> {
+        self.writer
+            .lock()
+            .expect("Agent writer lock was poisoned")
+            .flush()
+            .map_err(|err| EtwError::from_io(&err, "Could not flush the events to the coordinator"))
+    }
+
+    fn write_header(&mut self, header: &CaptureHeader) -> EtwResult<()> {
+        let header = serde_json::to_value(header).map_err(|err| {
+            EtwError::from_io(&io::Error::from(err), "Could not write the capture header")
+        })?;
+        self._send(&AgentMessage::Header { header })
+    }
+}
+
+/// What an agent sent, tagged with the agent it came from as it was given to [`Coordinator::connect`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostMessage {
+    pub host: String,
+    pub message: AgentMessage,
+}
+
+impl HostMessage {
+    /// The header or event with a `host` field added, for writing as one line of the aggregated stream
+    pub fn tagged(&self) -> Option<Value> {
+        match &self.message {
+            AgentMessage::Header { header } => Some(serde_json::json!({
+                "capture_header": Self::_tag(header.clone(), &self.host)
+            })),
+            AgentMessage::Event { event } => Some(Self::_tag(event.clone(), &self.host)),
+            AgentMessage::Error { .. } | AgentMessage::Finished => None,
+        }
+    }
+
+    fn _tag(mut value: Value, host: &str) -> Value {
+        if let Value::Object(fields) = &mut value {
+            fields.insert("host".to_string(), Value::String(host.to_string()));
+        }
+        value
+    }
+}
+
+/// Runs one capture on several agents at once and merges what they send back, in the order it arrives
+pub struct Coordinator {
+    stopper: CoordinatorStop,
+    messages: mpsc::Receiver<HostMessage>,
+    readers: Vec<JoinHandle<()>>,
+}
+
+impl Coordinator {
+    /// Connects to every agent in `hosts`, given as host:port. Fails if any of them cannot be reached
+    pub fn connect(hosts: &[String]) -> EtwResult<Self> {
+        let (sender, messages) = mpsc::channel();
+        let mut writers = Vec::new();
+        let mut readers = Vec::new();
+        for host in hosts {
+            let connect_error =
+                |err: io::Error| EtwError::from_io(&err, format!("Could not connect to {host}"));
+            let stream = TcpStream::connect(host.as_str()).map_err(connect_error)?;
+            let mut reader = BufReader::new(stream.try_clone().map_err(connect_error)?);
+            writers.push((host.clone(), stream));
+
+            let sender = sender.clone();
+            let host = host.clone();
+            let reader = thread::Builder::new()
+                .name(format!("etw-coordinator-{host}"))
+                .spawn(move || loop {
+                    // A line that does not parse is passed on as an error, a connection that fails ends the agent's
+                    // part of the capture the same as it finishing
+                    let (message, last) = match read_line::<AgentMessage>(&mut reader) {
+                        Ok(Some(message)) => {
+                            let last = message == AgentMessage::Finished;
+                            (message, last)
+                        }
+                        Ok(None) => break,
+                        Err(err) => (
+                            AgentMessage::Error {
+                                message: err.to_string(),
+                            },
+                            err.kind() != io::ErrorKind::InvalidData,
+                        ),
+                    };
+                    let message = HostMessage {
+                        host: host.clone(),
+                        message,
+                    };
+                    if sender.send(message).is_err() || last {
+                        break;
+                    }
+                })
+                .map_err(|err| EtwError::from_io(&err, "Could not spawn a coordinator thread"))?;
+            readers.push(reader);
+        }
+
+        Ok(Self {
+            stopper: CoordinatorStop {
+                agents: Arc::new(writers),
+            },
+            messages,
+            readers,
+        })
+    }
+
+    /// Starts a capture configured by `args` on every agent
+    pub fn start(&self, args: &[String]) -> EtwResult<()> {
+        self.stopper.send(&ControlCommand::Start {
+            args: args.to_vec(),
+        })
+    }
+
+    /// Stops the capture on every agent from another thread, e.g. a Ctrl-C handler or a timer
+    pub fn stopper(&self) -> CoordinatorStop {
+        self.stopper.clone()
+    }
+}
+
+/// Yields what the agents send until every one of them has finished or disconnected
+impl Iterator for Coordinator {
+    type Item = HostMessage;
+
+    fn next(&mut self) -> Option<HostMessage> {
+        let message = self.messages.recv().ok();
+        if message.is_none() {
+            for reader in self.readers.drain(..) {
+                let _ = reader.join();
+            }
+        }
+        message
+    }
+}
+
+/// Sends commands to every agent of a [`Coordinator`]
+#[derive(Clone)]
+pub struct CoordinatorStop {
+    agents: Arc<Vec<(String, TcpStream)>>,
+}
+
+impl CoordinatorStop {
+    /// Asks every agent to stop. An agent that cannot be reached has already stopped, as it stops when its coordinator
+    /// goes away
+    pub fn stop(&self) {
+        let _ = self.send(&ControlCommand::Stop);
+    }
+
+    fn send(&self, command: &ControlCommand) -> EtwResult<()> {
+        for (host, stream) in self.agents.iter() {
+            write_line(stream, command)
+                .map_err(|err| EtwError::from_io(&err, format!("Could not send to {host}")))?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes `message` as one line of JSON and flushes it
+fn write_line<T: Serialize>(mut writer: impl Write, message: &T) -> io::Result<()> {
+    serde_json::to_writer(&mut writer, message)?;
+    writer.write_all(b"\n")?;
+    writer.flush()
+}
+
+/// Reads one line of JSON. None once the other end has closed the connection
+fn read_line<T: for<'de> Deserialize<'de>>(reader: &mut impl BufRead) -> io::Result<Option<T>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    serde_json::from_str(&line)
+        .map(Some)
+        .map_err(io::Error::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_tagged_json_lines() {
+        let mut line = Vec::new();
+        write_line(
+            &mut line,
+            &ControlCommand::Start {
+                args: vec!["--kernel-flags".to_string(), "process".to_string()],
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(line.clone()).unwrap(),
+            "{\"command\":\"start\",\"args\":[\"--kernel-flags\",\"process\"]}\n"
+        );
+
+        let mut reader = io::Cursor::new(line);
+        assert!(matches!(
+            read_line::<ControlCommand>(&mut reader).unwrap(),
+            Some(ControlCommand::Start { args }) if args.len() == 2
+        ));
+        assert_eq!(read_line::<ControlCommand>(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn lines_that_do_not_parse_are_invalid_data() {
+        let mut reader = io::Cursor::new(b"{\"command\":\"reboot\"}\n".to_vec());
+        let err = read_line::<ControlCommand>(&mut reader).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn events_and_headers_are_tagged_with_their_host() {
+        let event = HostMessage {
+            host: "host1:9185".to_string(),
+            message: AgentMessage::Event {
+                event: serde_json::json!({ "event_id": 1 }),
+            },
+        };
+        assert_eq!(
+            event.tagged(),
+            Some(serde_json::json!({ "event_id": 1, "host": "host1:9185" }))
+        );
+
+        let header = HostMessage {
+            host: "host2:9185".to_string(),
+            message: AgentMessage::Header {
+                header: serde_json::json!({ "session": "EtwRustTool" }),
+            },
+        };
+        assert_eq!(
+            header.tagged(),
+            Some(serde_json::json!({
+                "capture_header": { "session": "EtwRustTool", "host": "host2:9185" }
+            }))
+        );
+
+        let finished = HostMessage {
+            host: "host1:9185".to_string(),
+            message: AgentMessage::Finished,
+        };
+        assert_eq!(finished.tagged(), None);
+    }
+}
@@ -0,0 +1,121 @@
+use windows::{core::GUID, Win32::System::Diagnostics::Etw::TRACE_LEVEL_INFORMATION};
+
+use super::{
+    controller::ProviderConfig,
+    filter::PROCESS_GUID,
+    parsed_event::{ParsedEvent, PropertyValue},
+    schemas::Fields,
+};
+
+/// Microsoft-Windows-Windows Error Reporting. Logs a report for every application crash, hang and kernel fault WER handles
+pub const WER_GUID: GUID = GUID::from_u128(0xcc79cf77_70d9_4082_9b52_23f3a3e92fe4);
+
+/// Microsoft-Windows-Kernel-General. Logs boot and shutdown of the OS
+pub const KERNEL_GENERAL_GUID: GUID = GUID::from_u128(0xa68ca8b7_004f_d7b6_a698_07e2de0f1f5d);
+
+/// Microsoft-Windows-Kernel-Power. Logs the reboot after a bugcheck, which Kernel-General has no event for
+pub const KERNEL_POWER_GUID: GUID = GUID::from_u128(0x331c3b3a_2005_44c2_ac5e_77220c37d6b4);
+
+// Events of the Application and System channels
+const EVENT_WER_REPORT: u16 = 1001;
+const EVENT_OS_STARTED: u16 = 12;
+const EVENT_OS_SHUTDOWN: u16 = 13;
+const EVENT_UNEXPECTED_REBOOT: u16 = 41;
+
+const OPCODE_PROCESS_END: u8 = 2;
+
+/// Enables WER, Kernel-General and Kernel-Power at information level
+pub fn providers() -> [ProviderConfig; 3] {
+    [WER_GUID, KERNEL_GENERAL_GUID, KERNEL_POWER_GUID].map(|guid| ProviderConfig {
+        guid,
+        level: TRACE_LEVEL_INFORMATION as u8,
+        match_any_keyword: 0,
+    })
+}
+
+/// A report WER filed, e.g. for an `APPCRASH` or `AppHangB1`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WerReport {
+    /// The kind of report, e.g. `APPCRASH`, `BEX64` or `LiveKernelEvent`
+    pub event_name: String,
+    pub bucket: String,
+    /// For application crashes, the crashing image name
+    pub application: String,
+    pub application_version: String,
+    /// For application crashes, the module the fault happened in
+    pub faulting_module: String,
+    /// For application crashes, the exception code as hex
+    pub exception_code: String,
+    pub report_id: String,
+    pub timestamp: i64,
+}
+
+impl WerReport {
+    /// Returns the event if it is a WER report event
+    pub fn from_event(event: &ParsedEvent) -> Option<Self> {
+        if event.provider != WER_GUID || event.event_id != EVENT_WER_REPORT {
+            return None;
+        }
+        let fields = Fields::of(event);
+
+        // The meaning of P1 to P10 depends on EventName, these are the APPCRASH ones
+        Some(Self {
+            event_name: fields.string("EventName"),
+            bucket: fields.string("Bucket"),
+            application: fields.string("P1"),
+            application_version: fields.string("P2"),
+            faulting_module: fields.string("P4"),
+            exception_code: fields.string("P7"),
+            report_id: fields.string("ReportId"),
+            timestamp: event.timestamp,
+        })
+    }
+
+    /// Whether the report is for a crashed application, rather than a hang or kernel event
+    pub fn is_application_crash(&self) -> bool {
+        matches!(self.event_name.as_str(), "APPCRASH" | "BEX" | "BEX64")
+    }
+}
+
+/// How the system went down or came back up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemEvent {
+    Started,
+    Shutdown,
+    /// The system rebooted without shutting down cleanly. `bugcheck_code` is 0 if it lost power or hung instead of crashing
+    UnexpectedReboot {
+        bugcheck_code: u32,
+    },
+}
+
+impl SystemEvent {
+    /// Returns the event if it is an OS start, shutdown or unexpected reboot event
+    pub fn from_event(event: &ParsedEvent) -> Option<Self> {
+        match (event.provider, event.event_id) {
+            (KERNEL_GENERAL_GUID, EVENT_OS_STARTED) => Some(Self::Started),
+            (KERNEL_GENERAL_GUID, EVENT_OS_SHUTDOWN) => Some(Self::Shutdown),
+            (KERNEL_POWER_GUID, EVENT_UNEXPECTED_REBOOT) => Some(Self::UnexpectedReboot {
+                bugcheck_code: event
+                    .get("BugcheckCode")
+                    .and_then(PropertyValue::as_u64)
+                    .unwrap_or_default() as u32,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the exit status of a kernel Process End event if the process died of an exception, such as
+/// `0xC0000005` for an access violation. Pairs with [`WerReport`] for processes WER did not report on
+pub fn crash_exit_status(event: &ParsedEvent) -> Option<u32> {
+    if event.provider != PROCESS_GUID || event.opcode != OPCODE_PROCESS_END {
+        return None;
+    }
+
+    // NTSTATUS values with the error severity bits set. ExitStatus is signed, so read it as i64 to keep them
+    event
+        .get("ExitStatus")
+        .and_then(PropertyValue::as_i64)
+        .map(|status| status as u32)
+        .filter(|status| status & 0xC000_0000 == 0xC000_0000)
+}
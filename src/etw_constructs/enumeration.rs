@@ -0,0 +1,174 @@
+//! Discovers the providers registered on the machine and the trace sessions running on it, the same as
+//! `logman query providers` and `logman query -ets`
+
+use std::{ffi::CStr, mem};
+
+use windows::{
+    core::{GUID, PCWSTR},
+    Win32::{
+        Foundation::{ERROR_INSUFFICIENT_BUFFER, ERROR_SUCCESS, WIN32_ERROR},
+        System::Diagnostics::Etw::{
+            QueryAllTracesA, TdhEnumerateProviders, EVENT_TRACE_FLAG, EVENT_TRACE_PROPERTIES,
+            EVENT_TRACE_REAL_TIME_MODE, PROVIDER_ENUMERATION_INFO, TRACE_PROVIDER_INFO,
+        },
+    },
+};
+
+use super::{
+    error::{EtwError, EtwResult},
+    session_stats::SessionStats,
+};
+
+/// Most sessions that can run at once
+const MAX_SESSIONS: usize = 64;
+const MAX_NAME_LEN: usize = 1024;
+
+/// Where the schema of a provider's events comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaSource {
+    /// An instrumentation manifest registered with wevtutil
+    Manifest,
+    /// A MOF class, used by the kernel and classic providers
+    Mof,
+}
+
+/// A provider registered on the machine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProviderInfo {
+    pub guid: GUID,
+    pub name: String,
+    pub schema_source: SchemaSource,
+}
+
+/// A trace session running on the machine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ActiveSession {
+    pub name: String,
+    /// The .etl file the session writes to, if any
+    pub log_file: Option<String>,
+    pub log_file_mode: u32,
+    /// Kernel flags, for sessions logging kernel events
+    pub enable_flags: EVENT_TRACE_FLAG,
+    pub stats: SessionStats,
+}
+
+impl ActiveSession {
+    pub fn is_real_time(&self) -> bool {
+        self.log_file_mode & EVENT_TRACE_REAL_TIME_MODE != 0
+    }
+}
+
+/// Every provider registered on the machine with [`TdhEnumerateProviders`], ordered by name
+pub fn list_providers() -> EtwResult<Vec<ProviderInfo>> {
+    let tdh_error = |status| EtwError::Tdh {
+        status,
+        context: "TdhEnumerateProviders could not list the providers".to_string(),
+    };
+
+    let mut buffer_size = 0u32;
+    let mut buffer: Vec<u8> = Vec::new();
+
+    // Providers can be registered between the two calls, so the size is asked for again until it fits
+    loop {
+        let status = WIN32_ERROR(unsafe {
+            TdhEnumerateProviders(
+                (!buffer.is_empty()).then(|| buffer.as_mut_ptr() as *mut PROVIDER_ENUMERATION_INFO),
+                &mut buffer_size,
+            )
+        });
+        match status {
+            // Nothing to size a buffer for means no providers
+            ERROR_SUCCESS if buffer.is_empty() => return Ok(Vec::new()),
+            ERROR_SUCCESS => break,
+            ERROR_INSUFFICIENT_BUFFER => buffer.resize(buffer_size as usize, 0),
+            status => return Err(tdh_error(status)),
+        }
+    }
+
+    let enumeration = unsafe { &*(buffer.as_ptr() as *const PROVIDER_ENUMERATION_INFO) };
+    let infos: &[TRACE_PROVIDER_INFO] = unsafe {
+        std::slice::from_raw_parts(
+            enumeration.TraceProviderInfoArray.as_ptr(),
+            enumeration.NumberOfProviders as usize,
+        )
+    };
+
+    let mut providers: Vec<ProviderInfo> = infos
+        .iter()
+        .map(|info| ProviderInfo {
+            guid: info.ProviderGuid,
+            // The name is a wide string at an offset from the start of the buffer
+            name: unsafe {
+                PCWSTR(buffer.as_ptr().add(info.ProviderNameOffset as usize) as *const u16)
+                    .to_string()
+            }
+            .unwrap_or_default(),
+            schema_source: if info.SchemaSource == 0 {
+                SchemaSource::Manifest
+            } else {
+                SchemaSource::Mof
+            },
+        })
+        .collect();
+    providers.sort_by(|a, b| a.name.to_lowercase().cmp(&b.name.to_lowercase()));
+    Ok(providers)
+}
+
+/// Every trace session running on the machine with [`QueryAllTracesA`], such as a stale NT Kernel Logger left behind
+/// by a crashed run
+pub fn list_active_sessions() -> EtwResult<Vec<ActiveSession>> {
+    // Each session gets its properties followed by room for its name and log file name
+    let logger_name_offset = mem::size_of::<EVENT_TRACE_PROPERTIES>();
+    let log_file_name_offset = logger_name_offset + MAX_NAME_LEN;
+    let buffer_size = log_file_name_offset + MAX_NAME_LEN;
+
+    let mut buffers: Vec<Vec<u8>> = (0..MAX_SESSIONS).map(|_| vec![0u8; buffer_size]).collect();
+    let mut properties: Vec<*mut EVENT_TRACE_PROPERTIES> = buffers
+        .iter_mut()
+        .map(|buffer| {
+            let properties = buffer.as_mut_ptr() as *mut EVENT_TRACE_PROPERTIES;
+            unsafe {
+                (*properties).Wnode.BufferSize = buffer_size as u32;
+                (*properties).LoggerNameOffset = logger_name_offset as u32;
+                (*properties).LogFileNameOffset = log_file_name_offset as u32;
+            }
+            properties
+        })
+        .collect();
+
+    let mut session_count = 0u32;
+    let status = unsafe { QueryAllTracesA(&mut properties, &mut session_count) };
+    if status != ERROR_SUCCESS {
+        return Err(EtwError::ControlTrace {
+            status,
+            context: "QueryAllTracesA could not list the running sessions".to_string(),
+        });
+    }
+
+    // An offset of 0 means the session has no such name
+    let string_at = |buffer: &[u8], offset: u32| {
+        if offset == 0 {
+            return String::new();
+        }
+        CStr::from_bytes_until_nul(&buffer[offset as usize..])
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    };
+
+    Ok(buffers
+        .iter()
+        .take(session_count as usize)
+        .map(|buffer| {
+            let running = unsafe { &*(buffer.as_ptr() as *const EVENT_TRACE_PROPERTIES) };
+            let log_file = string_at(buffer, running.LogFileNameOffset);
+
+            ActiveSession {
+                name: string_at(buffer, running.LoggerNameOffset),
+                log_file: (!log_file.is_empty()).then_some(log_file),
+                log_file_mode: running.LogFileMode,
+                enable_flags: running.EnableFlags,
+                stats: SessionStats::from(running),
+            }
+        })
+        .collect())
+}
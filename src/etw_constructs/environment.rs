@@ -0,0 +1,321 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    ffi::c_void,
+    mem,
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use windows::{
+    Wdk::System::Threading::{NtQueryInformationProcess, ProcessBasicInformation},
+    Win32::{
+        Foundation::{CloseHandle, FILETIME, HANDLE, WIN32_ERROR},
+        System::{
+            Diagnostics::Debug::ReadProcessMemory,
+            Threading::{
+                GetProcessTimes, OpenProcess, PROCESS_BASIC_INFORMATION, PROCESS_QUERY_INFORMATION,
+                PROCESS_VM_READ,
+            },
+        },
+    },
+};
+
+use super::{
+    error::{EtwError, EtwResult},
+    parsed_event::{ParsedEvent, PropertyValue},
+    taxonomy::{Action, CanonicalEvent},
+};
+
+// Offsets into the PEB and RTL_USER_PROCESS_PARAMETERS of a process with the same pointer size as ours.
+// Neither struct is documented past its first few fields, but these have not moved since Windows Vista
+#[cfg(target_pointer_width = "64")]
+mod offsets {
+    pub const PROCESS_PARAMETERS: usize = 0x20;
+    pub const CURRENT_DIRECTORY: usize = 0x38;
+    pub const ENVIRONMENT: usize = 0x80;
+    pub const ENVIRONMENT_SIZE: usize = 0x3f0;
+}
+#[cfg(target_pointer_width = "32")]
+mod offsets {
+    pub const PROCESS_PARAMETERS: usize = 0x10;
+    pub const CURRENT_DIRECTORY: usize = 0x24;
+    pub const ENVIRONMENT: usize = 0x48;
+    pub const ENVIRONMENT_SIZE: usize = 0x290;
+}
+
+/// Largest environment block read, anything past it is ignored
+const MAX_ENVIRONMENT_BYTES: usize = 64 * 1024;
+
+/// How much later than its Start event a process may have been created, in FILETIME ticks. The kernel logs the event
+/// after setting the create time, so anything past this is a new process that reused the id
+const CREATE_TIME_SLACK: i64 = 10_000; // 1ms
+
+/// The current directory and selected environment variables of a process
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ProcessEnvironment {
+    /// When the process that was read was created, in FILETIME ticks
+    pub create_time: i64,
+    pub current_directory: String,
+    /// Only the variables that were asked for and are set, keyed on the name the process has them under
+    pub variables: BTreeMap<String, String>,
+}
+
+impl ProcessEnvironment {
+    /// Reads the environment of the running process `process_id` out of its PEB. Only `variables` are kept, matched
+    /// ignoring case like Windows does. Needs the rights to read the process's memory, so protected processes and
+    /// processes of other users fail without admin rights
+    pub fn read(process_id: u32, variables: &[String]) -> EtwResult<Self> {
+        let read_error = |err: windows::core::Error| EtwError::Win32 {
+            status: WIN32_ERROR::from_error(&err).unwrap_or_default(),
+            context: format!("Could not read the environment of process {process_id}"),
+        };
+
+        let process = unsafe {
+            OpenProcess(
+                PROCESS_QUERY_INFORMATION | PROCESS_VM_READ,
+                false,
+                process_id,
+            )
+        }
+        .map_err(read_error)?;
+        let environment = Self::_read(process, variables).map_err(read_error);
+        let _ = unsafe { CloseHandle(process) };
+        environment
+    }
+
+    fn _read(process: HANDLE, variables: &[String]) -> windows::core::Result<Self> {
+        let (mut create_time, mut exit_time, mut kernel_time, mut user_time) = (
+            FILETIME::default(),
+            FILETIME::default(),
+            FILETIME::default(),
+            FILETIME::default(),
+        );
+        unsafe {
+            GetProcessTimes(
+                process,
+                &mut create_time,
+                &mut exit_time,
+                &mut kernel_time,
+                &mut user_time,
+            )
+        }?;
+
+        let mut basic_information = PROCESS_BASIC_INFORMATION::default();
+        unsafe {
+            NtQueryInformationProcess(
+                process,
+                ProcessBasicInformation,
+                &mut basic_information as *mut _ as *mut c_void,
+                mem::size_of::<PROCESS_BASIC_INFORMATION>() as u32,
+                &mut 0,
+            )
+        }
+        .ok()?;
+
+        let peb = basic_information.PebBaseAddress as usize;
+        let parameters: usize = Self::_read_value(process, peb + offsets::PROCESS_PARAMETERS)?;
+
+        // UNICODE_STRING: Length and MaximumLength in bytes, then the buffer pointer aligned to a pointer
+        let directory_length: u16 =
+            Self::_read_value(process, parameters + offsets::CURRENT_DIRECTORY)?;
+        let directory_buffer: usize = Self::_read_value(
+            process,
+            parameters + offsets::CURRENT_DIRECTORY + mem::size_of::<usize>(),
+        )?;
+        let current_directory = String::from_utf16_lossy(&Self::_read_utf16(
+            process,
+            directory_buffer,
+            directory_length as usize,
+        )?);
+
+        let environment: usize = Self::_read_value(process, parameters + offsets::ENVIRONMENT)?;
+        let environment_size: usize =
+            Self::_read_value(process, parameters + offsets::ENVIRONMENT_SIZE)?;
+        let block = Self::_read_utf16(
+            process,
+            environment,
+            environment_size.min(MAX_ENVIRONMENT_BYTES),
+        )?;
+
+        Ok(Self {
+            create_time: ((create_time.dwHighDateTime as i64) << 32)
+                | create_time.dwLowDateTime as i64,
+            current_directory,
+            variables: Self::_select(&block, variables),
+        })
+    }
+
+    /// Picks `variables` out of an environment block: NAME=value strings, each null terminated, ending with an empty one
+    fn _select(block: &[u16], variables: &[String]) -> BTreeMap<String, String> {
+        block
+            .split(|c| *c == 0)
+            .take_while(|entry| !entry.is_empty())
+            .map(String::from_utf16_lossy)
+            // Variables starting with = are the per-drive current directories, such as =C:=C:\Windows
+            .filter_map(|entry| {
+                let split = entry.get(1..)?.find('=')? + 1;
+                Some((entry[..split].to_string(), entry[split + 1..].to_string()))
+            })
+            .filter(|(name, _)| {
+                variables
+                    .iter()
+                    .any(|variable| variable.eq_ignore_ascii_case(name))
+            })
+            .collect()
+    }
+
+    fn _read_value<T: Default + Copy>(process: HANDLE, address: usize) -> windows::core::Result<T> {
+        let mut value = T::default();
+        unsafe {
+            ReadProcessMemory(
+                process,
+                address as *const c_void,
+                &mut value as *mut T as *mut c_void,
+                mem::size_of::<T>(),
+                None,
+            )
+        }?;
+        Ok(value)
+    }
+
+    fn _read_utf16(
+        process: HANDLE,
+        address: usize,
+        size_bytes: usize,
+    ) -> windows::core::Result<Vec<u16>> {
+        let mut buffer = vec![0u16; size_bytes / 2];
+        if buffer.is_empty() {
+            return Ok(buffer);
+        }
+
+        let mut read = 0;
+        unsafe {
+            ReadProcessMemory(
+                process,
+                address as *const c_void,
+                buffer.as_mut_ptr() as *mut c_void,
+                buffer.len() * 2,
+                Some(&mut read),
+            )
+        }?;
+        buffer.truncate(read / 2);
+        Ok(buffer)
+    }
+}
+
+type EnvironmentRequest = (u32, Sender<EtwResult<ProcessEnvironment>>);
+
+/// An event waiting in [`EnvironmentCapture`], with the environment read for it if it is a process start
+struct QueuedEvent {
+    event: ParsedEvent,
+    read: Option<(Receiver<EtwResult<ProcessEnvironment>>, Instant)>, // With the time to give up waiting at
+}
+
+/// Attaches the current directory and selected environment variables to kernel Process Start events, since the
+/// command line alone often is not enough for triage.
+///
+/// Reading another process's memory can stall, so the reads happen on a worker thread. Events are handed in with
+/// [`EnvironmentCapture::push`] and taken back out in the same order with [`EnvironmentCapture::pop_ready`], which
+/// never blocks: a Start event holds back the events behind it for at most [`EnvironmentCapture::timeout`]. Capture
+/// is best-effort: a process that already exited, that cannot be opened, or whose id was reused by the time it was
+/// read is left as it is. Only live sessions can be captured, since the processes of a recorded trace are long gone
+pub struct EnvironmentCapture {
+    requests: Sender<EnvironmentRequest>,
+    timeout: Duration,
+    queue: VecDeque<QueuedEvent>,
+}
+
+impl EnvironmentCapture {
+    /// Captures `variables` for every started process. Waits up to 50ms per process
+    pub fn new(variables: Vec<String>) -> EtwResult<Self> {
+        let (requests, pending) = mpsc::channel::<EnvironmentRequest>();
+        thread::Builder::new()
+            .name("etw-environment".to_string())
+            .spawn(move || {
+                for (process_id, reply) in pending {
+                    let _ = reply.send(ProcessEnvironment::read(process_id, &variables));
+                }
+            })
+            .map_err(|err| EtwError::from_io(&err, "Could not spawn the environment thread"))?;
+
+        Ok(Self {
+            requests,
+            timeout: Duration::from_millis(50),
+            queue: VecDeque::new(),
+        })
+    }
+
+    /// How long each Start event waits for the environment of its process
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Queues `event`, starting to read the environment of its process if it is a process start from any source
+    pub fn push(&mut self, event: ParsedEvent) {
+        let read = CanonicalEvent::from_event(&event)
+            .filter(|process| process.action == Action::ProcessStart)
+            .and_then(|process| {
+                let (reply, result) = mpsc::channel();
+                self.requests.send((process.process_id, reply)).ok()?;
+                Some((result, Instant::now() + self.timeout))
+            });
+
+        self.queue.push_back(QueuedEvent { event, read });
+    }
+
+    /// The oldest queued event, once the environment of its process was read or its time ran out. Adds a
+    /// `CurrentDirectory` property and an `Environment` struct of the captured variables to process starts
+    pub fn pop_ready(&mut self) -> Option<ParsedEvent> {
+        let queued = self.queue.front_mut()?;
+        let environment = match &queued.read {
+            None => None,
+            Some((result, deadline)) => match result.try_recv() {
+                Ok(environment) => environment.ok(),
+                Err(TryRecvError::Empty) if Instant::now() < *deadline => return None,
+                Err(_) => None,
+            },
+        };
+
+        let QueuedEvent { mut event, .. } = self.queue.pop_front()?;
+        if let Some(environment) = environment {
+            Self::_attach(&mut event, environment);
+        }
+        Some(event)
+    }
+
+    /// Every queued event, waiting out the reads still running
+    pub fn drain(&mut self) -> Vec<ParsedEvent> {
+        let mut events = Vec::with_capacity(self.queue.len());
+        while let Some(front) = self.queue.front() {
+            if let Some((_, deadline)) = &front.read {
+                thread::sleep(deadline.saturating_duration_since(Instant::now()));
+            }
+            events.extend(self.pop_ready());
+        }
+        events
+    }
+
+    fn _attach(event: &mut ParsedEvent, environment: ProcessEnvironment) {
+        // The id was reused if the process that was read started after the one the event is about
+        if environment.create_time > event.timestamp + CREATE_TIME_SLACK {
+            return;
+        }
+
+        event.properties.insert(
+            "CurrentDirectory".to_string(),
+            PropertyValue::String(environment.current_directory),
+        );
+        event.properties.insert(
+            "Environment".to_string(),
+            PropertyValue::Struct(
+                environment
+                    .variables
+                    .into_iter()
+                    .map(|(name, value)| (name, PropertyValue::String(value)))
+                    .collect(),
+            ),
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use std::{error::Error, fmt, io};
+
+use windows::Win32::Foundation::{
+    ERROR_ACCESS_DENIED, ERROR_ALREADY_EXISTS, ERROR_BAD_LENGTH, ERROR_BAD_PATHNAME,
+    ERROR_BROKEN_PIPE, ERROR_CANCELLED, ERROR_GEN_FAILURE, ERROR_INVALID_HANDLE,
+    ERROR_INVALID_PARAMETER, ERROR_INVALID_TIME, ERROR_NOACCESS, ERROR_NOT_SUPPORTED,
+    ERROR_NO_DATA, ERROR_NO_SYSTEM_RESOURCES, ERROR_WMI_INSTANCE_NOT_FOUND, WIN32_ERROR,
+};
+
+/// Errors returned by the ETW wrappers. Each variant records which Win32 call failed along with the status it returned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EtwError {
+    /// `StartTraceA` failed. `context` holds the state of the properties buffer at the time of the call
+    StartTrace {
+        status: WIN32_ERROR,
+        context: String,
+    },
+    /// `ControlTraceA` failed
+    ControlTrace {
+        status: WIN32_ERROR,
+        context: String,
+    },
+    /// `OpenTraceA` returned an invalid handle
+    OpenTrace {
+        status: WIN32_ERROR,
+        context: String,
+    },
+    /// `ProcessTrace` returned something other than success
+    ProcessTrace { status: WIN32_ERROR },
+    /// A TDH call failed while decoding an event
+    Tdh {
+        status: WIN32_ERROR,
+        context: String,
+    },
+    /// Any other Win32 call failed
+    Win32 {
+        status: WIN32_ERROR,
+        context: String,
+    },
+    /// Reading or writing a file failed. `status` is the OS error code behind the [`io::Error`]
+    Io {
+        status: WIN32_ERROR,
+        context: String,
+    },
+}
+
+impl EtwError {
+    /// The underlying Win32 status code
+    pub fn status(&self) -> WIN32_ERROR {
+        match self {
+            EtwError::StartTrace { status, .. }
+            | EtwError::ControlTrace { status, .. }
+            | EtwError::OpenTrace { status, .. }
+            | EtwError::ProcessTrace { status }
+            | EtwError::Tdh { status, .. }
+            | EtwError::Win32 { status, .. }
+            | EtwError::Io { status, .. } => *status,
+        }
+    }
+
+    /// Whether writing failed because the other end of a pipe was closed, the same as [`io::ErrorKind::BrokenPipe`]
+    pub fn is_broken_pipe(&self) -> bool {
+        matches!(
+            self,
+            EtwError::Io { status, .. } if *status == ERROR_BROKEN_PIPE || *status == ERROR_NO_DATA
+        )
+    }
+
+    /// Wraps an [`io::Error`], keeping its OS error code if it has one
+    pub fn from_io(err: &io::Error, context: impl Into<String>) -> Self {
+        EtwError::Io {
+            status: err
+                .raw_os_error()
+                .map_or(ERROR_GEN_FAILURE, |code| WIN32_ERROR(code as u32)),
+            context: format!("{}: {err}", context.into()),
+        }
+    }
+}
+
+impl fmt::Display for EtwError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EtwError::StartTrace { status, context } => {
+                let reason = match *status {
+                    ERROR_BAD_LENGTH => "The Wnode.Buffer size is incorrect or the backing buffer to the event trace properties is not large enough",
+                    ERROR_INVALID_PARAMETER => "TraceHandle is null, or LogFileNameOffset, LoggerNameOffset, LogFileMode or the session name is invalid",
+                    ERROR_ALREADY_EXISTS => "A session with this name or GUID already exists!",
+                    ERROR_BAD_PATHNAME => "The log file path is invalid",
+                    ERROR_NO_SYSTEM_RESOURCES => "Not enough system resources",
+                    ERROR_ACCESS_DENIED => "Only users with administrative privileges can run this!",
+                    ERROR_NOT_SUPPORTED => "The requested configuration is not supported on this system",
+                    _ => "Unspecified Error",
+                };
+                write!(f, "StartTraceA failed ({:?}): {reason}. {context}", status)
+            }
+            EtwError::ControlTrace { status, context } => {
+                write!(f, "ControlTraceA failed ({:?}): {context}", status)
+            }
+            EtwError::OpenTrace { status, context } => {
+                write!(f, "OpenTraceA failed ({:?}): {context}", status)
+            }
+            EtwError::ProcessTrace { status } => {
+                let reason = match *status {
+                    ERROR_BAD_LENGTH => "HandleCount is not valid or the number of handles is greater than 64.",
+                    ERROR_INVALID_HANDLE => "An element of HandleArray is not a valid event tracing session handle.",
+                    ERROR_INVALID_TIME => "EndTime is less than StartTime.",
+                    ERROR_INVALID_PARAMETER => "HandleArray is NULL, contains both file processing sessions and real-time processing sessions, or contains more than one real-time processing session.",
+                    ERROR_NOACCESS | ERROR_CANCELLED => "An exception occurred in one of the callback functions that receives the events.",
+                    ERROR_WMI_INSTANCE_NOT_FOUND => "The trace collection session from which you are trying to consume events in real time is not running or does not have the real-time trace mode enabled.",
+                    _ => "Unspecified Error",
+                };
+                write!(f, "ProcessTrace failed ({:?}): {reason}", status)
+            }
+            EtwError::Tdh { status, context } => {
+                write!(f, "TDH call failed ({:?}): {context}. Please consult https://learn.microsoft.com/en-us/windows/win32/debug/system-error-codes--0-499- for what the error code means.", status)
+            }
+            EtwError::Win32 { status, context } => {
+                write!(f, "Win32 call failed ({:?}): {context}", status)
+            }
+            EtwError::Io { status, context } => {
+                write!(f, "I/O failed ({:?}): {context}", status)
+            }
+        }
+    }
+}
+
+impl Error for EtwError {}
+
+/// Shorthand for results produced by this crate
+pub type EtwResult<T> = Result<T, EtwError>;
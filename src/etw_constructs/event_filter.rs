@@ -0,0 +1,292 @@
+use windows::core::GUID;
+
+use super::parsed_event::{ParsedEvent, PropertyValue};
+
+/// What a [`Comparison`] looks at
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Field {
+    Provider,
+    EventId,
+    Opcode,
+    ProcessId,
+    ThreadId,
+    /// A decoded property, compared by its display string, ignoring case
+    Property(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Guid(GUID),
+    Number(u64),
+    Text(String), // Lowercase
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Comparison {
+    field: Field,
+    value: Value,
+    negated: bool,
+}
+
+impl Comparison {
+    fn matches(&self, event: &ParsedEvent) -> bool {
+        let equal = match (&self.field, &self.value) {
+            (Field::Provider, Value::Guid(guid)) => event.provider == *guid,
+            (Field::EventId, Value::Number(id)) => event.event_id as u64 == *id,
+            (Field::Opcode, Value::Number(opcode)) => event.opcode as u64 == *opcode,
+            (Field::ProcessId, Value::Number(id)) => event.process_id as u64 == *id,
+            (Field::ThreadId, Value::Number(id)) => event.thread_id as u64 == *id,
+            (Field::Property(name), Value::Text(text)) => event
+                .get(name)
+                .is_some_and(|value| Self::_text(value) == *text),
+            _ => false,
+        };
+        equal != self.negated
+    }
+
+    fn _text(value: &PropertyValue) -> String {
+        match value.as_str() {
+            Some(text) => text.to_lowercase(),
+            None => value.to_string().to_lowercase(),
+        }
+    }
+}
+
+/// A filter expression compiled once, then matched against decoded events, so each sink can keep only the events it
+/// cares about.
+///
+/// An expression is comparisons joined by `&` (and) and `|` (or), with `&` binding tighter, e.g.
+/// `provider=process&opcode=1|provider=tcpip`. A comparison is `field=value` or `field!=value`, where the field is
+/// `provider`, `event_id`, `opcode`, `pid`, `tid` or the name of a decoded property. Numbers may be hex with a 0x
+/// prefix, and properties are compared by their display string, ignoring case. A value holding `&` or `|` goes in
+/// double quotes, e.g. `CommandLine="a.exe & b.exe"`, and cannot hold a double quote itself. An empty expression
+/// matches everything
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EventFilter {
+    expression: String,
+    clauses: Vec<Vec<Comparison>>, // Any clause has to match, and every comparison within it
+}
+
+impl EventFilter {
+    /// Compiles `expression`. `resolve_provider` turns the value of a `provider` comparison into a GUID, so callers
+    /// can accept their own provider names
+    pub fn compile(
+        expression: &str,
+        resolve_provider: impl Fn(&str) -> Result<GUID, String>,
+    ) -> Result<Self, String> {
+        if expression.matches('"').count() % 2 != 0 {
+            return Err(format!("{:?} does not close its quotes", expression.trim()));
+        }
+        let mut clauses = Vec::new();
+        if !expression.trim().is_empty() {
+            for clause in split_unquoted(expression, '|') {
+                let comparisons = split_unquoted(clause, '&')
+                    .into_iter()
+                    .map(|comparison| Self::_comparison(comparison, &resolve_provider))
+                    .collect::<Result<_, _>>()?;
+                clauses.push(comparisons);
+            }
+        }
+
+        Ok(Self {
+            expression: expression.trim().to_string(),
+            clauses,
+        })
+    }
+
+    pub fn matches(&self, event: &ParsedEvent) -> bool {
+        self.clauses.is_empty()
+            || self
+                .clauses
+                .iter()
+                .any(|clause| clause.iter().all(|comparison| comparison.matches(event)))
+    }
+
+    /// The expression the filter was compiled from
+    pub fn expression(&self) -> &str {
+        &self.expression
+    }
+
+    fn _comparison(
+        comparison: &str,
+        resolve_provider: &impl Fn(&str) -> Result<GUID, String>,
+    ) -> Result<Comparison, String> {
+        // Field names hold no `=`, so the first one ends the field and the value may hold more
+        let Some((field, value)) = comparison.split_once('=') else {
+            return Err(format!("{:?} is not field=value", comparison.trim()));
+        };
+        let (field, negated) = match field.strip_suffix('!') {
+            Some(field) => (field, true),
+            None => (field, false),
+        };
+        let (field, value) = (field.trim(), value.trim());
+        if field.is_empty() {
+            return Err(format!("{:?} has no field", comparison.trim()));
+        }
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+
+        let number = || {
+            match value.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => value.parse(),
+            }
+            .map(Value::Number)
+            .map_err(|_| format!("{value:?} is not a number"))
+        };
+        let (field, value) = match field {
+            "provider" => (Field::Provider, Value::Guid(resolve_provider(value)?)),
+            "event_id" => (Field::EventId, number()?),
+            "opcode" => (Field::Opcode, number()?),
+            "pid" => (Field::ProcessId, number()?),
+            "tid" => (Field::ThreadId, number()?),
+            name => (
+                Field::Property(name.to_string()),
+                Value::Text(value.to_lowercase()),
+            ),
+        };
+
+        Ok(Comparison {
+            field,
+            value,
+            negated,
+        })
+    }
+}
+
+/// Splits `text` on `separator` wherever it is outside double quotes
+fn split_unquoted(text: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+    for (index, c) in text.char_indices() {
+        if c == '"' {
+            quoted = !quoted;
+        } else if c == separator && !quoted {
+            parts.push(&text[start..index]);
+            start = index + c.len_utf8();
+        }
+    }
+    parts.push(&text[start..]);
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, time::SystemTime};
+
+    use super::*;
+    use crate::etw_constructs::parsed_event::EventArchitecture;
+
+    const PROCESS: GUID = GUID::from_u128(0x3d6fa8d0_fe05_11d0_9dda_00c04fd7ba7c);
+    const TCPIP: GUID = GUID::from_u128(0x9a280ac0_c8e0_11d1_84e2_00c04fb998a2);
+
+    fn resolve(name: &str) -> Result<GUID, String> {
+        match name {
+            "process" => Ok(PROCESS),
+            "tcpip" => Ok(TCPIP),
+            name => Err(format!("{name:?} is not a provider")),
+        }
+    }
+
+    fn compile(expression: &str) -> EventFilter {
+        EventFilter::compile(expression, resolve).unwrap()
+    }
+
+    fn event(provider: GUID, opcode: u8, properties: &[(&str, &str)]) -> ParsedEvent {
+        ParsedEvent {
+            provider,
+            event_id: 0,
+            opcode,
+            version: 0,
+            schema_hash: None,
+            schema_fields: None,
+            process_id: 4,
+            thread_id: 8,
+            timestamp: 0,
+            time: SystemTime::UNIX_EPOCH,
+            sequence: 0,
+            architecture: EventArchitecture::default(),
+            properties: properties
+                .iter()
+                .map(|(name, value)| (name.to_string(), PropertyValue::String(value.to_string())))
+                .collect::<BTreeMap<_, _>>(),
+            stack: Vec::new(),
+            task_name: None,
+            event_name: None,
+            extended: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let filter = compile("provider=process&opcode=1|provider=tcpip");
+
+        assert!(filter.matches(&event(PROCESS, 1, &[])));
+        assert!(!filter.matches(&event(PROCESS, 2, &[])));
+        assert!(filter.matches(&event(TCPIP, 2, &[])));
+    }
+
+    #[test]
+    fn not_equal_negates_the_comparison() {
+        let filter = compile("provider=process&opcode!=1");
+
+        assert!(!filter.matches(&event(PROCESS, 1, &[])));
+        assert!(filter.matches(&event(PROCESS, 2, &[])));
+        assert!(!filter.matches(&event(TCPIP, 2, &[])));
+    }
+
+    #[test]
+    fn numbers_may_be_hex() {
+        assert!(compile("pid=0x4&tid=8").matches(&event(PROCESS, 1, &[])));
+        assert!(!compile("pid=0x10").matches(&event(PROCESS, 1, &[])));
+    }
+
+    #[test]
+    fn empty_expressions_match_everything() {
+        assert!(compile("").matches(&event(TCPIP, 7, &[])));
+        assert!(compile("  ").matches(&event(TCPIP, 7, &[])));
+    }
+
+    #[test]
+    fn properties_are_compared_ignoring_case() {
+        let event = event(PROCESS, 1, &[("ImageFileName", "Cmd.exe")]);
+
+        assert!(compile("ImageFileName=cmd.EXE").matches(&event));
+        assert!(!compile("ImageFileName=powershell.exe").matches(&event));
+        assert!(!compile("CommandLine=cmd.exe").matches(&event));
+    }
+
+    #[test]
+    fn quoted_values_may_hold_separators() {
+        let filter = compile(r#"CommandLine="a.exe & b.exe | c=d"&provider=process"#);
+
+        assert!(filter.matches(&event(
+            PROCESS,
+            1,
+            &[("CommandLine", "A.exe & b.exe | c=d")]
+        )));
+        assert!(!filter.matches(&event(PROCESS, 1, &[("CommandLine", "a.exe")])));
+    }
+
+    #[test]
+    fn bad_comparisons_are_rejected() {
+        for expression in [
+            "provider",
+            "=process",
+            "!=1",
+            "opcode=one",
+            "pid=0xzz",
+            "provider=nope",
+            "provider=process&",
+            r#"CommandLine="a.exe"#,
+        ] {
+            assert!(
+                EventFilter::compile(expression, resolve).is_err(),
+                "{expression:?} compiled"
+            );
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use core::slice;
+use std::fmt::Write;
+
+use serde::Serialize;
+use windows::{
+    core::GUID,
+    Win32::System::Diagnostics::Etw::{
+        EVENT_HEADER_EXTENDED_DATA_ITEM, EVENT_HEADER_EXT_TYPE_EVENT_KEY,
+        EVENT_HEADER_EXT_TYPE_EVENT_SCHEMA_TL, EVENT_HEADER_EXT_TYPE_INSTANCE_INFO,
+        EVENT_HEADER_EXT_TYPE_PROCESS_START_KEY, EVENT_HEADER_EXT_TYPE_RELATED_ACTIVITYID,
+        EVENT_HEADER_EXT_TYPE_SID, EVENT_HEADER_EXT_TYPE_STACK_TRACE32,
+        EVENT_HEADER_EXT_TYPE_STACK_TRACE64, EVENT_HEADER_EXT_TYPE_TS_ID, EVENT_RECORD,
+    },
+};
+
+use super::{parsed_event::serialize_guid, schema_cache::trace_logging_schema_key};
+
+/// An extended data item a provider or ETW attached to an event, see
+/// https://learn.microsoft.com/en-us/windows/win32/api/evntcons/ns-evntcons-event_header_extended_data_item
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub enum ExtendedData {
+    /// The activity that caused the event's activity, for following work across threads and processes
+    RelatedActivityId(#[serde(serialize_with = "serialize_guid")] GUID),
+    /// The user the logging thread ran as, e.g. `S-1-5-18`
+    Sid(String),
+    /// The terminal services session the logging process ran in
+    TerminalSessionId(u32),
+    /// Identifies the provider instance of an event logged with `EventWriteEx`, and its parent
+    InstanceInfo {
+        instance_id: u32,
+        parent_instance_id: u32,
+        #[serde(serialize_with = "serialize_guid")]
+        parent_guid: GUID,
+    },
+    /// The call stack the event was logged from, innermost first. `match_id` pairs the kernel and user halves of a
+    /// stack that were logged separately
+    StackTrace { match_id: u64, frames: Vec<u64> },
+    /// A key unique to the process for as long as the machine is up, unlike its process id
+    ProcessStartKey(u64),
+    /// A key unique to the event, for spotting duplicates across sessions
+    EventKey(u64),
+    /// The TraceLogging metadata the event was decoded with, as the key it is cached on in
+    /// [`SchemaCache`](super::schema_cache::SchemaCache) rather than a copy of it. Its fields are in
+    /// [`ParsedEvent::schema_fields`](super::parsed_event::ParsedEvent::schema_fields)
+    TraceLoggingSchema(u64),
+    /// Any other item, left undecoded
+    Other { ext_type: u16, data: Vec<u8> },
+}
+
+impl ExtendedData {
+    /// Decodes every extended data item of `record`. Empty if it has none
+    pub fn from_record(record: &EVENT_RECORD) -> Vec<Self> {
+        if record.ExtendedData.is_null() || record.ExtendedDataCount == 0 {
+            return Vec::new();
+        }
+
+        unsafe { slice::from_raw_parts(record.ExtendedData, record.ExtendedDataCount as usize) }
+            .iter()
+            .map(Self::_from_item)
+            .collect()
+    }
+
+    /// The event key of `record`, if its provider was enabled with `EVENT_ENABLE_PROPERTY_EVENT_KEY`
+    pub fn event_key(record: &EVENT_RECORD) -> Option<u64> {
+        if record.ExtendedData.is_null() || record.ExtendedDataCount == 0 {
+            return None;
+        }
+
+        unsafe { slice::from_raw_parts(record.ExtendedData, record.ExtendedDataCount as usize) }
+            .iter()
+            .find(|item| item.ExtType as u32 == EVENT_HEADER_EXT_TYPE_EVENT_KEY)
+            .and_then(|item| match Self::_from_item(item) {
+                ExtendedData::EventKey(key) => Some(key),
+                _ => None,
+            })
+    }
+
+    /// The frames of the first stack trace in `items`, if there is one
+    pub fn stack_trace(items: &[Self]) -> Option<&[u64]> {
+        items.iter().find_map(|item| match item {
+            ExtendedData::StackTrace { frames, .. } => Some(frames.as_slice()),
+            _ => None,
+        })
+    }
+
+    fn _from_item(item: &EVENT_HEADER_EXTENDED_DATA_ITEM) -> Self {
+        let data: &[u8] = if item.DataPtr == 0 {
+            &[]
+        } else {
+            unsafe { slice::from_raw_parts(item.DataPtr as *const u8, item.DataSize as usize) }
+        };
+        let ext_type = item.ExtType as u32;
+
+        let decoded = match ext_type {
+            EVENT_HEADER_EXT_TYPE_RELATED_ACTIVITYID => {
+                Self::_u128(data, 0).map(|guid| Self::RelatedActivityId(GUID::from_u128(guid)))
+            }
+            EVENT_HEADER_EXT_TYPE_SID => sid_to_string(data).map(Self::Sid),
+            EVENT_HEADER_EXT_TYPE_TS_ID => read_u32(data, 0).map(Self::TerminalSessionId),
+            EVENT_HEADER_EXT_TYPE_INSTANCE_INFO => {
+                match (read_u32(data, 0), read_u32(data, 4), Self::_u128(data, 8)) {
+                    (Some(instance_id), Some(parent_instance_id), Some(parent_guid)) => {
+                        Some(Self::InstanceInfo {
+                            instance_id,
+                            parent_instance_id,
+                            parent_guid: GUID::from_u128(parent_guid),
+                        })
+                    }
+                    _ => None,
+                }
+            }
+            EVENT_HEADER_EXT_TYPE_STACK_TRACE32 => Self::_stack(data, 4),
+            EVENT_HEADER_EXT_TYPE_STACK_TRACE64 => Self::_stack(data, 8),
+            EVENT_HEADER_EXT_TYPE_PROCESS_START_KEY => {
+                Self::_u64(data, 0).map(Self::ProcessStartKey)
+            }
+            EVENT_HEADER_EXT_TYPE_EVENT_KEY => Self::_u64(data, 0).map(Self::EventKey),
+            EVENT_HEADER_EXT_TYPE_EVENT_SCHEMA_TL => {
+                Some(Self::TraceLoggingSchema(trace_logging_schema_key(data)))
+            }
+            _ => None,
+        };
+
+        // Items too short for their type are kept as they are rather than dropped
+        decoded.unwrap_or_else(|| Self::Other {
+            ext_type: item.ExtType,
+            data: data.to_vec(),
+        })
+    }
+
+    /// EVENT_EXTENDED_ITEM_STACK_TRACE32/64: a u64 MatchId followed by the frames
+    fn _stack(data: &[u8], frame_size: usize) -> Option<Self> {
+        Some(Self::StackTrace {
+            match_id: Self::_u64(data, 0)?,
+            frames: data[8..]
+                .chunks_exact(frame_size)
+                .map(|frame| {
+                    let mut bytes = [0u8; 8];
+                    bytes[..frame_size].copy_from_slice(frame);
+                    u64::from_le_bytes(bytes)
+                })
+                .collect(),
+        })
+    }
+
+    fn _u64(data: &[u8], offset: usize) -> Option<u64> {
+        Some(u64::from_le_bytes(
+            data.get(offset..offset + 8)?.try_into().ok()?,
+        ))
+    }
+
+    /// Reads a GUID stored as a little endian u32, two little endian u16s and 8 bytes
+    fn _u128(data: &[u8], offset: usize) -> Option<u128> {
+        let bytes = data.get(offset..offset + 16)?;
+        let data1 = u32::from_le_bytes(bytes[..4].try_into().ok()?) as u128;
+        let data2 = u16::from_le_bytes(bytes[4..6].try_into().ok()?) as u128;
+        let data3 = u16::from_le_bytes(bytes[6..8].try_into().ok()?) as u128;
+        let data4 = u64::from_be_bytes(bytes[8..].try_into().ok()?) as u128;
+        Some(data1 << 96 | data2 << 80 | data3 << 64 | data4)
+    }
+}
+
+/// Formats a binary SID as S-R-I-S-S..., the same as `ConvertSidToStringSid`
+pub(crate) fn sid_to_string(sid: &[u8]) -> Option<String> {
+    let revision = *sid.first()?;
+    let sub_authority_count = *sid.get(1)? as usize;
+    let authority = sid
+        .get(2..8)?
+        .iter()
+        .fold(0u64, |authority, byte| authority << 8 | *byte as u64);
+
+    let mut string = format!("S-{revision}-{authority}");
+    for index in 0..sub_authority_count {
+        let _ = write!(string, "-{}", read_u32(sid, 8 + index * 4)?);
+    }
+    Some(string)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
@@ -0,0 +1,236 @@
+use std::{collections::HashMap, time::Duration};
+
+use super::{clock::SessionClock, parsed_event::ParsedEvent, schemas::FileIoEvent};
+
+/// What kind of file operation was timed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileOperationKind {
+    Create,
+    Read,
+    Write,
+    Cleanup,
+    Close,
+    Flush,
+}
+
+/// A file operation from its FileIo start event to its OperationEnd event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileOperation {
+    pub kind: FileOperationKind,
+    /// Empty if the name of the file was never logged
+    pub file_name: String,
+    pub process_id: u32,
+    pub thread_id: u32,
+    pub latency: Duration,
+    /// How much of `latency` was spent in minifilter callbacks, such as an antivirus scanning the file on open
+    pub filter_time: Duration,
+    /// The NTSTATUS the operation completed with
+    pub status: u32,
+}
+
+/// Time spent in one minifilter callback across the whole trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FilterRoutineStats {
+    pub calls: u64,
+    pub total: Duration,
+    pub max: Duration,
+}
+
+/// A file operation waiting for its OperationEnd event
+#[derive(Debug)]
+struct PendingOperation {
+    kind: FileOperationKind,
+    file_key: u64,
+    process_id: u32,
+    thread_id: u32,
+    timestamp: i64,
+    filter_ticks: i64,
+}
+
+/// Correlates kernel FileIo events with the minifilter completion events logged for the same IRP, so the latency of each
+/// file operation can be split into time spent in filter drivers and everything else.
+/// Events are fed in with [`FileLatencyAnalyzer::add`] in the order they were logged
+#[derive(Debug)]
+pub struct FileLatencyAnalyzer {
+    clock: SessionClock, // Minifilter callback start times are raw session clock values
+    pending: HashMap<u64, PendingOperation>, // Keyed on IRP
+    file_names: HashMap<u64, String>, // Keyed on file object or file key
+    operations: Vec<FileOperation>,
+    filter_routines: HashMap<u64, FilterRoutineStats>, // Keyed on routine address
+}
+
+impl FileLatencyAnalyzer {
+    /// `clock` is the clock of the session the events come from, see
+    /// [`Consumer::session_clock`](super::consumer::Consumer::session_clock)
+    pub fn new(clock: SessionClock) -> Self {
+        Self {
+            clock,
+            pending: HashMap::new(),
+            file_names: HashMap::new(),
+            operations: Vec::new(),
+            filter_routines: HashMap::new(),
+        }
+    }
+
+    /// Takes in one event. Anything other than a FileIo event is ignored
+    pub fn add(&mut self, event: &ParsedEvent) {
+        let Ok(file_io) = FileIoEvent::try_from(event) else {
+            return;
+        };
+
+        match file_io {
+            FileIoEvent::Name {
+                file_object,
+                file_name,
+                ..
+            } => {
+                self.file_names.insert(file_object, file_name);
+            }
+            FileIoEvent::Create {
+                irp,
+                thread_id,
+                file_object,
+                open_path,
+                ..
+            } => {
+                self.file_names.insert(file_object, open_path);
+                self._start(
+                    event,
+                    irp,
+                    FileOperationKind::Create,
+                    file_object,
+                    thread_id,
+                );
+            }
+            FileIoEvent::ReadWrite {
+                write,
+                irp,
+                thread_id,
+                file_key,
+                ..
+            } => {
+                let kind = if write {
+                    FileOperationKind::Write
+                } else {
+                    FileOperationKind::Read
+                };
+                self._start(event, irp, kind, file_key, thread_id);
+            }
+            FileIoEvent::SimpleOp {
+                opcode,
+                irp,
+                thread_id,
+                file_key,
+                ..
+            } => {
+                let kind = match opcode {
+                    65 => FileOperationKind::Cleanup,
+                    66 => FileOperationKind::Close,
+                    _ => FileOperationKind::Flush,
+                };
+                self._start(event, irp, kind, file_key, thread_id);
+            }
+            FileIoEvent::MinifilterCompletion {
+                initial_time,
+                routine,
+                irp,
+                ..
+            } => {
+                let ticks = (event.timestamp - self.clock.ticks(initial_time)).max(0);
+
+                let stats = self.filter_routines.entry(routine).or_default();
+                stats.calls += 1;
+                stats.total += Self::_duration(ticks);
+                stats.max = stats.max.max(Self::_duration(ticks));
+
+                if let Some(pending) = self.pending.get_mut(&irp) {
+                    pending.filter_ticks += ticks;
+                }
+            }
+            FileIoEvent::OperationEnd { irp, status, .. } => {
+                let Some(pending) = self.pending.remove(&irp) else {
+                    return;
+                };
+
+                self.operations.push(FileOperation {
+                    kind: pending.kind,
+                    file_name: self
+                        .file_names
+                        .get(&pending.file_key)
+                        .cloned()
+                        .unwrap_or_default(),
+                    process_id: pending.process_id,
+                    thread_id: pending.thread_id,
+                    latency: Self::_duration(event.timestamp - pending.timestamp),
+                    filter_time: Self::_duration(pending.filter_ticks),
+                    status,
+                });
+            }
+        }
+    }
+
+    /// Every operation that completed, in the order they completed
+    pub fn operations(&self) -> &[FileOperation] {
+        &self.operations
+    }
+
+    /// The `count` operations that took longest
+    pub fn slowest(&self, count: usize) -> Vec<&FileOperation> {
+        let mut operations: Vec<&FileOperation> = self.operations.iter().collect();
+        operations.sort_by(|a, b| b.latency.cmp(&a.latency));
+        operations.truncate(count);
+        operations
+    }
+
+    /// Time spent in each minifilter callback, keyed on the callback address. Resolve the address against the loaded
+    /// images to find the filter driver
+    pub fn filter_routines(&self) -> &HashMap<u64, FilterRoutineStats> {
+        &self.filter_routines
+    }
+
+    /// Total time spent in minifilter callbacks over total latency, between 0 and 1
+    pub fn filter_share(&self) -> f64 {
+        let (filter, total) = self
+            .operations
+            .iter()
+            .fold((Duration::ZERO, Duration::ZERO), |(filter, total), op| {
+                (filter + op.filter_time, total + op.latency)
+            });
+
+        if total.is_zero() {
+            0.0
+        } else {
+            filter.as_secs_f64() / total.as_secs_f64()
+        }
+    }
+
+    fn _start(
+        &mut self,
+        event: &ParsedEvent,
+        irp: u64,
+        kind: FileOperationKind,
+        file_key: u64,
+        thread_id: u32,
+    ) {
+        self.pending.insert(
+            irp,
+            PendingOperation {
+                kind,
+                file_key,
+                process_id: event.process_id,
+                thread_id: if thread_id != 0 {
+                    thread_id
+                } else {
+                    event.thread_id
+                },
+                timestamp: event.timestamp,
+                filter_ticks: 0,
+            },
+        );
+    }
+
+    /// Event timestamps are in 100ns ticks
+    fn _duration(ticks: i64) -> Duration {
+        Duration::from_nanos(ticks.max(0) as u64 * 100)
+    }
+}
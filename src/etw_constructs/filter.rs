@@ -0,0 +1,225 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use windows::{
+    core::GUID,
+    Win32::System::Diagnostics::Etw::{
+        EVENT_RECORD, MAX_EVENT_FILTER_EVENT_ID_COUNT, MAX_EVENT_FILTER_PID_COUNT,
+    },
+};
+
+use super::{consumer::TraceHeaderInfo, parsed_event::ParsedEvent};
+
+/// Provider of the kernel Process events, https://learn.microsoft.com/en-us/windows/win32/etw/process
+pub const PROCESS_GUID: GUID = GUID::from_u128(0x3d6fa8d0_fe05_11d0_9dda_00c04fd7ba7c);
+
+// https://learn.microsoft.com/en-us/windows/win32/etw/process-typegroup1
+const OPCODE_START: u8 = 1;
+const OPCODE_END: u8 = 2;
+const OPCODE_DC_START: u8 = 3;
+
+/// What is known about a process from its start event
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct ProcessInfo {
+    parent_id: u32,
+    image_file_name: String,
+}
+
+/// Predicates checked against every event before it is decoded, so uninteresting events are dropped cheaply.
+/// Every predicate that is set has to match for an event to be kept; within one predicate any of the given values may match.
+///
+/// The NT Kernel Logger cannot filter on the provider side, so kernel events are filtered in the consumer. User-mode
+/// providers can also be handed the process and event id predicates with [`FilterSet::provider_filter`], so events
+/// that would be dropped here are never logged
+#[derive(Debug, Default)]
+pub struct FilterSet {
+    process_ids: HashSet<u32>,
+    parent_process_ids: HashSet<u32>,
+    image_names: Vec<String>, // Lowercase substrings
+    providers: HashSet<GUID>,
+    event_ids: HashSet<u16>,
+    opcodes: HashSet<u8>,
+    processes: Mutex<HashMap<u32, ProcessInfo>>, // Learned from process events when filtering on parent or image name
+}
+
+/// Predicates user-mode providers check before logging an event, passed to `EnableTraceEx2` as
+/// `EVENT_FILTER_DESCRIPTOR`s. An empty list does not filter
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProviderFilter {
+    /// Processes whose events are logged, at most [`MAX_EVENT_FILTER_PID_COUNT`]
+    pub process_ids: Vec<u32>,
+    /// Ids of the events logged, at most [`MAX_EVENT_FILTER_EVENT_ID_COUNT`]
+    pub event_ids: Vec<u16>,
+}
+
+impl FilterSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The process and event id predicates, for user-mode providers to check themselves. A predicate with more values
+    /// than a provider filter holds is left out and only checked here
+    pub fn provider_filter(&self) -> ProviderFilter {
+        fn sorted<T: Copy + Ord>(values: &HashSet<T>, max: u32) -> Vec<T> {
+            if values.len() > max as usize {
+                return Vec::new();
+            }
+            let mut values: Vec<T> = values.iter().copied().collect();
+            values.sort_unstable();
+            values
+        }
+
+        ProviderFilter {
+            process_ids: sorted(&self.process_ids, MAX_EVENT_FILTER_PID_COUNT),
+            event_ids: sorted(&self.event_ids, MAX_EVENT_FILTER_EVENT_ID_COUNT),
+        }
+    }
+
+    /// Keeps events of the process `process_id`
+    pub fn process_id(mut self, process_id: u32) -> Self {
+        self.process_ids.insert(process_id);
+        self
+    }
+
+    /// Keeps events of processes started by `parent_process_id`
+    pub fn parent_process_id(mut self, parent_process_id: u32) -> Self {
+        self.parent_process_ids.insert(parent_process_id);
+        self
+    }
+
+    /// Keeps events of processes whose image name contains `image_name`, ignoring case
+    pub fn image_name(mut self, image_name: &str) -> Self {
+        self.image_names.push(image_name.to_lowercase());
+        self
+    }
+
+    /// Keeps events logged by `provider`
+    pub fn provider(mut self, provider: GUID) -> Self {
+        self.providers.insert(provider);
+        self
+    }
+
+    /// Keeps events with the id `event_id`
+    pub fn event_id(mut self, event_id: u16) -> Self {
+        self.event_ids.insert(event_id);
+        self
+    }
+
+    /// Keeps events with the opcode `opcode`
+    pub fn opcode(mut self, opcode: u8) -> Self {
+        self.opcodes.insert(opcode);
+        self
+    }
+
+    /// Returns true if `record` passes every predicate. Only process events are decoded, and only when filtering on
+    /// parent process or image name, which need the process table those events build up.
+    /// Process events are matched against the process they describe rather than the one that logged them
+    pub fn matches(&self, record: &EVENT_RECORD, trace: Option<&TraceHeaderInfo>) -> bool {
+        let header = &record.EventHeader;
+        let tracks_processes = !self.parent_process_ids.is_empty() || !self.image_names.is_empty();
+        let mut processes = tracks_processes.then(|| {
+            self.processes
+                .lock()
+                .expect("Process table lock was poisoned")
+        });
+
+        // Process events are learned even if they are filtered out, otherwise the process table would stay empty
+        let process_id = processes
+            .as_mut()
+            .and_then(|processes| Self::_learn(processes, record, trace))
+            .unwrap_or(header.ProcessId);
+
+        if !Self::_allows(&self.providers, &header.ProviderId)
+            || !Self::_allows(&self.event_ids, &header.EventDescriptor.Id)
+            || !Self::_allows(&self.opcodes, &header.EventDescriptor.Opcode)
+            || !Self::_allows(&self.process_ids, &process_id)
+        {
+            return false;
+        }
+
+        let Some(processes) = processes else {
+            return true;
+        };
+        let Some(process) = processes.get(&process_id) else {
+            // Processes that started before the session are unknown until their DCStart rundown event
+            return false;
+        };
+
+        Self::_allows(&self.parent_process_ids, &process.parent_id)
+            && (self.image_names.is_empty()
+                || self
+                    .image_names
+                    .iter()
+                    .any(|name| process.image_file_name.to_lowercase().contains(name)))
+    }
+
+    /// Records the process described by a process event and returns its id. None for any other event
+    fn _learn(
+        processes: &mut HashMap<u32, ProcessInfo>,
+        record: &EVENT_RECORD,
+        trace: Option<&TraceHeaderInfo>,
+    ) -> Option<u32> {
+        let opcode = record.EventHeader.EventDescriptor.Opcode;
+        if record.EventHeader.ProviderId != PROCESS_GUID
+            || !matches!(opcode, OPCODE_START | OPCODE_END | OPCODE_DC_START)
+        {
+            return None;
+        }
+
+        let event = ParsedEvent::from_record_in_trace(record, trace).ok()?;
+        let process_id = event.get("ProcessId")?.as_u64()? as u32;
+
+        // Ids are reused, but a reused id gets a new start event which replaces the entry
+        if opcode != OPCODE_END {
+            processes.insert(
+                process_id,
+                ProcessInfo {
+                    parent_id: event
+                        .get("ParentId")
+                        .and_then(|x| x.as_u64())
+                        .unwrap_or_default() as u32,
+                    image_file_name: event
+                        .get("ImageFileName")
+                        .and_then(|x| x.as_str())
+                        .unwrap_or_default()
+                        .to_string(),
+                },
+            );
+        }
+
+        Some(process_id)
+    }
+
+    /// An empty set allows everything
+    fn _allows<T: Eq + std::hash::Hash>(set: &HashSet<T>, value: &T) -> bool {
+        set.is_empty() || set.contains(value)
+    }
+}
+
+/// The [`FilterSet`] of a running consumer, which can be swapped from another thread. Events that already passed the
+/// filter in use are not affected
+#[derive(Debug, Clone, Default)]
+pub struct SessionFilter {
+    filter: Arc<RwLock<Option<FilterSet>>>,
+}
+
+impl SessionFilter {
+    /// Filters events with `filter` from the next event on. None keeps every event
+    pub fn set(&self, filter: Option<FilterSet>) {
+        *self
+            .filter
+            .write()
+            .expect("Session filter lock was poisoned") = filter;
+    }
+
+    /// Whether the filter in use keeps the event of `record`. True if there is no filter
+    pub fn matches(&self, record: &EVENT_RECORD, trace: Option<&TraceHeaderInfo>) -> bool {
+        self.filter
+            .read()
+            .expect("Session filter lock was poisoned")
+            .as_ref()
+            .map_or(true, |filter| filter.matches(record, trace))
+    }
+}
@@ -0,0 +1,161 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use serde::Deserialize;
+
+use super::{
+    error::{EtwError, EtwResult},
+    event_filter::EventFilter,
+    privilege::PrivilegeRule,
+};
+
+/// Filter expressions read from a filter file, keyed on the name of the sink they apply to
+pub type SinkFilters = BTreeMap<String, EventFilter>;
+
+/// What a filter file sets. `rules` and `process_ids` are None when the file leaves them out, and keep what is in
+/// use. Sinks missing from `filters` or `projections` go back to the filter they were started with and every property
+#[derive(Debug, Clone, Default)]
+pub struct FilterUpdate {
+    pub filters: SinkFilters,
+    /// Properties each sink keeps, keyed on its name. An empty list keeps every property
+    pub projections: BTreeMap<String, Vec<String>>,
+    /// Privilege rules to check events against
+    pub rules: Option<Vec<PrivilegeRule>>,
+    /// Processes the session keeps events of, checked before events are decoded. An empty list keeps every process
+    pub process_ids: Option<Vec<u32>>,
+}
+
+/// The file as written
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FilterFile {
+    filters: BTreeMap<String, String>,
+    projections: BTreeMap<String, Vec<String>>,
+    rules: Option<Vec<String>>,
+    process_ids: Option<Vec<u32>>,
+}
+
+/// What the watcher saw since it was last asked
+type PendingUpdate = Option<Result<FilterUpdate, String>>;
+
+/// Watches a filter file and recompiles it whenever it changes, so the filters of a running capture can be tuned
+/// without restarting the session and losing its state.
+///
+/// The file is a JSON object with any of four sections: `filters` maps sink names to filter expressions, e.g.
+/// `{"net.jsonl": "provider=tcpip"}`, `projections` maps sink names to the properties they keep, `rules` lists the
+/// privilege rules to check by name, and `process_ids` lists the processes the session keeps events of. It is
+/// checked for changes on a background thread, and whoever writes the events picks up the update between two events
+/// with [`FilterFileWatcher::take_update`], so a swap never happens halfway through an event. A file that does not
+/// parse or compile is reported and what is in use is kept
+pub struct FilterFileWatcher {
+    update: Arc<Mutex<PendingUpdate>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl FilterFileWatcher {
+    /// Checks `path` every `interval`. `compile` turns each expression into a filter, so callers can resolve their own
+    /// provider names. The file is loaded right away, and the first [`FilterFileWatcher::take_update`] returns it, so
+    /// it is applied before the first event. Fails if that first load does
+    pub fn watch(
+        path: PathBuf,
+        interval: Duration,
+        compile: impl Fn(&str) -> Result<EventFilter, String> + Send + 'static,
+    ) -> EtwResult<Self> {
+        let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+        let mut last_modified: Option<SystemTime> = modified(&path).ok();
+
+        let initial = Self::_load(&path, &compile).map_err(|err| {
+            EtwError::from_io(
+                &io::Error::new(io::ErrorKind::InvalidData, err),
+                "Could not load the filter file",
+            )
+        })?;
+        let update = Arc::new(Mutex::new(Some(Ok(initial))));
+        let stopped = Arc::new(AtomicBool::new(false));
+        {
+            let update = Arc::clone(&update);
+            let stopped = Arc::clone(&stopped);
+            thread::Builder::new()
+                .name("etw-filter-watcher".to_string())
+                .spawn(move || {
+                    while !stopped.load(Ordering::Relaxed) {
+                        thread::sleep(interval);
+
+                        let Ok(current) = modified(&path) else {
+                            continue;
+                        };
+                        if last_modified == Some(current) {
+                            continue;
+                        }
+                        last_modified = Some(current);
+
+                        *update.lock().expect("Filter update lock was poisoned") =
+                            Some(Self::_load(&path, &compile));
+                    }
+                })
+                .map_err(|err| EtwError::from_io(&err, "Could not spawn the filter watcher"))?;
+        }
+
+        Ok(Self { update, stopped })
+    }
+
+    /// What the file sets since the last call, or why the file could not be used. None if it has not changed
+    pub fn take_update(&self) -> Option<Result<FilterUpdate, String>> {
+        // Events are not held up while the watcher is compiling
+        self.update.try_lock().ok()?.take()
+    }
+
+    fn _load(
+        path: &Path,
+        compile: &impl Fn(&str) -> Result<EventFilter, String>,
+    ) -> Result<FilterUpdate, String> {
+        let text = fs::read_to_string(path).map_err(|err| format!("{:?}: {err}", path))?;
+        let file: FilterFile =
+            serde_json::from_str(&text).map_err(|err| format!("{:?}: {err}", path))?;
+
+        let filters = file
+            .filters
+            .into_iter()
+            .map(|(sink, expression)| {
+                compile(&expression)
+                    .map(|filter| (sink.clone(), filter))
+                    .map_err(|err| format!("{:?}, filter of {sink:?}: {err}", path))
+            })
+            .collect::<Result<_, _>>()?;
+        let rules = file
+            .rules
+            .map(|names| {
+                names
+                    .iter()
+                    .map(|name| {
+                        PrivilegeRule::from_name(name)
+                            .ok_or_else(|| format!("{:?}: unknown rule {name:?}", path))
+                    })
+                    .collect::<Result<_, _>>()
+            })
+            .transpose()?;
+
+        Ok(FilterUpdate {
+            filters,
+            projections: file.projections,
+            rules,
+            process_ids: file.process_ids,
+        })
+    }
+}
+
+/// Stops the watcher thread after its next check
+impl Drop for FilterFileWatcher {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
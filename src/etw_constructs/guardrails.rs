@@ -0,0 +1,197 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use windows::Win32::System::Diagnostics::Etw::EVENT_TRACE_FLAG;
+
+use super::{controller::Controller, keep_alive::KeepAliveMarker, stop_handle::StopHandle};
+
+/// What to do when a guardrail limit is exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardrailAction {
+    /// Stop the session straight away
+    Stop,
+    /// Replace the enabled flags with `flags` the first time a limit is exceeded. The session is stopped if a limit is exceeded again
+    ReduceFlags { flags: EVENT_TRACE_FLAG },
+}
+
+/// Limits on how many resources a session may use before it is throttled or stopped. A limit of None is not checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guardrails {
+    /// Maximum non-paged memory used by the session buffers, in KB
+    pub max_buffer_memory_kb: Option<u64>,
+    /// Maximum bytes written by the session, taken from the number of buffers written
+    pub max_output_bytes: Option<u64>,
+    /// Maximum events per second received by the consumer, sustained for `sustained_for`
+    pub max_events_per_sec: Option<u64>,
+    pub sustained_for: Duration,
+    /// How often the session is queried
+    pub poll_interval: Duration,
+    pub action: GuardrailAction,
+}
+
+impl Default for Guardrails {
+    fn default() -> Self {
+        Self {
+            max_buffer_memory_kb: None,
+            max_output_bytes: None,
+            max_events_per_sec: None,
+            sustained_for: Duration::from_secs(5),
+            poll_interval: Duration::from_secs(1),
+            action: GuardrailAction::Stop,
+        }
+    }
+}
+
+/// The limit that was exceeded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardrailLimit {
+    BufferMemoryKb,
+    OutputBytes,
+    EventsPerSec,
+}
+
+/// Describes a guardrail being tripped and what was done about it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GuardrailReport {
+    pub limit: GuardrailLimit,
+    pub observed: u64,
+    pub threshold: u64,
+    pub action: GuardrailAction,
+    pub elapsed: Duration,
+}
+
+impl fmt::Display for GuardrailReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let limit = match self.limit {
+            GuardrailLimit::BufferMemoryKb => "buffer memory (KB)",
+            GuardrailLimit::OutputBytes => "output bytes",
+            GuardrailLimit::EventsPerSec => "events per second",
+        };
+        let action = match self.action {
+            GuardrailAction::Stop => "stopped the session".to_string(),
+            GuardrailAction::ReduceFlags { flags } => {
+                format!("reduced enabled flags to {:#x}", flags.0)
+            }
+        };
+        write!(
+            f,
+            "Guardrail tripped after {:?}: {limit} reached {} (limit {}), {action}",
+            self.elapsed, self.observed, self.threshold
+        )
+    }
+}
+
+impl Guardrails {
+    /// Polls the session of `stop_handle` until `finished` is set or the session is stopped, applying [`Guardrails::action`]
+    /// whenever a limit is exceeded. `events_consumed` is the consumer's event counter. Returns every guardrail that was tripped
+    pub fn watch(
+        &self,
+        stop_handle: &StopHandle,
+        events_consumed: Option<Arc<AtomicU64>>,
+        finished: Arc<AtomicBool>,
+    ) -> Vec<GuardrailReport> {
+        let mut reports = Vec::new();
+        let Some(session_name) = stop_handle.session_name() else {
+            return reports;
+        };
+        let mut flags_reduced = false;
+
+        let started = Instant::now();
+        let mut last_poll = started;
+        let mut last_event_count = 0;
+        let mut over_rate_since: Option<Instant> = None;
+
+        while !finished.load(Ordering::Relaxed) && !stop_handle.is_stopped() {
+            thread::sleep(self.poll_interval);
+
+            let Ok(properties) = Controller::query(session_name) else {
+                // The session has gone away, nothing left to guard
+                break;
+            };
+
+            let now = Instant::now();
+            let mut exceeded = None;
+
+            // BufferSize is in KB
+            let buffer_memory_kb = properties.NumberOfBuffers as u64 * properties.BufferSize as u64;
+            if let Some(threshold) = self.max_buffer_memory_kb {
+                if buffer_memory_kb > threshold {
+                    exceeded = Some((GuardrailLimit::BufferMemoryKb, buffer_memory_kb, threshold));
+                }
+            }
+
+            let output_bytes =
+                properties.BuffersWritten as u64 * properties.BufferSize as u64 * 1024;
+            if let Some(threshold) = self.max_output_bytes {
+                if output_bytes > threshold {
+                    exceeded = Some((GuardrailLimit::OutputBytes, output_bytes, threshold));
+                }
+            }
+
+            if let (Some(threshold), Some(events_consumed)) =
+                (self.max_events_per_sec, &events_consumed)
+            {
+                let event_count = events_consumed.load(Ordering::Relaxed);
+                let elapsed = now
+                    .duration_since(last_poll)
+                    .as_secs_f64()
+                    .max(f64::EPSILON);
+                let rate = ((event_count - last_event_count) as f64 / elapsed) as u64;
+                last_event_count = event_count;
+
+                if rate > threshold {
+                    let since = *over_rate_since.get_or_insert(now);
+                    if now.duration_since(since) >= self.sustained_for {
+                        exceeded = Some((GuardrailLimit::EventsPerSec, rate, threshold));
+                    }
+                } else {
+                    over_rate_since = None;
+                }
+            }
+            last_poll = now;
+
+            let Some((limit, observed, threshold)) = exceeded else {
+                continue;
+            };
+
+            let action = match self.action {
+                GuardrailAction::ReduceFlags { flags } if !flags_reduced => {
+                    match Controller::update_flags(session_name, flags) {
+                        Ok(()) => {
+                            flags_reduced = true;
+                            over_rate_since = None;
+                            self.action
+                        }
+                        Err(_) => GuardrailAction::Stop,
+                    }
+                }
+                _ => GuardrailAction::Stop,
+            };
+
+            reports.push(GuardrailReport {
+                limit,
+                observed,
+                threshold,
+                action,
+                elapsed: now.duration_since(started),
+            });
+
+            // A limit stops the session even if it is kept alive between consumers
+            if action == GuardrailAction::Stop {
+                let _ = Controller::stop(session_name);
+                KeepAliveMarker::remove(session_name);
+                stop_handle.stop();
+                break;
+            }
+        }
+
+        reports
+    }
+}
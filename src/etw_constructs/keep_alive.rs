@@ -0,0 +1,92 @@
+use std::{env, ffi::CStr, fs, io, path::PathBuf, process, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    clock,
+    controller::ControllerConfig,
+    error::{EtwError, EtwResult},
+    parsed_event::PropertyValue,
+};
+
+/// Records a session that was started with [`ControllerConfig::keep_alive`] and is left running between consumers. A
+/// new consumer reattaching to the session reads it to know the session was left running on purpose, rather than
+/// behind a crashed run. It lives under ProgramData, so every account sees the same marker the way they see the same
+/// session, and is removed once a session of that name is started without keep-alive
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeepAliveMarker {
+    pub session_name: String,
+    pub tool_version: String,
+    /// Kernel event classes the session was started with, as hex
+    pub enable_flags: String,
+    /// User-mode providers enabled in the session
+    pub providers: Vec<String>,
+    /// The last consumer attached to the session
+    pub consumer_process_id: u32,
+    /// When that consumer attached, RFC 3339
+    pub attached_at: String,
+}
+
+impl KeepAliveMarker {
+    /// The marker for `session_name` started with `config`, consumed by this process
+    pub fn new(session_name: &CStr, config: &ControllerConfig) -> Self {
+        Self {
+            session_name: session_name.to_string_lossy().into_owned(),
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+            enable_flags: format!("{:#x}", config.effective_flags().0),
+            providers: config
+                .providers
+                .iter()
+                .map(|provider| PropertyValue::Guid(provider.guid).to_string())
+                .collect(),
+            consumer_process_id: process::id(),
+            attached_at: clock::rfc3339_from_ticks(
+                clock::ticks_from_system_time(SystemTime::now()),
+            ),
+        }
+    }
+
+    /// The marker of `session_name`, or None if no session of that name was left running with keep-alive
+    pub fn read(session_name: &CStr) -> EtwResult<Option<Self>> {
+        let path = Self::path(&session_name.to_string_lossy());
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(EtwError::from_io(
+                    &err,
+                    format!("Could not read {:?}", path),
+                ))
+            }
+        };
+        serde_json::from_str(&text).map(Some).map_err(|err| {
+            EtwError::from_io(&io::Error::from(err), format!("Could not parse {:?}", path))
+        })
+    }
+
+    pub fn write(&self) -> EtwResult<()> {
+        let path = Self::path(&self.session_name);
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory).map_err(|err| {
+                EtwError::from_io(&err, format!("Could not create {:?}", directory))
+            })?;
+        }
+        let json = serde_json::to_string_pretty(self).expect("Markers always serialize");
+        fs::write(&path, json)
+            .map_err(|err| EtwError::from_io(&err, format!("Could not write {:?}", path)))
+    }
+
+    /// Removes the marker of `session_name` if there is one
+    pub fn remove(session_name: &CStr) {
+        let _ = fs::remove_file(Self::path(&session_name.to_string_lossy()));
+    }
+
+    /// Where the marker of `session_name` is kept, `%ProgramData%\<crate name>\<session name>.keepalive.json`.
+    /// The temp directory would differ between accounts, and between elevated and unelevated runs of the same account
+    pub fn path(session_name: &str) -> PathBuf {
+        env::var_os("ProgramData")
+            .map_or_else(|| PathBuf::from(r"C:\ProgramData"), PathBuf::from)
+            .join(env!("CARGO_PKG_NAME"))
+            .join(format!("{session_name}.keepalive.json"))
+    }
+}
@@ -0,0 +1,385 @@
+use std::{
+    collections::VecDeque,
+    fmt::{self, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use super::{clock, parsed_event::ParsedEvent};
+
+/// Breaches kept for the summary, the oldest are dropped past this
+const MAX_BREACHES_KEPT: usize = 100;
+
+/// Upper bounds of the age histogram buckets in milliseconds. Ages above the last bound fall in an overflow bucket
+const BUCKET_BOUNDS_MS: [u64; 15] = [
+    1, 2, 5, 10, 20, 50, 100, 200, 500, 1_000, 2_000, 5_000, 10_000, 30_000, 60_000,
+];
+
+/// A delivery latency objective: `quantile` of the events are written within `target` of being logged
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencySlo {
+    /// Between 0 and 1, e.g. 0.99
+    pub quantile: f64,
+    pub target: Duration,
+}
+
+impl fmt::Display for LatencySlo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}% within {:?}", self.quantile * 100.0, self.target)
+    }
+}
+
+/// Ages of the events delivered over some span
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    pub events: u64,
+    /// Events delivered within the target of the [`LatencySlo`]. 0 without one
+    pub within_target: u64,
+    pub max_age: Duration,
+    /// Kernel timestamp of the newest event delivered, in FILETIME ticks. Everything logged before it that is still to
+    /// come is late
+    pub newest_event: Option<i64>,
+    buckets: [u64; BUCKET_BOUNDS_MS.len() + 1],
+}
+
+impl LatencyStats {
+    /// Upper bound of the age `quantile` of the events were delivered within, to the resolution of the histogram. None
+    /// if no event was delivered
+    pub fn quantile(&self, quantile: f64) -> Option<Duration> {
+        if self.events == 0 {
+            return None;
+        }
+
+        let rank = (quantile.clamp(0.0, 1.0) * self.events as f64)
+            .ceil()
+            .max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Some(match BUCKET_BOUNDS_MS.get(bucket) {
+                    Some(&bound) => Duration::from_millis(bound).min(self.max_age),
+                    None => self.max_age,
+                });
+            }
+        }
+        Some(self.max_age)
+    }
+
+    /// Share of the events delivered within the target, 1 if none were delivered
+    pub fn within_target_ratio(&self) -> f64 {
+        if self.events == 0 {
+            1.0
+        } else {
+            self.within_target as f64 / self.events as f64
+        }
+    }
+
+    fn _add(&mut self, timestamp: i64, age: Duration, within_target: bool) {
+        self.events += 1;
+        self.within_target += within_target as u64;
+        self.max_age = self.max_age.max(age);
+        self.newest_event = Some(
+            self.newest_event
+                .map_or(timestamp, |newest| newest.max(timestamp)),
+        );
+
+        let age_ms = age.as_millis();
+        let bucket = BUCKET_BOUNDS_MS
+            .iter()
+            .position(|&bound| age_ms <= bound as u128)
+            .unwrap_or(BUCKET_BOUNDS_MS.len());
+        self.buckets[bucket] += 1;
+    }
+}
+
+/// A rolling window that missed the [`LatencySlo`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct SloBreach {
+    pub slo: LatencySlo,
+    pub window: Duration,
+    pub stats: LatencyStats,
+    /// How long the pipeline had been working on an event without writing it when the window ended, if longer than the
+    /// target. A stalled pipeline delivers nothing, so its window can miss the objective with no late event in it
+    pub stalled_for: Option<Duration>,
+    /// When the window ended
+    pub ended: SystemTime,
+}
+
+impl fmt::Display for SloBreach {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.2}% of {} events in the last {:?} were delivered within {:?}, below the {}% objective. Oldest was {:?}",
+            self.stats.within_target_ratio() * 100.0,
+            self.stats.events,
+            self.window,
+            self.slo.target,
+            self.slo.quantile * 100.0,
+            self.stats.max_age
+        )?;
+        if let Some(stalled_for) = self.stalled_for {
+            write!(f, ". Nothing was written for the last {stalled_for:?}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Measures how long events take from their kernel timestamp to leaving the last sink, for operating the collector
+/// against a latency objective. Keeps totals for the whole capture and for a rolling window, whose stats are the
+/// watermarks of how far behind delivery is right now. The window is checked by [`LatencyTracker::watch`] on its own
+/// thread, so a pipeline stuck on an event still has its windows end and miss the objective.
+///
+/// Only meaningful for real-time sessions, where events are delivered as they are logged. Clock adjustments can make
+/// the kernel timestamp later than the delivery time, such events count as delivered immediately
+#[derive(Debug)]
+pub struct LatencyTracker {
+    slo: Option<LatencySlo>,
+    window_length: Duration,
+    window_started: Instant,
+    window: LatencyStats,
+    total: LatencyStats,
+    breached_windows: u64,
+    breaches: VecDeque<SloBreach>,
+    busy_since: Option<Instant>, // When the oldest event taken in and not yet written was taken in
+}
+
+impl LatencyTracker {
+    pub fn new(slo: Option<LatencySlo>, window_length: Duration) -> Self {
+        Self {
+            slo,
+            window_length,
+            window_started: Instant::now(),
+            window: LatencyStats::default(),
+            total: LatencyStats::default(),
+            breached_windows: 0,
+            breaches: VecDeque::new(),
+            busy_since: None,
+        }
+    }
+
+    /// Notes that an event was taken off the stream, to be written
+    pub fn received(&mut self) {
+        self.busy_since.get_or_insert_with(Instant::now);
+    }
+
+    /// Takes in one event written to every sink at `delivered`
+    pub fn record(&mut self, event: &ParsedEvent, delivered: SystemTime) {
+        self.busy_since = None;
+
+        let age = delivered.duration_since(event.time).unwrap_or_default();
+        let within_target = self.slo.is_some_and(|slo| age <= slo.target);
+        self.window._add(event.timestamp, age, within_target);
+        self.total._add(event.timestamp, age, within_target);
+    }
+
+    /// Ends the rolling window if it has run its length. Returns it if it missed the objective
+    pub fn check(&mut self) -> Option<SloBreach> {
+        if self.window_started.elapsed() < self.window_length {
+            return None;
+        }
+
+        self.window_started = Instant::now();
+        let stats = std::mem::take(&mut self.window);
+        let slo = self.slo?;
+        let stalled_for = self
+            .busy_since
+            .map(|since| since.elapsed())
+            .filter(|stalled_for| *stalled_for > slo.target);
+        if stats.within_target_ratio() >= slo.quantile && stalled_for.is_none() {
+            return None;
+        }
+
+        self.breached_windows += 1;
+        let breach = SloBreach {
+            slo,
+            window: self.window_length,
+            stats,
+            stalled_for,
+            ended: SystemTime::now(),
+        };
+        if self.breaches.len() == MAX_BREACHES_KEPT {
+            self.breaches.pop_front();
+        }
+        self.breaches.push_back(breach.clone());
+        Some(breach)
+    }
+
+    /// Checks the window of `tracker` every tenth of its length until `finished` is set, calling `on_breach` with every
+    /// window that missed the objective. Meant to run on its own thread, which can be unparked to notice `finished`
+    /// straight away
+    pub fn watch(
+        tracker: &Mutex<Self>,
+        finished: &AtomicBool,
+        mut on_breach: impl FnMut(&SloBreach),
+    ) {
+        let interval = tracker
+            .lock()
+            .expect("Latency tracker lock was poisoned")
+            .window_length
+            / 10;
+        while !finished.load(Ordering::Relaxed) {
+            thread::park_timeout(interval);
+            let breach = tracker
+                .lock()
+                .expect("Latency tracker lock was poisoned")
+                .check();
+            if let Some(breach) = breach {
+                on_breach(&breach);
+            }
+        }
+    }
+
+    /// Ages in the current rolling window
+    pub fn watermark(&self) -> &LatencyStats {
+        &self.window
+    }
+
+    /// Ages over the whole capture
+    pub fn total(&self) -> &LatencyStats {
+        &self.total
+    }
+
+    pub fn slo(&self) -> Option<LatencySlo> {
+        self.slo
+    }
+
+    /// Whether the whole capture met the objective. None without one
+    pub fn slo_met(&self) -> Option<bool> {
+        self.slo
+            .map(|slo| self.total.within_target_ratio() >= slo.quantile)
+    }
+
+    /// Rolling windows that missed the objective
+    pub fn breached_windows(&self) -> u64 {
+        self.breached_windows
+    }
+
+    /// The last windows that missed the objective, oldest first
+    pub fn breaches(&self) -> impl Iterator<Item = &SloBreach> {
+        self.breaches.iter()
+    }
+
+    /// The stats in the Prometheus text exposition format, for [`MetricsServer`](super::metrics::MetricsServer)
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        let seconds = |age: Option<Duration>| age.unwrap_or_default().as_secs_f64();
+
+        let _ = writeln!(
+            text,
+            "# HELP etw_delivery_latency_seconds Age of events when they were written"
+        );
+        let _ = writeln!(text, "# TYPE etw_delivery_latency_seconds summary");
+        for quantile in [0.5, 0.9, 0.99] {
+            let _ = writeln!(
+                text,
+                "etw_delivery_latency_seconds{{quantile=\"{quantile}\"}} {}",
+                seconds(self.total.quantile(quantile))
+            );
+        }
+        let _ = writeln!(
+            text,
+            "etw_delivery_latency_seconds_count {}",
+            self.total.events
+        );
+        let _ = writeln!(
+            text,
+            "# HELP etw_delivery_window_latency_seconds Age of events written in the current window"
+        );
+        let _ = writeln!(text, "# TYPE etw_delivery_window_latency_seconds gauge");
+        for quantile in [0.5, 0.9, 0.99] {
+            let _ = writeln!(
+                text,
+                "etw_delivery_window_latency_seconds{{quantile=\"{quantile}\"}} {}",
+                seconds(self.window.quantile(quantile))
+            );
+        }
+        let _ = writeln!(text, "# TYPE etw_delivery_max_latency_seconds gauge");
+        let _ = writeln!(
+            text,
+            "etw_delivery_max_latency_seconds {}",
+            self.total.max_age.as_secs_f64()
+        );
+        let _ = writeln!(text, "# TYPE etw_delivery_stalled_seconds gauge");
+        let _ = writeln!(
+            text,
+            "etw_delivery_stalled_seconds {}",
+            seconds(self.busy_since.map(|since| since.elapsed()))
+        );
+
+        if let (Some(slo), Some(met)) = (self.slo, self.slo_met()) {
+            let _ = writeln!(text, "# TYPE etw_delivery_slo_target_seconds gauge");
+            let _ = writeln!(
+                text,
+                "etw_delivery_slo_target_seconds {}",
+                slo.target.as_secs_f64()
+            );
+            let _ = writeln!(text, "# TYPE etw_delivery_within_target_total counter");
+            let _ = writeln!(
+                text,
+                "etw_delivery_within_target_total {}",
+                self.total.within_target
+            );
+            let _ = writeln!(
+                text,
+                "# TYPE etw_delivery_slo_breached_windows_total counter"
+            );
+            let _ = writeln!(
+                text,
+                "etw_delivery_slo_breached_windows_total {}",
+                self.breached_windows
+            );
+            let _ = writeln!(text, "# TYPE etw_delivery_slo_met gauge");
+            let _ = writeln!(text, "etw_delivery_slo_met {}", met as u8);
+        }
+        text
+    }
+}
+
+/// Lists the delivery latency quantiles of the whole capture, and whether it met the objective
+impl fmt::Display for LatencyTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let quantile = |quantile| self.total.quantile(quantile).unwrap_or_default();
+        writeln!(
+            f,
+            "    {} events, p50 <= {:?}, p90 <= {:?}, p99 <= {:?}, max {:?}",
+            self.total.events,
+            quantile(0.5),
+            quantile(0.9),
+            quantile(0.99),
+            self.total.max_age
+        )?;
+        if let Some(newest_event) = self.total.newest_event {
+            writeln!(
+                f,
+                "    Newest event delivered was logged at {}",
+                clock::rfc3339_from_ticks(newest_event)
+            )?;
+        }
+        if let (Some(slo), Some(met)) = (self.slo, self.slo_met()) {
+            writeln!(
+                f,
+                "    Objective {slo}: {} ({:.2}%), missed in {} windows of {:?}",
+                if met { "met" } else { "missed" },
+                self.total.within_target_ratio() * 100.0,
+                self.breached_windows,
+                self.window_length
+            )?;
+        }
+        if self.breached_windows > self.breaches.len() as u64 {
+            writeln!(f, "    The last {} of them:", self.breaches.len())?;
+        }
+        for breach in &self.breaches {
+            writeln!(
+                f,
+                "      Ending {}: {breach}",
+                clock::rfc3339_from_ticks(clock::ticks_from_system_time(breach.ended))
+            )?;
+        }
+        Ok(())
+    }
+}
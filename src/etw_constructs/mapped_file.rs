@@ -0,0 +1,244 @@
+use std::{
+    fs::File,
+    io::{self, Write},
+    os::windows::io::AsRawHandle,
+    path::Path,
+    ptr,
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    time::{Duration, Instant},
+};
+
+use windows::{
+    core::PCWSTR,
+    Win32::{
+        Foundation::{CloseHandle, HANDLE},
+        System::Memory::{
+            CreateFileMappingW, FlushViewOfFile, MapViewOfFile, UnmapViewOfFile, FILE_MAP_WRITE,
+            MEMORY_MAPPED_VIEW_ADDRESS, PAGE_READWRITE,
+        },
+    },
+};
+
+use super::error::{EtwError, EtwResult};
+
+/// Options of a [`MappedFile`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MappedFileConfig {
+    /// Size the file is grown to up front. It doubles whenever it fills up
+    pub preallocate_bytes: u64,
+    /// How often written pages are handed to the OS to write out. Only [`Write::flush`] writes them out sooner
+    pub flush_interval: Duration,
+}
+
+impl Default for MappedFileConfig {
+    fn default() -> Self {
+        Self {
+            preallocate_bytes: 64 * 1024 * 1024,
+            flush_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Every [`MappedFile`] still open, so [`MappedFile::close_all`] can trim them when the process exits without dropping
+/// them
+static OPEN_FILES: Mutex<Vec<Weak<Mutex<MappedState>>>> = Mutex::new(Vec::new());
+
+/// Writes a file through a memory-mapped view instead of a write call per buffer, for outputs taking hundreds of
+/// thousands of events a second. The file is preallocated and padded with zeros until the writer is dropped, or
+/// [`MappedFile::close_all`] is called, which trims it to what was written
+pub struct MappedFile {
+    state: Arc<Mutex<MappedState>>, // Shared with OPEN_FILES
+}
+
+struct MappedState {
+    file: File,
+    mapping: HANDLE,
+    view: MEMORY_MAPPED_VIEW_ADDRESS, // Null when nothing is mapped
+    capacity: u64,                    // Size of the file and the view
+    position: u64,                    // Bytes written so far
+    config: MappedFileConfig,
+    last_flush: Instant,
+    closed: bool, // Set once the file was trimmed, after which writes fail
+}
+
+// The view is only ever accessed with the state locked
+unsafe impl Send for MappedState {}
+
+impl MappedFile {
+    /// Creates (or truncates) the file at `path` and maps its first `config.preallocate_bytes`
+    pub fn create(path: &Path, config: MappedFileConfig) -> EtwResult<Self> {
+        let create_error =
+            |err: io::Error| EtwError::from_io(&err, format!("Could not map {:?}", path));
+
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(create_error)?;
+        let capacity = config.preallocate_bytes.max(1);
+        let (mapping, view) = MappedState::_map(&file, capacity).map_err(create_error)?;
+
+        let state = Arc::new(Mutex::new(MappedState {
+            file,
+            mapping,
+            view,
+            capacity,
+            position: 0,
+            config,
+            last_flush: Instant::now(),
+            closed: false,
+        }));
+        let mut open_files = OPEN_FILES
+            .lock()
+            .expect("Mapped file list lock was poisoned");
+        open_files.retain(|open| open.strong_count() != 0);
+        open_files.push(Arc::downgrade(&state));
+
+        Ok(Self { state })
+    }
+
+    /// Unmaps every mapped file still open and trims it to what was written. For exiting the process without
+    /// unwinding, such as on a forced stop, since the files are only trimmed when dropped otherwise. Writes to them
+    /// fail afterwards
+    pub fn close_all() {
+        let open_files = match OPEN_FILES.lock() {
+            Ok(open_files) => open_files,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        for state in open_files.iter().filter_map(Weak::upgrade) {
+            if let Ok(mut state) = state.lock() {
+                state._close();
+            }
+        }
+    }
+
+    fn _state(&self) -> io::Result<MutexGuard<'_, MappedState>> {
+        let state = self
+            .state
+            .lock()
+            .map_err(|_| io::Error::other("The mapped file lock was poisoned"))?;
+        if state.closed {
+            return Err(io::Error::other("The mapped file was already closed"));
+        }
+        Ok(state)
+    }
+}
+
+impl MappedState {
+    /// Grows the file to `capacity` bytes and maps all of it
+    fn _map(file: &File, capacity: u64) -> io::Result<(HANDLE, MEMORY_MAPPED_VIEW_ADDRESS)> {
+        file.set_len(capacity)?;
+
+        let mapping = unsafe {
+            CreateFileMappingW(
+                HANDLE(file.as_raw_handle()),
+                None,
+                PAGE_READWRITE,
+                (capacity >> 32) as u32,
+                capacity as u32,
+                PCWSTR::null(),
+            )
+        }?;
+
+        let view = unsafe { MapViewOfFile(mapping, FILE_MAP_WRITE, 0, 0, capacity as usize) };
+        if view.Value.is_null() {
+            let err = io::Error::last_os_error();
+            let _ = unsafe { CloseHandle(mapping) };
+            return Err(err);
+        }
+
+        Ok((mapping, view))
+    }
+
+    /// Nothing is mapped after this until [`MappedState::_map`] succeeds again
+    fn _unmap(&mut self) {
+        if self.view.Value.is_null() {
+            return;
+        }
+
+        unsafe {
+            let _ = FlushViewOfFile(self.view.Value, self.position as usize);
+            let _ = UnmapViewOfFile(self.view);
+            let _ = CloseHandle(self.mapping);
+        }
+        self.view = MEMORY_MAPPED_VIEW_ADDRESS::default();
+        self.mapping = HANDLE::default();
+    }
+
+    /// Remaps the file at double its size, or at `needed` bytes if that is more. Only remaps it if it is big enough
+    /// already. Nothing is mapped if it fails, and the next write tries again
+    fn _grow(&mut self, needed: u64) -> io::Result<()> {
+        self._unmap();
+
+        let capacity = if needed <= self.capacity {
+            self.capacity
+        } else {
+            needed.max(self.capacity.saturating_mul(2))
+        };
+        let (mapping, view) = Self::_map(&self.file, capacity)?;
+        self.mapping = mapping;
+        self.view = view;
+        self.capacity = capacity;
+        Ok(())
+    }
+
+    /// Unmaps the file and trims off the preallocated space that was never written to
+    fn _close(&mut self) {
+        if self.closed {
+            return;
+        }
+
+        self._unmap();
+        let _ = self.file.set_len(self.position);
+        self.closed = true;
+    }
+}
+
+impl Write for MappedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self._state()?;
+        let end = state.position + buf.len() as u64;
+        if end > state.capacity || state.view.Value.is_null() {
+            state._grow(end)?;
+        }
+
+        unsafe {
+            ptr::copy_nonoverlapping(
+                buf.as_ptr(),
+                (state.view.Value as *mut u8).add(state.position as usize),
+                buf.len(),
+            );
+        }
+        state.position = end;
+
+        if state.last_flush.elapsed() >= state.config.flush_interval {
+            drop(state);
+            self.flush()?;
+        }
+        Ok(buf.len())
+    }
+
+    /// Hands the written pages to the OS to write out, the same as `msync`. Does not wait for them to reach the disk.
+    /// Does nothing if a failed remap left nothing mapped
+    fn flush(&mut self) -> io::Result<()> {
+        let mut state = self._state()?;
+        state.last_flush = Instant::now();
+        if state.view.Value.is_null() {
+            return Ok(());
+        }
+
+        unsafe { FlushViewOfFile(state.view.Value, state.position as usize) }?;
+        Ok(())
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.state.lock() {
+            state._close();
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use std::{collections::BTreeMap, ffi::c_void, fmt, mem};
+
+use windows::{
+    core::GUID,
+    Win32::{
+        Foundation::ERROR_SUCCESS,
+        System::Diagnostics::Etw::{
+            TraceSetInformation, TraceSystemTraceEnableFlagsInfo, CONTROLTRACE_HANDLE,
+            EVENT_TRACE_FLAG,
+        },
+    },
+};
+
+use super::{
+    error::{EtwError, EtwResult},
+    parsed_event::{ParsedEvent, PropertyValue},
+    schemas::Fields,
+};
+
+/// PageFault class of the kernel logger. Page faults and the MemInfo rundowns are logged under it
+pub const PAGE_FAULT_GUID: GUID = GUID::from_u128(0x3d6fa8d3_fe05_11d0_9dda_00c04fd7ba7c);
+
+const OPCODE_TRANSITION_FAULT: u8 = 10;
+const OPCODE_DEMAND_ZERO_FAULT: u8 = 11;
+const OPCODE_COPY_ON_WRITE: u8 = 12;
+const OPCODE_GUARD_PAGE_FAULT: u8 = 13;
+const OPCODE_HARD_PAGE_FAULT: u8 = 14;
+const OPCODE_ACCESS_VIOLATION: u8 = 15;
+const OPCODE_HARD_FAULT: u8 = 32;
+const OPCODE_MEM_INFO_WS: u8 = 113;
+const OPCODE_PROCESS_MEM_INFO: u8 = 125;
+
+// PERF_MEMINFO and PERF_MEMINFO_WS from ntwmi.h. Both are bits of the second group mask, the first holds the EnableFlags
+const PERF_MEMINFO: u32 = 0x0008_0000;
+const PERF_MEMINFO_WS: u32 = 0x0080_0000;
+const PERF_MEMINFO_MASK_INDEX: usize = 1;
+const GROUP_MASK_COUNT: usize = 8;
+
+/// Every architecture Windows runs on uses 4KB pages
+const PAGE_SIZE: u64 = 4096;
+
+/// Turns on the MemInfo and working set rundowns, which the kernel logs about every half second while the session runs.
+/// `enable_flags` must be the flags the session was started with, since they are set again along with the group masks.
+/// Only the NT Kernel Logger and system logger sessions log them
+pub fn enable_mem_info(
+    trace_handle: CONTROLTRACE_HANDLE,
+    enable_flags: EVENT_TRACE_FLAG,
+) -> EtwResult<()> {
+    let mut group_masks = [0u32; GROUP_MASK_COUNT];
+    group_masks[0] = enable_flags.0;
+    group_masks[PERF_MEMINFO_MASK_INDEX] |= PERF_MEMINFO | PERF_MEMINFO_WS;
+
+    let status = unsafe {
+        TraceSetInformation(
+            trace_handle,
+            TraceSystemTraceEnableFlagsInfo,
+            group_masks.as_ptr() as *const c_void,
+            mem::size_of_val(&group_masks) as u32,
+        )
+    };
+
+    match status {
+        ERROR_SUCCESS => Ok(()),
+        status => Err(EtwError::Win32 {
+            status,
+            context: "TraceSetInformation could not enable the MemInfo rundowns".to_string(),
+        }),
+    }
+}
+
+/// How a soft page fault was resolved without reading from disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SoftFaultKind {
+    /// The page was still in memory on the standby or modified list
+    Transition,
+    /// A zeroed page was handed out
+    DemandZero,
+    CopyOnWrite,
+    GuardPage,
+    AccessViolation,
+}
+
+/// The memory usage of one process at a rundown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WorkingSetSample {
+    pub process_id: u32,
+    pub working_set_bytes: u64,
+    pub private_working_set_bytes: u64,
+    pub commit_bytes: u64,
+}
+
+/// A kernel memory event, see https://learn.microsoft.com/en-us/windows/win32/etw/pagefault-v2
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryEvent {
+    SoftFault {
+        kind: SoftFaultKind,
+        process_id: u32,
+        thread_id: u32,
+        address: u64,
+    },
+    /// A page had to be read from disk, logged once the read completes
+    HardFault {
+        process_id: u32,
+        thread_id: u32,
+        address: u64,
+        bytes_read: u64,
+    },
+    /// A working set rundown, one sample per process
+    WorkingSets(Vec<WorkingSetSample>),
+}
+
+impl MemoryEvent {
+    /// Returns the event if it is a page fault or working set rundown of the PageFault class
+    pub fn from_event(event: &ParsedEvent) -> Option<Self> {
+        let fields = Fields::new(event, PAGE_FAULT_GUID).ok()?;
+
+        let kind = match event.opcode {
+            OPCODE_TRANSITION_FAULT => SoftFaultKind::Transition,
+            OPCODE_DEMAND_ZERO_FAULT => SoftFaultKind::DemandZero,
+            OPCODE_COPY_ON_WRITE => SoftFaultKind::CopyOnWrite,
+            OPCODE_GUARD_PAGE_FAULT => SoftFaultKind::GuardPage,
+            OPCODE_ACCESS_VIOLATION => SoftFaultKind::AccessViolation,
+            // HardPageFault is logged when the fault is taken, HardFault once the read completes. Only the second is
+            // kept so each hard fault is counted once
+            OPCODE_HARD_PAGE_FAULT => return None,
+            OPCODE_HARD_FAULT => {
+                let thread_id = fields.optional("TThreadId") as u32;
+                return Some(Self::HardFault {
+                    process_id: event.process_id,
+                    thread_id: if thread_id != 0 {
+                        thread_id
+                    } else {
+                        event.thread_id
+                    },
+                    address: fields.optional("VirtualAddress"),
+                    bytes_read: fields.optional("ByteCount"),
+                });
+            }
+            OPCODE_MEM_INFO_WS | OPCODE_PROCESS_MEM_INFO => {
+                return Some(Self::WorkingSets(Self::_samples(event)));
+            }
+            _ => return None,
+        };
+
+        Some(Self::SoftFault {
+            kind,
+            process_id: event.process_id,
+            thread_id: event.thread_id,
+            address: fields.optional("VirtualAddress"),
+        })
+    }
+
+    /// The rundowns log an array with one struct per process, or a single process as top level properties
+    fn _samples(event: &ParsedEvent) -> Vec<WorkingSetSample> {
+        let mut samples: Vec<WorkingSetSample> = event
+            .properties
+            .values()
+            .filter_map(|value| match value {
+                PropertyValue::Array(entries) => Some(entries.as_slice()),
+                _ => None,
+            })
+            .flatten()
+            .filter_map(|entry| match entry {
+                PropertyValue::Struct(members) => Self::_sample(members),
+                _ => None,
+            })
+            .collect();
+
+        if samples.is_empty() {
+            samples.extend(Self::_sample(&event.properties));
+        }
+        samples
+    }
+
+    fn _sample(members: &BTreeMap<String, PropertyValue>) -> Option<WorkingSetSample> {
+        let pages = |name: &str| {
+            members
+                .get(name)
+                .and_then(PropertyValue::as_u64)
+                .unwrap_or_default()
+                * PAGE_SIZE
+        };
+
+        Some(WorkingSetSample {
+            process_id: members.get("ProcessID")?.as_u64()? as u32,
+            working_set_bytes: pages("WorkingSetPageCount"),
+            private_working_set_bytes: pages("PrivateWorkingSetPageCount"),
+            commit_bytes: pages("CommitPageCount"),
+        })
+    }
+}
+
+/// Page faults and working set samples of one process
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ProcessMemoryStats {
+    /// Soft faults, keyed on how they were resolved
+    pub soft_faults: BTreeMap<SoftFaultKind, u64>,
+    pub hard_faults: u64,
+    /// Bytes read from disk by hard faults
+    pub hard_fault_bytes: u64,
+    /// Working set samples with their timestamps, in the order they were logged
+    pub samples: Vec<(i64, WorkingSetSample)>,
+}
+
+impl ProcessMemoryStats {
+    pub fn soft_fault_count(&self) -> u64 {
+        self.soft_faults.values().sum()
+    }
+
+    pub fn peak_working_set(&self) -> u64 {
+        self.samples
+            .iter()
+            .map(|(_, sample)| sample.working_set_bytes)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// How much the working set grew from the first sample to the last. Negative if it shrank
+    pub fn working_set_growth(&self) -> i64 {
+        match (self.samples.first(), self.samples.last()) {
+            (Some((_, first)), Some((_, last))) => {
+                last.working_set_bytes as i64 - first.working_set_bytes as i64
+            }
+            _ => 0,
+        }
+    }
+
+    /// How much the commit charge grew from the first sample to the last. Steady growth points at a leak
+    pub fn commit_growth(&self) -> i64 {
+        match (self.samples.first(), self.samples.last()) {
+            (Some((_, first)), Some((_, last))) => {
+                last.commit_bytes as i64 - first.commit_bytes as i64
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// Counts page faults and follows the working set and commit charge of each process over time, from kernel PageFault
+/// events and the MemInfo rundowns. Events are fed in with [`MemoryAnalyzer::add`] in the order they were logged
+#[derive(Debug, Default)]
+pub struct MemoryAnalyzer {
+    processes: BTreeMap<u32, ProcessMemoryStats>,
+}
+
+impl MemoryAnalyzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes in one event. Anything other than a page fault or working set rundown is ignored
+    pub fn add(&mut self, event: &ParsedEvent) {
+        let Some(memory_event) = MemoryEvent::from_event(event) else {
+            return;
+        };
+
+        match memory_event {
+            MemoryEvent::SoftFault {
+                kind, process_id, ..
+            } => {
+                *self
+                    .processes
+                    .entry(process_id)
+                    .or_default()
+                    .soft_faults
+                    .entry(kind)
+                    .or_default() += 1;
+            }
+            MemoryEvent::HardFault {
+                process_id,
+                bytes_read,
+                ..
+            } => {
+                let stats = self.processes.entry(process_id).or_default();
+                stats.hard_faults += 1;
+                stats.hard_fault_bytes += bytes_read;
+            }
+            MemoryEvent::WorkingSets(samples) => {
+                for sample in samples {
+                    self.processes
+                        .entry(sample.process_id)
+                        .or_default()
+                        .samples
+                        .push((event.timestamp, sample));
+                }
+            }
+        }
+    }
+
+    /// Statistics of every process seen, keyed on process id
+    pub fn processes(&self) -> &BTreeMap<u32, ProcessMemoryStats> {
+        &self.processes
+    }
+}
+
+impl fmt::Display for MemoryAnalyzer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (process_id, stats) in &self.processes {
+            write!(
+                f,
+                "Process {process_id}: {} soft faults, {} hard faults ({} bytes read)",
+                stats.soft_fault_count(),
+                stats.hard_faults,
+                stats.hard_fault_bytes
+            )?;
+            if let (Some((_, first)), Some((_, last))) =
+                (stats.samples.first(), stats.samples.last())
+            {
+                write!(
+                    f,
+                    ", working set {} -> {} bytes (peak {}), commit {} -> {} bytes",
+                    first.working_set_bytes,
+                    last.working_set_bytes,
+                    stats.peak_working_set(),
+                    first.commit_bytes,
+                    last.commit_bytes
+                )?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
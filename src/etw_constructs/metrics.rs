@@ -0,0 +1,107 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use super::{
+    error::{EtwError, EtwResult},
+    latency::LatencyTracker,
+};
+
+/// How often the listener looks for a connection, and how long it has to stop once dropped
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a scraper has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Serves the delivery latency of a capture over HTTP in the Prometheus text format, so the collector can be scraped
+/// and alerted on while it runs. Every request gets the same response, whatever its path. Stops when dropped
+pub struct MetricsServer {
+    address: SocketAddr,
+    finished: Arc<AtomicBool>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Listens on `address` on a thread of its own
+    pub fn bind(address: SocketAddr, latency: Arc<Mutex<LatencyTracker>>) -> EtwResult<Self> {
+        let bind_error = |err: io::Error| {
+            EtwError::from_io(&err, format!("Could not serve metrics on {address}"))
+        };
+
+        let listener = TcpListener::bind(address).map_err(bind_error)?;
+        // Polled, so the thread notices it is dropped without a connection coming in
+        listener.set_nonblocking(true).map_err(bind_error)?;
+        let address = listener.local_addr().map_err(bind_error)?;
+
+        let finished = Arc::new(AtomicBool::new(false));
+        let worker = {
+            let finished = Arc::clone(&finished);
+            thread::Builder::new()
+                .name("etw-metrics".to_string())
+                .spawn(move || {
+                    while !finished.load(Ordering::Relaxed) {
+                        match listener.accept() {
+                            // A scraper that goes away mid-request only loses its own response
+                            Ok((stream, _)) => {
+                                let _ = Self::_respond(stream, &latency);
+                            }
+                            Err(_) => thread::sleep(ACCEPT_INTERVAL),
+                        }
+                    }
+                })
+                .map_err(|err| EtwError::from_io(&err, "Could not spawn the metrics thread"))?
+        };
+
+        Ok(Self {
+            address,
+            finished,
+            worker: Some(worker),
+        })
+    }
+
+    /// The address listened on, with the port picked if port 0 was asked for
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    fn _respond(mut stream: TcpStream, latency: &Mutex<LatencyTracker>) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        // Only the end of the request headers is waited for, the request itself is not looked at
+        let mut request = Vec::new();
+        let mut buf = [0u8; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < 16 * 1024 {
+            match stream.read(&mut buf)? {
+                0 => break,
+                read => request.extend_from_slice(&buf[..read]),
+            }
+        }
+
+        let body = latency
+            .lock()
+            .expect("Latency tracker lock was poisoned")
+            .to_prometheus();
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{body}",
+            body.len()
+        )?;
+        stream.flush()
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.finished.store(true, Ordering::Relaxed);
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
@@ -1,28 +1,504 @@
-use std::ffi::CStr;
+use std::{
+    ffi::CStr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
 
-use windows::Win32::System::Diagnostics::Etw::EVENT_RECORD;
+use windows::Win32::{Foundation::ERROR_NOT_SUPPORTED, System::Diagnostics::Etw::EVENT_RECORD};
 
+#[cfg(feature = "async")]
+pub mod async_stream;
+pub mod audit;
+pub mod bits;
+pub mod bookmark;
+pub mod browser;
+pub mod capture_diff;
+pub mod capture_header;
+pub mod clock;
+pub mod clr;
 pub mod consumer;
 pub mod controller;
+pub mod coordinator;
+pub mod crash;
+pub mod enumeration;
+pub mod environment;
+pub mod error;
+pub mod event_filter;
+pub mod extended;
+pub mod file_latency;
+pub mod filter;
+pub mod filter_reload;
+pub mod guardrails;
+pub mod keep_alive;
+pub mod latency;
+pub mod mapped_file;
+pub mod memory;
+pub mod metrics;
+pub mod named_pipe;
+pub mod parallel_decode;
+pub mod parsed_event;
+pub mod pipeline_error;
+pub mod pnp;
+pub mod print_service;
+pub mod privilege;
+pub mod process_graph;
+pub mod process_tracker;
+pub mod raw_capture;
+pub mod rdp;
+pub mod regions;
+pub mod router;
+pub mod schema_cache;
+pub mod schemas;
+pub mod security;
+pub mod security_log;
+pub mod session_stats;
+pub mod sink;
+pub mod stack_walk;
+pub mod stop_handle;
+pub mod stream;
+pub mod system_config;
+pub mod taxonomy;
 pub mod tdh_wrapper;
+pub mod thread_groups;
+pub mod time_series;
+pub mod validation;
+pub mod wait;
+pub mod win32k;
+pub mod windows_update;
+
+pub use error::{EtwError, EtwResult};
+pub use parsed_event::{ParsedEvent, PropertyValue};
+pub use schemas::{
+    FileIoEvent, ImageEvent, NetworkEvent, ProcessEvent, RegistryEvent, SchedulerEvent,
+    SchemaError, ThreadEvent,
+};
 
 pub struct ETWSession {
-    _controller: controller::Controller,
-    consumer: consumer::Consumer,
+    _controller: Option<controller::Controller>, // None when replaying a recorded .etl file
+    consumer: Option<consumer::Consumer>,        // None when the controller only logs to a file
+    guardrails: Option<guardrails::Guardrails>,
+    guardrail_reports: Mutex<Vec<guardrails::GuardrailReport>>,
+    stop_handle: stop_handle::StopHandle,
 }
 
 impl ETWSession {
     pub fn new(
         session_name: &'static CStr,
         process_evt_handler: Option<unsafe extern "system" fn(*mut EVENT_RECORD)>,
-    ) -> Self {
-        Self {
-            _controller: controller::Controller::new(session_name),
-            consumer: consumer::Consumer::new(session_name, process_evt_handler),
+    ) -> EtwResult<Self> {
+        Self::with_config(
+            session_name,
+            controller::ControllerConfig::default(),
+            process_evt_handler,
+        )
+    }
+
+    /// Same as [`ETWSession::new`], but starts the controller with the options in `config`
+    pub fn with_config(
+        session_name: &'static CStr,
+        config: controller::ControllerConfig,
+        process_evt_handler: Option<unsafe extern "system" fn(*mut EVENT_RECORD)>,
+    ) -> EtwResult<Self> {
+        let real_time = config.is_real_time();
+        let guardrails = config.guardrails;
+        let stack_walk = config.stack_walk.clone();
+        let controller = controller::Controller::with_config(session_name, config)?;
+        let consumer = if real_time {
+            Some(consumer::Consumer::new(session_name, process_evt_handler)?)
+        } else {
+            None
+        };
+        if let Some(consumer) = consumer.as_ref().filter(|_| !stack_walk.is_empty()) {
+            consumer.set_stack_walk(stack_walk);
+        }
+        let stop_handle = match &consumer {
+            Some(consumer) => consumer.stop_handle(Some(session_name)),
+            None => stop_handle::StopHandle::new(Some(session_name), None, Arc::default()),
+        };
+        let stop_handle = if controller.keeps_running() {
+            stop_handle.keep_session()
+        } else {
+            stop_handle
+        };
+
+        Ok(Self {
+            _controller: Some(controller),
+            consumer,
+            guardrails,
+            guardrail_reports: Mutex::default(),
+            stop_handle,
+        })
+    }
+
+    /// Creates a session that replays the .etl file at `path` through the same event callback as a real-time session
+    pub fn from_file(
+        path: &Path,
+        process_evt_handler: Option<unsafe extern "system" fn(*mut EVENT_RECORD)>,
+    ) -> EtwResult<Self> {
+        let consumer = consumer::Consumer::from_file(path, process_evt_handler)?;
+        let stop_handle = consumer.stop_handle(None);
+
+        Ok(Self {
+            _controller: None,
+            consumer: Some(consumer),
+            guardrails: None,
+            guardrail_reports: Mutex::default(),
+            stop_handle,
+        })
+    }
+
+    /// Processes events until the session is stopped. If the session only logs to a file, there is nothing to consume
+    /// so this blocks until [`ETWSession::stop_handle`] is used instead. Guardrails, if configured, are checked on a separate thread while this runs
+    pub fn start_session(&self) -> EtwResult<()> {
+        let Some(guardrails) = self.guardrails else {
+            return self._consume();
+        };
+
+        let finished = Arc::new(AtomicBool::new(false));
+        let events_consumed = self.consumer.as_ref().map(|c| c.events_consumed());
+
+        thread::scope(|scope| {
+            let watcher = {
+                let finished = Arc::clone(&finished);
+                let stop_handle = &self.stop_handle;
+                scope.spawn(move || guardrails.watch(stop_handle, events_consumed, finished))
+            };
+
+            let result = self._consume();
+            finished.store(true, Ordering::Relaxed);
+
+            if let Ok(reports) = watcher.join() {
+                self.guardrail_reports
+                    .lock()
+                    .expect("Guardrail report lock was poisoned")
+                    .extend(reports);
+            }
+
+            result
+        })
+    }
+
+    /// Processes the session on a dedicated thread instead of blocking the caller. Every event is decoded into a
+    /// [`ParsedEvent`] and sent over a channel, which the returned stream iterates over until the session stops
+    pub fn events(self) -> EtwResult<stream::EventStream> {
+        let (sender, receiver) = mpsc::channel();
+        self._events(sender, receiver)
+    }
+
+    /// Same as [`ETWSession::events`], but as a [`futures_core::Stream`] buffering at most `config.capacity` events.
+    /// Only available with the `async` feature
+    #[cfg(feature = "async")]
+    pub fn event_stream(
+        self,
+        config: async_stream::AsyncStreamConfig,
+    ) -> EtwResult<async_stream::AsyncEventStream> {
+        // The stream's buffer is the only queue. Events are handed to it one at a time, so ProcessTrace waits on the
+        // stream when it blocks, and nothing queues without limit in front of it when it drops events
+        let (sender, receiver) = mpsc::sync_channel(0);
+        async_stream::AsyncEventStream::new(self._events(sender, receiver)?, config)
+    }
+
+    fn _events(
+        self,
+        sender: impl Into<consumer::EventSender>,
+        receiver: mpsc::Receiver<ParsedEvent>,
+    ) -> EtwResult<stream::EventStream> {
+        let pipeline_errors = Arc::<pipeline_error::PipelineErrorCounters>::default();
+        if let Some(consumer) = &self.consumer {
+            consumer.set_event_sender(sender);
+            consumer.set_pipeline_errors(Arc::clone(&pipeline_errors));
+        }
+
+        let stop_handle = self.stop_handle();
+        let worker = thread::Builder::new()
+            .name("etw-consumer".to_string())
+            .spawn(move || self.start_session())
+            .map_err(|err| EtwError::from_io(&err, "Could not spawn the consumer thread"))?;
+
+        Ok(stream::EventStream::new(
+            receiver,
+            stop_handle,
+            worker,
+            pipeline_errors,
+        ))
+    }
+
+    /// A handle that stops this session from another thread, without affecting any other session in the process
+    pub fn stop_handle(&self) -> stop_handle::StopHandle {
+        self.stop_handle.clone()
+    }
+
+    /// Drops events that do not match `filter` before they reach the event callback or stream. Has no effect if the
+    /// session only logs to a file
+    pub fn set_filter(&self, filter: filter::FilterSet) {
+        if let Some(consumer) = &self.consumer {
+            consumer.set_filter(filter);
+        }
+    }
+
+    /// A handle that swaps the filter of [`ETWSession::set_filter`] while the session runs, e.g. after
+    /// [`ETWSession::events`] took the session. None if the session only logs to a file
+    pub fn session_filter(&self) -> Option<filter::SessionFilter> {
+        self.consumer
+            .as_ref()
+            .map(consumer::Consumer::session_filter)
+    }
+
+    /// The header of the recorded .etl file or of the session, with the build and clock of the machine that logged it.
+    /// None if the session only logs to a file
+    pub fn trace_header(&self) -> Option<consumer::TraceHeaderInfo> {
+        self.consumer
+            .as_ref()
+            .and_then(consumer::Consumer::trace_header)
+    }
+
+    /// Looks for the schemas of events TDH cannot decode in the .man and .xml manifests under `dir`. Events whose schema
+    /// is still missing are streamed with their payload undecoded. Has no effect if the session only logs to a file
+    pub fn set_manifest_dir(&self, dir: PathBuf) {
+        if let Some(consumer) = &self.consumer {
+            consumer.set_manifest_dir(dir);
+        }
+    }
+
+    /// Decodes the events of a recorded .etl file on `threads` threads, which is several times faster for large files
+    /// on machines with many cores. Events are still streamed in the order they were read. Has no effect on live
+    /// sessions
+    pub fn set_decode_threads(&self, threads: usize) {
+        if let Some(consumer) = &self.consumer {
+            consumer.set_decode_threads(threads);
+        }
+    }
+
+    /// Routes events to per-provider and per-event handlers. Has no effect if the session only logs to a file
+    pub fn set_router(&self, router: router::Router) {
+        if let Some(consumer) = &self.consumer {
+            consumer.set_router(router);
         }
     }
 
-    pub fn start_session(&self) {
-        self.consumer.start_listening();
+    /// The controller of the live session, for tuning or flushing it while it runs. None when replaying a recorded .etl file
+    pub fn controller(&self) -> Option<&controller::Controller> {
+        self._controller.as_ref()
+    }
+
+    /// A handle that writes labelled marker events into the live session. None when replaying a recorded .etl file
+    pub fn bookmarker(&self) -> Option<bookmark::Bookmarker> {
+        self._controller
+            .as_ref()
+            .map(controller::Controller::bookmarker)
+    }
+
+    /// Writes a marker event labelled `label` into the live session
+    pub fn bookmark(&self, label: &str) -> EtwResult<()> {
+        match self.bookmarker() {
+            Some(bookmarker) => bookmarker.mark(label),
+            None => Err(EtwError::Win32 {
+                status: ERROR_NOT_SUPPORTED,
+                context: "Bookmarks can only be written to a live session".to_string(),
+            }),
+        }
+    }
+
+    /// Queries the counters of the live session, such as how many events it has lost. Fails when replaying a recorded .etl file
+    pub fn stats(&self) -> EtwResult<session_stats::SessionStats> {
+        match &self._controller {
+            Some(controller) => controller.query_stats(),
+            None => Err(EtwError::Win32 {
+                status: ERROR_NOT_SUPPORTED,
+                context: "Statistics can only be queried from a live session".to_string(),
+            }),
+        }
+    }
+
+    /// How many lost event notifications the consumer has received. 0 when the session only logs to a file
+    pub fn lost_notifications(&self) -> u64 {
+        self.consumer.as_ref().map_or(0, |consumer| {
+            consumer.lost_notifications().load(Ordering::Relaxed)
+        })
+    }
+
+    /// Every guardrail that was tripped while the session was running
+    pub fn guardrail_reports(&self) -> Vec<guardrails::GuardrailReport> {
+        self.guardrail_reports
+            .lock()
+            .expect("Guardrail report lock was poisoned")
+            .clone()
+    }
+
+    fn _consume(&self) -> EtwResult<()> {
+        match &self.consumer {
+            Some(consumer) => consumer.start_listening(),
+            None => {
+                while !self.stop_handle.is_stopped() {
+                    thread::sleep(Duration::from_millis(100));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Several sessions and .etl files consumed together, e.g. a kernel session next to a user-mode provider session, with
+/// their events delivered through one stream. Recorded .etl files are merged by a single `ProcessTrace` call so their
+/// events arrive in timestamp order. `ProcessTrace` cannot merge live sessions, so each of them is processed on its
+/// own thread and their events are interleaved as they arrive.
+///
+/// Each session keeps the handler, filter, router and guardrails it was set up with before being merged
+pub struct MergedSession {
+    _controllers: Vec<controller::Controller>,
+    consumers: Vec<consumer::MergedConsumer>,
+    guarded: Vec<GuardedSession>,
+    guardrail_reports: Mutex<Vec<guardrails::GuardrailReport>>,
+    stop_handle: stop_handle::StopHandle,
+}
+
+/// A merged session with guardrails, which are checked against that session alone
+struct GuardedSession {
+    guardrails: guardrails::Guardrails,
+    stop_handle: stop_handle::StopHandle,
+    events_consumed: Option<Arc<AtomicU64>>,
+}
+
+impl MergedSession {
+    /// Takes over `sessions`. Their controllers keep running until the merged session is dropped
+    pub fn new(sessions: Vec<ETWSession>) -> EtwResult<Self> {
+        let mut controllers = Vec::new();
+        let mut consumers = Vec::new();
+        let mut files = Vec::new();
+        let mut stop_handles = Vec::new();
+        let mut guarded = Vec::new();
+
+        for session in sessions {
+            let ETWSession {
+                _controller,
+                consumer,
+                guardrails,
+                stop_handle,
+                ..
+            } = session;
+
+            controllers.extend(_controller);
+            if let Some(guardrails) = guardrails {
+                guarded.push(GuardedSession {
+                    guardrails,
+                    stop_handle: stop_handle.clone(),
+                    events_consumed: consumer.as_ref().map(|c| c.events_consumed()),
+                });
+            }
+            stop_handles.push(stop_handle);
+            match consumer {
+                Some(consumer) if consumer.is_real_time() => {
+                    consumers.push(consumer::MergedConsumer::new(vec![consumer])?)
+                }
+                Some(consumer) => files.push(consumer),
+                None => {}
+            }
+        }
+
+        if !files.is_empty() {
+            consumers.push(consumer::MergedConsumer::new(files)?);
+        }
+
+        Ok(Self {
+            _controllers: controllers,
+            consumers,
+            guarded,
+            guardrail_reports: Mutex::default(),
+            stop_handle: stop_handle::StopHandle::merged(stop_handles),
+        })
+    }
+
+    /// Processes every session until all of them end. Stopping any of them does not stop the others, use
+    /// [`MergedSession::stop_handle`] for that. The guardrails of each session are checked on their own thread while
+    /// this runs, and only act on that session. Returns the first error any of them failed with
+    pub fn start_session(&self) -> EtwResult<()> {
+        let finished = Arc::new(AtomicBool::new(false));
+
+        thread::scope(|scope| {
+            let watchers: Vec<_> = self
+                .guarded
+                .iter()
+                .map(|guarded| {
+                    let finished = Arc::clone(&finished);
+                    let events_consumed = guarded.events_consumed.clone();
+                    scope.spawn(move || {
+                        guarded
+                            .guardrails
+                            .watch(&guarded.stop_handle, events_consumed, finished)
+                    })
+                })
+                .collect();
+            let workers: Vec<_> = self
+                .consumers
+                .iter()
+                .map(|consumer| scope.spawn(move || consumer.start_listening()))
+                .collect();
+
+            let result = workers
+                .into_iter()
+                .map(|worker| {
+                    worker
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+                .collect::<EtwResult<Vec<()>>>()
+                .map(|_| ());
+            finished.store(true, Ordering::Relaxed);
+
+            let mut reports = self
+                .guardrail_reports
+                .lock()
+                .expect("Guardrail report lock was poisoned");
+            for watcher in watchers {
+                if let Ok(tripped) = watcher.join() {
+                    reports.extend(tripped);
+                }
+            }
+
+            result
+        })
+    }
+
+    /// Every guardrail that was tripped while the sessions were running
+    pub fn guardrail_reports(&self) -> Vec<guardrails::GuardrailReport> {
+        self.guardrail_reports
+            .lock()
+            .expect("Guardrail report lock was poisoned")
+            .clone()
+    }
+
+    /// Same as [`ETWSession::events`], with the events of every session sent to the same stream
+    pub fn events(self) -> EtwResult<stream::EventStream> {
+        let (sender, receiver) = mpsc::channel();
+        let pipeline_errors = Arc::<pipeline_error::PipelineErrorCounters>::default();
+        for consumer in self.consumers.iter().flat_map(|merged| merged.consumers()) {
+            consumer.set_event_sender(sender.clone());
+            consumer.set_pipeline_errors(Arc::clone(&pipeline_errors));
+        }
+        // The stream ends once every consumer has dropped its sender
+        drop(sender);
+
+        let stop_handle = self.stop_handle();
+        let worker = thread::Builder::new()
+            .name("etw-merged-consumer".to_string())
+            .spawn(move || self.start_session())
+            .map_err(|err| EtwError::from_io(&err, "Could not spawn the consumer thread"))?;
+
+        Ok(stream::EventStream::new(
+            receiver,
+            stop_handle,
+            worker,
+            pipeline_errors,
+        ))
+    }
+
+    /// A handle that stops every merged session
+    pub fn stop_handle(&self) -> stop_handle::StopHandle {
+        self.stop_handle.clone()
     }
 }
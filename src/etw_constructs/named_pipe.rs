@@ -0,0 +1,145 @@
+use windows::{core::GUID, Win32::System::Diagnostics::Etw::TRACE_LEVEL_INFORMATION};
+
+use super::{
+    controller::ProviderConfig,
+    parsed_event::{ParsedEvent, PropertyValue},
+    schemas::{FileIoEvent, FILEIO_GUID},
+};
+
+/// Microsoft-Windows-Kernel-File. Logs file opens with the process that made them, unlike the kernel FileIo class
+pub const KERNEL_FILE_GUID: GUID = GUID::from_u128(0xedd08927_9cc4_4e65_b970_c2560fb5c289);
+
+/// KERNEL_FILE_KEYWORD_CREATE, only the Create events
+const KEYWORD_CREATE: u64 = 0x80;
+
+// Kernel-File events
+const EVENT_CREATE: u16 = 12;
+
+// Kernel FileIo opcodes
+const OPCODE_CREATE: u8 = 64;
+
+/// Where named pipes live in the object namespace
+const NAMED_PIPE_PREFIX: &str = r"\Device\NamedPipe\";
+/// Where pipes on other machines are opened, as `\Device\Mup\<host>\pipe\<name>`
+const MUP_PREFIX: &str = r"\Device\Mup\";
+
+// CreateDisposition, kept in the top byte of CreateOptions
+const FILE_OPEN: u32 = 1;
+/// CreateFile sets this for every open. NtCreateNamedPipeFile, which creates pipe instances, does not
+const FILE_NON_DIRECTORY_FILE: u32 = 0x40;
+
+/// Enables the Kernel-File Create events at information level. The kernel file-io flag logs the same opens, without
+/// this provider
+pub fn providers() -> [ProviderConfig; 1] {
+    [ProviderConfig {
+        guid: KERNEL_FILE_GUID,
+        level: TRACE_LEVEL_INFORMATION as u8,
+        match_any_keyword: KEYWORD_CREATE,
+    }]
+}
+
+/// What a process did with a named pipe
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PipeActivity {
+    /// A server created an instance of the pipe, e.g. with CreateNamedPipe
+    Create,
+    /// A client opened an existing pipe, e.g. with CreateFile or CallNamedPipe
+    Connect,
+}
+
+/// A named pipe being created or connected to. Pipes such as `\\remote\pipe\svcctl` are a common path for lateral
+/// movement, and the kernel only logs them as file opens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamedPipeEvent {
+    pub activity: PipeActivity,
+    /// The name after `\\.\pipe\`, e.g. `lsass` or `MSSE-1234-server`
+    pub pipe_name: String,
+    /// The machine the pipe is on, for pipes opened over SMB as `\\host\pipe\name`. None for local pipes
+    pub host: Option<String>,
+    /// For kernel FileIo events this is the process the event was logged in, which is not always the one that opened
+    /// the pipe, see `thread_id`
+    pub process_id: u32,
+    /// The thread that opened the pipe
+    pub thread_id: u32,
+    pub timestamp: i64,
+}
+
+impl NamedPipeEvent {
+    /// Returns the event if it is a Kernel-File or kernel FileIo Create event for a named pipe
+    pub fn from_event(event: &ParsedEvent) -> Option<Self> {
+        let (path, create_options, thread_id) = match (event.provider, event.event_id) {
+            (KERNEL_FILE_GUID, EVENT_CREATE) => (
+                event.get("FileName")?.as_str()?.to_string(),
+                event.get("CreateOptions").and_then(PropertyValue::as_u64)? as u32,
+                event
+                    .get("IssuingThreadId")
+                    .and_then(PropertyValue::as_u64)
+                    .map_or(event.thread_id, |id| id as u32),
+            ),
+            (FILEIO_GUID, _) if event.opcode == OPCODE_CREATE => match FileIoEvent::try_from(event)
+            {
+                Ok(FileIoEvent::Create {
+                    thread_id,
+                    create_options,
+                    open_path,
+                    ..
+                }) => (open_path, create_options, thread_id),
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        let (host, pipe_name) = Self::_pipe_name(&path)?;
+        let activity = Self::_activity(create_options, host.is_some());
+        Some(Self {
+            activity,
+            pipe_name,
+            host,
+            process_id: event.process_id,
+            thread_id,
+            timestamp: event.timestamp,
+        })
+    }
+
+    /// Pipe instances are only made by NtCreateNamedPipeFile, whose create options lack the FILE_NON_DIRECTORY_FILE
+    /// every CreateFile open has. The disposition alone cannot tell them apart: CreateNamedPipe opens with FILE_OPEN_IF
+    /// unless it asks for the first instance, and so do clients calling CreateFile with OPEN_ALWAYS. Only opens
+    /// without the option are told apart by their disposition, since connecting never makes a pipe. Pipes on other
+    /// machines can only be connected to
+    fn _activity(create_options: u32, remote: bool) -> PipeActivity {
+        if remote
+            || create_options & FILE_NON_DIRECTORY_FILE != 0
+            || create_options >> 24 == FILE_OPEN
+        {
+            PipeActivity::Connect
+        } else {
+            PipeActivity::Create
+        }
+    }
+
+    /// The host and pipe name if `path` is under `\Device\NamedPipe\` or `\Device\Mup\<host>\pipe\`, ignoring case.
+    /// None for the pipe file system itself
+    fn _pipe_name(path: &str) -> Option<(Option<String>, String)> {
+        if let Some(name) = Self::_strip_prefix(path, NAMED_PIPE_PREFIX) {
+            return (!name.is_empty()).then(|| (None, name.to_string()));
+        }
+
+        // Redirectors can add components of their own in front of the host, such as `;LanmanRedirector\`
+        let rest = Self::_strip_prefix(path, MUP_PREFIX)?;
+        let mut components = rest
+            .split('\\')
+            .skip_while(|component| component.starts_with(';'));
+        let host = components.next().filter(|host| !host.is_empty())?;
+        if !components.next()?.eq_ignore_ascii_case("pipe") {
+            return None;
+        }
+        let name = components.collect::<Vec<_>>().join("\\");
+        (!name.is_empty()).then(|| (Some(host.to_string()), name))
+    }
+
+    fn _strip_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+        path.get(..prefix.len())
+            .filter(|start| start.eq_ignore_ascii_case(prefix))
+            .map(|_| &path[prefix.len()..])
+    }
+}
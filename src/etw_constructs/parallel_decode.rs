@@ -0,0 +1,249 @@
+use core::slice;
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Receiver, Sender, SyncSender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use windows::Win32::System::Diagnostics::Etw::{
+    ETW_BUFFER_CONTEXT, EVENT_HEADER, EVENT_HEADER_EXTENDED_DATA_ITEM, EVENT_RECORD,
+};
+
+use super::{
+    consumer::{decode_record, EventSender, TraceHeaderInfo},
+    error::{EtwError, EtwResult},
+    parsed_event::ParsedEvent,
+    pipeline_error::PipelineErrorCounters,
+    schema_cache::SchemaCache,
+};
+
+/// Records handed to a decoder thread at a time. Large enough that the channels are not the bottleneck, small enough
+/// that the first events come out quickly
+const BATCH_SIZE: usize = 1024;
+
+/// Batches queued per decoder thread before the ProcessTrace callback waits for it, which bounds the memory held
+const QUEUED_BATCHES: usize = 2;
+
+/// An event copied out of the ProcessTrace callback along with its extended data, so it can be decoded on another
+/// thread after the callback has returned and ETW has reused its buffer
+struct OwnedRecord {
+    header: EVENT_HEADER,
+    buffer_context: ETW_BUFFER_CONTEXT,
+    userdata: Vec<u8>,
+    extended: Vec<EVENT_HEADER_EXTENDED_DATA_ITEM>, // DataPtr of each points into the matching `extended_data` entry
+    extended_data: Vec<Vec<u8>>,
+    sequence: u64,
+}
+
+impl OwnedRecord {
+    fn from_record(record: &EVENT_RECORD, sequence: u64) -> Self {
+        let copy = |ptr: *const u8, len: usize| {
+            if ptr.is_null() {
+                Vec::new()
+            } else {
+                unsafe { slice::from_raw_parts(ptr, len) }.to_vec()
+            }
+        };
+
+        let items: &[EVENT_HEADER_EXTENDED_DATA_ITEM] = if record.ExtendedData.is_null()
+            || record.ExtendedDataCount == 0
+        {
+            &[]
+        } else {
+            unsafe { slice::from_raw_parts(record.ExtendedData, record.ExtendedDataCount as usize) }
+        };
+        let extended_data: Vec<Vec<u8>> = items
+            .iter()
+            .map(|item| copy(item.DataPtr as *const u8, item.DataSize as usize))
+            .collect();
+        // The data of each item lives on the heap, so the pointers stay valid when the record is moved
+        let extended = items
+            .iter()
+            .zip(&extended_data)
+            .map(|(item, data)| EVENT_HEADER_EXTENDED_DATA_ITEM {
+                DataPtr: data.as_ptr() as u64,
+                ..*item
+            })
+            .collect();
+
+        Self {
+            header: record.EventHeader,
+            buffer_context: record.BufferContext,
+            userdata: copy(record.UserData as *const u8, record.UserDataLength as usize),
+            extended,
+            extended_data,
+            sequence,
+        }
+    }
+
+    /// An [`EVENT_RECORD`] pointing into this record, which must not outlive it. It has no `UserContext`
+    fn as_record(&self) -> EVENT_RECORD {
+        EVENT_RECORD {
+            EventHeader: self.header,
+            BufferContext: self.buffer_context,
+            ExtendedDataCount: self.extended.len() as u16,
+            UserDataLength: self.userdata.len() as u16,
+            ExtendedData: self.extended.as_ptr() as *mut _,
+            UserData: self.userdata.as_ptr() as *mut _,
+            ..Default::default()
+        }
+    }
+}
+
+struct PoolState {
+    batch: Vec<OwnedRecord>,
+    next_index: u64,
+    workers: Vec<SyncSender<(u64, Vec<OwnedRecord>)>>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+/// Decodes the events of a recorded trace on several threads. The ProcessTrace callback only copies each record into
+/// a batch, batches are handed round-robin to the decoder threads, and a merging thread sends the decoded batches on
+/// in the order they were read, so the stream sees the same order as when decoding on the callback's thread
+pub(crate) struct DecoderPool {
+    state: Mutex<PoolState>,
+}
+
+impl DecoderPool {
+    /// Starts `threads` decoder threads, each with a schema cache of its own so they never wait on each other, and the
+    /// merging thread, which sends to `sender`
+    pub(crate) fn new(
+        threads: usize,
+        sender: EventSender,
+        trace_header: Option<TraceHeaderInfo>,
+        manifest_dir: Option<PathBuf>,
+        pipeline_errors: Option<Arc<PipelineErrorCounters>>,
+    ) -> EtwResult<Self> {
+        let spawn_error =
+            |err: std::io::Error| EtwError::from_io(&err, "Could not spawn the decoder threads");
+        let (decoded_sender, decoded) = mpsc::channel();
+
+        let mut workers = Vec::new();
+        let mut handles = Vec::new();
+        for index in 0..threads.max(1) {
+            let (worker, batches) = mpsc::sync_channel(QUEUED_BATCHES);
+            let decoded_sender = decoded_sender.clone();
+            let manifest_dir = manifest_dir.clone();
+            let pipeline_errors = pipeline_errors.clone();
+            let handle = thread::Builder::new()
+                .name(format!("etw-decoder-{index}"))
+                .spawn(move || {
+                    Self::_decode_batches(
+                        batches,
+                        decoded_sender,
+                        trace_header,
+                        manifest_dir,
+                        pipeline_errors,
+                    )
+                })
+                .map_err(spawn_error)?;
+            workers.push(worker);
+            handles.push(handle);
+        }
+        // The merging thread stops once every decoder thread has dropped its sender
+        drop(decoded_sender);
+
+        handles.push(
+            thread::Builder::new()
+                .name("etw-decoder-merge".to_string())
+                .spawn(move || Self::_merge(decoded, sender))
+                .map_err(spawn_error)?,
+        );
+
+        Ok(Self {
+            state: Mutex::new(PoolState {
+                batch: Vec::with_capacity(BATCH_SIZE),
+                next_index: 0,
+                workers,
+                threads: handles,
+            }),
+        })
+    }
+
+    /// Copies `record` into the current batch, handing the batch to a decoder thread once it is full
+    pub(crate) fn push(&self, record: &EVENT_RECORD, sequence: u64) {
+        let mut state = self.state.lock().expect("Decoder pool lock was poisoned");
+        state.batch.push(OwnedRecord::from_record(record, sequence));
+        if state.batch.len() >= BATCH_SIZE {
+            Self::_dispatch(&mut state);
+        }
+    }
+
+    /// Hands over the last partial batch and waits for every event to be decoded and sent. Called once ProcessTrace
+    /// has returned
+    pub(crate) fn finish(&self) {
+        let threads = {
+            let mut state = self.state.lock().expect("Decoder pool lock was poisoned");
+            Self::_dispatch(&mut state);
+            state.workers.clear();
+            std::mem::take(&mut state.threads)
+        };
+        for thread in threads {
+            let _ = thread.join();
+        }
+    }
+
+    fn _dispatch(state: &mut PoolState) {
+        if state.batch.is_empty() || state.workers.is_empty() {
+            return;
+        }
+
+        let batch = std::mem::replace(&mut state.batch, Vec::with_capacity(BATCH_SIZE));
+        let index = state.next_index;
+        state.next_index += 1;
+        let worker = &state.workers[(index % state.workers.len() as u64) as usize];
+        let _ = worker.send((index, batch));
+    }
+
+    fn _decode_batches(
+        batches: Receiver<(u64, Vec<OwnedRecord>)>,
+        decoded: Sender<(u64, Vec<ParsedEvent>)>,
+        trace_header: Option<TraceHeaderInfo>,
+        manifest_dir: Option<PathBuf>,
+        pipeline_errors: Option<Arc<PipelineErrorCounters>>,
+    ) {
+        let schema_cache = SchemaCache::new();
+        if let Some(manifest_dir) = manifest_dir {
+            schema_cache.set_manifest_dir(manifest_dir);
+        }
+
+        for (index, records) in batches {
+            let events = records
+                .iter()
+                .map(|owned| {
+                    decode_record(
+                        &owned.as_record(),
+                        owned.sequence,
+                        trace_header.as_ref(),
+                        &schema_cache,
+                        pipeline_errors.as_deref(),
+                    )
+                    .unwrap_or_else(|error_event| error_event)
+                })
+                .collect();
+            if decoded.send((index, events)).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Sends the decoded batches on in index order, holding back those that finish before an earlier one
+    fn _merge(decoded: Receiver<(u64, Vec<ParsedEvent>)>, sender: EventSender) {
+        let mut pending = BTreeMap::new();
+        let mut next_index = 0;
+
+        for (index, events) in decoded {
+            pending.insert(index, events);
+            while let Some(events) = pending.remove(&next_index) {
+                for event in events {
+                    sender.send(event);
+                }
+                next_index += 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,781 @@
+use core::slice;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::Arc,
+    time::SystemTime,
+};
+
+use serde::{Serialize, Serializer};
+use windows::{
+    core::GUID,
+    Win32::System::Diagnostics::Etw::{
+        PropertyParamCount, PropertyParamFixedCount, PropertyParamLength, PropertyStruct,
+        EVENT_MAP_INFO, EVENT_PROPERTY_INFO, EVENT_RECORD,
+    },
+};
+
+use super::{
+    audit::AuditEvent,
+    bookmark::Bookmark,
+    clock,
+    consumer::TraceHeaderInfo,
+    error::EtwResult,
+    extended::ExtendedData,
+    schema_cache::{Schema, SchemaCache, SchemaField},
+    session_stats::LostEvent,
+    tdh_wrapper::Tdh,
+};
+
+// https://learn.microsoft.com/en-us/windows/win32/api/tdh/ne-tdh-_tdh_in_type
+mod in_type {
+    pub const INT8: u16 = 3;
+    pub const UINT8: u16 = 4;
+    pub const INT16: u16 = 5;
+    pub const UINT16: u16 = 6;
+    pub const INT32: u16 = 7;
+    pub const UINT32: u16 = 8;
+    pub const INT64: u16 = 9;
+    pub const UINT64: u16 = 10;
+    pub const FLOAT: u16 = 11;
+    pub const DOUBLE: u16 = 12;
+    pub const BOOLEAN: u16 = 13;
+    pub const BINARY: u16 = 14;
+    pub const GUID: u16 = 15;
+    pub const POINTER: u16 = 16;
+    pub const FILETIME: u16 = 17;
+    pub const SID: u16 = 19;
+    pub const HEXINT32: u16 = 20;
+    pub const HEXINT64: u16 = 21;
+    pub const SIZET: u16 = 308;
+    pub const HEXDUMP: u16 = 309;
+    pub const WBEMSID: u16 = 310;
+}
+
+/// A decoded property value
+#[derive(Debug, Clone, PartialEq)]
+pub enum PropertyValue {
+    Signed(i64),
+    Unsigned(u64),
+    Float(f64),
+    Boolean(bool),
+    String(String),
+    Guid(GUID),
+    Sid(String),
+    Binary(Vec<u8>),
+    Array(Vec<PropertyValue>),
+    Struct(BTreeMap<String, PropertyValue>),
+}
+
+impl PropertyValue {
+    /// The value as an unsigned integer, if it is one
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            PropertyValue::Unsigned(value) => Some(value),
+            PropertyValue::Signed(value) => u64::try_from(value).ok(),
+            PropertyValue::Boolean(value) => Some(value as u64),
+            _ => None,
+        }
+    }
+
+    /// The value as a signed integer, if it is one
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            PropertyValue::Signed(value) => Some(value),
+            PropertyValue::Unsigned(value) => Some(value as i64),
+            _ => None,
+        }
+    }
+
+    /// The value as a string slice, if it is a string or a SID
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            PropertyValue::String(value) | PropertyValue::Sid(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// Numbers, booleans and strings serialize as themselves. GUIDs and binary data serialize as their [`fmt::Display`] string
+impl Serialize for PropertyValue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            PropertyValue::Signed(value) => serializer.serialize_i64(*value),
+            PropertyValue::Unsigned(value) => serializer.serialize_u64(*value),
+            PropertyValue::Float(value) => serializer.serialize_f64(*value),
+            PropertyValue::Boolean(value) => serializer.serialize_bool(*value),
+            PropertyValue::String(value) | PropertyValue::Sid(value) => {
+                serializer.serialize_str(value)
+            }
+            PropertyValue::Guid(_) | PropertyValue::Binary(_) => serializer.collect_str(self),
+            PropertyValue::Array(values) => serializer.collect_seq(values),
+            PropertyValue::Struct(members) => serializer.collect_map(members),
+        }
+    }
+}
+
+impl fmt::Display for PropertyValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PropertyValue::Signed(value) => write!(f, "{value}"),
+            PropertyValue::Unsigned(value) => write!(f, "{value}"),
+            PropertyValue::Float(value) => write!(f, "{value}"),
+            PropertyValue::Boolean(value) => write!(f, "{value}"),
+            PropertyValue::String(value) | PropertyValue::Sid(value) => write!(f, "{value}"),
+            PropertyValue::Guid(value) => write!(f, "{{{:?}}}", value),
+            PropertyValue::Binary(value) => {
+                write!(f, "0x")?;
+                value.iter().try_for_each(|byte| write!(f, "{byte:02X}"))
+            }
+            PropertyValue::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{value}")?;
+                }
+                write!(f, "]")
+            }
+            PropertyValue::Struct(members) => {
+                write!(f, "{{")?;
+                for (i, (name, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{name}: {value}")?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+/// The architecture an event was logged with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+pub struct EventArchitecture {
+    /// Pointer size used to decode the event. Taken from the event header flags, or the logfile header if the flags are missing
+    pub pointer_size: u32,
+    /// Pointer size of the machine that recorded the trace, from the logfile header. None when no header is available, e.g. decoding a raw capture
+    pub trace_pointer_size: Option<u32>,
+}
+
+impl EventArchitecture {
+    /// True if the event was logged by a 32 bit process on a 64 bit machine
+    pub fn is_wow64(&self) -> bool {
+        self.pointer_size == 4 && self.trace_pointer_size == Some(8)
+    }
+}
+
+/// An event with every top level property decoded through TDH
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ParsedEvent {
+    #[serde(serialize_with = "serialize_guid")]
+    pub provider: GUID,
+    pub event_id: u16,
+    pub opcode: u8,
+    /// Version of the event in the provider's manifest, bumped when its fields change
+    pub version: u8,
+    /// [`Schema::hash`] of the schema the event was decoded with, serialized as 16 hex digits. Two events with the
+    /// same id and version but different hashes were logged by different builds of the provider. None for events
+    /// decoded without a schema, such as bookmarks
+    #[serde(
+        serialize_with = "serialize_schema_hash",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub schema_hash: Option<u64>,
+    /// [`Schema::fields`] of the schema the event was decoded with, shared with every other event decoded with it
+    #[serde(skip)]
+    pub schema_fields: Option<Arc<[SchemaField]>>,
+    pub process_id: u32,
+    pub thread_id: u32,
+    pub timestamp: i64, // FILETIME ticks, 100ns intervals since January 1, 1601 (UTC)
+    /// `timestamp` as wall-clock time, serialized as RFC 3339 so it lines up with other logs
+    #[serde(serialize_with = "serialize_time")]
+    pub time: SystemTime,
+    /// The event key the kernel stamped on the event, see [`SequenceMode`](super::controller::SequenceMode). Events
+    /// without one are numbered by their position among the events their trace delivered and the consumer's filter
+    /// kept, from 1. Events held back until their stack arrives are sent after events delivered later, so a lower
+    /// number after a higher one means the event was reordered. 0 for events decoded outside a consumer
+    pub sequence: u64,
+    pub architecture: EventArchitecture,
+    pub properties: BTreeMap<String, PropertyValue>,
+    /// Return addresses of the call stack the event was logged from, innermost first. Empty unless stack tracing was
+    /// enabled for the event, see [`ControllerConfig::stack_walk`](super::controller::ControllerConfig::stack_walk)
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub stack: Vec<u64>,
+    /// The task name from the event's schema
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub task_name: Option<String>,
+    /// The name a TraceLogging event was logged with. TraceLogging events have no id of their own, so this is what
+    /// tells them apart. None for manifest and MOF events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_name: Option<String>,
+    /// Extended data items from the event header, such as the user SID or related activity id. Only present when the
+    /// provider was enabled with the matching `EVENT_ENABLE_PROPERTY_*` flags or the logger attached them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extended: Vec<ExtendedData>,
+}
+
+/// Serializes a time as RFC 3339 UTC with 100ns precision
+pub fn serialize_time<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&clock::rfc3339_from_ticks(clock::ticks_from_system_time(
+        *time,
+    )))
+}
+
+/// Serializes a schema hash as 16 hex digits, since JSON numbers lose precision past 53 bits
+pub fn serialize_schema_hash<S: Serializer>(
+    hash: &Option<u64>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match hash {
+        Some(hash) => serializer.collect_str(&format_args!("{hash:016x}")),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Serializes a GUID the same way [`PropertyValue::Guid`] is displayed
+pub fn serialize_guid<S: Serializer>(guid: &GUID, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&PropertyValue::Guid(*guid))
+}
+
+impl ParsedEvent {
+    /// Decodes `record` with [`Tdh::get_event_information`], walking the property array so structs, arrays and
+    /// properties whose count or length come from another property are all decoded
+    pub fn from_record(record: &EVENT_RECORD) -> EtwResult<Self> {
+        Self::from_record_in_trace(record, None)
+    }
+
+    /// Same as [`ParsedEvent::from_record`], but uses the logfile header of the trace the event came from to pick the pointer size
+    /// when the event header does not say. Needed for traces recorded on a machine with a different architecture
+    pub fn from_record_in_trace(
+        record: &EVENT_RECORD,
+        trace_header: Option<&TraceHeaderInfo>,
+    ) -> EtwResult<Self> {
+        Self::_decode(record, trace_header, None)
+    }
+
+    /// Same as [`ParsedEvent::from_record_in_trace`], but takes the schema of the event from `cache` and formats
+    /// properties into its scratch buffer, so repeated events are decoded without any TDH schema calls
+    pub fn from_record_cached(
+        record: &EVENT_RECORD,
+        trace_header: Option<&TraceHeaderInfo>,
+        cache: &SchemaCache,
+    ) -> EtwResult<Self> {
+        Self::_decode(record, trace_header, Some(cache))
+    }
+
+    /// The event with its payload left undecoded, as a `RawData` binary property shown as hex, for events whose schema
+    /// could not be found. The header fields are filled in as usual
+    pub fn raw(record: &EVENT_RECORD, trace_header: Option<&TraceHeaderInfo>) -> Self {
+        let architecture = EventArchitecture {
+            pointer_size: Tdh::pointer_size(record, trace_header.map(|header| header.pointer_size)),
+            trace_pointer_size: trace_header.map(|header| header.pointer_size),
+        };
+        let userdata = if record.UserDataLength == 0 || record.UserData.is_null() {
+            Vec::new()
+        } else {
+            unsafe {
+                slice::from_raw_parts(record.UserData as *const u8, record.UserDataLength as usize)
+            }
+            .to_vec()
+        };
+
+        Self::_with_properties(
+            record,
+            architecture,
+            BTreeMap::from([("RawData".to_string(), PropertyValue::Binary(userdata))]),
+        )
+    }
+
+    fn _decode(
+        record: &EVENT_RECORD,
+        trace_header: Option<&TraceHeaderInfo>,
+        cache: Option<&SchemaCache>,
+    ) -> EtwResult<Self> {
+        let architecture = EventArchitecture {
+            pointer_size: Tdh::pointer_size(record, trace_header.map(|header| header.pointer_size)),
+            trace_pointer_size: trace_header.map(|header| header.pointer_size),
+        };
+
+        // Bookmarks have no schema registered with TDH, so they are decoded by hand
+        if let Some(bookmark) = Bookmark::from_record(record) {
+            return Ok(Self::_with_properties(
+                record,
+                architecture,
+                BTreeMap::from([("Label".to_string(), PropertyValue::String(bookmark.label))]),
+            ));
+        }
+
+        // Nor do the controller's audit events
+        if let Some(audit) = AuditEvent::from_record(record) {
+            let mut properties =
+                BTreeMap::from([("Action".to_string(), PropertyValue::String(audit.action))]);
+            properties.extend(
+                audit
+                    .parameters
+                    .into_iter()
+                    .map(|(name, value)| (name, PropertyValue::String(value))),
+            );
+            return Ok(Self::_with_properties(record, architecture, properties));
+        }
+
+        // Neither do lost event notifications
+        if let Some(lost) = LostEvent::from_record(record) {
+            return Ok(Self::_with_properties(
+                record,
+                architecture,
+                BTreeMap::from([(
+                    "Lost".to_string(),
+                    PropertyValue::String(lost.as_str().to_string()),
+                )]),
+            ));
+        }
+
+        let schema = match cache {
+            Some(cache) => cache.schema(record)?,
+            None => Arc::new(Schema::load(record)?),
+        };
+        let trace = schema.info();
+
+        // [EVENT_PROPERTY_INFO; 1] can be more than one element as given by PropertyCount
+        let property_infos = unsafe {
+            slice::from_raw_parts(
+                trace.EventPropertyInfoArray.as_ptr(),
+                trace.PropertyCount as usize,
+            )
+        };
+
+        let userdata: &[u8] = if record.UserDataLength == 0 || record.UserData.is_null() {
+            &[]
+        } else {
+            unsafe {
+                slice::from_raw_parts(record.UserData as *const u8, record.UserDataLength as usize)
+            }
+        };
+
+        let mut local_scratch = Vec::new();
+        let mut cache_scratch = cache.map(SchemaCache::scratch);
+        let scratch = cache_scratch.as_deref_mut().unwrap_or(&mut local_scratch);
+
+        let mut decoder = Decoder {
+            record,
+            schema: &schema,
+            property_infos,
+            trace_logging: schema.is_trace_logging(),
+            pointer_size: architecture.pointer_size,
+            userdata,
+            scratch,
+        };
+
+        let properties = decoder.decode_range(0, trace.TopLevelPropertyCount as usize)?;
+
+        let mut event = Self::_with_properties(record, architecture, properties);
+        event.schema_hash = Some(schema.hash());
+        event.schema_fields = Some(Arc::clone(schema.fields()));
+        if trace.TaskNameOffset != 0 {
+            event.task_name = Some(decoder.name(trace.TaskNameOffset));
+        }
+        if decoder.trace_logging {
+            // Older versions of TDH only report the event name as the task name
+            let name_offset = match unsafe { trace.Anonymous1.EventNameOffset } {
+                0 => trace.TaskNameOffset,
+                offset => offset,
+            };
+            event.event_name = (name_offset != 0).then(|| decoder.name(name_offset));
+        }
+
+        Ok(event)
+    }
+
+    fn _with_properties(
+        record: &EVENT_RECORD,
+        architecture: EventArchitecture,
+        properties: BTreeMap<String, PropertyValue>,
+    ) -> Self {
+        let extended = ExtendedData::from_record(record);
+
+        Self {
+            provider: record.EventHeader.ProviderId,
+            event_id: record.EventHeader.EventDescriptor.Id,
+            opcode: record.EventHeader.EventDescriptor.Opcode,
+            version: record.EventHeader.EventDescriptor.Version,
+            schema_hash: None,
+            schema_fields: None,
+            process_id: record.EventHeader.ProcessId,
+            thread_id: record.EventHeader.ThreadId,
+            timestamp: record.EventHeader.TimeStamp,
+            time: clock::system_time_from_ticks(record.EventHeader.TimeStamp),
+            sequence: 0,
+            architecture,
+            properties,
+            // Stacks captured with EVENT_ENABLE_PROPERTY_STACK_TRACE come embedded in the event rather than as StackWalk events
+            stack: ExtendedData::stack_trace(&extended)
+                .map(<[u64]>::to_vec)
+                .unwrap_or_default(),
+            task_name: None,
+            event_name: None,
+            extended,
+        }
+    }
+
+    /// Gets a property by name
+    pub fn get(&self, name: &str) -> Option<&PropertyValue> {
+        self.properties.get(name)
+    }
+
+    /// Flattens the properties into their display strings, as used by the typed `From<HashMap<String, String>>` conversions
+    pub fn to_string_map(&self) -> HashMap<String, String> {
+        self.properties
+            .iter()
+            .map(|(name, value)| (name.clone(), value.to_string()))
+            .collect()
+    }
+}
+
+/// Walks the [`EVENT_PROPERTY_INFO`] array of an event, consuming userdata as it goes
+struct Decoder<'a> {
+    record: &'a EVENT_RECORD,
+    schema: &'a Schema,
+    property_infos: &'a [EVENT_PROPERTY_INFO],
+    trace_logging: bool, // The schema came from the event's own TraceLogging metadata
+    pointer_size: u32,
+    userdata: &'a [u8],
+    scratch: &'a mut Vec<u16>, // Reused by every TdhFormatProperty call
+}
+
+impl Decoder<'_> {
+    /// Decodes the properties at `start..end` of the property array. Struct members are a contiguous range of the same array
+    fn decode_range(
+        &mut self,
+        start: usize,
+        end: usize,
+    ) -> EtwResult<BTreeMap<String, PropertyValue>> {
+        let mut properties = BTreeMap::new();
+
+        for index in start..end.min(self.property_infos.len()) {
+            let property_info = &self.property_infos[index];
+            let flags = property_info.Flags.0;
+
+            // The count and length of a property can be stored in an earlier sibling property, or for TraceLogging
+            // events in the property itself
+            let count = if flags & PropertyParamCount.0 != 0 {
+                self.param_value(&properties, index, unsafe {
+                    property_info.Anonymous2.countPropertyIndex
+                })
+            } else {
+                unsafe { property_info.Anonymous2.count as u64 }
+            };
+            let length = if flags & PropertyParamLength.0 != 0 {
+                self.param_value(&properties, index, unsafe {
+                    property_info.Anonymous3.lengthPropertyIndex
+                }) as u16
+            } else {
+                unsafe { property_info.Anonymous3.length }
+            };
+
+            let is_array =
+                count != 1 || flags & (PropertyParamCount.0 | PropertyParamFixedCount.0) != 0;
+
+            let mut values = Vec::with_capacity(count.min(1024) as usize);
+            for _ in 0..count {
+                let value = if flags & PropertyStruct.0 != 0 {
+                    let (struct_start, member_count) = unsafe {
+                        (
+                            property_info.Anonymous1.structType.StructStartIndex as usize,
+                            property_info.Anonymous1.structType.NumOfStructMembers as usize,
+                        )
+                    };
+                    PropertyValue::Struct(
+                        self.decode_range(struct_start, struct_start + member_count)?,
+                    )
+                } else {
+                    self.decode_scalar(property_info, length)?
+                };
+                values.push(value);
+            }
+
+            let value = if is_array {
+                PropertyValue::Array(values)
+            } else {
+                values.pop().unwrap_or(PropertyValue::Binary(Vec::new()))
+            };
+
+            properties.insert(self.name(property_info.NameOffset), value);
+        }
+
+        Ok(properties)
+    }
+
+    /// Formats a single non-struct property, then converts it to a typed value based on its in-type
+    fn decode_scalar(
+        &mut self,
+        property_info: &EVENT_PROPERTY_INFO,
+        length: u16,
+    ) -> EtwResult<PropertyValue> {
+        // Enum and bitmap properties name a value map, so they format to their symbolic names
+        let map_name_offset = unsafe { property_info.Anonymous1.nonStructType.MapNameOffset };
+        let map_buffer = if map_name_offset != 0 {
+            self.schema.map(self.record, map_name_offset)
+        } else {
+            None
+        };
+        let mapinfo = map_buffer
+            .as_ref()
+            .and_then(|buffer| unsafe { (buffer.as_ptr() as *const EVENT_MAP_INFO).as_ref() });
+
+        let consumed_bytes = Tdh::format_property_into(
+            self.schema.info(),
+            mapinfo,
+            self.pointer_size,
+            property_info,
+            length,
+            self.userdata,
+            self.scratch,
+        )?;
+        let formatted = &self.scratch[..];
+
+        let raw = &self.userdata[..consumed_bytes.min(self.userdata.len())];
+        self.userdata = &self.userdata[raw.len()..];
+
+        // Get the property data as all the valid bytes in the property data buffer up until the first nul byte
+        let formatted = String::from_utf16_lossy(
+            &formatted[..formatted
+                .iter()
+                .position(|x| *x == 0)
+                .unwrap_or(formatted.len())],
+        );
+
+        let property_in_type = unsafe { property_info.Anonymous1.nonStructType.InType };
+        let value = match property_in_type {
+            _ if mapinfo.is_some() => PropertyValue::String(formatted),
+            in_type::INT8 | in_type::INT16 | in_type::INT32 | in_type::INT64 => {
+                PropertyValue::Signed(Self::read_signed(raw))
+            }
+            in_type::UINT8
+            | in_type::UINT16
+            | in_type::UINT32
+            | in_type::UINT64
+            | in_type::HEXINT32
+            | in_type::HEXINT64
+            | in_type::POINTER
+            | in_type::SIZET
+            | in_type::FILETIME => PropertyValue::Unsigned(Self::read_unsigned(raw)),
+            in_type::FLOAT if raw.len() == 4 => PropertyValue::Float(f32::from_le_bytes(
+                raw.try_into().expect("length checked"),
+            ) as f64),
+            in_type::DOUBLE if raw.len() == 8 => {
+                PropertyValue::Float(f64::from_le_bytes(raw.try_into().expect("length checked")))
+            }
+            in_type::BOOLEAN => PropertyValue::Boolean(Self::read_unsigned(raw) != 0),
+            in_type::GUID if raw.len() == 16 => {
+                PropertyValue::Guid(GUID::from_u128(u128::from_be_bytes(Self::guid_bytes(raw))))
+            }
+            in_type::SID | in_type::WBEMSID => PropertyValue::Sid(formatted),
+            in_type::BINARY | in_type::HEXDUMP => PropertyValue::Binary(raw.to_vec()),
+            _ => PropertyValue::String(formatted),
+        };
+
+        Ok(value)
+    }
+
+    /// The count or length of the property at `index`, stored in the property at `param_index`. TraceLogging variable
+    /// length arrays and binary properties point at themselves, their count or length is a UINT16 in front of the data
+    fn param_value(
+        &mut self,
+        properties: &BTreeMap<String, PropertyValue>,
+        index: usize,
+        param_index: u16,
+    ) -> u64 {
+        if !self.trace_logging || (param_index as usize) < index {
+            return self.sibling_value(properties, param_index);
+        }
+
+        let Some(prefix) = self.userdata.get(..2) else {
+            return 0;
+        };
+        self.userdata = &self.userdata[2..];
+        u16::from_le_bytes([prefix[0], prefix[1]]) as u64
+    }
+
+    /// The integer value of an already decoded sibling property, used for counts and lengths
+    fn sibling_value(&self, properties: &BTreeMap<String, PropertyValue>, index: u16) -> u64 {
+        self.property_infos
+            .get(index as usize)
+            .and_then(|info| properties.get(&self.name(info.NameOffset)))
+            .and_then(PropertyValue::as_u64)
+            .unwrap_or_default()
+    }
+
+    /// Reads the nul terminated UTF-16 name at `offset` of the event information buffer
+    fn name(&self, offset: u32) -> String {
+        let name: Vec<u16> = self.schema.buffer()[offset as usize..]
+            .chunks(2)
+            .map(|x| u16::from_le_bytes([x[0], x.get(1).copied().unwrap_or_default()]))
+            .take_while(|x| *x != 0)
+            .collect();
+
+        String::from_utf16_lossy(&name)
+    }
+
+    fn read_unsigned(raw: &[u8]) -> u64 {
+        let mut bytes = [0u8; 8];
+        let len = raw.len().min(8);
+        bytes[..len].copy_from_slice(&raw[..len]);
+        u64::from_le_bytes(bytes)
+    }
+
+    /// Sign extends the little endian integer in `raw`
+    fn read_signed(raw: &[u8]) -> i64 {
+        match raw.len() {
+            1 => raw[0] as i8 as i64,
+            2 => i16::from_le_bytes([raw[0], raw[1]]) as i64,
+            4 => i32::from_le_bytes([raw[0], raw[1], raw[2], raw[3]]) as i64,
+            _ => Self::read_unsigned(raw) as i64,
+        }
+    }
+
+    /// GUIDs are stored as a little endian u32, two little endian u16s and 8 bytes. Returns the bytes in big endian order
+    fn guid_bytes(raw: &[u8]) -> [u8; 16] {
+        let mut bytes = [0u8; 16];
+        bytes[..4].copy_from_slice(&[raw[3], raw[2], raw[1], raw[0]]);
+        bytes[4..6].copy_from_slice(&[raw[5], raw[4]]);
+        bytes[6..8].copy_from_slice(&[raw[7], raw[6]]);
+        bytes[8..].copy_from_slice(&raw[8..16]);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::c_void;
+
+    use windows::Win32::System::Diagnostics::Etw::{
+        EVENT_HEADER_FLAG_32_BIT_HEADER, EVENT_HEADER_FLAG_64_BIT_HEADER,
+        EVENT_HEADER_FLAG_CLASSIC_HEADER,
+    };
+
+    use super::*;
+    use crate::etw_constructs::filter::PROCESS_GUID;
+
+    // Userdata of Process_V4 start events (opcode 1) as logged by the kernel of each architecture, for notepad.exe
+    // started by process 1337 as SYSTEM. Pointer sized fields, UniqueProcessKey, DirectoryTableBase and the
+    // TOKEN_USER in front of the SID, are 4 bytes on x86 and 8 on x64 and ARM64
+    const X86_PROCESS_START: &[u8] = &[
+        0x80, 0x70, 0x3f, 0x8a, 0x92, 0x10, 0x00, 0x00, 0x39, 0x05, 0x00, 0x00, 0x01, 0x00, 0x00,
+        0x00, 0x03, 0x01, 0x00, 0x00, 0x00, 0xa0, 0x1a, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe,
+        0xad, 0xde, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x12,
+        0x00, 0x00, 0x00, 0x6e, 0x6f, 0x74, 0x65, 0x70, 0x61, 0x64, 0x2e, 0x65, 0x78, 0x65, 0x00,
+        0x6e, 0x00, 0x6f, 0x00, 0x74, 0x00, 0x65, 0x00, 0x70, 0x00, 0x61, 0x00, 0x64, 0x00, 0x2e,
+        0x00, 0x65, 0x00, 0x78, 0x00, 0x65, 0x00, 0x20, 0x00, 0x74, 0x00, 0x65, 0x00, 0x73, 0x00,
+        0x74, 0x00, 0x2e, 0x00, 0x74, 0x00, 0x78, 0x00, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00,
+    ];
+
+    const X64_PROCESS_START: &[u8] = &[
+        0x80, 0x70, 0x3f, 0x0c, 0x82, 0xa1, 0xff, 0xff, 0x92, 0x10, 0x00, 0x00, 0x39, 0x05, 0x00,
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x00, 0xa0, 0x1a, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05,
+        0x12, 0x00, 0x00, 0x00, 0x6e, 0x6f, 0x74, 0x65, 0x70, 0x61, 0x64, 0x2e, 0x65, 0x78, 0x65,
+        0x00, 0x6e, 0x00, 0x6f, 0x00, 0x74, 0x00, 0x65, 0x00, 0x70, 0x00, 0x61, 0x00, 0x64, 0x00,
+        0x2e, 0x00, 0x65, 0x00, 0x78, 0x00, 0x65, 0x00, 0x20, 0x00, 0x74, 0x00, 0x65, 0x00, 0x73,
+        0x00, 0x74, 0x00, 0x2e, 0x00, 0x74, 0x00, 0x78, 0x00, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+    ];
+
+    const ARM64_PROCESS_START: &[u8] = &[
+        0x80, 0x10, 0x2d, 0x5e, 0x0c, 0x8a, 0xff, 0xff, 0x92, 0x10, 0x00, 0x00, 0x39, 0x05, 0x00,
+        0x00, 0x01, 0x00, 0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x00, 0x60, 0x1d, 0x01, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xef, 0xbe, 0xad, 0xde, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x05,
+        0x12, 0x00, 0x00, 0x00, 0x6e, 0x6f, 0x74, 0x65, 0x70, 0x61, 0x64, 0x2e, 0x65, 0x78, 0x65,
+        0x00, 0x6e, 0x00, 0x6f, 0x00, 0x74, 0x00, 0x65, 0x00, 0x70, 0x00, 0x61, 0x00, 0x64, 0x00,
+        0x2e, 0x00, 0x65, 0x00, 0x78, 0x00, 0x65, 0x00, 0x20, 0x00, 0x74, 0x00, 0x65, 0x00, 0x73,
+        0x00, 0x74, 0x00, 0x2e, 0x00, 0x74, 0x00, 0x78, 0x00, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+    ];
+    fn record(userdata: &[u8], flags: u32) -> EVENT_RECORD {
+        let mut record = EVENT_RECORD::default();
+        record.EventHeader.Flags = (EVENT_HEADER_FLAG_CLASSIC_HEADER | flags) as u16;
+        record.EventHeader.ProviderId = PROCESS_GUID;
+        record.EventHeader.EventDescriptor.Opcode = 1;
+        record.EventHeader.EventDescriptor.Version = 4;
+        record.UserDataLength = userdata.len() as u16;
+        record.UserData = userdata.as_ptr() as *mut c_void;
+        record
+    }
+
+    fn header(pointer_size: u32) -> TraceHeaderInfo {
+        TraceHeaderInfo {
+            pointer_size,
+            ..Default::default()
+        }
+    }
+
+    fn assert_process_start(event: &ParsedEvent, unique_process_key: u64) {
+        let unsigned = |name| event.get(name).and_then(PropertyValue::as_u64);
+        assert_eq!(unsigned("UniqueProcessKey"), Some(unique_process_key));
+        assert_eq!(unsigned("ProcessId"), Some(4242));
+        assert_eq!(unsigned("ParentId"), Some(1337));
+        assert_eq!(unsigned("SessionId"), Some(1));
+        assert_eq!(
+            event.get("ImageFileName").and_then(PropertyValue::as_str),
+            Some("notepad.exe")
+        );
+        assert_eq!(
+            event.get("CommandLine").and_then(PropertyValue::as_str),
+            Some("notepad.exe test.txt")
+        );
+    }
+
+    #[test]
+    fn decodes_x86_trace() {
+        let record = record(X86_PROCESS_START, EVENT_HEADER_FLAG_32_BIT_HEADER);
+        let event = ParsedEvent::from_record_in_trace(&record, Some(&header(4))).unwrap();
+
+        assert_eq!(event.architecture.pointer_size, 4);
+        assert!(!event.architecture.is_wow64());
+        assert_process_start(&event, 0x8a3f7080);
+    }
+
+    #[test]
+    fn decodes_x64_trace() {
+        let record = record(X64_PROCESS_START, EVENT_HEADER_FLAG_64_BIT_HEADER);
+        let event = ParsedEvent::from_record_in_trace(&record, Some(&header(8))).unwrap();
+
+        assert_eq!(event.architecture.pointer_size, 8);
+        assert_process_start(&event, 0xffffa1820c3f7080);
+    }
+
+    #[test]
+    fn decodes_arm64_trace() {
+        let record = record(ARM64_PROCESS_START, EVENT_HEADER_FLAG_64_BIT_HEADER);
+        let event = ParsedEvent::from_record_in_trace(&record, Some(&header(8))).unwrap();
+
+        assert_eq!(event.architecture.pointer_size, 8);
+        assert_process_start(&event, 0xffff8a0c5e2d1080);
+    }
+
+    #[test]
+    fn takes_pointer_size_from_trace_header_when_flags_do_not_say() {
+        let record = record(X86_PROCESS_START, 0);
+        let event = ParsedEvent::from_record_in_trace(&record, Some(&header(4))).unwrap();
+
+        assert_eq!(event.architecture.pointer_size, 4);
+        assert_process_start(&event, 0x8a3f7080);
+    }
+
+    #[test]
+    fn flags_32_bit_events_of_64_bit_traces_as_wow64() {
+        let record = record(X86_PROCESS_START, EVENT_HEADER_FLAG_32_BIT_HEADER);
+        let event = ParsedEvent::raw(&record, Some(&header(8)));
+
+        assert!(event.architecture.is_wow64());
+        assert_eq!(
+            event.get("RawData"),
+            Some(&PropertyValue::Binary(X86_PROCESS_START.to_vec()))
+        );
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+use windows::{core::GUID, Win32::System::Diagnostics::Etw::EVENT_RECORD};
+
+use super::{
+    clock,
+    error::EtwError,
+    parsed_event::{EventArchitecture, ParsedEvent, PropertyValue},
+};
+
+/// Provider GUID of the events the pipeline emits into its own stream when it fails to decode or write an event, so
+/// an unattended capture records its own problems
+pub const PIPELINE_ERROR_GUID: GUID = GUID::from_u128(0x4f1d8c27_93a6_4b5e_b0c2_6e7a3f9d1b84);
+
+/// Where in the pipeline something failed. Also the event id of the error event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PipelineStage {
+    /// The event could not be decoded, so it never reached the stream
+    Decode = 1,
+    /// The event was decoded but could not be written out
+    Sink = 2,
+    /// The event could not be written to a raw capture
+    Capture = 3,
+    /// The filter file could not be applied, so the outputs kept their filters. No event was lost
+    FilterFile = 4,
+}
+
+impl PipelineStage {
+    pub const ALL: [PipelineStage; 4] = [
+        PipelineStage::Decode,
+        PipelineStage::Sink,
+        PipelineStage::Capture,
+        PipelineStage::FilterFile,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PipelineStage::Decode => "Decode",
+            PipelineStage::Sink => "Sink",
+            PipelineStage::Capture => "Capture",
+            PipelineStage::FilterFile => "FilterFile",
+        }
+    }
+}
+
+/// An event the pipeline failed to handle, or a filter file it could not apply
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineError {
+    pub stage: PipelineStage,
+    pub error: EtwError,
+    /// Provider, id and opcode of the event that was lost. Zero for [`PipelineStage::FilterFile`]
+    pub provider: GUID,
+    pub event_id: u16,
+    pub opcode: u8,
+    pub process_id: u32,
+    pub thread_id: u32,
+    pub timestamp: i64,
+    pub sequence: u64,
+}
+
+impl PipelineError {
+    /// `record` could not be decoded
+    pub fn decode(record: &EVENT_RECORD, sequence: u64, error: EtwError) -> Self {
+        Self::_for_record(PipelineStage::Decode, record, sequence, error)
+    }
+
+    /// `record` could not be written to a raw capture
+    pub fn capture(record: &EVENT_RECORD, error: EtwError) -> Self {
+        Self::_for_record(PipelineStage::Capture, record, 0, error)
+    }
+
+    /// The filter file could not be applied, stamped with the time it was read
+    pub fn filter_file(error: EtwError) -> Self {
+        Self {
+            stage: PipelineStage::FilterFile,
+            error,
+            provider: GUID::zeroed(),
+            event_id: 0,
+            opcode: 0,
+            process_id: std::process::id(),
+            thread_id: 0,
+            timestamp: clock::ticks_from_system_time(SystemTime::now()),
+            sequence: 0,
+        }
+    }
+
+    /// `event` could not be written out
+    pub fn sink(event: &ParsedEvent, error: EtwError) -> Self {
+        Self {
+            stage: PipelineStage::Sink,
+            error,
+            provider: event.provider,
+            event_id: event.event_id,
+            opcode: event.opcode,
+            process_id: event.process_id,
+            thread_id: event.thread_id,
+            timestamp: event.timestamp,
+            sequence: event.sequence,
+        }
+    }
+
+    fn _for_record(
+        stage: PipelineStage,
+        record: &EVENT_RECORD,
+        sequence: u64,
+        error: EtwError,
+    ) -> Self {
+        let header = &record.EventHeader;
+        Self {
+            stage,
+            error,
+            provider: header.ProviderId,
+            event_id: header.EventDescriptor.Id,
+            opcode: header.EventDescriptor.Opcode,
+            process_id: header.ProcessId,
+            thread_id: header.ThreadId,
+            timestamp: header.TimeStamp,
+            sequence,
+        }
+    }
+
+    /// The error as a [`PIPELINE_ERROR_GUID`] event, stamped with the timestamp and sequence number of the event that
+    /// was lost so it lands where that event would have
+    pub fn to_event(&self) -> ParsedEvent {
+        let properties = BTreeMap::from([
+            (
+                "Stage".to_string(),
+                PropertyValue::String(self.stage.as_str().to_string()),
+            ),
+            (
+                "Error".to_string(),
+                PropertyValue::String(self.error.to_string()),
+            ),
+            (
+                "Status".to_string(),
+                PropertyValue::Unsigned(self.error.status().0 as u64),
+            ),
+            ("Provider".to_string(), PropertyValue::Guid(self.provider)),
+            (
+                "EventId".to_string(),
+                PropertyValue::Unsigned(self.event_id as u64),
+            ),
+            (
+                "Opcode".to_string(),
+                PropertyValue::Unsigned(self.opcode as u64),
+            ),
+        ]);
+
+        ParsedEvent {
+            provider: PIPELINE_ERROR_GUID,
+            event_id: self.stage as u16,
+            opcode: 0,
+            version: 0,
+            schema_hash: None,
+            schema_fields: None,
+            process_id: self.process_id,
+            thread_id: self.thread_id,
+            timestamp: self.timestamp,
+            time: clock::system_time_from_ticks(self.timestamp),
+            sequence: self.sequence,
+            architecture: EventArchitecture::default(),
+            properties,
+            stack: Vec::new(),
+            task_name: None,
+            event_name: None,
+            extended: Vec::new(),
+        }
+    }
+}
+
+/// How many events were lost at each stage of the pipeline. Shared between the consumer and whatever writes the
+/// stream out, so it can be read while the session is running
+#[derive(Debug, Default)]
+pub struct PipelineErrorCounters {
+    decode: AtomicU64,
+    sink: AtomicU64,
+    capture: AtomicU64,
+    filter_file: AtomicU64,
+    undecodable: Mutex<HashMap<u128, u64>>, // Keyed on provider
+    last_error: Mutex<Option<PipelineError>>,
+}
+
+impl PipelineErrorCounters {
+    pub fn record(&self, stage: PipelineStage) {
+        self._counter(stage).fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts `error` at its stage, and keeps it as the last error for the summary at the end of a capture
+    pub fn record_error(&self, error: &PipelineError) {
+        self.record(error.stage);
+        *self
+            .last_error
+            .lock()
+            .expect("Last pipeline error lock was poisoned") = Some(error.clone());
+    }
+
+    /// The last error given to [`PipelineErrorCounters::record_error`]
+    pub fn last_error(&self) -> Option<PipelineError> {
+        self.last_error
+            .lock()
+            .expect("Last pipeline error lock was poisoned")
+            .clone()
+    }
+
+    pub fn count(&self, stage: PipelineStage) -> u64 {
+        self._counter(stage).load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> u64 {
+        PipelineStage::ALL
+            .iter()
+            .map(|stage| self.count(*stage))
+            .sum()
+    }
+
+    /// Counts an event of `provider` that had no schema on this machine, and was written undecoded
+    pub fn record_undecodable(&self, provider: GUID) {
+        *self
+            .undecodable
+            .lock()
+            .expect("Undecodable counter lock was poisoned")
+            .entry(provider.to_u128())
+            .or_default() += 1;
+    }
+
+    /// How many events of each provider could not be decoded, most first
+    pub fn undecodable(&self) -> Vec<(GUID, u64)> {
+        let mut counts: Vec<(GUID, u64)> = self
+            .undecodable
+            .lock()
+            .expect("Undecodable counter lock was poisoned")
+            .iter()
+            .map(|(provider, count)| (GUID::from_u128(*provider), *count))
+            .collect();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.to_u128().cmp(&b.0.to_u128())));
+        counts
+    }
+
+    fn _counter(&self, stage: PipelineStage) -> &AtomicU64 {
+        match stage {
+            PipelineStage::Decode => &self.decode,
+            PipelineStage::Sink => &self.sink,
+            PipelineStage::Capture => &self.capture,
+            PipelineStage::FilterFile => &self.filter_file,
+        }
+    }
+}
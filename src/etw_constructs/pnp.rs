@@ -0,0 +1,120 @@
+use windows::{core::GUID, Win32::System::Diagnostics::Etw::TRACE_LEVEL_INFORMATION};
+
+use super::{controller::ProviderConfig, parsed_event::ParsedEvent, schemas::Fields};
+
+/// Microsoft-Windows-Kernel-PnP. Logs devices being configured, started and deleted
+pub const KERNEL_PNP_GUID: GUID = GUID::from_u128(0x9c205a39_1250_487d_abd7_e831c6290539);
+
+/// Microsoft-Windows-USB-USBPORT. Logs USB 2.0 host controller and device activity
+pub const USB_PORT_GUID: GUID = GUID::from_u128(0xc88a4ef5_d048_4013_9408_e04b7db2814a);
+
+/// Microsoft-Windows-USB-UCX. Logs USB 3.0 host controller extension activity
+pub const USB_UCX_GUID: GUID = GUID::from_u128(0x36da592d_e43a_4e28_af6f_4bc57c5a11e8);
+
+// Events of the Configuration channel
+const EVENT_DEVICE_CONFIGURED: u16 = 400;
+const EVENT_DEVICE_STARTED: u16 = 410;
+const EVENT_DEVICE_DELETED: u16 = 420;
+
+/// Enables every Kernel-PnP event at information level
+pub fn provider() -> ProviderConfig {
+    ProviderConfig {
+        guid: KERNEL_PNP_GUID,
+        level: TRACE_LEVEL_INFORMATION as u8,
+        match_any_keyword: 0,
+    }
+}
+
+/// Enables the USBPORT and UCX providers at information level. Their events have no stable schema across
+/// Windows versions, so they are only decoded as [`ParsedEvent`]s, arrivals and removals come from [`provider`]
+pub fn usb_providers() -> [ProviderConfig; 2] {
+    [USB_PORT_GUID, USB_UCX_GUID].map(|guid| ProviderConfig {
+        guid,
+        level: TRACE_LEVEL_INFORMATION as u8,
+        match_any_keyword: 0,
+    })
+}
+
+/// The driver a device was configured with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DriverInstall {
+    pub driver_name: String,
+    pub driver_version: String,
+    pub driver_provider: String,
+    pub matching_device_id: String,
+    /// Whether the driver ships with Windows
+    pub inbox: bool,
+}
+
+/// What happened to a device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceActivity {
+    /// A driver was installed or updated for the device
+    DriverInstalled(DriverInstall),
+    /// The device was started, which happens every time it is plugged in
+    Arrived {
+        service_name: String,
+        problem: u32,
+    },
+    Removed,
+}
+
+/// A Kernel-PnP event about one device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceEvent {
+    /// e.g. `USBSTOR\Disk&Ven_SanDisk&Prod_Cruzer\4C530001`
+    pub device_instance_id: String,
+    pub activity: DeviceActivity,
+    /// The NTSTATUS of the operation, 0 on success
+    pub status: u32,
+    pub timestamp: i64,
+}
+
+impl DeviceEvent {
+    /// Returns the event if it is a device configured, started or deleted event
+    pub fn from_event(event: &ParsedEvent) -> Option<Self> {
+        let fields = Fields::new(event, KERNEL_PNP_GUID).ok()?;
+
+        let activity = match event.event_id {
+            EVENT_DEVICE_CONFIGURED => DeviceActivity::DriverInstalled(DriverInstall {
+                driver_name: fields.string("DriverName"),
+                driver_version: fields.string("DriverVersion"),
+                driver_provider: fields.string("DriverProvider"),
+                matching_device_id: fields.string("MatchingDeviceId"),
+                inbox: fields.optional("DriverInbox") != 0,
+            }),
+            EVENT_DEVICE_STARTED => DeviceActivity::Arrived {
+                service_name: fields.string("ServiceName"),
+                problem: fields.optional("Problem") as u32,
+            },
+            EVENT_DEVICE_DELETED => DeviceActivity::Removed,
+            _ => return None,
+        };
+
+        Some(Self {
+            device_instance_id: fields.string("DeviceInstanceId"),
+            activity,
+            status: fields.optional("Status") as u32,
+            timestamp: event.timestamp,
+        })
+    }
+
+    /// Whether the device hangs off a USB bus
+    pub fn is_usb(&self) -> bool {
+        self._enumerator()
+            .is_some_and(|enumerator| enumerator.starts_with("USB"))
+    }
+
+    /// Whether the device is removable storage, such as a USB stick or SD card
+    pub fn is_removable_media(&self) -> bool {
+        self._enumerator()
+            .is_some_and(|enumerator| enumerator == "USBSTOR" || enumerator == "SD")
+    }
+
+    /// The bus enumerator the instance id starts with, upper cased
+    fn _enumerator(&self) -> Option<String> {
+        self.device_instance_id
+            .split_once('\\')
+            .map(|(enumerator, _)| enumerator.to_ascii_uppercase())
+    }
+}
@@ -0,0 +1,66 @@
+use windows::{core::GUID, Win32::System::Diagnostics::Etw::TRACE_LEVEL_INFORMATION};
+
+use super::{controller::ProviderConfig, parsed_event::ParsedEvent};
+
+/// Microsoft-Windows-PrintService. Logs print jobs as the spooler handles them
+pub const PRINT_SERVICE_GUID: GUID = GUID::from_u128(0x747ef6fd_e535_4d16_b510_42c90f6873a1);
+
+/// Event logged to the Operational channel once a document has been printed
+pub const EVENT_DOCUMENT_PRINTED: u16 = 307;
+
+/// Enables every PrintService event at information level
+pub fn provider() -> ProviderConfig {
+    ProviderConfig {
+        guid: PRINT_SERVICE_GUID,
+        level: TRACE_LEVEL_INFORMATION as u8,
+        match_any_keyword: 0,
+    }
+}
+
+/// A printed document, from event 307. `process_id` is the spooler that logged the event,
+/// the document's owner is `user` on the machine `client`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrintJob {
+    pub job_id: u32,
+    pub document: String,
+    pub user: String,
+    pub client: String,
+    pub printer: String,
+    pub port: String,
+    pub size_bytes: u64,
+    pub pages: u32,
+    pub process_id: u32,
+    pub thread_id: u32,
+    pub timestamp: i64,
+}
+
+impl PrintJob {
+    /// Returns the job if `event` is a PrintService document printed event
+    pub fn from_event(event: &ParsedEvent) -> Option<Self> {
+        if event.provider != PRINT_SERVICE_GUID || event.event_id != EVENT_DOCUMENT_PRINTED {
+            return None;
+        }
+
+        // The event's fields are named Param1 to Param8, and are all logged as strings
+        let param = |index: u32| {
+            event
+                .get(&format!("Param{index}"))
+                .map(ToString::to_string)
+                .unwrap_or_default()
+        };
+
+        Some(Self {
+            job_id: param(1).parse().unwrap_or_default(),
+            document: param(2),
+            user: param(3),
+            client: param(4),
+            printer: param(5),
+            port: param(6),
+            size_bytes: param(7).parse().unwrap_or_default(),
+            pages: param(8).parse().unwrap_or_default(),
+            process_id: event.process_id,
+            thread_id: event.thread_id,
+            timestamp: event.timestamp,
+        })
+    }
+}
@@ -0,0 +1,344 @@
+use std::fmt;
+
+use windows::core::GUID;
+
+use super::{
+    parsed_event::ParsedEvent,
+    process_tracker::{ProcessInfo, ProcessTracker},
+    schemas::Fields,
+};
+
+/// Microsoft-Windows-Security-Auditing. Only the EventLog-Security session receives its events, so they reach this tool
+/// through the Security event log, see [`SecurityLog`](super::security_log::SecurityLog), or a recorded trace of that
+/// session. The Sensitive Privilege Use and Token Right Adjusted audit subcategories have to be turned on
+pub const SECURITY_AUDITING_GUID: GUID = GUID::from_u128(0x54849625_5478_4994_a5ba_3e3b0328c30d);
+
+// Security-Auditing events of the Security channel
+const EVENT_SPECIAL_LOGON: u16 = 4672;
+const EVENT_PRIVILEGED_SERVICE_CALLED: u16 = 4673;
+const EVENT_PRIVILEGED_OBJECT_OPERATION: u16 = 4674;
+const EVENT_PRIMARY_TOKEN_ASSIGNED: u16 = 4696;
+const EVENT_TOKEN_RIGHT_ADJUSTED: u16 = 4703;
+
+/// LocalSystem, LocalService and NetworkService
+const SYSTEM_SIDS: [&str; 3] = ["S-1-5-18", "S-1-5-19", "S-1-5-20"];
+
+/// Images an interactive attacker runs commands through, lowercase
+const SHELLS: [&str; 5] = [
+    "cmd.exe",
+    "powershell.exe",
+    "pwsh.exe",
+    "wscript.exe",
+    "cscript.exe",
+];
+
+/// What a privilege event records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrivilegeActivity {
+    /// A logon was given sensitive privileges such as SeDebugPrivilege
+    SpecialLogon,
+    /// A privilege was exercised to call a privileged service
+    ServiceCalled,
+    /// A privilege was exercised to open or change a protected object
+    ObjectOperation,
+    /// A process started another process with a primary token other than its own
+    PrimaryTokenAssigned,
+    /// Privileges of a token were enabled or disabled
+    TokenRightAdjusted,
+}
+
+/// A Security-Auditing privilege use or token manipulation event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrivilegeEvent {
+    pub activity: PrivilegeActivity,
+    /// Account that used or adjusted the privileges, e.g. `S-1-5-21-...-1001`
+    pub subject_sid: String,
+    /// `DOMAIN\user`
+    pub subject_user: String,
+    pub subject_logon_id: u64,
+    /// Account the token belongs to for [`PrivilegeActivity::PrimaryTokenAssigned`] and
+    /// [`PrivilegeActivity::TokenRightAdjusted`], otherwise the subject
+    pub target_sid: String,
+    /// Process that used or adjusted the privileges. 0 for [`PrivilegeActivity::SpecialLogon`]
+    pub process_id: u32,
+    /// Full image path as logged
+    pub process_name: String,
+    /// Process started with the assigned token, for [`PrivilegeActivity::PrimaryTokenAssigned`]
+    pub new_process_id: Option<u32>,
+    pub new_process_name: Option<String>,
+    /// Privileges used, given or enabled, e.g. SeDebugPrivilege
+    pub privileges: Vec<String>,
+    /// Privileges disabled, for [`PrivilegeActivity::TokenRightAdjusted`]
+    pub disabled_privileges: Vec<String>,
+    /// Object or service the privilege was used on, e.g. a registry key
+    pub object_name: String,
+    pub timestamp: i64,
+}
+
+impl PrivilegeEvent {
+    /// Returns the event if it is a special logon, privileged service call, privileged object operation, primary token
+    /// assignment or token right adjustment
+    pub fn from_event(event: &ParsedEvent) -> Option<Self> {
+        let fields = Fields::new(event, SECURITY_AUDITING_GUID).ok()?;
+        let activity = match event.event_id {
+            EVENT_SPECIAL_LOGON => PrivilegeActivity::SpecialLogon,
+            EVENT_PRIVILEGED_SERVICE_CALLED => PrivilegeActivity::ServiceCalled,
+            EVENT_PRIVILEGED_OBJECT_OPERATION => PrivilegeActivity::ObjectOperation,
+            EVENT_PRIMARY_TOKEN_ASSIGNED => PrivilegeActivity::PrimaryTokenAssigned,
+            EVENT_TOKEN_RIGHT_ADJUSTED => PrivilegeActivity::TokenRightAdjusted,
+            _ => return None,
+        };
+
+        // Privilege lists are logged as one string, separated by newlines and tabs. "-" stands for none
+        let privilege_list = |name: &str| -> Vec<String> {
+            fields
+                .string(name)
+                .split_whitespace()
+                .filter(|privilege| *privilege != "-")
+                .map(str::to_string)
+                .collect()
+        };
+        let user = |domain: String, user: String| {
+            if domain.is_empty() {
+                user
+            } else {
+                format!("{domain}\\{user}")
+            }
+        };
+
+        let subject_sid = fields.string("SubjectUserSid");
+        let (target_sid, privileges) = match activity {
+            PrivilegeActivity::TokenRightAdjusted => (
+                fields.string("TargetUserSid"),
+                privilege_list("EnabledPrivilegeList"),
+            ),
+            PrivilegeActivity::PrimaryTokenAssigned => (fields.string("TargetUserSid"), Vec::new()),
+            _ => (subject_sid.clone(), privilege_list("PrivilegeList")),
+        };
+
+        Some(Self {
+            activity,
+            subject_user: user(
+                fields.string("SubjectDomainName"),
+                fields.string("SubjectUserName"),
+            ),
+            subject_sid,
+            subject_logon_id: fields.optional("SubjectLogonId"),
+            target_sid,
+            process_id: fields.optional("ProcessId") as u32,
+            process_name: fields.string("ProcessName"),
+            new_process_id: fields.unsigned("NewProcessId").map(|id| id as u32),
+            new_process_name: fields.optional_string("NewProcessName"),
+            privileges,
+            disabled_privileges: privilege_list("DisabledPrivilegeList"),
+            object_name: match activity {
+                PrivilegeActivity::ServiceCalled => fields.string("Service"),
+                _ => fields.string("ObjectName"),
+            },
+            timestamp: event.timestamp,
+        })
+    }
+
+    pub fn has_privilege(&self, privilege: &str) -> bool {
+        self.privileges
+            .iter()
+            .any(|p| p.eq_ignore_ascii_case(privilege))
+    }
+}
+
+/// A privilege escalation pattern of the built-in rule pack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrivilegeRule {
+    /// SeDebugPrivilege enabled by a process started from a shell, as credential dumpers do before opening lsass
+    DebugFromShell,
+    /// A service account assigning a token to a shell, as the potato exploits do with SeImpersonatePrivilege
+    ServiceTokenToShell,
+    /// SeTcbPrivilege or SeCreateTokenPrivilege used or enabled by an account other than the system accounts
+    TcbOutsideSystem,
+    /// SeLoadDriverPrivilege enabled by a process started from a shell, to load a vulnerable driver
+    DriverLoadFromShell,
+    /// SeBackupPrivilege used on the SAM or SECURITY hive, to copy password hashes
+    HiveBackup,
+}
+
+impl PrivilegeRule {
+    pub const ALL: [PrivilegeRule; 5] = [
+        PrivilegeRule::DebugFromShell,
+        PrivilegeRule::ServiceTokenToShell,
+        PrivilegeRule::TcbOutsideSystem,
+        PrivilegeRule::DriverLoadFromShell,
+        PrivilegeRule::HiveBackup,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PrivilegeRule::DebugFromShell => "DebugFromShell",
+            PrivilegeRule::ServiceTokenToShell => "ServiceTokenToShell",
+            PrivilegeRule::TcbOutsideSystem => "TcbOutsideSystem",
+            PrivilegeRule::DriverLoadFromShell => "DriverLoadFromShell",
+            PrivilegeRule::HiveBackup => "HiveBackup",
+        }
+    }
+
+    /// The rule with the [`PrivilegeRule::name`] `name`
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|rule| rule.name() == name)
+    }
+
+    /// Whether `event` matches the rule. `ancestors` are those of the process that logged it, parent first
+    pub fn matches(&self, event: &PrivilegeEvent, ancestors: &[ProcessInfo]) -> bool {
+        // The shell itself counts, e.g. an elevated PowerShell enabling SeDebugPrivilege
+        let from_shell = PrivilegeMonitor::_is_shell(&event.process_name)
+            || ancestors
+                .iter()
+                .any(|ancestor| PrivilegeMonitor::_is_shell(&ancestor.image_file_name));
+        let enabled = event.activity == PrivilegeActivity::TokenRightAdjusted;
+
+        match self {
+            PrivilegeRule::DebugFromShell => {
+                enabled && from_shell && event.has_privilege("SeDebugPrivilege")
+            }
+            PrivilegeRule::ServiceTokenToShell => {
+                event.activity == PrivilegeActivity::PrimaryTokenAssigned
+                    // IIS application pools and other virtual service accounts are S-1-5-82
+                    && (SYSTEM_SIDS[1..].contains(&event.subject_sid.as_str())
+                        || event.subject_sid.starts_with("S-1-5-82-"))
+                    && event
+                        .new_process_name
+                        .as_deref()
+                        .is_some_and(PrivilegeMonitor::_is_shell)
+            }
+            PrivilegeRule::TcbOutsideSystem => {
+                matches!(
+                    event.activity,
+                    PrivilegeActivity::TokenRightAdjusted
+                        | PrivilegeActivity::ServiceCalled
+                        | PrivilegeActivity::ObjectOperation
+                ) && !SYSTEM_SIDS.contains(&event.subject_sid.as_str())
+                    && (event.has_privilege("SeTcbPrivilege")
+                        || event.has_privilege("SeCreateTokenPrivilege"))
+            }
+            PrivilegeRule::DriverLoadFromShell => {
+                enabled && from_shell && event.has_privilege("SeLoadDriverPrivilege")
+            }
+            PrivilegeRule::HiveBackup => {
+                let object = event.object_name.to_lowercase();
+                event.activity == PrivilegeActivity::ObjectOperation
+                    && event.has_privilege("SeBackupPrivilege")
+                    && (object.ends_with(r"\machine\sam") || object.ends_with(r"\machine\security"))
+            }
+        }
+    }
+}
+
+/// A privilege event that matched a rule, with what the process cache knew about its process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrivilegeFinding {
+    pub rule: PrivilegeRule,
+    pub event: PrivilegeEvent,
+    /// None if the process was not seen in Process events
+    pub process: Option<ProcessInfo>,
+    /// Image names of the process's ancestors, parent first
+    pub ancestry: Vec<String>,
+}
+
+/// Lists the rule, who matched it and through which process chain
+impl fmt::Display for PrivilegeFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} ({}) by {} pid {}",
+            self.rule.name(),
+            self.event.privileges.join(", "),
+            self.event.subject_user,
+            self.event.process_name,
+            self.event.process_id
+        )?;
+        if let Some(new_process) = &self.event.new_process_name {
+            write!(f, " starting {new_process}")?;
+        }
+        if !self.event.object_name.is_empty() {
+            write!(f, " on {}", self.event.object_name)?;
+        }
+        if !self.ancestry.is_empty() {
+            write!(f, ", from {}", self.ancestry.join(" < "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks Security-Auditing privilege events against [`PrivilegeRule::ALL`], or the rules given with
+/// [`PrivilegeMonitor::set_rules`], joined against a [`ProcessTracker`] to know which processes the privileges were
+/// used from. Needs the Process kernel flag for rules that look at ancestry.
+///
+/// Events are fed in with [`PrivilegeMonitor::add`] in the order they were logged, after the tracker has seen them
+#[derive(Debug)]
+pub struct PrivilegeMonitor {
+    rules: Vec<PrivilegeRule>,
+    findings: Vec<PrivilegeFinding>,
+}
+
+impl Default for PrivilegeMonitor {
+    fn default() -> Self {
+        Self {
+            rules: PrivilegeRule::ALL.to_vec(),
+            findings: Vec::new(),
+        }
+    }
+}
+
+impl PrivilegeMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only checks `rules` from the next event on. Findings already raised are kept
+    pub fn set_rules(&mut self, rules: Vec<PrivilegeRule>) {
+        self.rules = rules;
+    }
+
+    /// Takes in one event and returns the findings it raised. Anything other than a privilege event is ignored
+    pub fn add(&mut self, event: &ParsedEvent, processes: &ProcessTracker) -> &[PrivilegeFinding] {
+        let Some(privilege_event) = PrivilegeEvent::from_event(event) else {
+            return &[];
+        };
+
+        let process = processes.get(privilege_event.process_id);
+        let ancestors = processes.ancestors(privilege_event.process_id);
+        let first = self.findings.len();
+        for &rule in &self.rules {
+            if rule.matches(&privilege_event, &ancestors) {
+                self.findings.push(PrivilegeFinding {
+                    rule,
+                    event: privilege_event.clone(),
+                    process: process.clone(),
+                    ancestry: ancestors
+                        .iter()
+                        .map(|ancestor| ancestor.image_file_name.clone())
+                        .collect(),
+                });
+            }
+        }
+        &self.findings[first..]
+    }
+
+    pub fn findings(&self) -> &[PrivilegeFinding] {
+        &self.findings
+    }
+
+    /// Compares the file name of `image`, which may be a full path
+    fn _is_shell(image: &str) -> bool {
+        let name = image.rsplit('\\').next().unwrap_or(image).to_lowercase();
+        SHELLS.contains(&name.as_str())
+    }
+}
+
+/// One finding per line
+impl fmt::Display for PrivilegeMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for finding in &self.findings {
+            writeln!(f, "    {finding}")?;
+        }
+        Ok(())
+    }
+}
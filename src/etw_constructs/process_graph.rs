@@ -0,0 +1,195 @@
+use std::{collections::BTreeMap, fmt::Write, fs, path::Path};
+
+use super::{
+    clock,
+    error::{EtwError, EtwResult},
+    process_tracker::{ProcessInfo, ProcessTracker},
+};
+
+/// The text a [`ProcessGraph`] is rendered as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// Graphviz, for `dot -Tsvg`
+    Dot,
+    /// For Markdown write-ups, which render a `mermaid` code block as a diagram
+    Mermaid,
+}
+
+impl GraphFormat {
+    /// The format files with the extension of `path` are written in: .dot or .gv for Graphviz, .mmd or .mermaid for
+    /// Mermaid
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "dot" | "gv" => Some(GraphFormat::Dot),
+            "mmd" | "mermaid" => Some(GraphFormat::Mermaid),
+            _ => None,
+        }
+    }
+}
+
+/// The spawn graph of a capture: every process a [`ProcessTracker`] saw, including the ones that exited and the ones
+/// whose id was reused, with an edge from each parent to the processes it started. Each process is labelled with its
+/// start time and exit code, and processes that matched a rule are highlighted with the rules they matched.
+///
+/// Nodes are keyed on the unique process key, or on the process id and start time for processes seen without one, so
+/// two processes that had the same id are two nodes
+#[derive(Debug, Clone, Default)]
+pub struct ProcessGraph {
+    processes: Vec<ProcessInfo>,
+    /// Child and parent, as indexes into `processes`
+    edges: Vec<(usize, usize)>,
+    /// Rules matched, keyed on the index into `processes`
+    annotations: BTreeMap<usize, Vec<String>>,
+}
+
+impl ProcessGraph {
+    pub fn new(tracker: &ProcessTracker) -> Self {
+        let processes = tracker.history();
+        // A parent id can have been held by several processes, the parent is the last one started before the child
+        let edges = processes
+            .iter()
+            .enumerate()
+            .filter_map(|(child, process)| {
+                processes
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, parent)| parent.is_parent_of(process))
+                    .max_by_key(|(_, parent)| parent.start_time)
+                    .map(|(parent, _)| (child, parent))
+            })
+            .collect();
+
+        Self {
+            processes,
+            edges,
+            annotations: BTreeMap::new(),
+        }
+    }
+
+    /// Marks the process that had `process_id` at `timestamp` as having matched `rule`. A rule is only listed once per
+    /// process, and processes that are not in the graph are ignored
+    pub fn annotate(&mut self, process_id: u32, timestamp: i64, rule: &str) {
+        let Some(index) = self
+            .processes
+            .iter()
+            .enumerate()
+            .filter(|(_, process)| {
+                process.process_id == process_id && process.was_running_at(timestamp)
+            })
+            .max_by_key(|(_, process)| process.start_time)
+            .map(|(index, _)| index)
+        else {
+            return;
+        };
+        let rules = self.annotations.entry(index).or_default();
+        if !rules.iter().any(|existing| existing == rule) {
+            rules.push(rule.to_string());
+        }
+    }
+
+    pub fn render(&self, format: GraphFormat) -> String {
+        match format {
+            GraphFormat::Dot => self._dot(),
+            GraphFormat::Mermaid => self._mermaid(),
+        }
+    }
+
+    pub fn write(&self, path: &Path, format: GraphFormat) -> EtwResult<()> {
+        fs::write(path, self.render(format))
+            .map_err(|err| EtwError::from_io(&err, format!("Could not write {:?}", path)))
+    }
+
+    fn _dot(&self) -> String {
+        let mut dot = String::from("digraph processes {\n    rankdir=LR;\n    node [shape=box];\n");
+        for (index, process) in self.processes.iter().enumerate() {
+            // Backslashes and quotes are escapes in DOT strings
+            let label: Vec<String> = self
+                ._label(index)
+                .iter()
+                .map(|line| line.replace('\\', "\\\\").replace('"', "\\\""))
+                .collect();
+            let _ = write!(
+                dot,
+                "    {} [label=\"{}\"",
+                Self::_node_id(process),
+                label.join("\\n")
+            );
+            if self.annotations.contains_key(&index) {
+                dot += ", color=red, penwidth=2";
+            }
+            dot += "];\n";
+        }
+        for (child, parent) in &self.edges {
+            let _ = writeln!(
+                dot,
+                "    {} -> {};",
+                Self::_node_id(&self.processes[*parent]),
+                Self::_node_id(&self.processes[*child])
+            );
+        }
+        dot += "}\n";
+        dot
+    }
+
+    fn _mermaid(&self) -> String {
+        let mut mermaid = String::from("flowchart LR\n");
+        for (index, process) in self.processes.iter().enumerate() {
+            let label = self._label(index).join("<br/>");
+            let _ = writeln!(
+                mermaid,
+                "    {}[\"{}\"]",
+                Self::_node_id(process),
+                label.replace('"', "#quot;")
+            );
+        }
+        for (child, parent) in &self.edges {
+            let _ = writeln!(
+                mermaid,
+                "    {} --> {}",
+                Self::_node_id(&self.processes[*parent]),
+                Self::_node_id(&self.processes[*child])
+            );
+        }
+        if !self.annotations.is_empty() {
+            mermaid += "    classDef finding stroke:#d00,stroke-width:2px\n";
+            for index in self.annotations.keys() {
+                let _ = writeln!(
+                    mermaid,
+                    "    class {} finding",
+                    Self::_node_id(&self.processes[*index])
+                );
+            }
+        }
+        mermaid
+    }
+
+    /// The unique process key where there is one. Processes only seen in a snapshot have neither a key nor a start
+    /// time, but there is only one snapshot, so their id is enough
+    fn _node_id(process: &ProcessInfo) -> String {
+        match (process.unique_process_key, process.start_time) {
+            (0, Some(start_time)) => format!("p{}_{start_time}", process.process_id),
+            (0, None) => format!("p{}", process.process_id),
+            (key, _) => format!("k{key:x}"),
+        }
+    }
+
+    /// Lines of the label of the process at `index`, unescaped
+    fn _label(&self, index: usize) -> Vec<String> {
+        let process = &self.processes[index];
+        let mut label = vec![format!(
+            "{} {}",
+            process.process_id, process.image_file_name
+        )];
+        label.push(match process.start_time {
+            Some(start_time) => format!("started {}", clock::rfc3339_from_ticks(start_time)),
+            None => "running before the capture".to_string(),
+        });
+        if let Some(exit_status) = process.exit_status {
+            label.push(format!("exited {exit_status:#x}"));
+        }
+        if let Some(rules) = self.annotations.get(&index) {
+            label.push(rules.join(", "));
+        }
+        label
+    }
+}
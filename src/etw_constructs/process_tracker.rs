@@ -0,0 +1,365 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, mem,
+    sync::{Mutex, MutexGuard},
+};
+
+use windows::Win32::{
+    Foundation::{CloseHandle, WIN32_ERROR},
+    System::Diagnostics::ToolHelp::{
+        CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W,
+        TH32CS_SNAPPROCESS,
+    },
+};
+
+use super::{
+    error::{EtwError, EtwResult},
+    parsed_event::{ParsedEvent, PropertyValue},
+    schemas::{ProcessEvent, ProcessOpcode},
+    taxonomy::{Action, CanonicalEvent},
+};
+
+/// What is known about a process from its Process events
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ProcessInfo {
+    pub process_id: u32,
+    pub parent_id: u32,
+    pub session_id: u32,
+    pub image_file_name: String,
+    /// Empty for processes only seen in a snapshot
+    pub command_line: String,
+    /// Timestamp of the Start event. None if the process was already running when the session started
+    pub start_time: Option<i64>,
+    /// Timestamp of the End event. None while the process is running
+    pub end_time: Option<i64>,
+    pub exit_status: Option<i32>,
+    /// Unique for as long as the machine is up, unlike the process id. 0 for processes only seen in a snapshot
+    pub unique_process_key: u64,
+}
+
+impl ProcessInfo {
+    pub fn is_running(&self) -> bool {
+        self.end_time.is_none()
+    }
+
+    /// Whether this process is really the parent of `child`. A process that started after `child`, or exited before
+    /// it, is another process that had the id of the real parent
+    pub fn is_parent_of(&self, child: &ProcessInfo) -> bool {
+        if self.process_id != child.parent_id || self.process_id == child.process_id {
+            return false;
+        }
+        let exited_before = match (self.end_time, child.start_time) {
+            (Some(end), Some(child_start)) => end < child_start,
+            _ => false,
+        };
+        let started_after = match (self.start_time, child.start_time) {
+            (Some(start), Some(child_start)) => start > child_start,
+            (Some(_), None) => true,
+            _ => false,
+        };
+        !exited_before && !started_after
+    }
+
+    /// Whether this process was running at `timestamp`. Processes seen without a Start event were running since
+    /// before the capture
+    pub fn was_running_at(&self, timestamp: i64) -> bool {
+        self.start_time.map_or(true, |start| start <= timestamp)
+            && self.end_time.map_or(true, |end| end >= timestamp)
+    }
+}
+
+/// Keeps a live map of every process from kernel Process events, so other events can be attributed to the full process
+/// rather than just an id. Processes already running when the session starts are learned from their DCStart rundown
+/// events, which the kernel logs when the session is started with the Process flag.
+///
+/// Events are fed in with [`ProcessTracker::add`] in the order they were logged. Exited processes are looked up by id
+/// until their id is reused, and are listed by [`ProcessTracker::history`] after that
+#[derive(Debug, Default)]
+pub struct ProcessTracker {
+    processes: Mutex<HashMap<u32, ProcessInfo>>, // Keyed on process id
+    replaced: Mutex<Vec<ProcessInfo>>,           // Processes whose id was reused by a later process
+}
+
+impl ProcessTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Seeds the tracker with the processes running right now, for sessions that were attached to and so never log a
+    /// rundown. Only the id, parent and image name are known for them
+    pub fn from_snapshot() -> EtwResult<Self> {
+        let snapshot_error = |err: windows::core::Error| EtwError::Win32 {
+            status: WIN32_ERROR::from_error(&err).unwrap_or_default(),
+            context: "Could not snapshot the running processes".to_string(),
+        };
+
+        let snapshot =
+            unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) }.map_err(snapshot_error)?;
+
+        let mut processes = HashMap::new();
+        let mut entry = PROCESSENTRY32W {
+            dwSize: mem::size_of::<PROCESSENTRY32W>() as u32,
+            ..Default::default()
+        };
+        let mut next = unsafe { Process32FirstW(snapshot, &mut entry) };
+        while next.is_ok() {
+            let name_len = entry
+                .szExeFile
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(entry.szExeFile.len());
+
+            processes.insert(
+                entry.th32ProcessID,
+                ProcessInfo {
+                    process_id: entry.th32ProcessID,
+                    parent_id: entry.th32ParentProcessID,
+                    image_file_name: String::from_utf16_lossy(&entry.szExeFile[..name_len]),
+                    ..Default::default()
+                },
+            );
+            next = unsafe { Process32NextW(snapshot, &mut entry) };
+        }
+        let _ = unsafe { CloseHandle(snapshot) };
+
+        Ok(Self {
+            processes: Mutex::new(processes),
+            replaced: Mutex::default(),
+        })
+    }
+
+    /// Takes in one event. Anything other than a kernel Process event is ignored
+    pub fn add(&self, event: &ParsedEvent) {
+        let Ok(process) = ProcessEvent::try_from(event) else {
+            return;
+        };
+
+        let mut processes = self._processes();
+        match process.opcode {
+            // Ids are reused, but a reused id gets a new start event which replaces the entry
+            ProcessOpcode::Start | ProcessOpcode::DcStart => {
+                let replaced = processes.insert(
+                    process.process_id,
+                    ProcessInfo {
+                        process_id: process.process_id,
+                        parent_id: process.parent_id,
+                        session_id: process.session_id,
+                        image_file_name: process.image_file_name,
+                        command_line: process.command_line,
+                        start_time: (process.opcode == ProcessOpcode::Start)
+                            .then_some(event.timestamp),
+                        end_time: None,
+                        exit_status: None,
+                        unique_process_key: process.unique_process_key,
+                    },
+                );
+                if let Some(replaced) = replaced {
+                    self.replaced
+                        .lock()
+                        .expect("Process tracker lock was poisoned")
+                        .push(replaced);
+                }
+            }
+            ProcessOpcode::End => {
+                let info = processes
+                    .entry(process.process_id)
+                    .or_insert_with(|| ProcessInfo {
+                        process_id: process.process_id,
+                        parent_id: process.parent_id,
+                        session_id: process.session_id,
+                        image_file_name: process.image_file_name,
+                        command_line: process.command_line,
+                        unique_process_key: process.unique_process_key,
+                        ..Default::default()
+                    });
+                info.end_time = Some(event.timestamp);
+                info.exit_status = Some(process.exit_status);
+            }
+            // DCEnd is the rundown logged when the session stops, the process is still running
+            ProcessOpcode::DcEnd => {}
+        }
+    }
+
+    /// The process with id `process_id`, or the last one that had it if it exited
+    pub fn get(&self, process_id: u32) -> Option<ProcessInfo> {
+        self._processes().get(&process_id).cloned()
+    }
+
+    /// Every process seen, ordered by id
+    pub fn processes(&self) -> Vec<ProcessInfo> {
+        let mut processes: Vec<ProcessInfo> = self._processes().values().cloned().collect();
+        processes.sort_by_key(|process| process.process_id);
+        processes
+    }
+
+    /// Every process seen, including the ones whose id was reused, ordered by start time and then id. Processes that
+    /// were running before the capture come first
+    pub fn history(&self) -> Vec<ProcessInfo> {
+        let mut history = self.processes();
+        history.extend(
+            self.replaced
+                .lock()
+                .expect("Process tracker lock was poisoned")
+                .iter()
+                .cloned(),
+        );
+        history.sort_by_key(|process| (process.start_time, process.process_id));
+        history
+    }
+
+    /// The process that started `process_id`, if it was seen and its id was not reused since
+    pub fn parent(&self, process_id: u32) -> Option<ProcessInfo> {
+        let processes = self._processes();
+        let child = processes.get(&process_id)?;
+        processes
+            .get(&child.parent_id)
+            .filter(|parent| parent.is_parent_of(child))
+            .cloned()
+    }
+
+    /// The process that logged `event`
+    pub fn process_of(&self, event: &ParsedEvent) -> Option<ProcessInfo> {
+        self.get(event.process_id)
+    }
+
+    /// Every process started by `process_id`, ordered by id
+    pub fn children(&self, process_id: u32) -> Vec<ProcessInfo> {
+        let processes = self._processes();
+        let Some(parent) = processes.get(&process_id) else {
+            return Vec::new();
+        };
+
+        let mut children: Vec<ProcessInfo> = processes
+            .values()
+            .filter(|child| parent.is_parent_of(child))
+            .cloned()
+            .collect();
+        children.sort_by_key(|child| child.process_id);
+        children
+    }
+
+    /// The parent of `process_id`, its parent and so on up to the first process whose parent is unknown
+    pub fn ancestors(&self, process_id: u32) -> Vec<ProcessInfo> {
+        let processes = self._processes();
+        let mut ancestors: Vec<ProcessInfo> = Vec::new();
+
+        let mut current = processes.get(&process_id);
+        while let Some(child) = current {
+            current = processes
+                .get(&child.parent_id)
+                .filter(|parent| parent.is_parent_of(child))
+                // Snapshot entries have no start time, so reused ids can form a cycle
+                .filter(|parent| ancestors.iter().all(|a| a.process_id != parent.process_id));
+            if let Some(parent) = current {
+                ancestors.push(parent.clone());
+            }
+        }
+        ancestors
+    }
+
+    /// Adds an `Ancestry` property to `event` if it is a process start from any source: an array of up to `levels`
+    /// ancestors, parent first, each a struct of its `ProcessId`, `ImageFileName` and `CommandLine`. The kernel Process
+    /// event of the start has to have been added first. Returns whether anything was attached
+    pub fn attach_ancestry(&self, event: &mut ParsedEvent, levels: usize) -> bool {
+        let process_id = match CanonicalEvent::from_event(event) {
+            Some(canonical) if canonical.action == Action::ProcessStart => canonical.process_id,
+            _ => return false,
+        };
+
+        let ancestry: Vec<PropertyValue> = self
+            .ancestors(process_id)
+            .into_iter()
+            .take(levels)
+            .map(|ancestor| {
+                PropertyValue::Struct(BTreeMap::from([
+                    (
+                        "ProcessId".to_string(),
+                        PropertyValue::Unsigned(ancestor.process_id as u64),
+                    ),
+                    (
+                        "ImageFileName".to_string(),
+                        PropertyValue::String(ancestor.image_file_name),
+                    ),
+                    (
+                        "CommandLine".to_string(),
+                        PropertyValue::String(ancestor.command_line),
+                    ),
+                ]))
+            })
+            .collect();
+        if ancestry.is_empty() {
+            return false;
+        }
+
+        event
+            .properties
+            .insert("Ancestry".to_string(), PropertyValue::Array(ancestry));
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self._processes().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self._processes().is_empty()
+    }
+
+    fn _write_tree(
+        f: &mut fmt::Formatter<'_>,
+        processes: &HashMap<u32, ProcessInfo>,
+        process: &ProcessInfo,
+        depth: usize,
+    ) -> fmt::Result {
+        write!(
+            f,
+            "{:indent$}{} {}",
+            "",
+            process.process_id,
+            process.image_file_name,
+            indent = depth * 2
+        )?;
+        if let Some(exit_status) = process.exit_status {
+            write!(f, " (exited {exit_status:#x})")?;
+        }
+        writeln!(f)?;
+
+        let mut children: Vec<&ProcessInfo> = processes
+            .values()
+            .filter(|child| process.is_parent_of(child))
+            .collect();
+        children.sort_by_key(|child| child.process_id);
+        for child in children {
+            Self::_write_tree(f, processes, child, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    fn _processes(&self) -> MutexGuard<'_, HashMap<u32, ProcessInfo>> {
+        self.processes
+            .lock()
+            .expect("Process tracker lock was poisoned")
+    }
+}
+
+/// Dumps every process as a tree, children indented under their parent
+impl fmt::Display for ProcessTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let processes = self._processes();
+
+        let mut roots: Vec<&ProcessInfo> = processes
+            .values()
+            .filter(|process| {
+                !processes
+                    .get(&process.parent_id)
+                    .is_some_and(|parent| parent.is_parent_of(process))
+            })
+            .collect();
+        roots.sort_by_key(|process| process.process_id);
+
+        for root in roots {
+            Self::_write_tree(f, &processes, root, 0)?;
+        }
+        Ok(())
+    }
+}
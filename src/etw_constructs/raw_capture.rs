@@ -0,0 +1,290 @@
+use core::slice;
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    mem,
+    path::Path,
+    ptr,
+};
+
+use windows::Win32::System::Diagnostics::Etw::{
+    ETW_BUFFER_CONTEXT, EVENT_HEADER, EVENT_HEADER_EXTENDED_DATA_ITEM, EVENT_RECORD,
+};
+
+use super::{
+    error::{EtwError, EtwResult},
+    mapped_file::{MappedFile, MappedFileConfig},
+};
+
+/// Written at the start of every raw capture file so the wrong kind of file is rejected when decoding
+const MAGIC: &[u8; 8] = b"ETWRAW02";
+
+/// The magic of captures written before events kept their extended data, which are still read
+const MAGIC_V1: &[u8; 8] = b"ETWRAW01";
+
+/// An event copied out of ETW with no decoding done. Holds everything TDH needs to decode the event later, including
+/// extended data such as the TraceLogging schema, stacks and the SID
+pub struct RawEvent {
+    pub header: EVENT_HEADER,
+    pub buffer_context: ETW_BUFFER_CONTEXT,
+    pub userdata: Vec<u8>,
+    extended: Vec<EVENT_HEADER_EXTENDED_DATA_ITEM>, // DataPtr of each points into the matching `extended_data` entry
+    extended_data: Vec<Vec<u8>>,
+}
+
+impl RawEvent {
+    /// Copies the header, userdata and extended data out of `record`. This is all the work done at capture time
+    pub fn from_record(record: &EVENT_RECORD) -> Self {
+        let copy = |ptr: *const u8, len: usize| {
+            if ptr.is_null() {
+                Vec::new()
+            } else {
+                unsafe { slice::from_raw_parts(ptr, len) }.to_vec()
+            }
+        };
+
+        let items: &[EVENT_HEADER_EXTENDED_DATA_ITEM] = if record.ExtendedData.is_null()
+            || record.ExtendedDataCount == 0
+        {
+            &[]
+        } else {
+            unsafe { slice::from_raw_parts(record.ExtendedData, record.ExtendedDataCount as usize) }
+        };
+
+        Self::_with_extended(
+            record.EventHeader,
+            record.BufferContext,
+            copy(record.UserData as *const u8, record.UserDataLength as usize),
+            items
+                .iter()
+                .map(|item| {
+                    (
+                        item.ExtType,
+                        copy(item.DataPtr as *const u8, item.DataSize as usize),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    /// The extended data items of the event, as their type and data
+    pub fn extended_data(&self) -> impl Iterator<Item = (u16, &[u8])> {
+        self.extended
+            .iter()
+            .zip(&self.extended_data)
+            .map(|(item, data)| (item.ExtType, data.as_slice()))
+    }
+
+    /// Builds an [`EVENT_RECORD`] pointing into this event, which can be passed to TDH or an event callback.
+    /// The record borrows `self`, so it must not outlive it
+    pub fn as_record(&self) -> EVENT_RECORD {
+        EVENT_RECORD {
+            EventHeader: self.header,
+            BufferContext: self.buffer_context,
+            ExtendedDataCount: self.extended.len() as u16,
+            UserDataLength: self.userdata.len() as u16,
+            ExtendedData: self.extended.as_ptr() as *mut _,
+            UserData: self.userdata.as_ptr() as *mut _,
+            ..Default::default()
+        }
+    }
+
+    /// The data of each item lives on the heap, so the pointers stay valid when the event is moved
+    fn _with_extended(
+        header: EVENT_HEADER,
+        buffer_context: ETW_BUFFER_CONTEXT,
+        userdata: Vec<u8>,
+        extended: Vec<(u16, Vec<u8>)>,
+    ) -> Self {
+        let (types, extended_data): (Vec<u16>, Vec<Vec<u8>>) = extended.into_iter().unzip();
+        let extended = types
+            .into_iter()
+            .zip(&extended_data)
+            .map(|(ext_type, data)| EVENT_HEADER_EXTENDED_DATA_ITEM {
+                ExtType: ext_type,
+                DataSize: data.len() as u16,
+                DataPtr: data.as_ptr() as u64,
+                ..Default::default()
+            })
+            .collect();
+
+        Self {
+            header,
+            buffer_context,
+            userdata,
+            extended,
+            extended_data,
+        }
+    }
+
+    /// Writes the event as [EVENT_HEADER][ETW_BUFFER_CONTEXT][u16 userdata length][userdata][u16 item count], then
+    /// [u16 type][u16 length][data] for each extended data item
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(Self::as_bytes(&self.header))?;
+        writer.write_all(Self::as_bytes(&self.buffer_context))?;
+        writer.write_all(&(self.userdata.len() as u16).to_le_bytes())?;
+        writer.write_all(&self.userdata)?;
+        writer.write_all(&(self.extended.len() as u16).to_le_bytes())?;
+        for (ext_type, data) in self.extended_data() {
+            writer.write_all(&ext_type.to_le_bytes())?;
+            writer.write_all(&(data.len() as u16).to_le_bytes())?;
+            writer.write_all(data)?;
+        }
+        Ok(())
+    }
+
+    /// Reads an event written by [`RawEvent::write_to`], without the extended data if the capture predates it.
+    /// Returns None at the end of the file
+    fn read_from(reader: &mut impl Read, has_extended: bool) -> io::Result<Option<Self>> {
+        let mut header_bytes = vec![0u8; mem::size_of::<EVENT_HEADER>()];
+        match reader.read_exact(&mut header_bytes) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        // A mapped capture that was never trimmed ends in zeros, and no event header has a size of 0
+        if header_bytes[..2] == [0, 0] {
+            return Ok(None);
+        }
+
+        let mut buffer_context_bytes = vec![0u8; mem::size_of::<ETW_BUFFER_CONTEXT>()];
+        reader.read_exact(&mut buffer_context_bytes)?;
+
+        let userdata = Self::_read_sized(reader)?;
+
+        let item_count = if has_extended {
+            Self::_read_u16(reader)?
+        } else {
+            0
+        };
+        let extended = (0..item_count)
+            .map(|_| Ok((Self::_read_u16(reader)?, Self::_read_sized(reader)?)))
+            .collect::<io::Result<Vec<(u16, Vec<u8>)>>>()?;
+
+        Ok(Some(Self::_with_extended(
+            unsafe { ptr::read_unaligned(header_bytes.as_ptr() as *const EVENT_HEADER) },
+            unsafe {
+                ptr::read_unaligned(buffer_context_bytes.as_ptr() as *const ETW_BUFFER_CONTEXT)
+            },
+            userdata,
+            extended,
+        )))
+    }
+
+    fn _read_u16(reader: &mut impl Read) -> io::Result<u16> {
+        let mut bytes = [0u8; 2];
+        reader.read_exact(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    /// Reads a u16 length, then that many bytes
+    fn _read_sized(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+        let mut data = vec![0u8; Self::_read_u16(reader)? as usize];
+        reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn as_bytes<T>(value: &T) -> &[u8] {
+        unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+    }
+}
+
+/// Copies the extended data, pointing the items of the copy at its own data
+impl Clone for RawEvent {
+    fn clone(&self) -> Self {
+        Self::_with_extended(
+            self.header,
+            self.buffer_context,
+            self.userdata.clone(),
+            self.extended_data()
+                .map(|(ext_type, data)| (ext_type, data.to_vec()))
+                .collect(),
+        )
+    }
+}
+
+/// Writes raw events to a compact capture file with minimal work per event. Decode the file later with [`RawReader`]
+pub struct RawWriter {
+    writer: Box<dyn Write + Send>,
+}
+
+impl RawWriter {
+    /// Creates (or truncates) the capture file at `path`
+    pub fn create(path: &Path) -> EtwResult<Self> {
+        let writer = File::create(path)
+            .map(BufWriter::new)
+            .map_err(|err| EtwError::from_io(&err, format!("Could not create {:?}", path)))?;
+        Self::_with_header(Box::new(writer))
+    }
+
+    /// Same as [`RawWriter::create`], but writes through a [`MappedFile`]. The file is only trimmed to the events in
+    /// it once the writer is dropped or [`MappedFile::close_all`] is called. [`RawReader`] stops at the zero padding
+    /// of a file that was not
+    pub fn create_mapped(path: &Path, config: MappedFileConfig) -> EtwResult<Self> {
+        Self::_with_header(Box::new(MappedFile::create(path, config)?))
+    }
+
+    fn _with_header(mut writer: Box<dyn Write + Send>) -> EtwResult<Self> {
+        writer
+            .write_all(MAGIC)
+            .map_err(|err| EtwError::from_io(&err, "Could not write the capture header"))?;
+
+        Ok(Self { writer })
+    }
+
+    /// Appends `record` to the capture file
+    pub fn write(&mut self, record: &EVENT_RECORD) -> EtwResult<()> {
+        RawEvent::from_record(record)
+            .write_to(&mut self.writer)
+            .map_err(|err| EtwError::from_io(&err, "Could not write the raw event"))
+    }
+
+    /// Flushes buffered events to disk
+    pub fn flush(&mut self) -> EtwResult<()> {
+        self.writer
+            .flush()
+            .map_err(|err| EtwError::from_io(&err, "Could not flush the capture file"))
+    }
+}
+
+/// Reads the events of a capture file written by [`RawWriter`]
+pub struct RawReader {
+    reader: BufReader<File>,
+    has_extended: bool, // False for captures written before events kept their extended data
+}
+
+impl RawReader {
+    /// Opens the capture file at `path`, checking it starts with the raw capture header
+    pub fn open(path: &Path) -> EtwResult<Self> {
+        let mut reader = File::open(path)
+            .map(BufReader::new)
+            .map_err(|err| EtwError::from_io(&err, format!("Could not open {:?}", path)))?;
+
+        let mut magic = [0u8; MAGIC.len()];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|err| EtwError::from_io(&err, "Could not read the capture header"))?;
+        if &magic != MAGIC && &magic != MAGIC_V1 {
+            return Err(EtwError::from_io(
+                &io::Error::from(io::ErrorKind::InvalidData),
+                format!("{:?} is not a raw capture file", path),
+            ));
+        }
+
+        Ok(Self {
+            reader,
+            has_extended: &magic == MAGIC,
+        })
+    }
+}
+
+impl Iterator for RawReader {
+    type Item = EtwResult<RawEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        RawEvent::read_from(&mut self.reader, self.has_extended)
+            .map_err(|err| EtwError::from_io(&err, "Could not read the raw event"))
+            .transpose()
+    }
+}
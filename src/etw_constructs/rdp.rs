@@ -0,0 +1,121 @@
+use windows::{core::GUID, Win32::System::Diagnostics::Etw::TRACE_LEVEL_INFORMATION};
+
+use super::{controller::ProviderConfig, parsed_event::ParsedEvent, schemas::Fields};
+
+/// Microsoft-Windows-TerminalServices-LocalSessionManager. Logs logons, logoffs, disconnects and reconnects of sessions
+pub const LOCAL_SESSION_MANAGER_GUID: GUID =
+    GUID::from_u128(0x5d896912_022d_40aa_a3a8_4fa5515c76d7);
+
+/// Microsoft-Windows-TerminalServices-RemoteConnectionManager. Logs remote users passing network authentication
+pub const REMOTE_CONNECTION_MANAGER_GUID: GUID =
+    GUID::from_u128(0xc76baa63_ae81_421c_b425_340b4b24157f);
+
+/// Microsoft-Windows-RemoteDesktopServices-RdpCoreTS. Logs the TCP connections RDP clients make
+pub const RDP_CORE_GUID: GUID = GUID::from_u128(0x1139c61b_b549_4251_8ed3_27250a1edec8);
+
+// LocalSessionManager events of the Operational channel
+const EVENT_SESSION_LOGON: u16 = 21;
+const EVENT_SESSION_LOGOFF: u16 = 23;
+const EVENT_SESSION_DISCONNECTED: u16 = 24;
+const EVENT_SESSION_RECONNECTED: u16 = 25;
+
+// RemoteConnectionManager events of the Operational channel
+const EVENT_AUTHENTICATION_SUCCEEDED: u16 = 1149;
+
+// RdpCoreTS events of the Operational channel
+const EVENT_CONNECTION_ACCEPTED: u16 = 131;
+
+/// Enables every session, authentication and connection event at information level
+pub fn providers() -> [ProviderConfig; 3] {
+    [
+        LOCAL_SESSION_MANAGER_GUID,
+        REMOTE_CONNECTION_MANAGER_GUID,
+        RDP_CORE_GUID,
+    ]
+    .map(|guid| ProviderConfig {
+        guid,
+        level: TRACE_LEVEL_INFORMATION as u8,
+        match_any_keyword: 0,
+    })
+}
+
+/// What happened to a remote session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionActivity {
+    /// A client opened a TCP connection to the RDP listener. Logged before the user is known
+    Connected,
+    /// The user passed network level authentication
+    Authenticated,
+    Logon,
+    Disconnected,
+    Reconnected,
+    Logoff,
+}
+
+/// A remote desktop event about one session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RdpSessionEvent {
+    pub activity: SessionActivity,
+    /// `DOMAIN\user`, empty for [`SessionActivity::Connected`]
+    pub user: String,
+    /// None for [`SessionActivity::Connected`] and [`SessionActivity::Authenticated`], which come before a session exists
+    pub session_id: Option<u32>,
+    /// Where the client connected from. `LOCAL` for console sessions, may include a port
+    pub client_address: String,
+    pub timestamp: i64,
+}
+
+impl RdpSessionEvent {
+    /// Returns the event if it is a session logon, logoff, disconnect, reconnect, authentication or connection event
+    pub fn from_event(event: &ParsedEvent) -> Option<Self> {
+        let fields = Fields::of(event);
+
+        let (activity, user, session_id, client_address) = match (event.provider, event.event_id) {
+            (LOCAL_SESSION_MANAGER_GUID, id) => {
+                let activity = match id {
+                    EVENT_SESSION_LOGON => SessionActivity::Logon,
+                    EVENT_SESSION_LOGOFF => SessionActivity::Logoff,
+                    EVENT_SESSION_DISCONNECTED => SessionActivity::Disconnected,
+                    EVENT_SESSION_RECONNECTED => SessionActivity::Reconnected,
+                    _ => return None,
+                };
+                (
+                    activity,
+                    fields.string("User"),
+                    fields.unsigned("SessionID"),
+                    fields.string("Address"),
+                )
+            }
+            // The user, domain and address are logged as generic parameters
+            (REMOTE_CONNECTION_MANAGER_GUID, EVENT_AUTHENTICATION_SUCCEEDED) => (
+                SessionActivity::Authenticated,
+                match (fields.string("Param2"), fields.string("Param1")) {
+                    (domain, user) if domain.is_empty() => user,
+                    (domain, user) => format!("{domain}\\{user}"),
+                },
+                None,
+                fields.string("Param3"),
+            ),
+            (RDP_CORE_GUID, EVENT_CONNECTION_ACCEPTED) => (
+                SessionActivity::Connected,
+                String::new(),
+                None,
+                fields.string("ClientIP"),
+            ),
+            _ => return None,
+        };
+
+        Some(Self {
+            activity,
+            user,
+            session_id: session_id.map(|id| id as u32),
+            client_address,
+            timestamp: event.timestamp,
+        })
+    }
+
+    /// Whether the session came from another machine rather than the console
+    pub fn is_remote(&self) -> bool {
+        !self.client_address.is_empty() && self.client_address != "LOCAL"
+    }
+}
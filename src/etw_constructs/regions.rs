@@ -0,0 +1,428 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use windows::core::GUID;
+
+use super::{
+    clock,
+    clr::{DOTNET_RUNTIME_GUID, EVENT_GC_RESTART_EE_END, EVENT_GC_SUSPEND_EE_BEGIN},
+    error::{EtwError, EtwResult},
+    parsed_event::{ParsedEvent, PropertyValue},
+    privilege::PrivilegeFinding,
+};
+
+/// DiskIo kernel events, https://learn.microsoft.com/en-us/windows/win32/etw/diskio
+pub const DISK_IO_GUID: GUID = GUID::from_u128(0x3d6fa8d4_fe05_11d0_9dda_00c04fd7ba7c);
+
+/// Root every region of this tool is listed under in WPA
+const REGION_ROOT_GUID: GUID = GUID::from_u128(0x9e4b7a21_6c3f_4d8e_a15b_0f2c8d6e3a71);
+
+/// Rule match regions get this GUID with the hash of their rule's name mixed into its last 8 bytes
+const RULE_REGION_GUID: GUID = GUID::from_u128(0x4d7e2a95_1f06_4b18_8c63_5a9e0b7d2f44);
+
+/// DiskIo opcodes: Read and Write complete the ReadInit and WriteInit logged with the disk-io-init kernel flag
+const DISK_IO_READ: u8 = 10;
+const DISK_IO_WRITE: u8 = 11;
+const DISK_IO_READ_INIT: u8 = 12;
+const DISK_IO_WRITE_INIT: u8 = 13;
+
+/// An event that starts or stops a region. Classic kernel events are told apart by opcode, manifest events by id and
+/// version, which WPA only matches exactly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionEvent {
+    pub provider: GUID,
+    pub id: Option<(u16, u8)>,
+    pub opcode: Option<u8>,
+}
+
+impl RegionEvent {
+    pub fn id(provider: GUID, id: u16, version: u8) -> Self {
+        Self {
+            provider,
+            id: Some((id, version)),
+            opcode: None,
+        }
+    }
+
+    pub fn opcode(provider: GUID, opcode: u8) -> Self {
+        Self {
+            provider,
+            id: None,
+            opcode: Some(opcode),
+        }
+    }
+
+    /// The region event `event` is, by id and version for manifest events
+    fn of(event: &ParsedEvent) -> Self {
+        Self::id(event.provider, event.event_id, event.version)
+    }
+}
+
+/// What an [`Anomaly`] is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AnomalyKind {
+    /// A GC pause longer than [`AnomalyThresholds::gc_pause`]
+    GcPause,
+    /// A disk I/O that took longer than [`AnomalyThresholds::disk_io`]
+    DiskIo,
+    /// An event that matched the privilege rule of this name
+    RuleMatch(&'static str),
+}
+
+/// Something the analyzers flagged in a trace, from when it started to when it ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Anomaly {
+    pub kind: AnomalyKind,
+    pub process_id: u32,
+    /// FILETIME ticks. The same for a rule match, which is a single event
+    pub start: i64,
+    pub end: i64,
+    pub description: String,
+}
+
+/// How long a GC pause or disk I/O has to last to be an anomaly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnomalyThresholds {
+    pub gc_pause: Duration,
+    pub disk_io: Duration,
+}
+
+impl Default for AnomalyThresholds {
+    fn default() -> Self {
+        Self {
+            gc_pause: Duration::from_millis(50),
+            disk_io: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Collects the anomalies a [`RegionsFile`] is written for: GC pauses and disk I/Os over their thresholds, and
+/// privilege rule matches. Events are fed in with [`AnomalyDetector::add`] in the order they were logged, and the
+/// findings of a [`PrivilegeMonitor`](super::privilege::PrivilegeMonitor) with [`AnomalyDetector::add_findings`]
+#[derive(Debug, Default)]
+pub struct AnomalyDetector {
+    thresholds: AnomalyThresholds,
+    suspended: HashMap<u32, (i64, RegionEvent)>, // Keyed on process id
+    disk_ios: HashMap<u64, i64>,                 // Keyed on IRP, holds when the I/O was started
+    anomalies: Vec<Anomaly>,
+    /// The start and stop events of each kind of anomaly, as they were seen in the trace
+    events: BTreeMap<AnomalyKind, (RegionEvent, RegionEvent)>,
+}
+
+impl AnomalyDetector {
+    pub fn new(thresholds: AnomalyThresholds) -> Self {
+        Self {
+            thresholds,
+            ..Default::default()
+        }
+    }
+
+    /// Takes in one event. Anything other than a GC suspension or disk I/O event is ignored
+    pub fn add(&mut self, event: &ParsedEvent) {
+        if event.provider == DOTNET_RUNTIME_GUID {
+            match event.event_id {
+                EVENT_GC_SUSPEND_EE_BEGIN => {
+                    self.suspended
+                        .insert(event.process_id, (event.timestamp, RegionEvent::of(event)));
+                }
+                EVENT_GC_RESTART_EE_END => {
+                    let Some((suspended_at, start)) = self.suspended.remove(&event.process_id)
+                    else {
+                        return;
+                    };
+                    let pause = Self::_duration(event.timestamp - suspended_at);
+                    if pause >= self.thresholds.gc_pause {
+                        self.events
+                            .insert(AnomalyKind::GcPause, (start, RegionEvent::of(event)));
+                        self.anomalies.push(Anomaly {
+                            kind: AnomalyKind::GcPause,
+                            process_id: event.process_id,
+                            start: suspended_at,
+                            end: event.timestamp,
+                            description: format!("GC pause of {pause:?}"),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        } else if event.provider == DISK_IO_GUID {
+            let Some(irp) = event.get("Irp").and_then(PropertyValue::as_u64) else {
+                return;
+            };
+            match event.opcode {
+                DISK_IO_READ_INIT | DISK_IO_WRITE_INIT => {
+                    self.disk_ios.insert(irp, event.timestamp);
+                }
+                DISK_IO_READ | DISK_IO_WRITE => {
+                    let Some(started_at) = self.disk_ios.remove(&irp) else {
+                        return;
+                    };
+                    let latency = Self::_duration(event.timestamp - started_at);
+                    if latency >= self.thresholds.disk_io {
+                        self.events.insert(
+                            AnomalyKind::DiskIo,
+                            (
+                                RegionEvent::opcode(DISK_IO_GUID, event.opcode + 2),
+                                RegionEvent::opcode(DISK_IO_GUID, event.opcode),
+                            ),
+                        );
+                        self.anomalies.push(Anomaly {
+                            kind: AnomalyKind::DiskIo,
+                            process_id: event.process_id,
+                            start: started_at,
+                            end: event.timestamp,
+                            description: format!(
+                                "{} of {} bytes taking {latency:?}",
+                                if event.opcode == DISK_IO_WRITE {
+                                    "Write"
+                                } else {
+                                    "Read"
+                                },
+                                event
+                                    .get("TransferSize")
+                                    .and_then(PropertyValue::as_u64)
+                                    .unwrap_or_default()
+                            ),
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Takes in the findings `event` raised
+    pub fn add_findings(&mut self, event: &ParsedEvent, findings: &[PrivilegeFinding]) {
+        for finding in findings {
+            let kind = AnomalyKind::RuleMatch(finding.rule.name());
+            self.events
+                .insert(kind, (RegionEvent::of(event), RegionEvent::of(event)));
+            self.anomalies.push(Anomaly {
+                kind,
+                process_id: finding.event.process_id,
+                start: event.timestamp,
+                end: event.timestamp,
+                description: finding.to_string(),
+            });
+        }
+    }
+
+    /// Every anomaly found, in the order they ended
+    pub fn anomalies(&self) -> &[Anomaly] {
+        &self.anomalies
+    }
+
+    /// Event timestamps are in 100ns ticks
+    fn _duration(ticks: i64) -> Duration {
+        Duration::from_nanos(ticks.max(0) as u64 * 100)
+    }
+}
+
+/// An interval WPA highlights, from each start event to the stop event that matches it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionDefinition {
+    pub guid: GUID,
+    pub name: String,
+    pub friendly_name: String,
+    /// Any of these starts the region
+    pub start: Vec<RegionEvent>,
+    pub stop: Vec<RegionEvent>,
+    /// Only pair start and stop events of the same process
+    pub same_process: bool,
+    /// Only pair start and stop events whose payloads have the same value in these fields, as start field and stop
+    /// field, e.g. the IRP of an I/O
+    pub match_fields: Vec<(String, String)>,
+}
+
+/// A WPA regions of interest file for the anomalies this tool's analysis found in a trace: long GC pauses and disk
+/// I/Os, and privilege rule matches. Loading it in WPA (Trace > Trace Properties, or `wpa -region`) next to the .etl
+/// shows them as regions.
+///
+/// WPA only pairs start and stop events, so a region covers every interval of the same kind of events, not only the
+/// ones over the threshold. Sort them by duration to find those, or look them up in the comments the file lists each
+/// anomaly in. Only kinds of anomaly that were found get a region
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RegionsFile {
+    pub regions: Vec<RegionDefinition>,
+    /// Listed as comments, with their times
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl RegionsFile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A region for each kind of anomaly `detector` found, with the start and stop events they were found between
+    pub fn from_anomalies(detector: &AnomalyDetector) -> Self {
+        let regions = detector
+            .events
+            .iter()
+            .map(|(kind, (start, stop))| match kind {
+                // From the runtime suspending the process to it resuming it, as ClrAnalyzer measures pauses
+                AnomalyKind::GcPause => RegionDefinition {
+                    guid: GUID::from_u128(0x2f6d8b13_4a7e_4c91_b3d5_7e1a9c0f4b62),
+                    name: "GcPause".to_string(),
+                    friendly_name: format!(
+                        "GC pause, {} over {:?}",
+                        Self::_count(detector, *kind),
+                        detector.thresholds.gc_pause
+                    ),
+                    start: vec![*start],
+                    stop: vec![*stop],
+                    same_process: true,
+                    match_fields: Vec::new(),
+                },
+                // ReadInit and WriteInit to Read and Write, needs the disk-io-init kernel flag
+                AnomalyKind::DiskIo => RegionDefinition {
+                    guid: GUID::from_u128(0x8a1f4c6e_7d2b_4e93_8f05_b6c4e2a9d370),
+                    name: "DiskIo".to_string(),
+                    friendly_name: format!(
+                        "Disk I/O, {} over {:?}",
+                        Self::_count(detector, *kind),
+                        detector.thresholds.disk_io
+                    ),
+                    start: [DISK_IO_READ_INIT, DISK_IO_WRITE_INIT]
+                        .map(|opcode| RegionEvent::opcode(DISK_IO_GUID, opcode))
+                        .to_vec(),
+                    stop: [DISK_IO_READ, DISK_IO_WRITE]
+                        .map(|opcode| RegionEvent::opcode(DISK_IO_GUID, opcode))
+                        .to_vec(),
+                    same_process: false,
+                    match_fields: vec![("Irp".to_string(), "Irp".to_string())],
+                },
+                // A single event, so the region marks the moment it was logged
+                AnomalyKind::RuleMatch(rule) => RegionDefinition {
+                    guid: Self::_rule_guid(rule),
+                    name: format!("RuleMatch{rule}"),
+                    friendly_name: format!(
+                        "Rule {rule}, {} matches",
+                        Self::_count(detector, *kind)
+                    ),
+                    start: vec![*start],
+                    stop: vec![*stop],
+                    same_process: true,
+                    match_fields: Vec::new(),
+                },
+            })
+            .collect();
+
+        Self {
+            regions,
+            anomalies: detector.anomalies.clone(),
+        }
+    }
+
+    /// The file written next to `etl_path`, e.g. trace.etl.regions.xml
+    pub fn path_for(etl_path: &Path) -> PathBuf {
+        let mut path = etl_path.as_os_str().to_owned();
+        path.push(".regions.xml");
+        PathBuf::from(path)
+    }
+
+    pub fn to_xml(&self) -> String {
+        let mut xml = String::from(
+            "<?xml version='1.0' encoding='utf-8' standalone='yes'?>\n\
+             <InstrumentationManifest>\n  <Instrumentation>\n    <Regions>\n",
+        );
+        xml += &format!(
+            "      <RegionRoot Guid=\"{}\" Name=\"EtwRustTool\" FriendlyName=\"Etw Rust Tool\">\n",
+            PropertyValue::Guid(REGION_ROOT_GUID)
+        );
+
+        for anomaly in &self.anomalies {
+            xml += &format!(
+                "      <!-- {} to {} pid {}: {} -->\n",
+                clock::rfc3339_from_ticks(anomaly.start),
+                clock::rfc3339_from_ticks(anomaly.end),
+                anomaly.process_id,
+                Self::_escape(&anomaly.description).replace("--", "- -")
+            );
+        }
+        for region in &self.regions {
+            xml += &format!(
+                "        <Region Guid=\"{}\" Name=\"{}\" FriendlyName=\"{}\">\n",
+                PropertyValue::Guid(region.guid),
+                Self::_escape(&region.name),
+                Self::_escape(&region.friendly_name)
+            );
+            for (element, events) in [("Start", &region.start), ("Stop", &region.stop)] {
+                xml += &format!("          <{element}>\n");
+                for event in events {
+                    xml += &format!("            {}\n", Self::_event(event));
+                }
+                xml += &format!("          </{element}>\n");
+            }
+
+            if region.same_process || !region.match_fields.is_empty() {
+                xml += "          <Match>\n";
+                xml += &format!(
+                    "            <Event{}>\n",
+                    if region.same_process {
+                        " PID=\"true\""
+                    } else {
+                        ""
+                    }
+                );
+                for (field, target_field) in &region.match_fields {
+                    xml += &format!(
+                        "              <Payload FieldName=\"{}\" TargetFieldName=\"{}\" />\n",
+                        Self::_escape(field),
+                        Self::_escape(target_field)
+                    );
+                }
+                xml += "            </Event>\n          </Match>\n";
+            }
+            xml += "        </Region>\n";
+        }
+
+        xml += "      </RegionRoot>\n    </Regions>\n  </Instrumentation>\n</InstrumentationManifest>\n";
+        xml
+    }
+
+    pub fn write(&self, path: &Path) -> EtwResult<()> {
+        fs::write(path, self.to_xml())
+            .map_err(|err| EtwError::from_io(&err, format!("Could not write {:?}", path)))
+    }
+
+    fn _event(event: &RegionEvent) -> String {
+        let mut element = format!(
+            "<Event Provider=\"{}\"",
+            PropertyValue::Guid(event.provider)
+        );
+        if let Some((id, version)) = event.id {
+            element += &format!(" Id=\"{id}\" Version=\"{version}\"");
+        }
+        if let Some(opcode) = event.opcode {
+            element += &format!(" Opcode=\"{opcode}\"");
+        }
+        element + " />"
+    }
+
+    fn _count(detector: &AnomalyDetector, kind: AnomalyKind) -> usize {
+        detector
+            .anomalies
+            .iter()
+            .filter(|anomaly| anomaly.kind == kind)
+            .count()
+    }
+
+    /// A stable GUID for the region of each rule, from a hash of its name
+    fn _rule_guid(rule: &str) -> GUID {
+        let hash = rule.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        });
+        GUID::from_u128(RULE_REGION_GUID.to_u128() ^ hash as u128)
+    }
+
+    fn _escape(text: &str) -> String {
+        text.replace('&', "&amp;")
+            .replace('"', "&quot;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+
+use windows::{core::GUID, Win32::System::Diagnostics::Etw::EVENT_RECORD};
+
+use super::consumer::TraceHeaderInfo;
+
+/// A routed event handler. Closures can capture whatever state the handler needs, instead of going through globals
+pub type EventHandler = Box<dyn Fn(&EVENT_RECORD, Option<&TraceHeaderInfo>) + Send + Sync>;
+
+/// Routing table consulted for every event, so each provider or event can have its own handlers instead of one
+/// callback that demultiplexes everything itself.
+///
+/// Handlers run from the most specific route to the least: event id, then opcode, then provider.
+/// The fallback handlers only run if no other route matched
+#[derive(Default)]
+pub struct Router {
+    by_event_id: HashMap<(GUID, u16), Vec<EventHandler>>,
+    by_opcode: HashMap<(GUID, u8), Vec<EventHandler>>, // Kernel events are told apart by opcode, their event id is 0
+    by_provider: HashMap<GUID, Vec<EventHandler>>,
+    fallback: Vec<EventHandler>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Routes every event of `provider` to `handler`
+    pub fn on_provider(
+        mut self,
+        provider: GUID,
+        handler: impl Fn(&EVENT_RECORD, Option<&TraceHeaderInfo>) + Send + Sync + 'static,
+    ) -> Self {
+        self.by_provider
+            .entry(provider)
+            .or_default()
+            .push(Box::new(handler));
+        self
+    }
+
+    /// Routes events of `provider` with the id `event_id` to `handler`
+    pub fn on_event_id(
+        mut self,
+        provider: GUID,
+        event_id: u16,
+        handler: impl Fn(&EVENT_RECORD, Option<&TraceHeaderInfo>) + Send + Sync + 'static,
+    ) -> Self {
+        self.by_event_id
+            .entry((provider, event_id))
+            .or_default()
+            .push(Box::new(handler));
+        self
+    }
+
+    /// Routes events of `provider` with the opcode `opcode` to `handler`
+    pub fn on_opcode(
+        mut self,
+        provider: GUID,
+        opcode: u8,
+        handler: impl Fn(&EVENT_RECORD, Option<&TraceHeaderInfo>) + Send + Sync + 'static,
+    ) -> Self {
+        self.by_opcode
+            .entry((provider, opcode))
+            .or_default()
+            .push(Box::new(handler));
+        self
+    }
+
+    /// Routes every event no other route matched to `handler`
+    pub fn otherwise(
+        mut self,
+        handler: impl Fn(&EVENT_RECORD, Option<&TraceHeaderInfo>) + Send + Sync + 'static,
+    ) -> Self {
+        self.fallback.push(Box::new(handler));
+        self
+    }
+
+    /// Calls every handler routed to `record`. Returns true if any route other than the fallback matched
+    pub fn dispatch(&self, record: &EVENT_RECORD, trace: Option<&TraceHeaderInfo>) -> bool {
+        let provider = record.EventHeader.ProviderId;
+        let descriptor = &record.EventHeader.EventDescriptor;
+
+        let mut routed = false;
+        for handler in [
+            self.by_event_id.get(&(provider, descriptor.Id)),
+            self.by_opcode.get(&(provider, descriptor.Opcode)),
+            self.by_provider.get(&provider),
+        ]
+        .into_iter()
+        .flatten()
+        .flatten()
+        {
+            routed = true;
+            handler(record, trace);
+        }
+
+        if !routed {
+            for handler in &self.fallback {
+                handler(record, trace);
+            }
+        }
+
+        routed
+    }
+}
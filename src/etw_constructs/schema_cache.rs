@@ -0,0 +1,457 @@
+use core::slice;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    os::windows::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, OnceLock},
+};
+
+use serde::Serialize;
+use windows::{
+    core::{GUID, PCWSTR},
+    Win32::{
+        Foundation::{ERROR_NOT_FOUND, ERROR_SUCCESS, WIN32_ERROR},
+        System::Diagnostics::Etw::{
+            DecodingSourceTlg, PropertyParamCount, PropertyParamFixedCount, PropertyStruct,
+            TdhLoadManifest, EVENT_HEADER_EXT_TYPE_EVENT_SCHEMA_TL, EVENT_PROPERTY_INFO,
+            EVENT_RECORD, TRACE_EVENT_INFO,
+        },
+    },
+};
+
+use super::{
+    error::{EtwError, EtwResult},
+    parsed_event::PropertyValue,
+    tdh_wrapper::Tdh,
+};
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// TraceLogging schemas cached, past this they are loaded for every event again
+const MAX_TRACE_LOGGING_SCHEMAS: usize = 4096;
+
+/// Feeds `bytes` into the FNV-1a `hash`, which starts at `FNV_OFFSET`
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash = (hash ^ *byte as u64).wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// Identifies the TraceLogging metadata blob an event carries in its extended data, the same for every event written
+/// with the same schema. Stands in for the blob in [`ExtendedData`](super::extended::ExtendedData)
+pub fn trace_logging_schema_key(blob: &[u8]) -> u64 {
+    fnv1a(FNV_OFFSET, blob)
+}
+
+/// Identifies the schema of an event. Classic kernel events all have id 0, so the opcode is part of the key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct SchemaKey {
+    provider: GUID,
+    id: u16,
+    version: u8,
+    opcode: u8,
+}
+
+impl SchemaKey {
+    fn of(record: &EVENT_RECORD) -> Self {
+        let descriptor = &record.EventHeader.EventDescriptor;
+        Self {
+            provider: record.EventHeader.ProviderId,
+            id: descriptor.Id,
+            version: descriptor.Version,
+            opcode: descriptor.Opcode,
+        }
+    }
+}
+
+/// A property of an event schema as TDH describes it, listed in [`SchemaManifest`](super::sink::SchemaManifest)s
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SchemaField {
+    pub name: String,
+    /// [`TDH_INTYPE`](https://learn.microsoft.com/en-us/windows/win32/api/tdh/ne-tdh-_tdh_in_type) of the property,
+    /// how its bytes are laid out. 0 for structs
+    pub in_type: u16,
+    /// `TDH_OUTTYPE` of the property, how it is formatted. 0 for structs
+    pub out_type: u16,
+    /// The property holds a fixed or variable number of values rather than one
+    pub array: bool,
+    /// Members of a struct property, in the order they are laid out
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<SchemaField>,
+}
+
+/// The [`TRACE_EVENT_INFO`] of an event along with the value maps its properties use, loaded lazily
+pub struct Schema {
+    buffer: Vec<u8>,
+    hash: u64,
+    fields: Arc<[SchemaField]>,
+    maps: Mutex<HashMap<u32, Option<Arc<Vec<u8>>>>>, // Keyed on MapNameOffset, None if TDH has no map by that name
+}
+
+impl Schema {
+    /// Loads the schema of `record` with [`Tdh::get_event_information`]
+    pub fn load(record: &EVENT_RECORD) -> EtwResult<Self> {
+        let buffer = Tdh::get_event_information(record, None)?;
+        Ok(Self {
+            hash: Self::_layout_hash(&buffer),
+            fields: Self::_fields(&buffer).into(),
+            buffer,
+            maps: Mutex::default(),
+        })
+    }
+
+    pub fn info(&self) -> &TRACE_EVENT_INFO {
+        unsafe { &*(self.buffer.as_ptr() as *const TRACE_EVENT_INFO) }
+    }
+
+    /// Identifies the layout of the event's properties: their names, types, order and how their counts and lengths
+    /// are found. A provider update that changes the layout of an event changes its hash
+    pub fn hash(&self) -> u64 {
+        self.hash
+    }
+
+    /// The top level properties of the event in the order they are laid out, shared by every event decoded with the
+    /// schema
+    pub fn fields(&self) -> &Arc<[SchemaField]> {
+        &self.fields
+    }
+
+    /// Whether TDH read the schema from the event's own TraceLogging metadata rather than a manifest or MOF class
+    pub fn is_trace_logging(&self) -> bool {
+        self.info().DecodingSource == DecodingSourceTlg
+    }
+
+    /// The whole event information buffer, which the name and map offsets of the properties point into
+    pub fn buffer(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    /// The [`EVENT_MAP_INFO`](windows::Win32::System::Diagnostics::Etw::EVENT_MAP_INFO) buffer of the map named at
+    /// `map_name_offset`, fetched with [`Tdh::get_event_map_information`] the first time it is needed
+    pub fn map(&self, record: &EVENT_RECORD, map_name_offset: u32) -> Option<Arc<Vec<u8>>> {
+        self.maps
+            .lock()
+            .expect("Schema map lock was poisoned")
+            .entry(map_name_offset)
+            .or_insert_with(|| {
+                let map_name = PCWSTR::from_raw(
+                    self.buffer[map_name_offset as usize..].as_ptr() as *const u16
+                );
+                Tdh::get_event_map_information(record, map_name)
+                    .ok()
+                    .map(Arc::new)
+            })
+            .clone()
+    }
+
+    fn _fields(buffer: &[u8]) -> Vec<SchemaField> {
+        let info = unsafe { &*(buffer.as_ptr() as *const TRACE_EVENT_INFO) };
+        let property_infos = unsafe {
+            slice::from_raw_parts(
+                info.EventPropertyInfoArray.as_ptr(),
+                info.PropertyCount as usize,
+            )
+        };
+        Self::_field_range(
+            buffer,
+            property_infos,
+            0,
+            info.TopLevelPropertyCount as usize,
+        )
+    }
+
+    /// The properties at `start..end` of the property array. Struct members are a contiguous range of the same array
+    fn _field_range(
+        buffer: &[u8],
+        property_infos: &[EVENT_PROPERTY_INFO],
+        start: usize,
+        end: usize,
+    ) -> Vec<SchemaField> {
+        property_infos
+            .get(start..end.min(property_infos.len()))
+            .unwrap_or_default()
+            .iter()
+            .map(|property_info| {
+                let name = buffer[property_info.NameOffset as usize..]
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .take_while(|c| *c != 0)
+                    .collect::<Vec<u16>>();
+                let flags = property_info.Flags.0;
+                let array = unsafe { property_info.Anonymous2.count } != 1
+                    || flags & (PropertyParamCount.0 | PropertyParamFixedCount.0) != 0;
+
+                let (in_type, out_type, members) = if flags & PropertyStruct.0 != 0 {
+                    let (first, count) = unsafe {
+                        (
+                            property_info.Anonymous1.structType.StructStartIndex as usize,
+                            property_info.Anonymous1.structType.NumOfStructMembers as usize,
+                        )
+                    };
+                    (
+                        0,
+                        0,
+                        Self::_field_range(buffer, property_infos, first, first + count),
+                    )
+                } else {
+                    let (in_type, out_type) = unsafe {
+                        (
+                            property_info.Anonymous1.nonStructType.InType,
+                            property_info.Anonymous1.nonStructType.OutType,
+                        )
+                    };
+                    (in_type, out_type, Vec::new())
+                };
+
+                SchemaField {
+                    name: String::from_utf16_lossy(&name),
+                    in_type,
+                    out_type,
+                    array,
+                    members,
+                }
+            })
+            .collect()
+    }
+
+    /// FNV-1a over the property array, which unlike [`std::hash::DefaultHasher`] hashes the same on every machine and
+    /// Rust version. Offsets into the buffer are left out, since they move when names change length
+    fn _layout_hash(buffer: &[u8]) -> u64 {
+        let mut hash = FNV_OFFSET;
+        let mut feed = |bytes: &[u8]| hash = fnv1a(hash, bytes);
+
+        let info = unsafe { &*(buffer.as_ptr() as *const TRACE_EVENT_INFO) };
+        let property_infos = unsafe {
+            slice::from_raw_parts(
+                info.EventPropertyInfoArray.as_ptr(),
+                info.PropertyCount as usize,
+            )
+        };
+
+        feed(&info.TopLevelPropertyCount.to_le_bytes());
+        for property_info in property_infos {
+            let name = buffer[property_info.NameOffset as usize..]
+                .chunks_exact(2)
+                .take_while(|c| *c != [0, 0])
+                .flatten()
+                .copied()
+                .collect::<Vec<u8>>();
+            feed(&name);
+            feed(&property_info.Flags.0.to_le_bytes());
+            // InType and OutType, or StructStartIndex and NumOfStructMembers for structs
+            let (first, second) = unsafe {
+                (
+                    property_info.Anonymous1.nonStructType.InType,
+                    property_info.Anonymous1.nonStructType.OutType,
+                )
+            };
+            feed(&first.to_le_bytes());
+            feed(&second.to_le_bytes());
+            // The count and length, or the index of the properties holding them
+            feed(&unsafe { property_info.Anonymous2.count }.to_le_bytes());
+            feed(&unsafe { property_info.Anonymous3.length }.to_le_bytes());
+        }
+
+        hash
+    }
+}
+
+/// Caches event schemas keyed on (provider, event id, version, opcode), so repeated events are decoded without any
+/// TDH schema calls. Also holds a scratch buffer reused by every `TdhFormatProperty` call.
+///
+/// Schemas TDH cannot find are looked for in the manifest directory, if one is set, and otherwise remembered as
+/// missing so later events with the same key fail without asking TDH again
+#[derive(Default)]
+pub struct SchemaCache {
+    schemas: Mutex<HashMap<SchemaKey, Arc<Schema>>>,
+    trace_logging: Mutex<HashMap<(GUID, u64), (Box<[u8]>, Arc<Schema>)>>, // Keyed on provider and schema key
+    missing: Mutex<HashSet<SchemaKey>>,
+    manifest_dir: OnceLock<PathBuf>,
+    manifests_loaded: OnceLock<usize>, // How many manifests of the directory TDH loaded, set on the first miss
+    scratch: Mutex<Vec<u16>>,
+}
+
+impl SchemaCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The schema of `record`, loaded and cached the first time an event with its key is seen.
+    /// TraceLogging events carry their own schema and share ids, so theirs are cached on the metadata blob instead
+    pub fn schema(&self, record: &EVENT_RECORD) -> EtwResult<Arc<Schema>> {
+        if let Some(blob) = Self::_own_schema(record) {
+            return self._trace_logging_schema(record, blob);
+        }
+
+        let key = SchemaKey::of(record);
+        if self
+            .missing
+            .lock()
+            .expect("Schema cache lock was poisoned")
+            .contains(&key)
+        {
+            return Err(Self::_missing_error(&key));
+        }
+        if let Some(schema) = self
+            .schemas
+            .lock()
+            .expect("Schema cache lock was poisoned")
+            .get(&key)
+        {
+            return Ok(Arc::clone(schema));
+        }
+
+        // Loaded without holding the lock, a duplicate load on another thread is harmless
+        let schema = match Schema::load(record) {
+            Err(err) if err.status() == ERROR_NOT_FOUND => {
+                let retried = self._load_manifests().then(|| Schema::load(record).ok());
+                match retried.flatten() {
+                    Some(schema) => schema,
+                    None => {
+                        self.missing
+                            .lock()
+                            .expect("Schema cache lock was poisoned")
+                            .insert(key);
+                        return Err(err);
+                    }
+                }
+            }
+            schema => schema?,
+        };
+        let schema = Arc::new(schema);
+        self.schemas
+            .lock()
+            .expect("Schema cache lock was poisoned")
+            .insert(key, Arc::clone(&schema));
+
+        Ok(schema)
+    }
+
+    /// Looks for schemas TDH cannot find in the .man and .xml instrumentation manifests under `dir`, e.g. copied from
+    /// the machine the providers are installed on when consuming inside a minimal container. They are loaded the first
+    /// time a schema is missing. Can only be set once
+    pub fn set_manifest_dir(&self, dir: PathBuf) {
+        let _ = self.manifest_dir.set(dir);
+    }
+
+    pub fn manifest_dir(&self) -> Option<&Path> {
+        self.manifest_dir.get().map(PathBuf::as_path)
+    }
+
+    /// How many manifests were loaded from the manifest directory. None if none were looked for yet
+    pub fn manifests_loaded(&self) -> Option<usize> {
+        self.manifests_loaded.get().copied()
+    }
+
+    /// The scratch buffer for formatting properties. Holding it blocks other threads decoding with this cache
+    pub fn scratch(&self) -> MutexGuard<'_, Vec<u16>> {
+        self.scratch
+            .lock()
+            .expect("Scratch buffer lock was poisoned")
+    }
+
+    /// How many schemas are cached
+    pub fn len(&self) -> usize {
+        let schemas = self
+            .schemas
+            .lock()
+            .expect("Schema cache lock was poisoned")
+            .len();
+        schemas
+            + self
+                .trace_logging
+                .lock()
+                .expect("Schema cache lock was poisoned")
+                .len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Loads every manifest of the manifest directory into TDH, once. Returns whether any of them loaded
+    fn _load_manifests(&self) -> bool {
+        let Some(dir) = self.manifest_dir.get() else {
+            return false;
+        };
+        let loaded = *self.manifests_loaded.get_or_init(|| {
+            let Ok(entries) = fs::read_dir(dir) else {
+                return 0;
+            };
+            entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| {
+                    path.extension()
+                        .is_some_and(|extension| extension == "man" || extension == "xml")
+                })
+                .filter(|path| Self::_load_manifest(path))
+                .count()
+        });
+        loaded != 0
+    }
+
+    fn _load_manifest(path: &Path) -> bool {
+        let wide: Vec<u16> = path.as_os_str().encode_wide().chain([0]).collect();
+        WIN32_ERROR(unsafe { TdhLoadManifest(PCWSTR(wide.as_ptr())) }) == ERROR_SUCCESS
+    }
+
+    fn _missing_error(key: &SchemaKey) -> EtwError {
+        EtwError::Tdh {
+            status: ERROR_NOT_FOUND,
+            context: format!(
+                "No schema for event {} version {} opcode {} of provider {}",
+                key.id,
+                key.version,
+                key.opcode,
+                PropertyValue::Guid(key.provider)
+            ),
+        }
+    }
+
+    /// The schema of a TraceLogging event, cached on its provider and metadata blob. The blob is compared as well, so
+    /// a hash collision loads the schema again rather than decoding with the wrong one
+    fn _trace_logging_schema(&self, record: &EVENT_RECORD, blob: &[u8]) -> EtwResult<Arc<Schema>> {
+        let key = (
+            record.EventHeader.ProviderId,
+            trace_logging_schema_key(blob),
+        );
+        if let Some((cached, schema)) = self
+            .trace_logging
+            .lock()
+            .expect("Schema cache lock was poisoned")
+            .get(&key)
+        {
+            if **cached == *blob {
+                return Ok(Arc::clone(schema));
+            }
+        }
+
+        let schema = Arc::new(Schema::load(record)?);
+        let mut trace_logging = self
+            .trace_logging
+            .lock()
+            .expect("Schema cache lock was poisoned");
+        if trace_logging.len() < MAX_TRACE_LOGGING_SCHEMAS {
+            trace_logging.insert(key, (blob.into(), Arc::clone(&schema)));
+        }
+        Ok(schema)
+    }
+
+    /// The TraceLogging metadata blob in the extended data of `record`, if it has one
+    fn _own_schema(record: &EVENT_RECORD) -> Option<&[u8]> {
+        if record.ExtendedData.is_null() {
+            return None;
+        }
+
+        unsafe { slice::from_raw_parts(record.ExtendedData, record.ExtendedDataCount as usize) }
+            .iter()
+            .find(|item| item.ExtType as u32 == EVENT_HEADER_EXT_TYPE_EVENT_SCHEMA_TL)
+            .map(|item| match item.DataPtr {
+                0 => &[][..],
+                data => unsafe { slice::from_raw_parts(data as *const u8, item.DataSize as usize) },
+            })
+    }
+}
@@ -0,0 +1,619 @@
+//! Typed views of the kernel MOF event classes, see https://learn.microsoft.com/en-us/windows/win32/etw/nt-kernel-logger-constants.
+//! Each class is converted from a [`ParsedEvent`] with `TryFrom`, keyed by the event's opcode. The conversion fails on
+//! events of other providers or opcodes, so a field that compiles is a field the event has. The classes are re-exported
+//! from [`super`]
+
+use std::{
+    error::Error,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use windows::core::GUID;
+
+use super::{
+    filter::PROCESS_GUID,
+    parsed_event::{ParsedEvent, PropertyValue},
+};
+
+pub const THREAD_GUID: GUID = GUID::from_u128(0x3d6fa8d1_fe05_11d0_9dda_00c04fd7ba7c);
+pub const IMAGE_LOAD_GUID: GUID = GUID::from_u128(0x2cb15d1d_5fc1_11d2_abe1_00a0c911f518);
+pub const TCPIP_GUID: GUID = GUID::from_u128(0x9a280ac0_c8e0_11d1_84e2_00c04fb998a2);
+pub const UDPIP_GUID: GUID = GUID::from_u128(0xbf3a50c5_a9c9_4988_a005_2df0b7c80f80);
+pub const REGISTRY_GUID: GUID = GUID::from_u128(0xae53722e_c863_11d2_8659_00c04fa321a1);
+pub const FILEIO_GUID: GUID = GUID::from_u128(0x90cbdc39_4a3e_11d1_84f4_0000f80464e3);
+
+/// Why a [`ParsedEvent`] could not be converted to a typed event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaError {
+    /// The event was logged by a different provider
+    Provider { expected: GUID, actual: GUID },
+    /// The provider is right, but the opcode is not one the schema knows
+    Opcode(u8),
+    /// A field the schema needs is missing or has the wrong type
+    MissingField(&'static str),
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaError::Provider { expected, actual } => {
+                write!(f, "Expected provider {:?}, got {:?}", expected, actual)
+            }
+            SchemaError::Opcode(opcode) => write!(f, "Opcode {opcode} is not part of the schema"),
+            SchemaError::MissingField(name) => write!(f, "Field {name} is missing"),
+        }
+    }
+}
+
+impl Error for SchemaError {}
+
+/// Process_TypeGroup1, https://learn.microsoft.com/en-us/windows/win32/etw/process-typegroup1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessOpcode {
+    Start,
+    End,
+    DcStart,
+    DcEnd,
+}
+
+/// A process start, end or rundown event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessEvent {
+    pub opcode: ProcessOpcode,
+    pub process_id: u32,
+    pub parent_id: u32,
+    pub session_id: u32,
+    /// Only meaningful on End events
+    pub exit_status: i32,
+    pub unique_process_key: u64,
+    pub image_file_name: String,
+    /// Empty on versions of the class older than Windows Vista
+    pub command_line: String,
+}
+
+impl TryFrom<&ParsedEvent> for ProcessEvent {
+    type Error = SchemaError;
+
+    fn try_from(event: &ParsedEvent) -> Result<Self, Self::Error> {
+        let fields = Fields::new(event, PROCESS_GUID)?;
+        let opcode = match event.opcode {
+            1 => ProcessOpcode::Start,
+            2 => ProcessOpcode::End,
+            3 => ProcessOpcode::DcStart,
+            4 => ProcessOpcode::DcEnd,
+            opcode => return Err(SchemaError::Opcode(opcode)),
+        };
+
+        Ok(Self {
+            opcode,
+            process_id: fields.required("ProcessId")? as u32,
+            parent_id: fields.optional("ParentId") as u32,
+            session_id: fields.optional("SessionId") as u32,
+            // ExitStatus is signed, so NTSTATUS error codes do not fit as_u64
+            exit_status: event
+                .get("ExitStatus")
+                .and_then(PropertyValue::as_i64)
+                .unwrap_or_default() as i32,
+            unique_process_key: fields.optional("UniqueProcessKey"),
+            image_file_name: fields.string("ImageFileName"),
+            command_line: fields.string("CommandLine"),
+        })
+    }
+}
+
+/// Thread_TypeGroup1, https://learn.microsoft.com/en-us/windows/win32/etw/thread-typegroup1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadOpcode {
+    Start,
+    End,
+    DcStart,
+    DcEnd,
+}
+
+/// A thread start, end or rundown event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadEvent {
+    pub opcode: ThreadOpcode,
+    pub process_id: u32,
+    pub thread_id: u32,
+    pub stack_base: u64,
+    pub stack_limit: u64,
+    pub user_stack_base: u64,
+    pub user_stack_limit: u64,
+    pub start_address: u64,
+    pub win32_start_address: u64,
+    pub teb_base: u64,
+    pub base_priority: u8,
+    pub page_priority: u8,
+    pub io_priority: u8,
+}
+
+impl TryFrom<&ParsedEvent> for ThreadEvent {
+    type Error = SchemaError;
+
+    fn try_from(event: &ParsedEvent) -> Result<Self, Self::Error> {
+        let fields = Fields::new(event, THREAD_GUID)?;
+        let opcode = match event.opcode {
+            1 => ThreadOpcode::Start,
+            2 => ThreadOpcode::End,
+            3 => ThreadOpcode::DcStart,
+            4 => ThreadOpcode::DcEnd,
+            opcode => return Err(SchemaError::Opcode(opcode)),
+        };
+
+        Ok(Self {
+            opcode,
+            process_id: fields.required("ProcessId")? as u32,
+            thread_id: fields.required("TThreadId")? as u32,
+            stack_base: fields.optional("StackBase"),
+            stack_limit: fields.optional("StackLimit"),
+            user_stack_base: fields.optional("UserStackBase"),
+            user_stack_limit: fields.optional("UserStackLimit"),
+            start_address: fields.optional("StartAddr"),
+            win32_start_address: fields.optional("Win32StartAddr"),
+            teb_base: fields.optional("TebBase"),
+            base_priority: fields.optional("BasePriority") as u8,
+            page_priority: fields.optional("PagePriority") as u8,
+            io_priority: fields.optional("IoPriority") as u8,
+        })
+    }
+}
+
+/// CSwitch and ReadyThread, https://learn.microsoft.com/en-us/windows/win32/etw/cswitch and
+/// https://learn.microsoft.com/en-us/windows/win32/etw/readythread. Logged with the cswitch and dispatcher kernel flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedulerEvent {
+    /// A processor switched from `old_thread_id` to `new_thread_id`
+    ContextSwitch {
+        new_thread_id: u32,
+        old_thread_id: u32,
+        /// KWAIT_REASON the old thread is waiting for, when `old_thread_state` is Waiting (5)
+        old_thread_wait_reason: u8,
+        /// KTHREAD_STATE of the old thread, e.g. 1 (Ready) when it was preempted or 5 (Waiting)
+        old_thread_state: u8,
+    },
+    /// `thread_id` was made ready to run, by the thread the event was logged on
+    ReadyThread { thread_id: u32 },
+}
+
+impl TryFrom<&ParsedEvent> for SchedulerEvent {
+    type Error = SchemaError;
+
+    fn try_from(event: &ParsedEvent) -> Result<Self, Self::Error> {
+        let fields = Fields::new(event, THREAD_GUID)?;
+        Ok(match event.opcode {
+            36 => SchedulerEvent::ContextSwitch {
+                new_thread_id: fields.required("NewThreadId")? as u32,
+                old_thread_id: fields.required("OldThreadId")? as u32,
+                old_thread_wait_reason: fields.optional("OldThreadWaitReason") as u8,
+                old_thread_state: fields.optional("OldThreadState") as u8,
+            },
+            50 => SchedulerEvent::ReadyThread {
+                thread_id: fields.required("TThreadId")? as u32,
+            },
+            opcode => return Err(SchemaError::Opcode(opcode)),
+        })
+    }
+}
+
+/// Image_Load, https://learn.microsoft.com/en-us/windows/win32/etw/image-load
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageOpcode {
+    Load,
+    Unload,
+    DcStart,
+    DcEnd,
+}
+
+/// An image load, unload or rundown event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageEvent {
+    pub opcode: ImageOpcode,
+    pub process_id: u32,
+    pub image_base: u64,
+    pub image_size: u64,
+    pub image_checksum: u32,
+    pub time_date_stamp: u32,
+    pub default_base: u64,
+    pub file_name: String,
+}
+
+impl TryFrom<&ParsedEvent> for ImageEvent {
+    type Error = SchemaError;
+
+    fn try_from(event: &ParsedEvent) -> Result<Self, Self::Error> {
+        let fields = Fields::new(event, IMAGE_LOAD_GUID)?;
+        let opcode = match event.opcode {
+            10 => ImageOpcode::Load,
+            2 => ImageOpcode::Unload,
+            3 => ImageOpcode::DcStart,
+            4 => ImageOpcode::DcEnd,
+            opcode => return Err(SchemaError::Opcode(opcode)),
+        };
+
+        Ok(Self {
+            opcode,
+            process_id: fields.required("ProcessId")? as u32,
+            image_base: fields.required("ImageBase")?,
+            image_size: fields.required("ImageSize")?,
+            image_checksum: fields.optional("ImageCheckSum") as u32,
+            time_date_stamp: fields.optional("TimeDateStamp") as u32,
+            default_base: fields.optional("DefaultBase"),
+            file_name: fields.string("FileName"),
+        })
+    }
+}
+
+/// TcpIp and UdpIp events. IPv6 events use the IPv4 opcode plus 16
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkOpcode {
+    Send,
+    Receive,
+    Connect,
+    Disconnect,
+    Retransmit,
+    Accept,
+    Reconnect,
+}
+
+/// TcpIp_TypeGroup1/UdpIp_TypeGroup1 and their IPv6 counterparts, https://learn.microsoft.com/en-us/windows/win32/etw/tcpip
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkEvent {
+    pub opcode: NetworkOpcode,
+    pub udp: bool,
+    pub process_id: u32,
+    pub size: u32,
+    pub destination: IpAddr,
+    pub source: IpAddr,
+    pub destination_port: u16,
+    pub source_port: u16,
+    pub connection_id: u64,
+}
+
+impl TryFrom<&ParsedEvent> for NetworkEvent {
+    type Error = SchemaError;
+
+    fn try_from(event: &ParsedEvent) -> Result<Self, Self::Error> {
+        let udp = event.provider == UDPIP_GUID;
+        let fields = Fields::new(event, if udp { UDPIP_GUID } else { TCPIP_GUID })?;
+
+        let opcode = match event.opcode {
+            10 | 26 => NetworkOpcode::Send,
+            11 | 27 => NetworkOpcode::Receive,
+            12 | 28 if !udp => NetworkOpcode::Connect,
+            13 | 29 if !udp => NetworkOpcode::Disconnect,
+            14 | 30 if !udp => NetworkOpcode::Retransmit,
+            15 | 31 if !udp => NetworkOpcode::Accept,
+            16 | 32 if !udp => NetworkOpcode::Reconnect,
+            opcode => return Err(SchemaError::Opcode(opcode)),
+        };
+
+        Ok(Self {
+            opcode,
+            udp,
+            process_id: fields.required("PID")? as u32,
+            size: fields.optional("size") as u32,
+            destination: fields.address("daddr")?,
+            source: fields.address("saddr")?,
+            destination_port: fields.port("dport")?,
+            source_port: fields.port("sport")?,
+            connection_id: fields.optional("connid"),
+        })
+    }
+}
+
+/// Registry_TypeGroup1, https://learn.microsoft.com/en-us/windows/win32/etw/registry-typegroup1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistryOpcode {
+    Create,
+    Open,
+    Delete,
+    Query,
+    SetValue,
+    DeleteValue,
+    QueryValue,
+    EnumerateKey,
+    EnumerateValueKey,
+    QueryMultipleValue,
+    SetInformation,
+    Flush,
+    KcbCreate,
+    KcbDelete,
+    KcbRundownBegin,
+    KcbRundownEnd,
+    Virtualize,
+    Close,
+}
+
+/// A registry operation on a key, identified by the handle of its key control block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegistryEvent {
+    pub opcode: RegistryOpcode,
+    pub initial_time: i64,
+    pub status: u32,
+    pub index: u32,
+    pub key_handle: u64,
+    pub key_name: String,
+}
+
+impl TryFrom<&ParsedEvent> for RegistryEvent {
+    type Error = SchemaError;
+
+    fn try_from(event: &ParsedEvent) -> Result<Self, Self::Error> {
+        let fields = Fields::new(event, REGISTRY_GUID)?;
+        let opcode = match event.opcode {
+            10 => RegistryOpcode::Create,
+            11 => RegistryOpcode::Open,
+            12 => RegistryOpcode::Delete,
+            13 => RegistryOpcode::Query,
+            14 => RegistryOpcode::SetValue,
+            15 => RegistryOpcode::DeleteValue,
+            16 => RegistryOpcode::QueryValue,
+            17 => RegistryOpcode::EnumerateKey,
+            18 => RegistryOpcode::EnumerateValueKey,
+            19 => RegistryOpcode::QueryMultipleValue,
+            20 => RegistryOpcode::SetInformation,
+            21 => RegistryOpcode::Flush,
+            22 => RegistryOpcode::KcbCreate,
+            23 => RegistryOpcode::KcbDelete,
+            24 => RegistryOpcode::KcbRundownBegin,
+            25 => RegistryOpcode::KcbRundownEnd,
+            26 => RegistryOpcode::Virtualize,
+            27 => RegistryOpcode::Close,
+            opcode => return Err(SchemaError::Opcode(opcode)),
+        };
+
+        Ok(Self {
+            opcode,
+            initial_time: fields.optional("InitialTime") as i64,
+            status: fields.optional("Status") as u32,
+            index: fields.optional("Index") as u32,
+            key_handle: fields.required("KeyHandle")?,
+            key_name: fields.string("KeyName"),
+        })
+    }
+}
+
+/// FileIo events. Each group of opcodes has its own fields, https://learn.microsoft.com/en-us/windows/win32/etw/fileio
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileIoEvent {
+    /// FileIo_Name: Name (0), FileCreate (32), FileDelete (35) and FileRundown (36)
+    Name {
+        opcode: u8,
+        file_object: u64,
+        file_name: String,
+    },
+    /// FileIo_Create: Create (64)
+    Create {
+        irp: u64,
+        thread_id: u32,
+        file_object: u64,
+        create_options: u32,
+        file_attributes: u32,
+        share_access: u32,
+        open_path: String,
+    },
+    /// FileIo_ReadWrite: Read (67) and Write (68)
+    ReadWrite {
+        write: bool,
+        offset: u64,
+        irp: u64,
+        thread_id: u32,
+        file_object: u64,
+        file_key: u64,
+        io_size: u32,
+        io_flags: u32,
+    },
+    /// FileIo_SimpleOp: Cleanup (65), Close (66) and Flush (73)
+    SimpleOp {
+        opcode: u8,
+        irp: u64,
+        thread_id: u32,
+        file_object: u64,
+        file_key: u64,
+    },
+    /// FileIo_OpEnd: OperationEnd (76)
+    OperationEnd {
+        irp: u64,
+        extra_info: u64,
+        status: u32,
+    },
+    /// A minifilter callback finishing: FltPreOpCompletion (98) and FltPostOpCompletion (99). Only logged when the
+    /// session enables the FLT_IO group, e.g. a trace recorded with `xperf -on FILE_IO+FILE_IO_INIT+FLT_IO`
+    MinifilterCompletion {
+        post_operation: bool,
+        /// When the callback was called, as a raw timestamp of the session clock rather than FILETIME ticks like the
+        /// event timestamp. Convert it with [`SessionClock::ticks`](super::clock::SessionClock::ticks)
+        initial_time: i64,
+        /// Address of the callback, which identifies the minifilter
+        routine: u64,
+        file_object: u64,
+        irp: u64,
+        major_function: u32,
+    },
+}
+
+impl TryFrom<&ParsedEvent> for FileIoEvent {
+    type Error = SchemaError;
+
+    fn try_from(event: &ParsedEvent) -> Result<Self, Self::Error> {
+        let fields = Fields::new(event, FILEIO_GUID)?;
+
+        Ok(match event.opcode {
+            opcode @ (0 | 32 | 35 | 36) => FileIoEvent::Name {
+                opcode,
+                file_object: fields.required("FileObject")?,
+                file_name: fields.string("FileName"),
+            },
+            64 => FileIoEvent::Create {
+                irp: fields.optional("IrpPtr"),
+                thread_id: fields.optional("TTID") as u32,
+                file_object: fields.required("FileObject")?,
+                create_options: fields.optional("CreateOptions") as u32,
+                file_attributes: fields.optional("FileAttributes") as u32,
+                share_access: fields.optional("ShareAccess") as u32,
+                open_path: fields.string("OpenPath"),
+            },
+            opcode @ (67 | 68) => FileIoEvent::ReadWrite {
+                write: opcode == 68,
+                offset: fields.required("Offset")?,
+                irp: fields.optional("IrpPtr"),
+                thread_id: fields.optional("TTID") as u32,
+                file_object: fields.required("FileObject")?,
+                file_key: fields.optional("FileKey"),
+                io_size: fields.required("IoSize")? as u32,
+                io_flags: fields.optional("IoFlags") as u32,
+            },
+            opcode @ (65 | 66 | 73) => FileIoEvent::SimpleOp {
+                opcode,
+                irp: fields.optional("IrpPtr"),
+                thread_id: fields.optional("TTID") as u32,
+                file_object: fields.required("FileObject")?,
+                file_key: fields.optional("FileKey"),
+            },
+            76 => FileIoEvent::OperationEnd {
+                irp: fields.required("IrpPtr")?,
+                extra_info: fields.optional("ExtraInfo"),
+                status: fields.optional("NtStatus") as u32,
+            },
+            opcode @ (98 | 99) => FileIoEvent::MinifilterCompletion {
+                post_operation: opcode == 99,
+                initial_time: fields.required("InitialTime")? as i64,
+                routine: fields.required("RoutineAddr")?,
+                file_object: fields.optional("FileObject"),
+                irp: fields.optional("IrpPtr"),
+                major_function: fields.optional("MajorFunction") as u32,
+            },
+            opcode => return Err(SchemaError::Opcode(opcode)),
+        })
+    }
+}
+
+impl FileIoEvent {
+    /// The file object the operation was on. 0 for operation ends, which only have the IRP
+    pub fn file_object(&self) -> u64 {
+        match self {
+            FileIoEvent::Name { file_object, .. }
+            | FileIoEvent::Create { file_object, .. }
+            | FileIoEvent::ReadWrite { file_object, .. }
+            | FileIoEvent::SimpleOp { file_object, .. }
+            | FileIoEvent::MinifilterCompletion { file_object, .. } => *file_object,
+            FileIoEvent::OperationEnd { .. } => 0,
+        }
+    }
+
+    /// The IRP of the operation, which matches it to its [`FileIoEvent::OperationEnd`]. None for name events
+    pub fn irp(&self) -> Option<u64> {
+        match self {
+            FileIoEvent::Name { .. } => None,
+            FileIoEvent::Create { irp, .. }
+            | FileIoEvent::ReadWrite { irp, .. }
+            | FileIoEvent::SimpleOp { irp, .. }
+            | FileIoEvent::OperationEnd { irp, .. }
+            | FileIoEvent::MinifilterCompletion { irp, .. } => Some(*irp),
+        }
+    }
+
+    /// The thread that issued the operation, for the groups that log it
+    pub fn thread_id(&self) -> Option<u32> {
+        match self {
+            FileIoEvent::Create { thread_id, .. }
+            | FileIoEvent::ReadWrite { thread_id, .. }
+            | FileIoEvent::SimpleOp { thread_id, .. } => Some(*thread_id),
+            _ => None,
+        }
+    }
+}
+
+/// Also converts owned events, so `event.try_into()` works without borrowing first
+macro_rules! try_from_owned {
+    ($($schema:ty),*) => {
+        $(
+            impl TryFrom<ParsedEvent> for $schema {
+                type Error = SchemaError;
+
+                fn try_from(event: ParsedEvent) -> Result<Self, Self::Error> {
+                    Self::try_from(&event)
+                }
+            }
+        )*
+    };
+}
+
+try_from_owned!(
+    ProcessEvent,
+    ThreadEvent,
+    SchedulerEvent,
+    ImageEvent,
+    NetworkEvent,
+    RegistryEvent,
+    FileIoEvent
+);
+
+/// Reads the fields of an event, for the typed events here and in the provider modules
+pub(crate) struct Fields<'a> {
+    event: &'a ParsedEvent,
+}
+
+impl<'a> Fields<'a> {
+    pub(crate) fn new(event: &'a ParsedEvent, provider: GUID) -> Result<Self, SchemaError> {
+        if event.provider != provider {
+            return Err(SchemaError::Provider {
+                expected: provider,
+                actual: event.provider,
+            });
+        }
+        Ok(Self::of(event))
+    }
+
+    /// The fields of `event` whatever its provider, for events read from more than one
+    pub(crate) fn of(event: &'a ParsedEvent) -> Self {
+        Self { event }
+    }
+
+    pub(crate) fn required(&self, name: &'static str) -> Result<u64, SchemaError> {
+        self.unsigned(name).ok_or(SchemaError::MissingField(name))
+    }
+
+    /// Fields that older versions of the class do not have are 0
+    pub(crate) fn optional(&self, name: &str) -> u64 {
+        self.unsigned(name).unwrap_or_default()
+    }
+
+    /// None if the event has no such field, or it is not a number
+    pub(crate) fn unsigned(&self, name: &str) -> Option<u64> {
+        self.event.get(name).and_then(PropertyValue::as_u64)
+    }
+
+    pub(crate) fn signed(&self, name: &str) -> Option<i64> {
+        self.event.get(name).and_then(PropertyValue::as_i64)
+    }
+
+    /// Empty if the event has no such field
+    pub(crate) fn string(&self, name: &str) -> String {
+        self.optional_string(name).unwrap_or_default()
+    }
+
+    pub(crate) fn optional_string(&self, name: &str) -> Option<String> {
+        self.event.get(name).map(ToString::to_string)
+    }
+
+    /// IPv4 addresses are a u32 in network byte order, IPv6 addresses are 16 raw bytes
+    fn address(&self, name: &'static str) -> Result<IpAddr, SchemaError> {
+        match self.event.get(name) {
+            Some(PropertyValue::Unsigned(value)) => {
+                Ok(IpAddr::V4(Ipv4Addr::from((*value as u32).to_le_bytes())))
+            }
+            Some(PropertyValue::Binary(bytes)) => <[u8; 16]>::try_from(bytes.as_slice())
+                .map(|bytes| IpAddr::V6(Ipv6Addr::from(bytes)))
+                .map_err(|_| SchemaError::MissingField(name)),
+            _ => Err(SchemaError::MissingField(name)),
+        }
+    }
+
+    /// Ports are logged in network byte order
+    fn port(&self, name: &'static str) -> Result<u16, SchemaError> {
+        self.required(name).map(|port| (port as u16).swap_bytes())
+    }
+}
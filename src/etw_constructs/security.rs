@@ -0,0 +1,163 @@
+use std::ffi::c_void;
+
+use windows::{
+    core::{GUID, PCWSTR, PWSTR},
+    Win32::{
+        Foundation::{BOOL, ERROR_FILE_NOT_FOUND, ERROR_MORE_DATA, ERROR_SUCCESS, WIN32_ERROR},
+        Security::{
+            GetAce, GetLengthSid, GetSecurityDescriptorDacl, LookupAccountSidW, ACCESS_ALLOWED_ACE,
+            ACCESS_ALLOWED_ACE_TYPE, ACCESS_DENIED_ACE_TYPE, ACE_HEADER, ACL, PSECURITY_DESCRIPTOR,
+            PSID, SID_NAME_USE,
+        },
+        System::Diagnostics::Etw::EventAccessQuery,
+    },
+};
+
+use super::{
+    error::{EtwError, EtwResult},
+    extended::sid_to_string,
+};
+
+/// Used for every session and provider without a security descriptor of its own, see
+/// https://learn.microsoft.com/en-us/windows/win32/etw/configuring-and-starting-a-private-logger-session
+pub const DEFAULT_SECURITY_GUID: GUID = GUID::from_u128(0x0811c1af_7a07_4a06_82ed_869455cdf713);
+
+/// Lets an account open the session for real-time consumption, from evntrace.h
+const TRACELOG_ACCESS_REALTIME: u32 = 0x0400;
+
+/// An account the security descriptor of a session grants or denies real-time consumption
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsumerAccess {
+    /// e.g. `S-1-5-32-544`
+    pub sid: String,
+    /// e.g. `BUILTIN\Administrators`. None if the SID could not be looked up
+    pub account: Option<String>,
+    /// False if the account is explicitly denied, which wins over any ACE granting it access
+    pub allowed: bool,
+}
+
+/// Every account the security descriptor of the session with control GUID `session_guid` grants or denies
+/// [`TRACELOG_ACCESS_REALTIME`]. Sessions without a descriptor of their own fall back to [`DEFAULT_SECURITY_GUID`]
+pub fn real_time_consumers(session_guid: &GUID) -> EtwResult<Vec<ConsumerAccess>> {
+    let descriptor = match query_descriptor(session_guid) {
+        Err(err) if err.status() == ERROR_FILE_NOT_FOUND => {
+            query_descriptor(&DEFAULT_SECURITY_GUID)?
+        }
+        descriptor => descriptor?,
+    };
+    let descriptor_ptr = PSECURITY_DESCRIPTOR(descriptor.as_ptr() as *mut c_void);
+
+    let mut dacl_present = BOOL::default();
+    let mut dacl: *mut ACL = std::ptr::null_mut();
+    let mut dacl_defaulted = BOOL::default();
+    unsafe {
+        GetSecurityDescriptorDacl(
+            descriptor_ptr,
+            &mut dacl_present,
+            &mut dacl,
+            &mut dacl_defaulted,
+        )
+    }
+    .map_err(|err| EtwError::Win32 {
+        status: WIN32_ERROR::from_error(&err).unwrap_or_default(),
+        context: "GetSecurityDescriptorDacl could not read the DACL".to_string(),
+    })?;
+
+    // A missing DACL grants everyone access, but ETW always stores one
+    let Some(acl) = (unsafe { dacl.as_ref() }).filter(|_| dacl_present.as_bool()) else {
+        return Ok(Vec::new());
+    };
+
+    let mut consumers = Vec::new();
+    for index in 0..acl.AceCount as u32 {
+        let mut ace: *mut c_void = std::ptr::null_mut();
+        if unsafe { GetAce(acl, index, &mut ace) }.is_err() {
+            continue;
+        }
+
+        let header = unsafe { &*(ace as *const ACE_HEADER) };
+        let allowed = match header.AceType as u32 {
+            ACCESS_ALLOWED_ACE_TYPE => true,
+            ACCESS_DENIED_ACE_TYPE => false,
+            _ => continue,
+        };
+
+        // Denied ACEs share the layout of allowed ones
+        let ace = unsafe { &*(ace as *const ACCESS_ALLOWED_ACE) };
+        if ace.Mask & TRACELOG_ACCESS_REALTIME == 0 {
+            continue;
+        }
+
+        let sid = PSID(&ace.SidStart as *const u32 as *mut c_void);
+        let sid_bytes =
+            unsafe { std::slice::from_raw_parts(sid.0 as *const u8, GetLengthSid(sid) as usize) };
+        consumers.push(ConsumerAccess {
+            sid: sid_to_string(sid_bytes).unwrap_or_default(),
+            account: account_name(sid),
+            allowed,
+        });
+    }
+
+    Ok(consumers)
+}
+
+/// The self-relative security descriptor stored for `guid`, with [`EventAccessQuery`]
+fn query_descriptor(guid: &GUID) -> EtwResult<Vec<u8>> {
+    let mut buffer_size = 0u32;
+    let status = WIN32_ERROR(unsafe {
+        EventAccessQuery(guid, PSECURITY_DESCRIPTOR::default(), &mut buffer_size)
+    });
+    if status != ERROR_MORE_DATA && status != ERROR_SUCCESS {
+        return Err(EtwError::Win32 {
+            status,
+            context: format!("EventAccessQuery found no security descriptor for {guid:?}"),
+        });
+    }
+
+    let mut buffer = vec![0u8; buffer_size as usize];
+    let status = WIN32_ERROR(unsafe {
+        EventAccessQuery(
+            guid,
+            PSECURITY_DESCRIPTOR(buffer.as_mut_ptr() as *mut c_void),
+            &mut buffer_size,
+        )
+    });
+
+    match status {
+        ERROR_SUCCESS => Ok(buffer),
+        status => Err(EtwError::Win32 {
+            status,
+            context: format!("EventAccessQuery could not read the security descriptor of {guid:?}"),
+        }),
+    }
+}
+
+/// DOMAIN\name of `sid`, with [`LookupAccountSidW`]
+fn account_name(sid: PSID) -> Option<String> {
+    let mut name = [0u16; 256];
+    let mut name_len = name.len() as u32;
+    let mut domain = [0u16; 256];
+    let mut domain_len = domain.len() as u32;
+    let mut name_use = SID_NAME_USE::default();
+
+    unsafe {
+        LookupAccountSidW(
+            PCWSTR::null(),
+            sid,
+            PWSTR(name.as_mut_ptr()),
+            &mut name_len,
+            PWSTR(domain.as_mut_ptr()),
+            &mut domain_len,
+            &mut name_use,
+        )
+    }
+    .ok()?;
+
+    let name = String::from_utf16_lossy(&name[..name_len as usize]);
+    let domain = String::from_utf16_lossy(&domain[..domain_len as usize]);
+    Some(if domain.is_empty() {
+        name
+    } else {
+        format!("{domain}\\{name}")
+    })
+}
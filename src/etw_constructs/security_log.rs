@@ -0,0 +1,238 @@
+use std::{
+    collections::BTreeMap,
+    ffi::c_void,
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+};
+
+use windows::{
+    core::{w, PCWSTR},
+    Win32::{
+        Foundation::{HANDLE, WIN32_ERROR},
+        System::EventLog::{
+            EvtClose, EvtRender, EvtRenderEventXml, EvtSubscribe, EvtSubscribeActionDeliver,
+            EvtSubscribeToFutureEvents, EVT_HANDLE, EVT_SUBSCRIBE_NOTIFY_ACTION,
+        },
+    },
+};
+
+use super::{
+    clock,
+    error::{EtwError, EtwResult},
+    parsed_event::{EventArchitecture, ParsedEvent, PropertyValue},
+    privilege::SECURITY_AUDITING_GUID,
+};
+
+/// The privilege use and token events [`PrivilegeEvent`](super::privilege::PrivilegeEvent) reads, and the process
+/// creation and exit events [`CanonicalEvent`](super::taxonomy::CanonicalEvent) reads
+const SECURITY_QUERY: PCWSTR = w!(
+    "*[System[Provider[@Name='Microsoft-Windows-Security-Auditing'] and \
+     (EventID=4672 or EventID=4673 or EventID=4674 or EventID=4688 or EventID=4689 or EventID=4696 or \
+     EventID=4703)]]"
+);
+
+/// Reads Security-Auditing privilege and process events out of the Security event log as they are written. Only the
+/// EventLog-Security session receives the provider's events, so enabling it on a session of this tool delivers
+/// nothing. Reading the Security log needs admin rights.
+///
+/// Events are turned into [`ParsedEvent`]s with the same property names as their manifest, each value a string or, for
+/// numbers, unsigned. Their sequence is 0, since they do not come from a trace
+pub struct SecurityLog {
+    subscription: EVT_HANDLE,
+    _sender: Box<Sender<ParsedEvent>>, // Handed to the subscription callback, so it must outlive the subscription
+    receiver: Receiver<ParsedEvent>,
+}
+
+// The subscription handle is only closed in Drop
+unsafe impl Send for SecurityLog {}
+
+impl SecurityLog {
+    /// Subscribes to the events written to the Security log from now on
+    pub fn subscribe() -> EtwResult<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let sender = Box::new(sender);
+
+        let subscription = unsafe {
+            EvtSubscribe(
+                EVT_HANDLE::default(),
+                HANDLE::default(),
+                w!("Security"),
+                SECURITY_QUERY,
+                EVT_HANDLE::default(),
+                Some(&*sender as *const Sender<ParsedEvent> as *const c_void),
+                Some(on_security_event),
+                EvtSubscribeToFutureEvents.0,
+            )
+        }
+        .map_err(|err| EtwError::Win32 {
+            status: WIN32_ERROR::from_error(&err).unwrap_or_default(),
+            context: "Could not subscribe to the Security event log".to_string(),
+        })?;
+
+        Ok(Self {
+            subscription,
+            _sender: sender,
+            receiver,
+        })
+    }
+
+    /// The next event read, if one is waiting
+    pub fn try_next(&self) -> Option<ParsedEvent> {
+        match self.receiver.try_recv() {
+            Ok(event) => Some(event),
+            Err(TryRecvError::Empty | TryRecvError::Disconnected) => None,
+        }
+    }
+
+    /// Turns the XML rendering of a Security-Auditing event into a [`ParsedEvent`]. None if it is not one
+    pub fn parse_xml(xml: &str) -> Option<ParsedEvent> {
+        let event_id = Self::_element(xml, "EventID")?.parse().ok()?;
+        let version = Self::_element(xml, "Version")
+            .and_then(|version| version.parse().ok())
+            .unwrap_or_default();
+        let timestamp = Self::_attribute(xml, "TimeCreated", "SystemTime")
+            .and_then(|time| clock::ticks_from_rfc3339(&time))
+            .unwrap_or_default();
+        let execution = |name: &str| {
+            Self::_attribute(xml, "Execution", name)
+                .and_then(|id| id.parse().ok())
+                .unwrap_or_default()
+        };
+
+        let mut properties = BTreeMap::new();
+        let mut rest = xml.split_once("<EventData>").map_or("", |(_, rest)| rest);
+        while let Some(start) = rest.find("<Data Name=") {
+            rest = &rest[start + "<Data Name=".len()..];
+            let quote = rest.chars().next()?;
+            let (name, after_name) = rest[1..].split_once(quote)?;
+            let value = match after_name.strip_prefix("/>") {
+                Some(_) => String::new(),
+                None => after_name
+                    .strip_prefix('>')?
+                    .split_once("</Data>")?
+                    .0
+                    .to_string(),
+            };
+            properties.insert(
+                Self::_unescape(name),
+                Self::_value(&Self::_unescape(&value)),
+            );
+        }
+
+        Some(ParsedEvent {
+            provider: SECURITY_AUDITING_GUID,
+            event_id,
+            opcode: 0,
+            version,
+            schema_hash: None,
+            schema_fields: None,
+            process_id: execution("ProcessID"),
+            thread_id: execution("ThreadID"),
+            timestamp,
+            time: clock::system_time_from_ticks(timestamp),
+            sequence: 0,
+            architecture: EventArchitecture::default(),
+            properties,
+            stack: Vec::new(),
+            task_name: None,
+            event_name: None,
+            extended: Vec::new(),
+        })
+    }
+
+    /// Numbers are logged as decimal or as 0x prefixed hex, such as logon ids and process ids
+    fn _value(value: &str) -> PropertyValue {
+        let number = match value.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => {
+                value.parse().ok()
+            }
+            None => None,
+        };
+        number.map_or_else(
+            || PropertyValue::String(value.to_string()),
+            PropertyValue::Unsigned,
+        )
+    }
+
+    /// The text of the first `<name>` element, which may have attributes
+    fn _element(xml: &str, name: &str) -> Option<String> {
+        let start = xml.find(&format!("<{name}"))?;
+        let after = &xml[start + name.len() + 1..];
+        let text = after[after.find('>')? + 1..].split_once('<')?.0;
+        Some(Self::_unescape(text))
+    }
+
+    /// The value of `attribute` of the first `<element>`
+    fn _attribute(xml: &str, element: &str, attribute: &str) -> Option<String> {
+        let start = xml.find(&format!("<{element} "))?;
+        let tag = xml[start..].split_once('>')?.0;
+        let after = &tag[tag.find(&format!(" {attribute}="))? + attribute.len() + 2..];
+        let quote = after.chars().next()?;
+        Some(Self::_unescape(after[1..].split_once(quote)?.0))
+    }
+
+    fn _unescape(text: &str) -> String {
+        text.replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&")
+    }
+}
+
+impl Drop for SecurityLog {
+    fn drop(&mut self) {
+        // Closing the subscription waits for a callback that is running, so the sender is not freed under it
+        let _ = unsafe { EvtClose(self.subscription) };
+    }
+}
+
+/// Renders each delivered event as XML and sends it on the channel given as the subscription context
+unsafe extern "system" fn on_security_event(
+    action: EVT_SUBSCRIBE_NOTIFY_ACTION,
+    context: *const c_void,
+    event: EVT_HANDLE,
+) -> u32 {
+    if action != EvtSubscribeActionDeliver {
+        return 0;
+    }
+    let Some(sender) = (unsafe { (context as *const Sender<ParsedEvent>).as_ref() }) else {
+        return 0;
+    };
+
+    let (mut used, mut property_count) = (0, 0);
+    // The first call only reports the size needed
+    let _ = unsafe {
+        EvtRender(
+            EVT_HANDLE::default(),
+            event,
+            EvtRenderEventXml.0,
+            0,
+            None,
+            &mut used,
+            &mut property_count,
+        )
+    };
+    let mut buffer = vec![0u16; (used as usize).div_ceil(2)];
+    if unsafe {
+        EvtRender(
+            EVT_HANDLE::default(),
+            event,
+            EvtRenderEventXml.0,
+            (buffer.len() * 2) as u32,
+            Some(buffer.as_mut_ptr() as *mut c_void),
+            &mut used,
+            &mut property_count,
+        )
+    }
+    .is_err()
+    {
+        return 0;
+    }
+
+    let xml = String::from_utf16_lossy(buffer.split(|c| *c == 0).next().unwrap_or_default());
+    if let Some(parsed) = SecurityLog::parse_xml(&xml) {
+        let _ = sender.send(parsed);
+    }
+    0
+}
@@ -0,0 +1,95 @@
+use windows::{
+    core::GUID,
+    Win32::System::Diagnostics::Etw::{
+        EVENT_RECORD, EVENT_TRACE_PROPERTIES, EVENT_TRACE_USE_PAGED_MEMORY,
+    },
+};
+
+/// Provider GUID of the notifications ETW sends real-time consumers when events were dropped before reaching them
+pub const RT_LOST_EVENT_GUID: GUID = GUID::from_u128(0x6a399ae0_4bc6_4de9_870b_3657f8947e7e);
+
+// Opcodes of the RTLostEvent class
+const OPCODE_RT_LOST_EVENT: u8 = 32;
+const OPCODE_RT_LOST_BUFFER: u8 = 33;
+const OPCODE_RT_LOST_FILE: u8 = 34;
+
+/// Counters of a running session, as returned by `ControlTrace(QUERY)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SessionStats {
+    /// Events dropped because every buffer was full. Nonzero means the capture is incomplete
+    pub events_lost: u32,
+    /// Buffers that could not be written to the log file
+    pub log_buffers_lost: u32,
+    /// Buffers that could not be delivered to real-time consumers, usually because they were too slow
+    pub real_time_buffers_lost: u32,
+    pub buffers_written: u32,
+    pub number_of_buffers: u32,
+    pub free_buffers: u32,
+    /// Size of each buffer in KB
+    pub buffer_size_kb: u32,
+    /// Whether the buffers come from paged pool rather than nonpaged pool
+    pub paged_memory: bool,
+}
+
+impl SessionStats {
+    /// Whether the session dropped anything, in which case the capture is missing events
+    pub fn has_losses(&self) -> bool {
+        self.events_lost != 0 || self.log_buffers_lost != 0 || self.real_time_buffers_lost != 0
+    }
+
+    /// Memory taken by the buffers allocated so far, from the pool given by [`SessionStats::paged_memory`]
+    pub fn buffer_memory_bytes(&self) -> u64 {
+        self.number_of_buffers as u64 * self.buffer_size_kb as u64 * 1024
+    }
+}
+
+impl From<&EVENT_TRACE_PROPERTIES> for SessionStats {
+    fn from(properties: &EVENT_TRACE_PROPERTIES) -> Self {
+        Self {
+            events_lost: properties.EventsLost,
+            log_buffers_lost: properties.LogBuffersLost,
+            real_time_buffers_lost: properties.RealTimeBuffersLost,
+            buffers_written: properties.BuffersWritten,
+            number_of_buffers: properties.NumberOfBuffers,
+            free_buffers: properties.FreeBuffers,
+            buffer_size_kb: properties.BufferSize,
+            paged_memory: properties.LogFileMode & EVENT_TRACE_USE_PAGED_MEMORY != 0,
+        }
+    }
+}
+
+/// A notification that ETW dropped data before it reached the consumer. Delivered through the event callback
+/// like any other event, but never filtered out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LostEvent {
+    /// One or more events were lost
+    Events,
+    /// A whole buffer of events was lost
+    Buffer,
+    /// The backing file of the real-time session could not be read, so everything in it was lost
+    File,
+}
+
+impl LostEvent {
+    /// Returns the notification if `record` is one
+    pub fn from_record(record: &EVENT_RECORD) -> Option<Self> {
+        if record.EventHeader.ProviderId != RT_LOST_EVENT_GUID {
+            return None;
+        }
+
+        match record.EventHeader.EventDescriptor.Opcode {
+            OPCODE_RT_LOST_EVENT => Some(Self::Events),
+            OPCODE_RT_LOST_BUFFER => Some(Self::Buffer),
+            OPCODE_RT_LOST_FILE => Some(Self::File),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Events => "RTLostEvent",
+            Self::Buffer => "RTLostBuffer",
+            Self::File => "RTLostFile",
+        }
+    }
+}
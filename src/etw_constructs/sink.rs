@@ -0,0 +1,507 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use windows::core::GUID;
+
+use super::{
+    capture_header::CaptureHeader,
+    clock,
+    error::{EtwError, EtwResult},
+    event_filter::EventFilter,
+    mapped_file::{MappedFile, MappedFileConfig},
+    parsed_event::{serialize_guid, serialize_schema_hash, ParsedEvent, PropertyValue},
+    schema_cache::SchemaField,
+};
+
+/// Somewhere decoded events are written to, such as a file or stdout
+pub trait EventSink {
+    fn write(&mut self, event: &ParsedEvent) -> EtwResult<()>;
+
+    /// Writes out anything still buffered
+    fn flush(&mut self) -> EtwResult<()>;
+
+    /// Writes `header` at the start of the output. Called once, before the first event. Sinks with nowhere to put it
+    /// ignore it
+    fn write_header(&mut self, _header: &CaptureHeader) -> EtwResult<()> {
+        Ok(())
+    }
+
+    /// Writes out anything that only goes at the end, such as the schema manifest, then flushes. Called once the last
+    /// event is written
+    fn close(&mut self) -> EtwResult<()> {
+        self.flush()
+    }
+}
+
+/// The fields of one event schema, from the first event seen with it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SchemaEntry {
+    #[serde(serialize_with = "serialize_guid")]
+    pub provider: GUID,
+    pub event_id: u16,
+    pub opcode: u8,
+    pub version: u8,
+    #[serde(serialize_with = "serialize_schema_hash")]
+    pub schema_hash: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_name: Option<String>,
+    /// The top level properties in the order they are laid out, with their TDH types
+    pub fields: Vec<SchemaField>,
+}
+
+/// Every event schema written to an output, so downstream parsers can tell which field layout each event has when a
+/// provider update changes it. Events decoded without a schema are left out
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SchemaManifest {
+    schemas: Vec<SchemaEntry>,
+    #[serde(skip)]
+    seen: HashSet<(u128, u16, u8, u8, u64)>,
+}
+
+impl SchemaManifest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the schema of `event` if it has not been seen yet
+    pub fn add(&mut self, event: &ParsedEvent) {
+        let (Some(schema_hash), Some(fields)) = (event.schema_hash, &event.schema_fields) else {
+            return;
+        };
+        let key = (
+            event.provider.to_u128(),
+            event.event_id,
+            event.opcode,
+            event.version,
+            schema_hash,
+        );
+        if !self.seen.insert(key) {
+            return;
+        }
+
+        self.schemas.push(SchemaEntry {
+            provider: event.provider,
+            event_id: event.event_id,
+            opcode: event.opcode,
+            version: event.version,
+            schema_hash: event.schema_hash,
+            event_name: event.event_name.clone(),
+            fields: fields.to_vec(),
+        });
+    }
+
+    /// Schemas in the order they were first seen
+    pub fn schemas(&self) -> &[SchemaEntry] {
+        &self.schemas
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.schemas.is_empty()
+    }
+
+    /// Where the manifest of the output at `path` goes: a file named after it with `.schemas.json` appended. None for
+    /// stdout
+    fn path_for(path: &Path) -> Option<PathBuf> {
+        (path != Path::new("-")).then(|| {
+            let mut manifest_path = path.as_os_str().to_owned();
+            manifest_path.push(".schemas.json");
+            PathBuf::from(manifest_path)
+        })
+    }
+
+    /// Writes the manifest as JSON to `path`, unless no schema was seen
+    fn write_to(&self, path: Option<&Path>) -> EtwResult<()> {
+        let Some(path) = path.filter(|_| !self.is_empty()) else {
+            return Ok(());
+        };
+        let manifest_error = |err: io::Error| {
+            EtwError::from_io(
+                &err,
+                format!("Could not write the schema manifest to {:?}", path),
+            )
+        };
+
+        let mut writer = BufWriter::new(File::create(path).map_err(manifest_error)?);
+        serde_json::to_writer_pretty(&mut writer, self)
+            .map_err(io::Error::from)
+            .and_then(|()| writer.flush())
+            .map_err(manifest_error)
+    }
+}
+
+/// Opens `path` for writing, or stdout if `path` is `-`
+pub fn open_output(path: &Path) -> EtwResult<Box<dyn Write + Send>> {
+    if path == Path::new("-") {
+        return Ok(Box::new(io::stdout()));
+    }
+
+    File::create(path)
+        .map(|file| Box::new(BufWriter::new(file)) as Box<dyn Write + Send>)
+        .map_err(|err| EtwError::from_io(&err, format!("Could not create {:?}", path)))
+}
+
+/// Writes one JSON object per line, ready to be piped into jq or a log shipper. The capture header is the first line,
+/// of the form `{"capture_header": {...}}`, so every other line is an event.
+/// Closing it writes the schema manifest next to the file as JSON, in a file named after it with `.schemas.json`
+/// appended. None is written when writing to stdout
+pub struct JsonLinesSink {
+    writer: Box<dyn Write + Send>,
+    manifest: SchemaManifest,
+    manifest_path: Option<PathBuf>,
+}
+
+impl JsonLinesSink {
+    /// Writes to the file at `path`, or stdout if `path` is `-`
+    pub fn create(path: &Path) -> EtwResult<Self> {
+        Ok(Self {
+            writer: open_output(path)?,
+            manifest: SchemaManifest::new(),
+            manifest_path: SchemaManifest::path_for(path),
+        })
+    }
+
+    /// Writes to the file at `path` through a [`MappedFile`], for event rates a buffered writer cannot keep up with
+    pub fn create_mapped(path: &Path, config: MappedFileConfig) -> EtwResult<Self> {
+        Ok(Self {
+            writer: Box::new(MappedFile::create(path, config)?),
+            manifest: SchemaManifest::new(),
+            manifest_path: SchemaManifest::path_for(path),
+        })
+    }
+
+    pub fn stdout() -> Self {
+        Self {
+            writer: Box::new(io::stdout()),
+            manifest: SchemaManifest::new(),
+            manifest_path: None,
+        }
+    }
+}
+
+impl EventSink for JsonLinesSink {
+    fn write(&mut self, event: &ParsedEvent) -> EtwResult<()> {
+        self.manifest.add(event);
+        serde_json::to_writer(&mut self.writer, event)
+            .map_err(io::Error::from)
+            .and_then(|()| self.writer.write_all(b"\n"))
+            .map_err(|err| EtwError::from_io(&err, "Could not write the event as JSON"))
+    }
+
+    fn flush(&mut self) -> EtwResult<()> {
+        self.writer
+            .flush()
+            .map_err(|err| EtwError::from_io(&err, "Could not flush the JSON output"))
+    }
+
+    fn write_header(&mut self, header: &CaptureHeader) -> EtwResult<()> {
+        serde_json::to_writer(
+            &mut self.writer,
+            &serde_json::json!({ "capture_header": header }),
+        )
+        .map_err(io::Error::from)
+        .and_then(|()| self.writer.write_all(b"\n"))
+        .map_err(|err| EtwError::from_io(&err, "Could not write the capture header"))
+    }
+
+    fn close(&mut self) -> EtwResult<()> {
+        self.flush()?;
+        self.manifest.write_to(self.manifest_path.as_deref())
+    }
+}
+
+/// Writes one row per event. Events have different properties, so they all go in one column as a JSON object. The
+/// schema version and hash are the last columns, so readers of the earlier layout find every other column where it was.
+/// The capture header comes before the column names, as a comment line of the form `# capture_header: {...}` that
+/// readers skip with their comment option, e.g. `comment='#'` in pandas.
+/// A CSV file has no room for the schema manifest, so closing it writes the manifest next to it as JSON, in a file
+/// named after it with `.schemas.json` appended. None is written when writing to stdout
+pub struct CsvSink {
+    writer: Box<dyn Write + Send>,
+    wrote_header: bool,
+    manifest: SchemaManifest,
+    manifest_path: Option<PathBuf>,
+}
+
+impl CsvSink {
+    const HEADER: &'static str =
+        "sequence,timestamp,time,provider,event_id,opcode,process_id,thread_id,properties,stack,version,schema_hash";
+
+    /// Writes to the file at `path`, or stdout if `path` is `-`
+    pub fn create(path: &Path) -> EtwResult<Self> {
+        Ok(Self {
+            writer: open_output(path)?,
+            wrote_header: false,
+            manifest: SchemaManifest::new(),
+            manifest_path: SchemaManifest::path_for(path),
+        })
+    }
+
+    pub fn stdout() -> Self {
+        Self {
+            writer: Box::new(io::stdout()),
+            wrote_header: false,
+            manifest: SchemaManifest::new(),
+            manifest_path: None,
+        }
+    }
+
+    /// Quotes `field` if it contains a comma, quote or newline, doubling any quotes inside it
+    fn escape(field: &str) -> String {
+        if field.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", field.replace('"', "\"\""))
+        } else {
+            field.to_string()
+        }
+    }
+}
+
+impl EventSink for CsvSink {
+    fn write(&mut self, event: &ParsedEvent) -> EtwResult<()> {
+        let csv_error =
+            |err: io::Error| EtwError::from_io(&err, "Could not write the event as CSV");
+
+        if !self.wrote_header {
+            writeln!(self.writer, "{}", Self::HEADER).map_err(csv_error)?;
+            self.wrote_header = true;
+        }
+        self.manifest.add(event);
+
+        let properties = serde_json::to_string(&event.properties)
+            .map_err(|err| csv_error(io::Error::from(err)))?;
+
+        writeln!(
+            self.writer,
+            "{},{},{},{},{},{},{},{},{},{},{},{}",
+            event.sequence,
+            event.timestamp,
+            clock::rfc3339_from_ticks(event.timestamp),
+            PropertyValue::Guid(event.provider),
+            event.event_id,
+            event.opcode,
+            event.process_id,
+            event.thread_id,
+            Self::escape(&properties),
+            // Space separated, so the frames need no quoting
+            event
+                .stack
+                .iter()
+                .map(|frame| format!("{frame:#x}"))
+                .collect::<Vec<_>>()
+                .join(" "),
+            event.version,
+            event
+                .schema_hash
+                .map(|hash| format!("{hash:016x}"))
+                .unwrap_or_default()
+        )
+        .map_err(csv_error)
+    }
+
+    fn flush(&mut self) -> EtwResult<()> {
+        self.writer
+            .flush()
+            .map_err(|err| EtwError::from_io(&err, "Could not flush the CSV output"))
+    }
+
+    fn write_header(&mut self, header: &CaptureHeader) -> EtwResult<()> {
+        write_comment_header(&mut self.writer, header)
+    }
+
+    fn close(&mut self) -> EtwResult<()> {
+        self.flush()?;
+        self.manifest.write_to(self.manifest_path.as_deref())
+    }
+}
+
+/// Writes `header` as a `# capture_header: {...}` line, for CSV outputs
+pub(crate) fn write_comment_header(
+    writer: &mut dyn Write,
+    header: &CaptureHeader,
+) -> EtwResult<()> {
+    let header_error =
+        |err: io::Error| EtwError::from_io(&err, "Could not write the capture header");
+
+    let header = serde_json::to_string(header).map_err(|err| header_error(io::Error::from(err)))?;
+    writeln!(writer, "# capture_header: {header}").map_err(header_error)
+}
+
+/// Several sinks, each with its own [`EventFilter`] and projection, written to as one. Sinks that share an expression
+/// share its compiled filter, so every distinct expression is evaluated once per event no matter how many sinks use it
+#[derive(Default)]
+pub struct FilteredSinks {
+    filters: Vec<EventFilter>,
+    sinks: Vec<NamedSink>,
+    matched: Vec<bool>, // Scratch space, one entry per filter
+}
+
+struct NamedSink {
+    name: String,
+    filter: usize,               // Index into `filters`
+    initial_filter: EventFilter, // The filter it was added with
+    projection: Vec<String>,     // Properties written, every property if empty
+    sink: Box<dyn EventSink>,
+}
+
+impl FilteredSinks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `sink` under `name`, which only gets the events `filter` matches
+    pub fn sink(
+        mut self,
+        name: impl Into<String>,
+        sink: Box<dyn EventSink>,
+        filter: EventFilter,
+    ) -> Self {
+        let initial_filter = filter.clone();
+        let filter = self._filter_index(filter);
+        self.sinks.push(NamedSink {
+            name: name.into(),
+            filter,
+            initial_filter,
+            projection: Vec::new(),
+            sink,
+        });
+        self
+    }
+
+    /// Swaps the filter of the sink `name` from the next event on. Returns false if there is no such sink
+    pub fn set_filter(&mut self, name: &str, filter: EventFilter) -> bool {
+        let Some(position) = self._position(name) else {
+            return false;
+        };
+        self.sinks[position].filter = self._filter_index(filter);
+
+        // Drop the filters no sink uses any more, so they are not evaluated for nothing
+        let mut filters = Vec::new();
+        for NamedSink { filter: index, .. } in &mut self.sinks {
+            let filter = &self.filters[*index];
+            *index = match filters.iter().position(|known| known == filter) {
+                Some(index) => index,
+                None => {
+                    filters.push(filter.clone());
+                    filters.len() - 1
+                }
+            };
+        }
+        self.filters = filters;
+        true
+    }
+
+    /// Puts back the filter the sink `name` was added with. Returns false if there is no such sink
+    pub fn reset_filter(&mut self, name: &str) -> bool {
+        let Some(position) = self._position(name) else {
+            return false;
+        };
+        let filter = self.sinks[position].initial_filter.clone();
+        self.set_filter(name, filter)
+    }
+
+    /// Names of the sinks, in the order they were added
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.sinks.iter().map(|sink| sink.name.as_str())
+    }
+
+    /// Only writes the properties named in `properties` to the sink `name` from the next event on, or every property
+    /// if it is empty. The header fields of events are always written. Returns false if there is no such sink
+    pub fn set_projection(&mut self, name: &str, properties: Vec<String>) -> bool {
+        let Some(position) = self._position(name) else {
+            return false;
+        };
+        self.sinks[position].projection = properties;
+        true
+    }
+
+    fn _position(&self, name: &str) -> Option<usize> {
+        self.sinks.iter().position(|sink| sink.name == name)
+    }
+
+    fn _filter_index(&mut self, filter: EventFilter) -> usize {
+        match self.filters.iter().position(|known| *known == filter) {
+            Some(index) => index,
+            None => {
+                self.filters.push(filter);
+                self.filters.len() - 1
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+
+    /// Runs `action` on every sink, returning the first error only once all of them have had their turn
+    fn _each(
+        &mut self,
+        mut action: impl FnMut(&mut dyn EventSink) -> EtwResult<()>,
+    ) -> EtwResult<()> {
+        let mut result = Ok(());
+        for NamedSink { sink, .. } in &mut self.sinks {
+            if let (Err(err), Ok(())) = (action(sink.as_mut()), &result) {
+                result = Err(err);
+            }
+        }
+        result
+    }
+}
+
+/// A sink that fails to write an event does not keep it from the other sinks
+impl EventSink for FilteredSinks {
+    fn write(&mut self, event: &ParsedEvent) -> EtwResult<()> {
+        self.matched.clear();
+        self.matched
+            .extend(self.filters.iter().map(|filter| filter.matches(event)));
+
+        let mut result = Ok(());
+        for NamedSink {
+            filter,
+            projection,
+            sink,
+            ..
+        } in &mut self.sinks
+        {
+            if !self.matched[*filter] {
+                continue;
+            }
+            let written = if projection.is_empty() {
+                sink.write(event)
+            } else {
+                let mut projected = event.clone();
+                projected
+                    .properties
+                    .retain(|name, _| projection.iter().any(|kept| kept == name));
+                sink.write(&projected)
+            };
+            if let (Err(err), Ok(())) = (written, &result) {
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    fn flush(&mut self) -> EtwResult<()> {
+        self._each(|sink| sink.flush())
+    }
+
+    fn close(&mut self) -> EtwResult<()> {
+        self._each(|sink| sink.close())
+    }
+
+    /// Each sink gets the header with its own filter
+    fn write_header(&mut self, header: &CaptureHeader) -> EtwResult<()> {
+        let mut result = Ok(());
+        for NamedSink { filter, sink, .. } in &mut self.sinks {
+            let header = header.with_filter(self.filters[*filter].expression());
+            if let (Err(err), Ok(())) = (sink.write_header(&header), &result) {
+                result = Err(err);
+            }
+        }
+        result
+    }
+}
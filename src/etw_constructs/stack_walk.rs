@@ -0,0 +1,229 @@
+use core::slice;
+use std::{
+    collections::HashMap,
+    ffi::c_void,
+    mem,
+    sync::{Mutex, MutexGuard},
+};
+
+use windows::{
+    core::GUID,
+    Win32::{
+        Foundation::ERROR_SUCCESS,
+        System::Diagnostics::Etw::{
+            TraceSetInformation, TraceStackTracingInfo, CLASSIC_EVENT_ID, CONTROLTRACE_HANDLE,
+            EVENT_RECORD,
+        },
+    },
+};
+
+use super::{
+    error::{EtwError, EtwResult},
+    parsed_event::ParsedEvent,
+};
+
+/// Provider GUID of the StackWalk events the kernel logs after each stack traced event
+pub const STACK_WALK_GUID: GUID = GUID::from_u128(0xdef2fe46_7bd6_4b80_bd94_f57fe20d0ce3);
+
+const OPCODE_STACK: u8 = 32;
+
+/// Most events ETW can stack trace in one session
+const MAX_STACK_TRACED_EVENTS: usize = 256;
+
+/// A kernel event class and opcode to capture the call stack of, e.g. the Process GUID and opcode 1 for process starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StackTracedEvent {
+    pub provider: GUID,
+    pub opcode: u8,
+}
+
+/// Turns on stack tracing for `events` in the session with [`TraceSetInformation`]. Replaces any events set before
+pub fn enable(trace_handle: CONTROLTRACE_HANDLE, events: &[StackTracedEvent]) -> EtwResult<()> {
+    let event_ids: Vec<CLASSIC_EVENT_ID> = events
+        .iter()
+        .take(MAX_STACK_TRACED_EVENTS)
+        .map(|event| CLASSIC_EVENT_ID {
+            EventGuid: event.provider,
+            Type: event.opcode,
+            ..Default::default()
+        })
+        .collect();
+
+    let status = unsafe {
+        TraceSetInformation(
+            trace_handle,
+            TraceStackTracingInfo,
+            event_ids.as_ptr() as *const c_void,
+            (event_ids.len() * mem::size_of::<CLASSIC_EVENT_ID>()) as u32,
+        )
+    };
+
+    match status {
+        ERROR_SUCCESS => Ok(()),
+        status => Err(EtwError::Win32 {
+            status,
+            context: format!(
+                "TraceSetInformation could not enable stack tracing for {} events",
+                event_ids.len()
+            ),
+        }),
+    }
+}
+
+/// The call stack of an earlier event, logged by the kernel right after it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackWalk {
+    /// Timestamp of the event the stack belongs to
+    pub event_timestamp: i64,
+    pub process_id: u32,
+    pub thread_id: u32,
+    /// Return addresses, innermost frame first. Kernel frames come before user frames
+    pub frames: Vec<u64>,
+}
+
+impl StackWalk {
+    /// Returns the stack if `record` is a StackWalk event. `pointer_size` is the size of each frame, 4 or 8
+    pub fn from_record(record: &EVENT_RECORD, pointer_size: u32) -> Option<Self> {
+        if record.EventHeader.ProviderId != STACK_WALK_GUID
+            || record.EventHeader.EventDescriptor.Opcode != OPCODE_STACK
+            || record.UserData.is_null()
+        {
+            return None;
+        }
+
+        // The userdata is laid out as [EventTimeStamp: u64][StackProcess: u32][StackThread: u32][frames]
+        let userdata = unsafe {
+            slice::from_raw_parts(record.UserData as *const u8, record.UserDataLength as usize)
+        };
+        let (header, frames) = userdata.split_at_checked(16)?;
+
+        Some(Self {
+            event_timestamp: i64::from_le_bytes(header[..8].try_into().ok()?),
+            process_id: u32::from_le_bytes(header[8..12].try_into().ok()?),
+            thread_id: u32::from_le_bytes(header[12..].try_into().ok()?),
+            frames: frames
+                .chunks_exact(pointer_size as usize)
+                .map(|frame| {
+                    let mut bytes = [0u8; 8];
+                    bytes[..frame.len()].copy_from_slice(frame);
+                    u64::from_le_bytes(bytes)
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Holds back stack traced events until their [`StackWalk`] arrives, then attaches its frames to them.
+/// The stack follows its event on the same thread, so one event is held per thread at most. Events whose stack has not
+/// come [`HOLD_DEADLINE`] after them, by the timestamps of the events seen since, are released without it, and so are
+/// the oldest once [`MAX_HELD_EVENTS`] are held
+#[derive(Debug, Default)]
+pub struct StackCorrelator {
+    traced: Vec<StackTracedEvent>,
+    pending: Mutex<HashMap<u32, ParsedEvent>>, // Keyed on thread id
+    last_sweep: Mutex<i64>, // Timestamp of the event held events were last checked against
+}
+
+/// How long after its event a stack may come, in FILETIME ticks (1s). The kernel logs it right after the event, so a
+/// stack that late was lost
+pub const HOLD_DEADLINE: i64 = 10_000_000;
+/// Most events held at once, one per thread
+pub const MAX_HELD_EVENTS: usize = 4096;
+
+impl StackCorrelator {
+    pub fn new(traced: Vec<StackTracedEvent>) -> Self {
+        Self {
+            traced,
+            pending: Mutex::default(),
+            last_sweep: Mutex::default(),
+        }
+    }
+
+    /// Holds `event` if it is stack traced, otherwise hands it to `release`. Held events that will not get their stack
+    /// are handed to `release` first: the one `event` replaces on the same thread, those past [`HOLD_DEADLINE`] and the
+    /// oldest past [`MAX_HELD_EVENTS`]
+    pub fn hold(&self, event: ParsedEvent, mut release: impl FnMut(ParsedEvent)) {
+        for expired in self._expired(event.timestamp) {
+            release(expired);
+        }
+
+        let is_traced = self
+            .traced
+            .iter()
+            .any(|traced| traced.provider == event.provider && traced.opcode == event.opcode);
+        if !is_traced {
+            release(event);
+            return;
+        }
+
+        let mut pending = self._pending();
+        let oldest = (pending.len() >= MAX_HELD_EVENTS && !pending.contains_key(&event.thread_id))
+            .then(|| {
+                pending
+                    .iter()
+                    .min_by_key(|(_, held)| held.timestamp)
+                    .map(|(thread_id, _)| *thread_id)
+            })
+            .flatten()
+            .and_then(|thread_id| pending.remove(&thread_id));
+        let replaced = pending.insert(event.thread_id, event);
+        drop(pending);
+
+        oldest.into_iter().chain(replaced).for_each(release);
+    }
+
+    /// Attaches `stack` to the held event it belongs to and returns that event. None if no held event matches
+    pub fn attach(&self, stack: StackWalk) -> Option<ParsedEvent> {
+        let mut pending = self._pending();
+        let held = pending.get(&stack.thread_id)?;
+        if held.timestamp != stack.event_timestamp {
+            return None;
+        }
+
+        let mut event = pending.remove(&stack.thread_id)?;
+        event.stack = stack.frames;
+        Some(event)
+    }
+
+    /// Every event still held, for when the trace ends
+    pub fn drain(&self) -> Vec<ParsedEvent> {
+        let mut events: Vec<ParsedEvent> =
+            self._pending().drain().map(|(_, event)| event).collect();
+        events.sort_by_key(|event| event.timestamp);
+        events
+    }
+
+    /// Takes out the held events logged more than [`HOLD_DEADLINE`] before `now`, oldest first. They are only looked
+    /// for every tenth of the deadline, so most events do not scan the held ones
+    fn _expired(&self, now: i64) -> Vec<ParsedEvent> {
+        {
+            let mut last_sweep = self
+                .last_sweep
+                .lock()
+                .expect("Stack correlator lock was poisoned");
+            if now - *last_sweep < HOLD_DEADLINE / 10 {
+                return Vec::new();
+            }
+            *last_sweep = now;
+        }
+
+        let mut pending = self._pending();
+        let expired_threads: Vec<u32> = pending
+            .iter()
+            .filter(|(_, held)| now - held.timestamp > HOLD_DEADLINE)
+            .map(|(thread_id, _)| *thread_id)
+            .collect();
+        let mut expired: Vec<ParsedEvent> = expired_threads
+            .iter()
+            .filter_map(|thread_id| pending.remove(thread_id))
+            .collect();
+        expired.sort_by_key(|event| event.timestamp);
+        expired
+    }
+
+    fn _pending(&self) -> MutexGuard<'_, HashMap<u32, ParsedEvent>> {
+        self.pending
+            .lock()
+            .expect("Stack correlator lock was poisoned")
+    }
+}
@@ -0,0 +1,123 @@
+use std::{
+    ffi::CStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use windows::Win32::System::Diagnostics::Etw::{CloseTrace, PROCESSTRACE_HANDLE};
+
+use super::controller::Controller;
+
+/// Stop state shared between a session, its consumer's buffer callback and every [`StopHandle`]
+#[derive(Debug, Default)]
+pub(crate) struct StopState {
+    stopped: AtomicBool,
+    trace_closed: AtomicBool,
+}
+
+impl StopState {
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Calls [`CloseTrace`] on `reghandle` unless it has already been closed
+    pub(crate) fn close_trace(&self, reghandle: PROCESSTRACE_HANDLE) {
+        if reghandle.Value != 0 && !self.trace_closed.swap(true, Ordering::AcqRel) {
+            unsafe {
+                let _ = CloseTrace(reghandle);
+            }
+        }
+    }
+}
+
+/// Stops one session from any thread, without touching any other session running in the process
+#[derive(Debug, Clone)]
+pub struct StopHandle {
+    session_name: Option<&'static CStr>, // None when replaying a recorded .etl file
+    reghandle: Option<PROCESSTRACE_HANDLE>, // None when the controller only logs to a file
+    state: Arc<StopState>,
+    merged: Vec<StopHandle>, // Stopped along with this handle
+    keep_session: bool,      // Whether only the consumer is stopped, leaving the session running
+}
+
+impl StopHandle {
+    pub(crate) fn new(
+        session_name: Option<&'static CStr>,
+        reghandle: Option<PROCESSTRACE_HANDLE>,
+        state: Arc<StopState>,
+    ) -> Self {
+        Self {
+            session_name,
+            reghandle,
+            state,
+            merged: Vec::new(),
+            keep_session: false,
+        }
+    }
+
+    /// Makes [`StopHandle::stop`] and [`StopHandle::force_stop`] only detach the consumer, for sessions kept alive with
+    /// [`ControllerConfig::keep_alive`](super::controller::ControllerConfig::keep_alive)
+    pub(crate) fn keep_session(mut self) -> Self {
+        self.keep_session = true;
+        self
+    }
+
+    /// A handle that stops every session in `handles` at once
+    pub(crate) fn merged(handles: Vec<StopHandle>) -> Self {
+        Self {
+            merged: handles,
+            ..Self::new(None, None, Arc::default())
+        }
+    }
+
+    pub(crate) fn session_name(&self) -> Option<&'static CStr> {
+        self.session_name
+    }
+
+    /// Stops the session: the consumer's buffer callback starts returning false, the controller session is stopped with
+    /// `ControlTrace(STOP)` unless it is kept alive, and the trace is closed with [`CloseTrace`], so `ProcessTrace`
+    /// returns. Calling it again does nothing
+    pub fn stop(&self) {
+        if self.state.stopped.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        if let Some(session_name) = self.session_name.filter(|_| !self.keep_session) {
+            let _ = Controller::stop(session_name);
+        }
+
+        if let Some(reghandle) = self.reghandle {
+            self.state.close_trace(reghandle);
+        }
+
+        for handle in &self.merged {
+            handle.stop();
+        }
+    }
+
+    /// Stops the session with `ControlTrace(STOP)` and closes the trace even if [`StopHandle::stop`] was already called,
+    /// for when a graceful stop is stuck, e.g. behind a sink blocked on a full pipe. Takes none of the locks the
+    /// session's threads use, so it works from a console control handler while they are blocked holding them
+    pub fn force_stop(&self) {
+        self.state.stopped.store(true, Ordering::Release);
+
+        if let Some(session_name) = self.session_name.filter(|_| !self.keep_session) {
+            let _ = Controller::stop(session_name);
+        }
+
+        if let Some(reghandle) = self.reghandle {
+            self.state.close_trace(reghandle);
+        }
+
+        for handle in &self.merged {
+            handle.force_stop();
+        }
+    }
+
+    /// Whether [`StopHandle::stop`] has been called on this handle or any of its clones
+    pub fn is_stopped(&self) -> bool {
+        self.state.is_stopped()
+    }
+}
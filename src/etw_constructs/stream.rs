@@ -0,0 +1,93 @@
+use std::{
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use super::{
+    error::EtwResult, parsed_event::ParsedEvent, pipeline_error::PipelineErrorCounters,
+    stop_handle::StopHandle,
+};
+
+/// Events of a session that is being processed on a dedicated thread. Iterating blocks until the next event arrives,
+/// and ends once the session stops
+pub struct EventStream {
+    receiver: Receiver<ParsedEvent>,
+    stop_handle: StopHandle,
+    worker: Option<JoinHandle<EtwResult<()>>>,
+    pipeline_errors: Arc<PipelineErrorCounters>,
+}
+
+impl EventStream {
+    pub(crate) fn new(
+        receiver: Receiver<ParsedEvent>,
+        stop_handle: StopHandle,
+        worker: JoinHandle<EtwResult<()>>,
+        pipeline_errors: Arc<PipelineErrorCounters>,
+    ) -> Self {
+        Self {
+            receiver,
+            stop_handle,
+            worker: Some(worker),
+            pipeline_errors,
+        }
+    }
+
+    /// The underlying channel, for callers that want to select on it or receive with their own timeouts
+    pub fn receiver(&self) -> &Receiver<ParsedEvent> {
+        &self.receiver
+    }
+
+    /// Waits up to `timeout` for the next event. Returns [`RecvTimeoutError::Disconnected`] once the session has stopped
+    pub fn next_timeout(&self, timeout: Duration) -> Result<ParsedEvent, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout)
+    }
+
+    /// How many events were lost on their way through the stream. Events that could not be decoded are counted by the
+    /// consumer, and whatever writes the stream out should record the events it fails to write. Each of them also
+    /// shows up in the stream as a [`PIPELINE_ERROR_GUID`](super::pipeline_error::PIPELINE_ERROR_GUID) event
+    pub fn pipeline_errors(&self) -> Arc<PipelineErrorCounters> {
+        Arc::clone(&self.pipeline_errors)
+    }
+
+    /// A handle that stops the session from any thread
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop_handle.clone()
+    }
+
+    /// Stops the session. Events already in the channel can still be received
+    pub fn stop(&self) {
+        self.stop_handle.stop();
+    }
+
+    /// Stops the session and waits for the processing thread to finish, returning the result of `ProcessTrace`
+    pub fn join(mut self) -> EtwResult<()> {
+        self.stop();
+        match self.worker.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(panic)) => std::panic::resume_unwind(panic),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Iterator for EventStream {
+    type Item = ParsedEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// Stops the session if the stream is dropped without being joined, so the processing thread does not run forever
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            self.stop_handle.stop();
+            let _ = worker.join();
+        }
+    }
+}
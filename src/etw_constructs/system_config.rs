@@ -0,0 +1,379 @@
+use std::{collections::HashMap, ffi::c_void, time::SystemTime};
+
+use serde::Serialize;
+use windows::{
+    core::{s, GUID, PCWSTR, PWSTR},
+    Win32::{
+        Foundation::ERROR_SUCCESS,
+        Storage::FileSystem::{
+            GetDiskFreeSpaceExW, GetDriveTypeW, GetLogicalDriveStringsW, GetVolumeInformationW,
+        },
+        System::{
+            Diagnostics::Etw::EVENT_RECORD,
+            Memory::{GlobalMemoryStatusEx, MEMORYSTATUSEX},
+            Performance::QueryPerformanceFrequency,
+            Registry::{RegGetValueA, HKEY_LOCAL_MACHINE, RRF_RT_REG_DWORD},
+            SystemInformation::{
+                ComputerNameDnsDomain, ComputerNameNetBIOS, GetComputerNameExW,
+                GetNativeSystemInfo, GetTickCount64, COMPUTER_NAME_FORMAT,
+                PROCESSOR_ARCHITECTURE_AMD64, PROCESSOR_ARCHITECTURE_ARM64,
+                PROCESSOR_ARCHITECTURE_IA64, SYSTEM_INFO,
+            },
+        },
+    },
+};
+
+use super::{
+    clock, consumer::TraceHeaderInfo, parsed_event::ParsedEvent, validation::SystemCapabilities,
+};
+
+/// Provider of the trace header event (opcode 0) logged at the start of every kernel trace. Holds the OS build information
+pub const EVENT_TRACE_GUID: GUID = GUID::from_u128(0x68fdd900_4a3e_11d1_84f4_0000f80464e3);
+/// Provider of the SystemConfig rundown events. These are logged when a kernel session stops unless [`EVENT_TRACE_FLAG_NO_SYSCONFIG`](windows::Win32::System::Diagnostics::Etw::EVENT_TRACE_FLAG_NO_SYSCONFIG) is set
+pub const EVENT_TRACE_CONFIG_GUID: GUID = GUID::from_u128(0x01853a65_418f_4f36_aefc_dc0f1d2fd235);
+
+// https://learn.microsoft.com/en-us/windows/win32/etw/systemconfig
+const OPCODE_HEADER: u8 = 0;
+const OPCODE_CPU: u8 = 10;
+const OPCODE_PHYSICAL_DISK: u8 = 11;
+const OPCODE_LOGICAL_DISK: u8 = 12;
+const OPCODE_NIC: u8 = 13;
+
+/// Returns true if `record` is the trace header or one of the SystemConfig rundown events
+pub fn is_system_config_event(record: &EVENT_RECORD) -> bool {
+    let provider = record.EventHeader.ProviderId;
+    provider == EVENT_TRACE_CONFIG_GUID
+        || (provider == EVENT_TRACE_GUID
+            && record.EventHeader.EventDescriptor.Opcode == OPCODE_HEADER)
+}
+
+/// OS build information taken from the trace header event
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct BuildInfo {
+    pub provider_version: u32, // The build number of the OS that recorded the trace
+    pub number_of_processors: u32,
+    pub cpu_speed_mhz: u32,
+    pub pointer_size: u32,
+    pub timer_resolution: u32,
+    pub perf_freq: u64,
+    pub boot_time: u64,
+}
+
+impl From<HashMap<String, String>> for BuildInfo {
+    fn from(value: HashMap<String, String>) -> Self {
+        Self {
+            provider_version: number(&value, "ProviderVersion"),
+            number_of_processors: number(&value, "NumberOfProcessors"),
+            cpu_speed_mhz: number(&value, "CPUSpeed"),
+            pointer_size: number(&value, "PointerSize"),
+            timer_resolution: number(&value, "TimerResolution"),
+            perf_freq: number(&value, "PerfFreq"),
+            boot_time: number(&value, "BootTime"),
+        }
+    }
+}
+
+/// SystemConfig_CPU, https://learn.microsoft.com/en-us/windows/win32/etw/systemconfig-cpu
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct CpuInfo {
+    pub mhz: u32,
+    pub number_of_processors: u32,
+    pub mem_size: u32, // In MB
+    pub page_size: u32,
+    pub allocation_granularity: u32,
+    pub computer_name: String,
+    pub domain_name: String,
+    pub hyper_threading_flag: u32,
+}
+
+impl From<HashMap<String, String>> for CpuInfo {
+    fn from(value: HashMap<String, String>) -> Self {
+        Self {
+            mhz: number(&value, "MHz"),
+            number_of_processors: number(&value, "NumberOfProcessors"),
+            mem_size: number(&value, "MemSize"),
+            page_size: number(&value, "PageSize"),
+            allocation_granularity: number(&value, "AllocationGranularity"),
+            computer_name: value.get("ComputerName").cloned().unwrap_or_default(),
+            domain_name: value.get("DomainName").cloned().unwrap_or_default(),
+            hyper_threading_flag: number(&value, "HyperThreadingFlag"),
+        }
+    }
+}
+
+/// SystemConfig_PhyDisk, https://learn.microsoft.com/en-us/windows/win32/etw/systemconfig-phydisk
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct PhysicalDiskInfo {
+    pub disk_number: u32,
+    pub bytes_per_sector: u32,
+    pub sectors_per_track: u32,
+    pub tracks_per_cylinder: u32,
+    pub cylinders: u64,
+    pub partition_count: u32,
+    pub write_cache_enabled: u8,
+    pub manufacturer: String,
+    pub boot_drive_letter: String,
+}
+
+impl From<HashMap<String, String>> for PhysicalDiskInfo {
+    fn from(value: HashMap<String, String>) -> Self {
+        Self {
+            disk_number: number(&value, "DiskNumber"),
+            bytes_per_sector: number(&value, "BytesPerSector"),
+            sectors_per_track: number(&value, "SectorsPerTrack"),
+            tracks_per_cylinder: number(&value, "TracksPerCylinder"),
+            cylinders: number(&value, "Cylinders"),
+            partition_count: number(&value, "PartitionCount"),
+            write_cache_enabled: number(&value, "WriteCacheEnabled"),
+            manufacturer: value.get("Manufacturer").cloned().unwrap_or_default(),
+            boot_drive_letter: value.get("BootDriveLetter").cloned().unwrap_or_default(),
+        }
+    }
+}
+
+/// SystemConfig_LogDisk, https://learn.microsoft.com/en-us/windows/win32/etw/systemconfig-logdisk
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct LogicalDiskInfo {
+    pub disk_number: u32,
+    pub drive_letter: String,
+    pub drive_type: u32,
+    pub partition_size: u64,
+    pub file_system: String,
+}
+
+impl From<HashMap<String, String>> for LogicalDiskInfo {
+    fn from(value: HashMap<String, String>) -> Self {
+        Self {
+            disk_number: number(&value, "DiskNumber"),
+            drive_letter: value.get("DriveLetterString").cloned().unwrap_or_default(),
+            drive_type: number(&value, "DriveType"),
+            partition_size: number(&value, "PartitionSize"),
+            file_system: value.get("FileSystem").cloned().unwrap_or_default(),
+        }
+    }
+}
+
+/// SystemConfig_NIC, https://learn.microsoft.com/en-us/windows/win32/etw/systemconfig-nic
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct NicInfo {
+    pub description: String,
+    pub physical_address: String,
+    pub ip_addresses: String,
+    pub dns_server_addresses: String,
+}
+
+impl From<HashMap<String, String>> for NicInfo {
+    fn from(value: HashMap<String, String>) -> Self {
+        Self {
+            description: value.get("NICDescription").cloned().unwrap_or_default(),
+            physical_address: value.get("PhysicalAddr").cloned().unwrap_or_default(),
+            ip_addresses: value.get("IpAddresses").cloned().unwrap_or_default(),
+            dns_server_addresses: value.get("DnsServerAddresses").cloned().unwrap_or_default(),
+        }
+    }
+}
+
+/// Describes the hardware and OS a trace was recorded on. Built up from the trace header and the SystemConfig rundown events
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct MachineProfile {
+    pub build: Option<BuildInfo>,
+    pub cpu: Option<CpuInfo>,
+    pub physical_disks: Vec<PhysicalDiskInfo>,
+    pub logical_disks: Vec<LogicalDiskInfo>,
+    pub nics: Vec<NicInfo>,
+}
+
+impl MachineProfile {
+    /// Adds a decoded system config event to the profile. Events that are not understood are ignored
+    pub fn record(&mut self, event: &ParsedEvent) {
+        let provider = event.provider;
+        let opcode = event.opcode;
+        let properties = event.to_string_map();
+
+        if provider == EVENT_TRACE_GUID && opcode == OPCODE_HEADER {
+            self.build = Some(BuildInfo::from(properties));
+            return;
+        }
+
+        if provider != EVENT_TRACE_CONFIG_GUID {
+            return;
+        }
+
+        match opcode {
+            OPCODE_CPU => self.cpu = Some(CpuInfo::from(properties)),
+            OPCODE_PHYSICAL_DISK => self.physical_disks.push(PhysicalDiskInfo::from(properties)),
+            OPCODE_LOGICAL_DISK => self.logical_disks.push(LogicalDiskInfo::from(properties)),
+            OPCODE_NIC => self.nics.push(NicInfo::from(properties)),
+            _ => {}
+        }
+    }
+
+    /// Returns true if no system config events have been recorded yet
+    pub fn is_empty(&self) -> bool {
+        self.build.is_none()
+            && self.cpu.is_none()
+            && self.physical_disks.is_empty()
+            && self.logical_disks.is_empty()
+            && self.nics.is_empty()
+    }
+
+    /// The build information of the machine that recorded a trace, from the header of the .etl file. The SystemConfig
+    /// rundown events at the end of the file are still needed for the rest of the profile
+    pub fn from_trace_header(header: &TraceHeaderInfo) -> Self {
+        Self {
+            build: Some(BuildInfo {
+                provider_version: header.provider_version,
+                number_of_processors: header.number_of_processors,
+                cpu_speed_mhz: header.cpu_speed_mhz,
+                pointer_size: header.pointer_size,
+                timer_resolution: header.timer_resolution,
+                perf_freq: header.perf_freq as u64,
+                boot_time: header.boot_time as u64,
+            }),
+            ..Self::default()
+        }
+    }
+
+    /// The profile of this machine, read before a live capture starts since the SystemConfig rundown events are only
+    /// logged when a kernel session stops. Values that could not be read are left empty, and physical disks and NICs
+    /// are only known from the rundown. Logical disk drive types are those of `GetDriveTypeW`
+    pub fn detect() -> Self {
+        let mut system_info = SYSTEM_INFO::default();
+        unsafe { GetNativeSystemInfo(&mut system_info) };
+        let architecture = unsafe { system_info.Anonymous.Anonymous.wProcessorArchitecture };
+        let pointer_size = match architecture {
+            PROCESSOR_ARCHITECTURE_AMD64
+            | PROCESSOR_ARCHITECTURE_ARM64
+            | PROCESSOR_ARCHITECTURE_IA64 => 8,
+            _ => 4,
+        };
+
+        let mut memory = MEMORYSTATUSEX {
+            dwLength: std::mem::size_of::<MEMORYSTATUSEX>() as u32,
+            ..Default::default()
+        };
+        let mem_size = match unsafe { GlobalMemoryStatusEx(&mut memory) } {
+            Ok(()) => (memory.ullTotalPhys / (1024 * 1024)) as u32,
+            Err(_) => 0,
+        };
+
+        let mut perf_freq = 0i64;
+        let _ = unsafe { QueryPerformanceFrequency(&mut perf_freq) };
+        // The boot time is logged as a FILETIME, in the same 100ns ticks
+        let uptime = unsafe { GetTickCount64() } as i64 * 10_000;
+        let boot_time = clock::ticks_from_system_time(SystemTime::now()) - uptime;
+        let mhz = Self::_cpu_mhz();
+
+        Self {
+            build: Some(BuildInfo {
+                provider_version: SystemCapabilities::detect()
+                    .map(|capabilities| capabilities.build_number)
+                    .unwrap_or_default(),
+                number_of_processors: system_info.dwNumberOfProcessors,
+                cpu_speed_mhz: mhz,
+                pointer_size,
+                timer_resolution: 0,
+                perf_freq: perf_freq as u64,
+                boot_time: boot_time as u64,
+            }),
+            cpu: Some(CpuInfo {
+                mhz,
+                number_of_processors: system_info.dwNumberOfProcessors,
+                mem_size,
+                page_size: system_info.dwPageSize,
+                allocation_granularity: system_info.dwAllocationGranularity,
+                computer_name: Self::_computer_name(ComputerNameNetBIOS),
+                domain_name: Self::_computer_name(ComputerNameDnsDomain),
+                hyper_threading_flag: 0,
+            }),
+            physical_disks: Vec::new(),
+            logical_disks: Self::_logical_disks(),
+            nics: Vec::new(),
+        }
+    }
+
+    /// The nominal speed of the first processor. 0 if it could not be read
+    fn _cpu_mhz() -> u32 {
+        let mut mhz = 0u32;
+        let mut size = std::mem::size_of::<u32>() as u32;
+        let status = unsafe {
+            RegGetValueA(
+                HKEY_LOCAL_MACHINE,
+                s!("HARDWARE\\DESCRIPTION\\System\\CentralProcessor\\0"),
+                s!("~MHz"),
+                RRF_RT_REG_DWORD,
+                None,
+                Some(&mut mhz as *mut u32 as *mut c_void),
+                Some(&mut size),
+            )
+        };
+        if status == ERROR_SUCCESS {
+            mhz
+        } else {
+            0
+        }
+    }
+
+    /// Empty if the name could not be read
+    fn _computer_name(format: COMPUTER_NAME_FORMAT) -> String {
+        let mut buf = [0u16; 256];
+        let mut size = buf.len() as u32;
+        match unsafe { GetComputerNameExW(format, PWSTR(buf.as_mut_ptr()), &mut size) } {
+            Ok(()) => String::from_utf16_lossy(&buf[..size as usize]),
+            Err(_) => String::new(),
+        }
+    }
+
+    /// Every drive letter in use, with the size and file system of the volumes that are mounted
+    fn _logical_disks() -> Vec<LogicalDiskInfo> {
+        let mut buf = [0u16; 512];
+        let len = unsafe { GetLogicalDriveStringsW(Some(&mut buf)) } as usize;
+        if len == 0 || len > buf.len() {
+            return Vec::new();
+        }
+
+        // A list of null terminated roots such as C:\, ending with an empty one
+        buf[..len]
+            .split(|c| *c == 0)
+            .filter(|root| !root.is_empty())
+            .map(|root| {
+                let root: Vec<u16> = root.iter().copied().chain(std::iter::once(0)).collect();
+                let root_path = PCWSTR(root.as_ptr());
+
+                let mut file_system = [0u16; 64];
+                let file_system = match unsafe {
+                    GetVolumeInformationW(root_path, None, None, None, None, Some(&mut file_system))
+                } {
+                    Ok(()) => String::from_utf16_lossy(
+                        file_system.split(|c| *c == 0).next().unwrap_or_default(),
+                    ),
+                    Err(_) => String::new(),
+                };
+                let mut partition_size = 0u64;
+                let _ = unsafe {
+                    GetDiskFreeSpaceExW(root_path, None, Some(&mut partition_size), None)
+                };
+
+                LogicalDiskInfo {
+                    disk_number: 0,
+                    drive_letter: String::from_utf16_lossy(&root[..2]),
+                    drive_type: unsafe { GetDriveTypeW(root_path) },
+                    partition_size,
+                    file_system,
+                }
+            })
+            .collect()
+    }
+}
+
+/// TdhFormatProperty formats numbers as decimal, except for hex out types which are prefixed with 0x
+fn number<T: TryFrom<u64> + Default>(properties: &HashMap<String, String>, name: &str) -> T {
+    properties
+        .get(name)
+        .and_then(|val| match val.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => val.parse().ok(),
+        })
+        .and_then(|val| T::try_from(val).ok())
+        .unwrap_or_default()
+}
@@ -0,0 +1,380 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    mem,
+};
+
+use windows::core::GUID;
+
+use super::{
+    parsed_event::{ParsedEvent, PropertyValue},
+    privilege::SECURITY_AUDITING_GUID,
+    schemas::{Fields, ProcessEvent, ProcessOpcode},
+};
+
+/// Provider GUID of canonical events, see [`CanonicalEvent::to_event`]
+pub const TAXONOMY_GUID: GUID = GUID::from_u128(0x3b8e5f2a_9c41_4d7e_b6a0_5e2d1c8f7a94);
+
+/// Microsoft-Windows-Kernel-Process. Logs process starts and stops like the kernel Process class, without needing the
+/// kernel logger
+pub const KERNEL_PROCESS_GUID: GUID = GUID::from_u128(0x22fb2cd6_0e7b_422b_a0c7_2fad1fd0e716);
+
+// Kernel-Process events, with the WINEVENT_KEYWORD_PROCESS keyword
+const EVENT_PROCESS_START: u16 = 1;
+const EVENT_PROCESS_STOP: u16 = 2;
+
+// Security-Auditing events of the Security channel, with Audit Process Creation and Termination turned on
+const EVENT_PROCESS_CREATED: u16 = 4688;
+const EVENT_PROCESS_EXITED: u16 = 4689;
+
+/// Where a canonical event was read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventSource {
+    /// The kernel logger's MOF classes, such as Process
+    KernelMof,
+    KernelProcess,
+    SecurityAuditing,
+}
+
+impl EventSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventSource::KernelMof => "kernel-mof",
+            EventSource::KernelProcess => "kernel-process",
+            EventSource::SecurityAuditing => "security-auditing",
+        }
+    }
+
+    fn _from_str(source: &str) -> Option<Self> {
+        [
+            EventSource::KernelMof,
+            EventSource::KernelProcess,
+            EventSource::SecurityAuditing,
+        ]
+        .into_iter()
+        .find(|candidate| candidate.as_str() == source)
+    }
+}
+
+/// The action a canonical event describes. Its value is the event id of the canonical event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    ProcessStart = 1,
+    ProcessStop = 2,
+}
+
+impl Action {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::ProcessStart => "ProcessStart",
+            Action::ProcessStop => "ProcessStop",
+        }
+    }
+}
+
+/// One action described the same way whichever provider logged it, so rules and sinks only handle one shape. Fields a
+/// source does not log are None or empty
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanonicalEvent {
+    pub action: Action,
+    pub source: EventSource,
+    pub process_id: u32,
+    /// None for [`Action::ProcessStop`] from sources that do not log it
+    pub parent_id: Option<u32>,
+    pub session_id: Option<u32>,
+    /// File name of the image, e.g. cmd.exe
+    pub image_file_name: String,
+    /// Full path of the image as the source logged it, NT or DOS. None for the kernel MOF classes, which only log the
+    /// file name
+    pub image_path: Option<String>,
+    pub command_line: Option<String>,
+    /// Account the process runs as, only logged by Security-Auditing
+    pub user_sid: Option<String>,
+    /// For [`Action::ProcessStop`]
+    pub exit_code: Option<i32>,
+    pub timestamp: i64,
+}
+
+impl CanonicalEvent {
+    /// Returns the canonical form of `event` if it is a process start or stop from any source, or is already a
+    /// canonical event. Rundown events of processes that were already running are not actions, so they return None
+    pub fn from_event(event: &ParsedEvent) -> Option<Self> {
+        let fields = Fields::of(event);
+
+        let (action, source, process_id, parent_id) = match (event.provider, event.event_id) {
+            (TAXONOMY_GUID, id) => {
+                let action = match id {
+                    1 => Action::ProcessStart,
+                    2 => Action::ProcessStop,
+                    _ => return None,
+                };
+                (
+                    action,
+                    EventSource::_from_str(&fields.optional_string("Source")?)?,
+                    fields.unsigned("ProcessId")?,
+                    fields.unsigned("ParentId"),
+                )
+            }
+            (KERNEL_PROCESS_GUID, EVENT_PROCESS_START) => (
+                Action::ProcessStart,
+                EventSource::KernelProcess,
+                fields.unsigned("ProcessID")?,
+                fields.unsigned("ParentProcessID"),
+            ),
+            (KERNEL_PROCESS_GUID, EVENT_PROCESS_STOP) => (
+                Action::ProcessStop,
+                EventSource::KernelProcess,
+                fields.unsigned("ProcessID")?,
+                None,
+            ),
+            // The creator is logged as ProcessId, the new process as NewProcessId
+            (SECURITY_AUDITING_GUID, EVENT_PROCESS_CREATED) => (
+                Action::ProcessStart,
+                EventSource::SecurityAuditing,
+                fields.unsigned("NewProcessId")?,
+                fields.unsigned("ProcessId"),
+            ),
+            (SECURITY_AUDITING_GUID, EVENT_PROCESS_EXITED) => (
+                Action::ProcessStop,
+                EventSource::SecurityAuditing,
+                fields.unsigned("ProcessId")?,
+                None,
+            ),
+            _ => return Self::_from_kernel(event),
+        };
+
+        let (image_path, exit_code, user_sid) = match event.provider {
+            TAXONOMY_GUID => (
+                fields.optional_string("ImagePath"),
+                fields.signed("ExitCode"),
+                fields.optional_string("UserSid"),
+            ),
+            SECURITY_AUDITING_GUID => (
+                fields.optional_string(match action {
+                    Action::ProcessStart => "NewProcessName",
+                    Action::ProcessStop => "ProcessName",
+                }),
+                fields.signed("Status"),
+                fields
+                    .optional_string("TargetUserSid")
+                    .or_else(|| fields.optional_string("SubjectUserSid")),
+            ),
+            _ => (
+                fields.optional_string("ImageName"),
+                fields.signed("ExitCode"),
+                None,
+            ),
+        };
+        let image_file_name = match event.provider {
+            TAXONOMY_GUID => fields.optional_string("ImageFileName").unwrap_or_default(),
+            _ => image_path
+                .as_deref()
+                .map(Self::_file_name)
+                .unwrap_or_default(),
+        };
+
+        Some(Self {
+            action,
+            source,
+            process_id: process_id as u32,
+            parent_id: parent_id.map(|id| id as u32),
+            session_id: fields
+                .unsigned("SessionID")
+                .or_else(|| fields.unsigned("SessionId"))
+                .map(|id| id as u32),
+            image_file_name,
+            image_path,
+            command_line: fields
+                .optional_string("CommandLine")
+                .filter(|command_line| !command_line.is_empty()),
+            user_sid,
+            exit_code: exit_code.map(|code| code as i32),
+            timestamp: event.timestamp,
+        })
+    }
+
+    fn _from_kernel(event: &ParsedEvent) -> Option<Self> {
+        let process = ProcessEvent::try_from(event).ok()?;
+        let action = match process.opcode {
+            ProcessOpcode::Start => Action::ProcessStart,
+            ProcessOpcode::End => Action::ProcessStop,
+            ProcessOpcode::DcStart | ProcessOpcode::DcEnd => return None,
+        };
+
+        Some(Self {
+            action,
+            source: EventSource::KernelMof,
+            process_id: process.process_id,
+            parent_id: Some(process.parent_id),
+            session_id: Some(process.session_id),
+            image_file_name: process.image_file_name,
+            image_path: None,
+            command_line: Some(process.command_line)
+                .filter(|command_line| !command_line.is_empty()),
+            user_sid: None,
+            exit_code: (action == Action::ProcessStop).then_some(process.exit_status),
+            timestamp: event.timestamp,
+        })
+    }
+
+    /// The canonical event as a [`TAXONOMY_GUID`] event with the action as its id, stamped with the header of `original`
+    /// so it lands where the event it was read from would have. Fields that are None are left out
+    pub fn to_event(&self, original: &ParsedEvent) -> ParsedEvent {
+        let mut properties = BTreeMap::from([
+            (
+                "Action".to_string(),
+                PropertyValue::String(self.action.as_str().to_string()),
+            ),
+            (
+                "Source".to_string(),
+                PropertyValue::String(self.source.as_str().to_string()),
+            ),
+            (
+                "ProcessId".to_string(),
+                PropertyValue::Unsigned(self.process_id as u64),
+            ),
+            (
+                "ImageFileName".to_string(),
+                PropertyValue::String(self.image_file_name.clone()),
+            ),
+        ]);
+        let optional = [
+            (
+                "ParentId",
+                self.parent_id.map(|id| PropertyValue::Unsigned(id as u64)),
+            ),
+            (
+                "SessionId",
+                self.session_id.map(|id| PropertyValue::Unsigned(id as u64)),
+            ),
+            (
+                "ImagePath",
+                self.image_path.clone().map(PropertyValue::String),
+            ),
+            (
+                "CommandLine",
+                self.command_line.clone().map(PropertyValue::String),
+            ),
+            ("UserSid", self.user_sid.clone().map(PropertyValue::Sid)),
+            (
+                "ExitCode",
+                self.exit_code
+                    .map(|code| PropertyValue::Signed(code as i64)),
+            ),
+        ];
+        for (name, value) in optional {
+            if let Some(value) = value {
+                properties.insert(name.to_string(), value);
+            }
+        }
+
+        ParsedEvent {
+            provider: TAXONOMY_GUID,
+            event_id: self.action as u16,
+            opcode: 0,
+            version: 0,
+            schema_hash: None,
+            schema_fields: None,
+            properties,
+            task_name: Some(self.action.as_str().to_string()),
+            event_name: None,
+            ..original.clone()
+        }
+    }
+
+    /// The last component of a DOS or NT path
+    fn _file_name(path: &str) -> String {
+        path.rsplit('\\').next().unwrap_or(path).to_string()
+    }
+
+    /// Fills in the fields this event lacks from `other`, a copy of the same action from another source. The kernel
+    /// MOF classes log the command line, Kernel-Process the image path and Security-Auditing the user
+    fn _merge(&mut self, other: CanonicalEvent) {
+        self.parent_id = self.parent_id.or(other.parent_id);
+        self.session_id = self.session_id.or(other.session_id);
+        if self.image_file_name.is_empty() {
+            self.image_file_name = other.image_file_name;
+        }
+        self.image_path = self.image_path.take().or(other.image_path);
+        self.command_line = self.command_line.take().or(other.command_line);
+        self.user_sid = self.user_sid.take().or(other.user_sid);
+        self.exit_code = self.exit_code.or(other.exit_code);
+    }
+}
+
+/// Turns events into their canonical form, and merges the copies of an action logged by more than one enabled source,
+/// such as a process start from both the kernel logger and Kernel-Process, into one event. The first source to report
+/// an action gives the event its source and header, the copies fill in the fields it lacks.
+///
+/// Events are fed in with [`Normalizer::normalize`] in the order they were logged. Canonical events are held for
+/// [`Normalizer::DUPLICATE_WINDOW`] so the copies can catch up, which puts them behind events logged after them. A copy
+/// that turns up after its action was released, such as one read from the Security log, is dropped
+#[derive(Debug, Default)]
+pub struct Normalizer {
+    /// Canonical events waiting for copies, oldest first, with the event they were read from
+    held: VecDeque<(CanonicalEvent, ParsedEvent)>,
+    /// Latest released action per process id, with the source that reported it and when
+    released: HashMap<(Action, u32), (EventSource, i64)>,
+}
+
+impl Normalizer {
+    /// Copies from other sources have to land within this many 100ns ticks of the first to be merged, 1s
+    const DUPLICATE_WINDOW: i64 = 10_000_000;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hands `event` to `release` as is if it has no canonical form. Otherwise holds its canonical form, or merges it
+    /// into the held copy of the same action from another source. Held events past the window are released first
+    pub fn normalize(&mut self, event: ParsedEvent, mut release: impl FnMut(ParsedEvent)) {
+        while let Some((canonical, _)) = self.held.front() {
+            if event.timestamp - canonical.timestamp <= Self::DUPLICATE_WINDOW {
+                break;
+            }
+            let (canonical, original) = self.held.pop_front().expect("front was checked");
+            release(self._release(canonical, &original));
+        }
+
+        let Some(canonical) = CanonicalEvent::from_event(&event) else {
+            release(event);
+            return;
+        };
+
+        let copy_of = self.held.iter_mut().find(|(held, _)| {
+            held.action == canonical.action
+                && held.process_id == canonical.process_id
+                && held.source != canonical.source
+                && (canonical.timestamp - held.timestamp).abs() <= Self::DUPLICATE_WINDOW
+        });
+        if let Some((held, _)) = copy_of {
+            held._merge(canonical);
+            return;
+        }
+
+        let key = (canonical.action, canonical.process_id);
+        if let Some(&(source, timestamp)) = self.released.get(&key) {
+            if source != canonical.source
+                && (canonical.timestamp - timestamp).abs() <= Self::DUPLICATE_WINDOW
+            {
+                return;
+            }
+        }
+        self.held.push_back((canonical, event));
+    }
+
+    /// Releases every held event, for when no more events are coming
+    pub fn drain(&mut self) -> Vec<ParsedEvent> {
+        let held = mem::take(&mut self.held);
+        held.into_iter()
+            .map(|(canonical, original)| self._release(canonical, &original))
+            .collect()
+    }
+
+    fn _release(&mut self, canonical: CanonicalEvent, original: &ParsedEvent) -> ParsedEvent {
+        self.released.insert(
+            (canonical.action, canonical.process_id),
+            (canonical.source, canonical.timestamp),
+        );
+        canonical.to_event(original)
+    }
+}
@@ -1,16 +1,22 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, mem};
 
 use windows::{
-    core::PWSTR,
+    core::{PCWSTR, PWSTR},
     Win32::{
         Foundation::{ERROR_INSUFFICIENT_BUFFER, ERROR_SUCCESS, WIN32_ERROR},
         System::Diagnostics::Etw::{
-            TdhFormatProperty, TdhGetEventInformation, EVENT_MAP_INFO, EVENT_PROPERTY_INFO,
-            EVENT_RECORD, TDH_CONTEXT, TRACE_EVENT_INFO,
+            TdhFormatProperty, TdhGetEventInformation, TdhGetEventMapInformation,
+            EVENT_HEADER_FLAG_32_BIT_HEADER, EVENT_HEADER_FLAG_64_BIT_HEADER, EVENT_MAP_INFO,
+            EVENT_PROPERTY_INFO, EVENT_RECORD, TDH_CONTEXT, TRACE_EVENT_INFO,
         },
     },
 };
 
+use super::{
+    error::{EtwError, EtwResult},
+    parsed_event::{ParsedEvent, PropertyValue},
+};
+
 #[derive(Debug, Default)]
 pub struct ProcessTypeGroup1 {
     _unique_process_key: u64, // I know it says u32 in the description, but I have had values that go up to 64
@@ -58,14 +64,45 @@ impl From<HashMap<String, String>> for ProcessTypeGroup1 {
     }
 }
 
+impl From<&ParsedEvent> for ProcessTypeGroup1 {
+    fn from(event: &ParsedEvent) -> Self {
+        let unsigned = |name: &str| {
+            event
+                .get(name)
+                .and_then(PropertyValue::as_u64)
+                .unwrap_or_default()
+        };
+        let string = |name: &str| event.get(name).map(ToString::to_string).unwrap_or_default();
+
+        Self {
+            _unique_process_key: unsigned("UniqueProcessKey"),
+            _process_id: unsigned("ProcessId") as u32,
+            _parent_id: unsigned("ParentId") as u32,
+            _session_id: unsigned("SessionId") as u32,
+            _exit_status: event
+                .get("ExitStatus")
+                .and_then(PropertyValue::as_i64)
+                .unwrap_or_default() as i32,
+            _directory_table_base: unsigned("DirectoryTableBase"),
+            _user_sid: string("UserSID"),
+            _image_file_name: string("ImageFileName"),
+            _command_line: string("CommandLine"),
+        }
+    }
+}
+
 pub struct Tdh;
 
 impl Tdh {
-    /// Gets information about the event. Returns a Vec<u8> on success with the event information, a WIN32ERROR on failure
+    /// Gets information about the event. Returns a Vec<u8> on success with the event information, an [`EtwError::Tdh`] on failure
     pub fn get_event_information(
         record: &EVENT_RECORD,
         tdh_context: Option<&[TDH_CONTEXT]>,
-    ) -> Result<Vec<u8>, WIN32_ERROR> {
+    ) -> EtwResult<Vec<u8>> {
+        let tdh_error = |status| EtwError::Tdh {
+            status,
+            context: "TdhGetEventInformation could not get the event information".to_string(),
+        };
         let mut expected_buf_size = 0;
 
         let int_tdh_info = |buffer: Option<&mut Vec<u8>>, expected_buf_size: &mut u32| unsafe {
@@ -79,65 +116,151 @@ impl Tdh {
         let status = int_tdh_info(None, &mut expected_buf_size);
 
         if status != ERROR_INSUFFICIENT_BUFFER {
-            return Err(status);
+            return Err(tdh_error(status));
         }
 
         let mut buffer = vec![0u8; expected_buf_size as usize];
 
         match int_tdh_info(Some(&mut buffer), &mut expected_buf_size) {
             ERROR_SUCCESS => Ok(buffer),
-            error_code => Err(error_code),
+            error_code => Err(tdh_error(error_code)),
+        }
+    }
+
+    /// Gets the value map named `map_name` of the event, used to turn enum and bitmap values into their symbolic names.
+    /// `map_name` is usually read from the `MapNameOffset` of an [`EVENT_PROPERTY_INFO`] in the event information buffer.
+    /// Returns a Vec<u8> holding an [`EVENT_MAP_INFO`] on success, an [`EtwError::Tdh`] on failure
+    pub fn get_event_map_information(
+        record: &EVENT_RECORD,
+        map_name: PCWSTR,
+    ) -> EtwResult<Vec<u8>> {
+        let tdh_error = |status| EtwError::Tdh {
+            status,
+            context: "TdhGetEventMapInformation could not get the map information".to_string(),
+        };
+        let mut expected_buf_size = 0;
+
+        let int_tdh_map_info = |buffer: Option<&mut Vec<u8>>, expected_buf_size: &mut u32| unsafe {
+            WIN32_ERROR(TdhGetEventMapInformation(
+                record,
+                map_name,
+                buffer.map(|s| s.as_mut_ptr() as *mut EVENT_MAP_INFO),
+                expected_buf_size,
+            ))
+        };
+        let status = int_tdh_map_info(None, &mut expected_buf_size);
+
+        if status != ERROR_INSUFFICIENT_BUFFER {
+            return Err(tdh_error(status));
+        }
+
+        let mut buffer = vec![0u8; expected_buf_size as usize];
+
+        match int_tdh_map_info(Some(&mut buffer), &mut expected_buf_size) {
+            ERROR_SUCCESS => Ok(buffer),
+            error_code => Err(tdh_error(error_code)),
+        }
+    }
+
+    /// The size of a pointer in the event's userdata. Events logged by 32 bit processes or on 32 bit machines use 4 byte pointers.
+    /// If the event header does not say, `trace_pointer_size` (the pointer size from the logfile header of the trace) is used,
+    /// so traces recorded on another architecture decode correctly. Falls back to the pointer size of this machine
+    pub fn pointer_size(record: &EVENT_RECORD, trace_pointer_size: Option<u32>) -> u32 {
+        if record.EventHeader.Flags as u32 & EVENT_HEADER_FLAG_32_BIT_HEADER != 0 {
+            4
+        } else if record.EventHeader.Flags as u32 & EVENT_HEADER_FLAG_64_BIT_HEADER != 0 {
+            8
+        } else {
+            trace_pointer_size
+                .filter(|size| *size == 4 || *size == 8)
+                .unwrap_or(mem::size_of::<*const u32>() as u32)
         }
     }
 
     /// Gets the data of a property whose name is identifed by the `property_info` field. Uses `tdhformatproperty` to do this.
-    /// Returns a Vector of bytes corresponding to the property value on success and the data consumed from userdata - a WIN32_ERROR on failure.
+    /// If `mapinfo` is given, values are formatted as the names they map to.
+    /// `property_length` is the length of the property, which may come from another property when `PropertyParamLength` is set.
+    /// Returns a Vector of bytes corresponding to the property value on success and the data consumed from userdata - an [`EtwError::Tdh`] on failure.
     pub fn format_property(
         event: &TRACE_EVENT_INFO,
-        _mapinfo: Option<&EVENT_MAP_INFO>,
+        mapinfo: Option<&EVENT_MAP_INFO>,
         pointer_size: u32,
         property_info: &EVENT_PROPERTY_INFO,
+        property_length: u16,
         userdata: &[u8],
-    ) -> Result<(Vec<u16>, usize), WIN32_ERROR> {
-        let mut buf_size = 0;
+    ) -> EtwResult<(Vec<u16>, usize)> {
+        let mut buffer = Vec::new();
+        let consumed_data = Self::format_property_into(
+            event,
+            mapinfo,
+            pointer_size,
+            property_info,
+            property_length,
+            userdata,
+            &mut buffer,
+        )?;
+
+        Ok((buffer, consumed_data))
+    }
+
+    /// Same as [`Tdh::format_property`], but formats into `buffer` so it can be reused between calls. `buffer` only grows
+    /// when the value does not fit, so repeated events are usually formatted with a single `TdhFormatProperty` call.
+    /// The formatted value is nul terminated. Returns the data consumed from userdata
+    pub fn format_property_into(
+        event: &TRACE_EVENT_INFO,
+        mapinfo: Option<&EVENT_MAP_INFO>,
+        pointer_size: u32,
+        property_info: &EVENT_PROPERTY_INFO,
+        property_length: u16,
+        userdata: &[u8],
+        buffer: &mut Vec<u16>,
+    ) -> EtwResult<usize> {
+        let tdh_error = |status| EtwError::Tdh {
+            status,
+            context: "TdhFormatProperty could not format the property".to_string(),
+        };
         let mut consumed_data = 0;
 
-        let int_tdh_format =
-            |buffer: Option<&mut Vec<u16>>, buf_size: &mut u32, consumed_data: &mut u16| {
-                WIN32_ERROR(unsafe {
-                    TdhFormatProperty(
-                        event,
-                        None,
-                        pointer_size,
-                        property_info.Anonymous1.nonStructType.InType,
-                        if property_info.Anonymous1.nonStructType.OutType == 0 {
-                            // https://learn.microsoft.com/en-us/windows/win32/api/tdh/ns-tdh-event_property_info if this is null, use intype
-                            property_info.Anonymous1.nonStructType.InType
-                        } else {
-                            property_info.Anonymous1.nonStructType.OutType
-                        },
-                        property_info.Anonymous3.length,
-                        userdata,
-                        buf_size,
-                        buffer
-                            .map(|x| PWSTR::from_raw(x.as_mut_ptr()))
-                            .unwrap_or_else(PWSTR::null),
-                        consumed_data,
-                    )
-                })
-            };
-
-        let status = int_tdh_format(None, &mut buf_size, &mut consumed_data);
+        let int_tdh_format = |buffer: &mut Vec<u16>, consumed_data: &mut u16| {
+            // Nothing is written for empty values, so make sure nothing from the last call is left over
+            buffer[0] = 0;
+            let mut buf_size = (buffer.len() * mem::size_of::<u16>()) as u32;
+            let status = WIN32_ERROR(unsafe {
+                TdhFormatProperty(
+                    event,
+                    mapinfo.map(|x| x as *const EVENT_MAP_INFO),
+                    pointer_size,
+                    property_info.Anonymous1.nonStructType.InType,
+                    if property_info.Anonymous1.nonStructType.OutType == 0 {
+                        // https://learn.microsoft.com/en-us/windows/win32/api/tdh/ns-tdh-event_property_info if this is null, use intype
+                        property_info.Anonymous1.nonStructType.InType
+                    } else {
+                        property_info.Anonymous1.nonStructType.OutType
+                    },
+                    property_length,
+                    userdata,
+                    &mut buf_size,
+                    PWSTR::from_raw(buffer.as_mut_ptr()),
+                    consumed_data,
+                )
+            });
+            (status, buf_size)
+        };
 
-        if status != ERROR_INSUFFICIENT_BUFFER {
-            return Err(status);
+        if buffer.is_empty() {
+            buffer.resize(256, 0);
         }
 
-        let mut buffer = vec![0u16; buf_size as usize];
+        let (mut status, required_size) = int_tdh_format(buffer, &mut consumed_data);
+        if status == ERROR_INSUFFICIENT_BUFFER {
+            // The required size is given in bytes
+            buffer.resize((required_size as usize).div_ceil(mem::size_of::<u16>()), 0);
+            status = int_tdh_format(buffer, &mut consumed_data).0;
+        }
 
-        match int_tdh_format(Some(&mut buffer), &mut buf_size, &mut consumed_data) {
-            ERROR_SUCCESS => Ok((buffer, consumed_data as usize)),
-            error => Err(error),
+        match status {
+            ERROR_SUCCESS => Ok(consumed_data as usize),
+            error => Err(tdh_error(error)),
         }
     }
 }
@@ -0,0 +1,134 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use super::parsed_event::ParsedEvent;
+
+/// Bounds on how long a [`ThreadGroups`] holds events back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadGroupConfig {
+    /// Most events in one batch. A thread with this many buffered events is emitted straight away
+    pub max_batch: usize,
+    /// Most events buffered across every thread. Past it, the thread that has waited longest is emitted
+    pub max_buffered: usize,
+    /// Longest a thread's events are held, measured in event time against the newest event seen
+    pub max_delay: Duration,
+}
+
+impl Default for ThreadGroupConfig {
+    fn default() -> Self {
+        Self {
+            max_batch: 256,
+            max_buffered: 4096,
+            max_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Consecutive events of one thread, in the order they were delivered
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadBatch {
+    pub thread_id: u32,
+    pub events: Vec<ParsedEvent>,
+}
+
+/// Regroups a stream of events into per-thread batches, for providers whose multi-event operations (start, steps,
+/// stop) interleave with those of other threads. Handlers then see each thread's events together and in order, instead
+/// of keeping per-thread state themselves.
+///
+/// Batches come out in the order their first event arrived. Buffering is bounded by [`ThreadGroupConfig`], so a thread
+/// can be split across several batches. Wrap `stream.by_ref()` to still be able to join the stream afterwards. A
+/// thread's events are only emitted when another event arrives or the stream ends, so on a quiet live session the last
+/// batches wait for the next event
+pub struct ThreadGroups<I> {
+    events: I,
+    config: ThreadGroupConfig,
+    pending: HashMap<u32, Vec<ParsedEvent>>, // Keyed on thread id
+    order: VecDeque<u32>, // Threads with pending events, by when their oldest pending event arrived
+    buffered: usize,
+    ready: VecDeque<ThreadBatch>,
+    finished: bool,
+}
+
+impl<I: Iterator<Item = ParsedEvent>> ThreadGroups<I> {
+    pub fn new(events: I, config: ThreadGroupConfig) -> Self {
+        Self {
+            events,
+            config,
+            pending: HashMap::new(),
+            order: VecDeque::new(),
+            buffered: 0,
+            ready: VecDeque::new(),
+            finished: false,
+        }
+    }
+
+    fn _add(&mut self, event: ParsedEvent) {
+        let thread_id = event.thread_id;
+        let timestamp = event.timestamp;
+
+        let events = self.pending.entry(thread_id).or_default();
+        if events.is_empty() {
+            self.order.push_back(thread_id);
+        }
+        events.push(event);
+        self.buffered += 1;
+
+        if events.len() >= self.config.max_batch.max(1) {
+            self._emit(thread_id);
+        }
+        while self.buffered > self.config.max_buffered {
+            let Some(&oldest) = self.order.front() else {
+                break;
+            };
+            self._emit(oldest);
+        }
+
+        // Timestamps are 100ns ticks
+        let max_delay = (self.config.max_delay.as_nanos() / 100) as i64;
+        while let Some(&oldest) = self.order.front() {
+            let waited_since = self.pending[&oldest][0].timestamp;
+            if timestamp.saturating_sub(waited_since) <= max_delay {
+                break;
+            }
+            self._emit(oldest);
+        }
+    }
+
+    /// Moves every pending event of `thread_id` into a batch
+    fn _emit(&mut self, thread_id: u32) {
+        let Some(events) = self.pending.remove(&thread_id) else {
+            return;
+        };
+
+        self.buffered -= events.len();
+        self.order.retain(|id| *id != thread_id);
+        self.ready.push_back(ThreadBatch { thread_id, events });
+    }
+}
+
+impl<I: Iterator<Item = ParsedEvent>> Iterator for ThreadGroups<I> {
+    type Item = ThreadBatch;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(batch) = self.ready.pop_front() {
+                return Some(batch);
+            }
+            if self.finished {
+                return None;
+            }
+
+            match self.events.next() {
+                Some(event) => self._add(event),
+                None => {
+                    self.finished = true;
+                    while let Some(thread_id) = self.order.front().copied() {
+                        self._emit(thread_id);
+                    }
+                }
+            }
+        }
+    }
+}
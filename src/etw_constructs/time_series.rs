@@ -0,0 +1,195 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    mem,
+    path::Path,
+    time::Duration,
+};
+
+use serde::Serialize;
+use windows::core::GUID;
+
+use super::{
+    capture_header::CaptureHeader,
+    clock,
+    error::{EtwError, EtwResult},
+    parsed_event::{serialize_guid, ParsedEvent, PropertyValue},
+    sink::{open_output, write_comment_header, EventSink},
+};
+
+/// How a [`TimeSeriesSink`] writes its buckets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeSeriesFormat {
+    /// One JSON object per row
+    JsonLines,
+    Csv,
+}
+
+/// One row of a time series: how many events of one provider, id and process were logged in one bucket
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TimeSeriesRow {
+    /// Start of the bucket in FILETIME ticks
+    pub bucket_start: i64,
+    /// `bucket_start` as RFC 3339
+    pub time: String,
+    #[serde(serialize_with = "serialize_guid")]
+    pub provider: GUID,
+    pub event_id: u16,
+    /// Kernel events share event id 0 and are told apart by opcode
+    pub opcode: u8,
+    pub process_id: u32,
+    pub count: u64,
+}
+
+/// Bucket start, provider, event id, opcode and process id. Providers are kept as u128 since GUIDs have no order
+type BucketKey = (i64, u128, u16, u8, u32);
+
+/// How far behind the newest event seen an event can be and still be counted in a bucket that has not been written.
+/// Real-time sessions deliver each CPU's buffers separately, once they fill up or the 1s flush timer goes off
+const LATENESS_TICKS: i64 = 20_000_000;
+
+/// Counts events per provider, event id and process in fixed time buckets instead of writing each one, so activity over
+/// a long capture can be charted without loading every event. A bucket is written out once the newest event timestamp
+/// is [`LATENESS_TICKS`] past its end, and the rest when the sink is closed, so only the recent buckets are kept in
+/// memory. An event later than that is written as another row for its bucket
+pub struct TimeSeriesSink {
+    writer: Box<dyn Write + Send>,
+    format: TimeSeriesFormat,
+    bucket_ticks: i64,
+    counts: BTreeMap<BucketKey, u64>,
+    watermark: i64, // Newest event timestamp seen
+    wrote_csv_header: bool,
+}
+
+impl TimeSeriesSink {
+    const HEADER: &'static str = "bucket_start,time,provider,event_id,opcode,process_id,count";
+
+    /// Writes to the file at `path`, or stdout if `path` is `-`. Buckets are `bucket` long, at least 1ms
+    pub fn create(path: &Path, format: TimeSeriesFormat, bucket: Duration) -> EtwResult<Self> {
+        Ok(Self {
+            writer: open_output(path)?,
+            format,
+            bucket_ticks: (bucket.as_nanos() / 100).max(10_000) as i64,
+            counts: BTreeMap::new(),
+            watermark: i64::MIN,
+            wrote_csv_header: false,
+        })
+    }
+
+    /// Every row counted and not written yet, ordered by bucket
+    pub fn rows(&self) -> impl Iterator<Item = TimeSeriesRow> + '_ {
+        self.counts
+            .iter()
+            .map(|(key, count)| Self::_row(key, *count))
+    }
+
+    fn _row(
+        &(bucket_start, provider, event_id, opcode, process_id): &BucketKey,
+        count: u64,
+    ) -> TimeSeriesRow {
+        TimeSeriesRow {
+            bucket_start,
+            time: clock::rfc3339_from_ticks(bucket_start),
+            provider: GUID::from_u128(provider),
+            event_id,
+            opcode,
+            process_id,
+            count,
+        }
+    }
+
+    /// Writes the buckets that ended [`LATENESS_TICKS`] or more before the watermark
+    fn _write_complete(&mut self) -> io::Result<()> {
+        let complete_before = self
+            .watermark
+            .saturating_sub(LATENESS_TICKS)
+            .saturating_sub(self.bucket_ticks);
+        if !self
+            .counts
+            .first_key_value()
+            .is_some_and(|((bucket_start, ..), _)| *bucket_start <= complete_before)
+        {
+            return Ok(());
+        }
+
+        let pending = self.counts.split_off(&(complete_before + 1, 0, 0, 0, 0));
+        let complete = mem::replace(&mut self.counts, pending);
+        self._write_rows(complete)
+    }
+
+    fn _write_rows(&mut self, counts: BTreeMap<BucketKey, u64>) -> io::Result<()> {
+        if self.format == TimeSeriesFormat::Csv && !self.wrote_csv_header {
+            writeln!(self.writer, "{}", Self::HEADER)?;
+            self.wrote_csv_header = true;
+        }
+
+        for row in counts.iter().map(|(key, count)| Self::_row(key, *count)) {
+            match self.format {
+                TimeSeriesFormat::JsonLines => {
+                    serde_json::to_writer(&mut self.writer, &row)?;
+                    self.writer.write_all(b"\n")?;
+                }
+                TimeSeriesFormat::Csv => writeln!(
+                    self.writer,
+                    "{},{},{},{},{},{},{}",
+                    row.bucket_start,
+                    row.time,
+                    PropertyValue::Guid(row.provider),
+                    row.event_id,
+                    row.opcode,
+                    row.process_id,
+                    row.count
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl EventSink for TimeSeriesSink {
+    fn write(&mut self, event: &ParsedEvent) -> EtwResult<()> {
+        let bucket_start = event.timestamp - event.timestamp.rem_euclid(self.bucket_ticks);
+        *self
+            .counts
+            .entry((
+                bucket_start,
+                event.provider.to_u128(),
+                event.event_id,
+                event.opcode,
+                event.process_id,
+            ))
+            .or_default() += 1;
+
+        self.watermark = self.watermark.max(event.timestamp);
+        self._write_complete()
+            .map_err(|err| EtwError::from_io(&err, "Could not write the time series"))
+    }
+
+    fn flush(&mut self) -> EtwResult<()> {
+        self.writer
+            .flush()
+            .map_err(|err| EtwError::from_io(&err, "Could not flush the time series"))
+    }
+
+    /// Goes before the rows, the same way as in [`JsonLinesSink`](super::sink::JsonLinesSink) and
+    /// [`CsvSink`](super::sink::CsvSink) outputs
+    fn write_header(&mut self, header: &CaptureHeader) -> EtwResult<()> {
+        match self.format {
+            TimeSeriesFormat::JsonLines => serde_json::to_writer(
+                &mut self.writer,
+                &serde_json::json!({ "capture_header": header }),
+            )
+            .map_err(io::Error::from)
+            .and_then(|()| self.writer.write_all(b"\n"))
+            .map_err(|err| EtwError::from_io(&err, "Could not write the capture header")),
+            TimeSeriesFormat::Csv => write_comment_header(&mut self.writer, header),
+        }
+    }
+
+    fn close(&mut self) -> EtwResult<()> {
+        let counts = mem::take(&mut self.counts);
+        self._write_rows(counts)
+            .map_err(|err| EtwError::from_io(&err, "Could not write the time series"))?;
+        self.flush()
+    }
+}
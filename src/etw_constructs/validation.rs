@@ -0,0 +1,353 @@
+use std::{
+    ffi::{c_void, CStr},
+    fmt, mem,
+};
+
+use windows::{
+    core::s,
+    Win32::{
+        Foundation::{
+            CloseHandle, ERROR_ACCESS_DENIED, ERROR_INVALID_PARAMETER, ERROR_NOT_SUPPORTED,
+            ERROR_SUCCESS, HANDLE, WIN32_ERROR,
+        },
+        Security::{GetTokenInformation, TokenElevation, TOKEN_ELEVATION, TOKEN_QUERY},
+        System::{
+            Diagnostics::Etw::{
+                EVENT_TRACE_FLAG_VAMAP, EVENT_TRACE_PROPERTIES, EVENT_TRACE_REAL_TIME_MODE,
+            },
+            Registry::{RegGetValueA, HKEY_LOCAL_MACHINE, RRF_RT_REG_SZ},
+            Threading::{GetCurrentProcess, OpenProcessToken},
+        },
+    },
+};
+
+use super::{
+    controller::{BufferMemory, Controller, ControllerConfig, LogFileMode},
+    error::{EtwError, EtwResult},
+};
+
+/// Build number of Windows 8, the first release with `EVENT_TRACE_SYSTEM_LOGGER_MODE` and `EVENT_TRACE_FLAG_NO_SYSCONFIG`
+const WINDOWS_8_BUILD: u32 = 9200;
+
+/// Largest `BufferSize` ETW accepts
+const MAX_BUFFER_SIZE_KB: u32 = 1024;
+
+/// What the machine the session is started on supports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SystemCapabilities {
+    /// Windows build number, e.g. 19045
+    pub build_number: u32,
+    /// Whether the process runs with an elevated (administrator) token
+    pub elevated: bool,
+}
+
+impl SystemCapabilities {
+    /// Reads the build number from the registry and the elevation of the process token.
+    /// The registry is used because `GetVersionEx` reports an old version to processes without a compatibility manifest
+    pub fn detect() -> EtwResult<Self> {
+        Ok(Self {
+            build_number: Self::_build_number()?,
+            elevated: Self::_is_elevated()?,
+        })
+    }
+
+    fn _build_number() -> EtwResult<u32> {
+        let mut buf = [0u8; 32];
+        let mut buf_size = buf.len() as u32;
+
+        let status = unsafe {
+            RegGetValueA(
+                HKEY_LOCAL_MACHINE,
+                s!("SOFTWARE\\Microsoft\\Windows NT\\CurrentVersion"),
+                s!("CurrentBuildNumber"),
+                RRF_RT_REG_SZ,
+                None,
+                Some(buf.as_mut_ptr() as *mut c_void),
+                Some(&mut buf_size),
+            )
+        };
+
+        if status != ERROR_SUCCESS {
+            return Err(EtwError::Win32 {
+                status,
+                context: "Could not read CurrentBuildNumber from the registry".to_string(),
+            });
+        }
+
+        let build_number: String = buf
+            .iter()
+            .take_while(|x| **x != 0)
+            .map(|x| *x as char)
+            .collect();
+        build_number.parse().map_err(|_| EtwError::Win32 {
+            status: ERROR_INVALID_PARAMETER,
+            context: format!("{build_number:?} is not a build number"),
+        })
+    }
+
+    fn _is_elevated() -> EtwResult<bool> {
+        let mut token = HANDLE::default();
+        unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) }.map_err(
+            |err| EtwError::Win32 {
+                status: WIN32_ERROR::from_error(&err).unwrap_or_default(),
+                context: "Could not open the process token".to_string(),
+            },
+        )?;
+
+        let mut elevation = TOKEN_ELEVATION::default();
+        let mut returned_size = 0u32;
+        let result = unsafe {
+            GetTokenInformation(
+                token,
+                TokenElevation,
+                Some(&mut elevation as *mut TOKEN_ELEVATION as *mut c_void),
+                mem::size_of::<TOKEN_ELEVATION>() as u32,
+                &mut returned_size,
+            )
+        };
+        unsafe {
+            let _ = CloseHandle(token);
+        }
+
+        result.map_err(|err| EtwError::Win32 {
+            status: WIN32_ERROR::from_error(&err).unwrap_or_default(),
+            context: "Could not query the elevation of the process token".to_string(),
+        })?;
+
+        Ok(elevation.TokenIsElevated != 0)
+    }
+}
+
+/// Why a requested feature cannot be used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unavailability {
+    /// The process lacks the rights the feature needs
+    Privilege,
+    /// The OS is older than the build that introduced the feature
+    OsVersion { required_build: u32 },
+    /// The options given for the feature are inconsistent
+    Config,
+    /// The feature is not enabled on the already running session being attached to
+    NotEnabled,
+}
+
+/// A requested feature of a [`ControllerConfig`] that cannot be used, and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnavailableFeature {
+    pub feature: String,
+    pub reason: Unavailability,
+    pub detail: String,
+}
+
+impl UnavailableFeature {
+    fn new(feature: impl Into<String>, reason: Unavailability, detail: impl Into<String>) -> Self {
+        Self {
+            feature: feature.into(),
+            reason,
+            detail: detail.into(),
+        }
+    }
+
+    /// The status reported when starting the session fails because of this feature
+    pub fn status(&self) -> WIN32_ERROR {
+        match self.reason {
+            Unavailability::Privilege => ERROR_ACCESS_DENIED,
+            Unavailability::OsVersion { .. } | Unavailability::NotEnabled => ERROR_NOT_SUPPORTED,
+            Unavailability::Config => ERROR_INVALID_PARAMETER,
+        }
+    }
+
+    /// Turns every unavailable feature into a single error listing all of them. Ok if `features` is empty
+    pub fn into_result(features: Vec<Self>) -> EtwResult<()> {
+        let Some(first) = features.first() else {
+            return Ok(());
+        };
+
+        Err(EtwError::StartTrace {
+            status: first.status(),
+            context: format!(
+                "Requested features are unavailable: {}",
+                features
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            ),
+        })
+    }
+}
+
+impl fmt::Display for UnavailableFeature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            Unavailability::OsVersion { required_build } => write!(
+                f,
+                "{} requires Windows build {required_build} or later ({})",
+                self.feature, self.detail
+            ),
+            _ => write!(f, "{}: {}", self.feature, self.detail),
+        }
+    }
+}
+
+impl ControllerConfig {
+    /// Lists every requested feature that `capabilities` cannot provide to the session `session_name`. Empty if the session can be started as configured
+    pub fn validate(
+        &self,
+        session_name: &CStr,
+        capabilities: &SystemCapabilities,
+    ) -> Vec<UnavailableFeature> {
+        let mut unavailable = Vec::new();
+
+        if !self.providers.is_empty() && Controller::is_kernel_logger(session_name) {
+            unavailable.push(UnavailableFeature::new(
+                "User-mode providers",
+                Unavailability::Config,
+                "the NT Kernel Logger only logs kernel events, start a session with a different name",
+            ));
+        }
+
+        if !capabilities.elevated {
+            unavailable.push(UnavailableFeature::new(
+                "Kernel session",
+                Unavailability::Privilege,
+                "starting or stopping the kernel logger requires administrator rights",
+            ));
+        }
+
+        let windows_8 = Unavailability::OsVersion {
+            required_build: WINDOWS_8_BUILD,
+        };
+        if capabilities.build_number < WINDOWS_8_BUILD {
+            unavailable.push(UnavailableFeature::new(
+                "System logger mode",
+                windows_8,
+                format!("running on build {}", capabilities.build_number),
+            ));
+            if !self.system_config {
+                unavailable.push(UnavailableFeature::new(
+                    "Disabling the SystemConfig rundown",
+                    windows_8,
+                    "EVENT_TRACE_FLAG_NO_SYSCONFIG",
+                ));
+            }
+            if self.enable_flags.contains(EVENT_TRACE_FLAG_VAMAP) {
+                unavailable.push(UnavailableFeature::new(
+                    "Virtual address map events",
+                    windows_8,
+                    "EVENT_TRACE_FLAG_VAMAP",
+                ));
+            }
+        }
+
+        if let Some(log_file) = &self.log_file {
+            match log_file.mode {
+                LogFileMode::NewFile { .. } if !log_file.path.to_string_lossy().contains("%d") => {
+                    unavailable.push(UnavailableFeature::new(
+                        "New file log mode",
+                        Unavailability::Config,
+                        format!("{:?} does not contain %d", log_file.path),
+                    ));
+                }
+                LogFileMode::Circular { max_size_mb: 0 }
+                | LogFileMode::NewFile { max_size_mb: 0 } => {
+                    unavailable.push(UnavailableFeature::new(
+                        "Log file size limit",
+                        Unavailability::Config,
+                        "circular and new file modes need a maximum size above 0",
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        if self.buffer_memory == BufferMemory::Paged {
+            let detail = if Controller::is_kernel_logger(session_name) {
+                Some("the NT Kernel Logger always uses nonpaged pool, start a session with a different name")
+            } else if self.enable_flags.0 != 0 || !self.stack_walk.is_empty() || self.mem_info {
+                Some("kernel events need nonpaged pool, clear the kernel flags, stack walking and MemInfo")
+            } else {
+                None
+            };
+            if let Some(detail) = detail {
+                unavailable.push(UnavailableFeature::new(
+                    "Paged buffer memory",
+                    Unavailability::Config,
+                    detail,
+                ));
+            }
+        }
+
+        let buffers = &self.buffers;
+        if buffers.buffer_size_kb > MAX_BUFFER_SIZE_KB {
+            unavailable.push(UnavailableFeature::new(
+                "Buffer size",
+                Unavailability::Config,
+                format!(
+                    "{} KB is above the {MAX_BUFFER_SIZE_KB} KB limit",
+                    buffers.buffer_size_kb
+                ),
+            ));
+        }
+        if buffers.maximum_buffers != 0 && buffers.minimum_buffers > buffers.maximum_buffers {
+            unavailable.push(UnavailableFeature::new(
+                "Buffer count",
+                Unavailability::Config,
+                format!(
+                    "minimum of {} buffers is above the maximum of {}",
+                    buffers.minimum_buffers, buffers.maximum_buffers
+                ),
+            ));
+        }
+
+        unavailable
+    }
+
+    /// Lists every requested feature that is missing from `running`, the properties of an already running session
+    pub fn diff(&self, running: &EVENT_TRACE_PROPERTIES) -> Vec<UnavailableFeature> {
+        let mut unavailable = Vec::new();
+
+        let missing_flags = self.enable_flags.0 & !running.EnableFlags.0;
+        if missing_flags != 0 {
+            unavailable.push(UnavailableFeature::new(
+                "Kernel flags",
+                Unavailability::NotEnabled,
+                format!(
+                    "{missing_flags:#x} are not enabled (running session has {:#x})",
+                    running.EnableFlags.0
+                ),
+            ));
+        }
+
+        if self.is_real_time() && running.LogFileMode & EVENT_TRACE_REAL_TIME_MODE == 0 {
+            unavailable.push(UnavailableFeature::new(
+                "Real-time delivery",
+                Unavailability::NotEnabled,
+                "the running session only logs to a file",
+            ));
+        }
+
+        let paged = self.buffer_memory.log_file_mode();
+        if running.LogFileMode & paged != paged {
+            unavailable.push(UnavailableFeature::new(
+                "Paged buffer memory",
+                Unavailability::NotEnabled,
+                "the running session allocates its buffers from nonpaged pool",
+            ));
+        }
+
+        let sequence = self.sequence.log_file_mode();
+        if running.LogFileMode & sequence != sequence {
+            unavailable.push(UnavailableFeature::new(
+                "Sequence numbers",
+                Unavailability::NotEnabled,
+                format!(
+                    "the running session does not use {:?} sequence numbers",
+                    self.sequence
+                ),
+            ));
+        }
+
+        unavailable
+    }
+}
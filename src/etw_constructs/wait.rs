@@ -0,0 +1,218 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+    time::Duration,
+};
+
+use super::{parsed_event::ParsedEvent, schemas::SchedulerEvent};
+
+/// KWAIT_REASON of a thread waiting in GetMessage or WaitMessage for its next window message, which is where a UI
+/// thread goes back to once it has handled its input
+pub const WR_USER_REQUEST: u8 = 13;
+
+/// KTHREAD_STATE of a thread switched out to wait, rather than preempted while it could still run
+const STATE_WAITING: u8 = 5;
+
+/// Waits kept per thread, the oldest are dropped past this
+const MAX_WAITS_PER_THREAD: usize = 256;
+
+/// A span a thread spent switched out, waiting on something
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Wait {
+    pub thread_id: u32,
+    /// FILETIME ticks
+    pub start: i64,
+    pub end: i64,
+    /// KWAIT_REASON, see [`wait_reason_name`]
+    pub reason: u8,
+    /// The thread that made it ready to run again. None unless the dispatcher kernel flag logged it
+    pub readied_by: Option<u32>,
+}
+
+/// A thread switched out to wait, as reported by [`WaitAnalyzer::add`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitStart {
+    pub thread_id: u32,
+    pub timestamp: i64,
+    pub reason: u8,
+}
+
+/// How a thread's time in a span went on waiting
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WaitBreakdown {
+    /// Time waited per KWAIT_REASON, clipped to the span
+    pub by_reason: BTreeMap<u8, Duration>,
+    /// Threads that ended the waits, with how many of them each ended
+    pub readied_by: BTreeMap<u32, u32>,
+}
+
+impl WaitBreakdown {
+    pub fn total(&self) -> Duration {
+        self.by_reason.values().sum()
+    }
+}
+
+/// Lists the reasons longest first, e.g. `WrUserRequest 120ms, Executive 3ms; readied by thread 1234 (2)`
+impl fmt::Display for WaitBreakdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.by_reason.is_empty() {
+            return write!(f, "no waits");
+        }
+
+        let mut reasons: Vec<(&u8, &Duration)> = self.by_reason.iter().collect();
+        reasons.sort_by(|a, b| b.1.cmp(a.1));
+        let reasons: Vec<String> = reasons
+            .iter()
+            .map(|(reason, waited)| format!("{} {waited:?}", wait_reason_name(**reason)))
+            .collect();
+        write!(f, "{}", reasons.join(", "))?;
+
+        if !self.readied_by.is_empty() {
+            let readiers: Vec<String> = self
+                .readied_by
+                .iter()
+                .map(|(thread_id, count)| format!("thread {thread_id} ({count})"))
+                .collect();
+            write!(f, "; readied by {}", readiers.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Rebuilds when each thread waited, why, and which thread woke it up, from the kernel CSwitch events of the cswitch
+/// kernel flag and the ReadyThread events of the dispatcher flag. The last waits of each thread are kept, for
+/// explaining what a thread was doing during a delay reported by another provider
+#[derive(Debug, Default)]
+pub struct WaitAnalyzer {
+    waiting: HashMap<u32, Wait>, // Threads switched out to wait, keyed on thread id. `end` is not known yet
+    waits: HashMap<u32, VecDeque<Wait>>, // Keyed on thread id, oldest first
+}
+
+impl WaitAnalyzer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes in a scheduler event. Returns the wait it started, if it switched a thread out to wait. Other events are
+    /// ignored
+    pub fn add(&mut self, event: &ParsedEvent) -> Option<WaitStart> {
+        match SchedulerEvent::try_from(event).ok()? {
+            SchedulerEvent::ContextSwitch {
+                new_thread_id,
+                old_thread_id,
+                old_thread_wait_reason,
+                old_thread_state,
+            } => {
+                if let Some(mut wait) = self.waiting.remove(&new_thread_id) {
+                    wait.end = event.timestamp;
+                    let waits = self.waits.entry(new_thread_id).or_default();
+                    if waits.len() == MAX_WAITS_PER_THREAD {
+                        waits.pop_front();
+                    }
+                    waits.push_back(wait);
+                }
+
+                // Thread 0 is the idle thread, it never waits on anything
+                if old_thread_id == 0 || old_thread_state != STATE_WAITING {
+                    return None;
+                }
+                self.waiting.insert(
+                    old_thread_id,
+                    Wait {
+                        thread_id: old_thread_id,
+                        start: event.timestamp,
+                        end: event.timestamp,
+                        reason: old_thread_wait_reason,
+                        readied_by: None,
+                    },
+                );
+                Some(WaitStart {
+                    thread_id: old_thread_id,
+                    timestamp: event.timestamp,
+                    reason: old_thread_wait_reason,
+                })
+            }
+            SchedulerEvent::ReadyThread { thread_id } => {
+                if let Some(wait) = self.waiting.get_mut(&thread_id) {
+                    wait.readied_by = Some(event.thread_id);
+                }
+                None
+            }
+        }
+    }
+
+    /// The kept waits of `thread_id` that overlap `from..to`, oldest first, along with a wait still going on
+    pub fn waits(&self, thread_id: u32, from: i64, to: i64) -> impl Iterator<Item = Wait> + '_ {
+        let ongoing = self
+            .waiting
+            .get(&thread_id)
+            .map(|wait| Wait { end: to, ..*wait });
+        self.waits
+            .get(&thread_id)
+            .into_iter()
+            .flatten()
+            .copied()
+            .chain(ongoing)
+            .filter(move |wait| wait.start < to && wait.end > from)
+    }
+
+    /// What `thread_id` waited on during `from..to`
+    pub fn breakdown(&self, thread_id: u32, from: i64, to: i64) -> WaitBreakdown {
+        let mut breakdown = WaitBreakdown::default();
+        for wait in self.waits(thread_id, from, to) {
+            // Timestamps are 100ns ticks
+            let ticks = (wait.end.min(to) - wait.start.max(from)).max(0) as u64;
+            *breakdown.by_reason.entry(wait.reason).or_default() +=
+                Duration::from_nanos(ticks * 100);
+            if let Some(readier) = wait.readied_by.filter(|_| wait.end <= to) {
+                *breakdown.readied_by.entry(readier).or_default() += 1;
+            }
+        }
+        breakdown
+    }
+}
+
+/// Name of a KWAIT_REASON, https://learn.microsoft.com/en-us/windows/win32/etw/cswitch
+pub fn wait_reason_name(reason: u8) -> &'static str {
+    match reason {
+        0 => "Executive",
+        1 => "FreePage",
+        2 => "PageIn",
+        3 => "PoolAllocation",
+        4 => "DelayExecution",
+        5 => "Suspended",
+        6 => "UserRequest",
+        7 => "WrExecutive",
+        8 => "WrFreePage",
+        9 => "WrPageIn",
+        10 => "WrPoolAllocation",
+        11 => "WrDelayExecution",
+        12 => "WrSuspended",
+        WR_USER_REQUEST => "WrUserRequest",
+        14 => "WrEventPair",
+        15 => "WrQueue",
+        16 => "WrLpcReceive",
+        17 => "WrLpcReply",
+        18 => "WrVirtualMemory",
+        19 => "WrPageOut",
+        20 => "WrRendezvous",
+        21 => "WrKeyedEvent",
+        22 => "WrTerminated",
+        23 => "WrProcessInSwap",
+        24 => "WrCpuRateControl",
+        25 => "WrCalloutStack",
+        26 => "WrKernel",
+        27 => "WrResource",
+        28 => "WrPushLock",
+        29 => "WrMutex",
+        30 => "WrQuantumEnd",
+        31 => "WrDispatchInt",
+        32 => "WrPreempted",
+        33 => "WrYieldExecution",
+        34 => "WrFastMutex",
+        35 => "WrGuardedMutex",
+        36 => "WrRundown",
+        37 => "WrAlertByThreadId",
+        _ => "Unknown",
+    }
+}
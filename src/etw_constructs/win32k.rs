@@ -0,0 +1,240 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    time::Duration,
+};
+
+use windows::{core::GUID, Win32::System::Diagnostics::Etw::TRACE_LEVEL_INFORMATION};
+
+use super::{
+    controller::ProviderConfig,
+    parsed_event::ParsedEvent,
+    schemas::Fields,
+    wait::{WaitAnalyzer, WaitBreakdown, WR_USER_REQUEST},
+};
+
+/// Microsoft-Windows-Win32k. Logs keyboard and mouse input, message pump delays and hung window detection
+pub const WIN32K_GUID: GUID = GUID::from_u128(0x8c416c79_d49b_4f01_a467_e56d3aa8234c);
+
+/// UI delays kept for the summary, the oldest are dropped past this
+const MAX_UI_DELAYS: usize = 1000;
+
+/// Enables every Win32k event at information level. Narrow `match_any_keyword` to cut the volume down,
+/// the keywords of the installed provider are listed by `wevtutil gp Microsoft-Windows-Win32k`
+pub fn provider() -> ProviderConfig {
+    ProviderConfig {
+        guid: WIN32K_GUID,
+        level: TRACE_LEVEL_INFORMATION as u8,
+        match_any_keyword: 0,
+    }
+}
+
+/// Ids of the Win32k input delay events. The defaults are those of the manifests of Windows 10 and 11, as listed by
+/// `wevtutil gp Microsoft-Windows-Win32k /ge /gm`. Override them for a build that numbers them differently
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Win32kEventIds {
+    /// InputProcessDelay, logged when input sat in a thread's queue for too long. Carries TimeSinceOldestInputMs
+    pub input_process_delay: u16,
+    /// MessageCheckDelay, logged when a thread took too long to check its queue again after removing input.
+    /// Carries TimeSinceInputRemoveMs
+    pub message_check_delay: u16,
+}
+
+impl Default for Win32kEventIds {
+    fn default() -> Self {
+        Self {
+            input_process_delay: 38,
+            message_check_delay: 39,
+        }
+    }
+}
+
+/// The Win32k events the UI analysis reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Win32kEventKind {
+    InputProcessDelay,
+    MessageCheckDelay,
+    Other,
+}
+
+impl fmt::Display for Win32kEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::InputProcessDelay => "InputProcessDelay",
+            Self::MessageCheckDelay => "MessageCheckDelay",
+            Self::Other => "Other",
+        })
+    }
+}
+
+/// A Win32k event, with the fields used for input and UI responsiveness investigations pulled out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Win32kEvent {
+    pub event_id: u16,
+    pub kind: Win32kEventKind,
+    pub process_id: u32,
+    pub thread_id: u32,
+    pub timestamp: i64,
+    /// The thread that owns the message queue the event is about, which is not always the logging thread
+    pub target_thread_id: Option<u32>,
+    /// How long input waited on the thread, for delay events
+    pub delay_ms: Option<u64>,
+}
+
+impl Win32kEvent {
+    /// Returns the event if it was logged by Win32k, with the default event ids
+    pub fn from_event(event: &ParsedEvent) -> Option<Self> {
+        Self::from_event_with(event, &Win32kEventIds::default())
+    }
+
+    /// Returns the event if it was logged by Win32k. The delay field is read based on which event `ids` says it is
+    pub fn from_event_with(event: &ParsedEvent, ids: &Win32kEventIds) -> Option<Self> {
+        let fields = Fields::new(event, WIN32K_GUID).ok()?;
+
+        let (kind, delay_ms) = match event.event_id {
+            id if id == ids.input_process_delay => (
+                Win32kEventKind::InputProcessDelay,
+                fields.unsigned("TimeSinceOldestInputMs"),
+            ),
+            id if id == ids.message_check_delay => (
+                Win32kEventKind::MessageCheckDelay,
+                fields.unsigned("TimeSinceInputRemoveMs"),
+            ),
+            _ => (Win32kEventKind::Other, None),
+        };
+
+        Some(Self {
+            event_id: event.event_id,
+            kind,
+            process_id: event.process_id,
+            thread_id: event.thread_id,
+            timestamp: event.timestamp,
+            target_thread_id: fields.unsigned("ThreadId").map(|x| x as u32),
+            delay_ms,
+        })
+    }
+
+    /// Whether the event reports input waiting on a thread for at least `threshold_ms`
+    pub fn is_delayed(&self, threshold_ms: u64) -> bool {
+        self.delay_ms.is_some_and(|delay| delay >= threshold_ms)
+    }
+}
+
+/// A delay Win32k reported on a UI thread, with what the thread waited on meanwhile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UiDelay {
+    pub event: Win32kEvent,
+    /// When the delayed input arrived, in FILETIME ticks: the event timestamp less its delay
+    pub input_at: i64,
+    /// What the thread waited on from `input_at` to the event
+    pub waits: WaitBreakdown,
+    /// From the input arriving to the thread going back to waiting for messages. None until it does
+    pub input_to_paint: Option<Duration>,
+}
+
+/// Correlates Win32k input delays with the waits of the thread that was slow to take in its input. Needs the
+/// cswitch kernel flag for the waits, and the dispatcher flag for which thread ended each of them.
+///
+/// Input to paint is approximated as the time from the input arriving to the thread next waiting in GetMessage or
+/// WaitMessage (WrUserRequest), which is when it has handled the input and painted, as painting happens from the
+/// thread's message loop before it waits for the next message
+pub struct UiAnalyzer {
+    threshold_ms: u64,
+    ids: Win32kEventIds,
+    waits: WaitAnalyzer,
+    delays: VecDeque<UiDelay>,
+    pending: HashMap<u32, Vec<usize>>, // Delays waiting for input to paint, by thread id, as indexes into `delays`
+    dropped: usize, // Delays dropped off the front of `delays`, so the indexes in `pending` stay valid
+}
+
+impl UiAnalyzer {
+    /// Keeps delays of at least `threshold_ms`
+    pub fn new(threshold_ms: u64, ids: Win32kEventIds) -> Self {
+        Self {
+            threshold_ms,
+            ids,
+            waits: WaitAnalyzer::new(),
+            delays: VecDeque::new(),
+            pending: HashMap::new(),
+            dropped: 0,
+        }
+    }
+
+    /// Takes in Win32k and scheduler events. Other events are ignored
+    pub fn add(&mut self, event: &ParsedEvent) {
+        if let Some(wait) = self.waits.add(event) {
+            if wait.reason == WR_USER_REQUEST {
+                self._resolve(wait.thread_id, wait.timestamp);
+            }
+            return;
+        }
+
+        let Some(win32k) = Win32kEvent::from_event_with(event, &self.ids) else {
+            return;
+        };
+        if win32k.kind == Win32kEventKind::Other || !win32k.is_delayed(self.threshold_ms) {
+            return;
+        }
+        let thread_id = win32k.target_thread_id.unwrap_or(win32k.thread_id);
+        // Delays are in ms, timestamps in 100ns ticks
+        let input_at = win32k.timestamp - win32k.delay_ms.unwrap_or_default() as i64 * 10_000;
+
+        if self.delays.len() == MAX_UI_DELAYS {
+            self.delays.pop_front();
+            self.dropped += 1;
+        }
+        self.delays.push_back(UiDelay {
+            waits: self.waits.breakdown(thread_id, input_at, win32k.timestamp),
+            event: win32k,
+            input_at,
+            input_to_paint: None,
+        });
+        self.pending
+            .entry(thread_id)
+            .or_default()
+            .push(self.dropped + self.delays.len() - 1);
+    }
+
+    /// The delays kept, oldest first
+    pub fn delays(&self) -> &VecDeque<UiDelay> {
+        &self.delays
+    }
+
+    /// Resolves the input to paint of the delays pending on `thread_id`, which went back to its message loop at
+    /// `timestamp`
+    fn _resolve(&mut self, thread_id: u32, timestamp: i64) {
+        for index in self.pending.remove(&thread_id).unwrap_or_default() {
+            let Some(delay) = index
+                .checked_sub(self.dropped)
+                .and_then(|index| self.delays.get_mut(index))
+            else {
+                continue;
+            };
+            let ticks = (timestamp - delay.input_at).max(0) as u64;
+            delay.input_to_paint = Some(Duration::from_nanos(ticks * 100));
+        }
+    }
+}
+
+/// One line per delay, e.g. `MessageCheckDelay on thread 1234 of process 56: input waited 250ms, input to paint 310ms,
+/// waits: WrUserRequest 200ms`
+impl fmt::Display for UiAnalyzer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for delay in &self.delays {
+            let event = &delay.event;
+            write!(
+                f,
+                "{} on thread {} of process {}: input waited {}ms",
+                event.kind,
+                event.target_thread_id.unwrap_or(event.thread_id),
+                event.process_id,
+                event.delay_ms.unwrap_or_default(),
+            )?;
+            if let Some(input_to_paint) = delay.input_to_paint {
+                write!(f, ", input to paint {input_to_paint:?}")?;
+            }
+            writeln!(f, ", waits: {}", delay.waits)?;
+        }
+        Ok(())
+    }
+}
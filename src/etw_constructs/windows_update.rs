@@ -0,0 +1,69 @@
+use windows::{core::GUID, Win32::System::Diagnostics::Etw::TRACE_LEVEL_INFORMATION};
+
+use super::{controller::ProviderConfig, parsed_event::ParsedEvent, schemas::Fields};
+
+/// Microsoft-Windows-WindowsUpdateClient. Logs update downloads and installs
+pub const WINDOWS_UPDATE_CLIENT_GUID: GUID =
+    GUID::from_u128(0x945a8954_c147_4acd_923f_40c45405a658);
+
+// Events of the Operational channel
+const EVENT_INSTALL_SUCCEEDED: u16 = 19;
+const EVENT_INSTALL_FAILED: u16 = 20;
+const EVENT_INSTALL_STARTED: u16 = 43;
+const EVENT_DOWNLOAD_STARTED: u16 = 44;
+
+/// Enables every Windows Update client event at information level
+pub fn provider() -> ProviderConfig {
+    ProviderConfig {
+        guid: WINDOWS_UPDATE_CLIENT_GUID,
+        level: TRACE_LEVEL_INFORMATION as u8,
+        match_any_keyword: 0,
+    }
+}
+
+/// What happened to an update
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateStatus {
+    DownloadStarted,
+    InstallStarted,
+    Installed,
+    /// Holds the HRESULT the install failed with
+    InstallFailed(u32),
+}
+
+/// A Windows Update client event about one update
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateEvent {
+    pub title: String,
+    pub update_id: String,
+    pub revision: u32,
+    pub status: UpdateStatus,
+    pub process_id: u32,
+    pub timestamp: i64,
+}
+
+impl UpdateEvent {
+    /// Returns the event if it is an update download or install event
+    pub fn from_event(event: &ParsedEvent) -> Option<Self> {
+        let fields = Fields::new(event, WINDOWS_UPDATE_CLIENT_GUID).ok()?;
+
+        let status = match event.event_id {
+            EVENT_DOWNLOAD_STARTED => UpdateStatus::DownloadStarted,
+            EVENT_INSTALL_STARTED => UpdateStatus::InstallStarted,
+            EVENT_INSTALL_SUCCEEDED => UpdateStatus::Installed,
+            EVENT_INSTALL_FAILED => {
+                UpdateStatus::InstallFailed(fields.optional("errorCode") as u32)
+            }
+            _ => return None,
+        };
+
+        Some(Self {
+            title: fields.string("updateTitle"),
+            update_id: fields.string("updateGuid"),
+            revision: fields.optional("updateRevisionNumber") as u32,
+            status,
+            process_id: event.process_id,
+            timestamp: event.timestamp,
+        })
+    }
+}
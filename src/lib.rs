@@ -0,0 +1 @@
+pub mod etw_constructs;
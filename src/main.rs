@@ -1,30 +1,134 @@
-mod etw_constructs;
-
-use core::slice;
-use std::{collections::HashMap, ffi::CString, mem, sync::LazyLock};
+use std::{
+    ffi::{CStr, CString},
+    fs::File,
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc, LazyLock, Mutex, Once,
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
 
+use cli::{Cli, Command, OutputFormat};
+use etw_constructs::audit::AuditEvent;
+use etw_constructs::bookmark::Bookmark;
+use etw_constructs::capture_diff::CaptureSummary;
+use etw_constructs::capture_header::CaptureHeader;
+use etw_constructs::clr::ClrAnalyzer;
+use etw_constructs::consumer;
+use etw_constructs::controller::{Controller, ControllerConfig, ExistingSessionPolicy};
+use etw_constructs::coordinator::{Agent, AgentConnection, AgentMessage, Coordinator};
+use etw_constructs::enumeration;
+use etw_constructs::environment::EnvironmentCapture;
+use etw_constructs::event_filter::EventFilter;
+use etw_constructs::filter::{FilterSet, SessionFilter, PROCESS_GUID};
+use etw_constructs::filter_reload::{FilterFileWatcher, FilterUpdate};
+use etw_constructs::keep_alive::KeepAliveMarker;
+use etw_constructs::latency::LatencyTracker;
+use etw_constructs::mapped_file::MappedFile;
+use etw_constructs::memory::MemoryAnalyzer;
+use etw_constructs::metrics::MetricsServer;
+use etw_constructs::pipeline_error::{
+    PipelineError, PipelineErrorCounters, PipelineStage, PIPELINE_ERROR_GUID,
+};
+use etw_constructs::privilege::PrivilegeMonitor;
+use etw_constructs::process_graph::ProcessGraph;
+use etw_constructs::process_tracker::ProcessTracker;
+use etw_constructs::raw_capture::{RawReader, RawWriter};
+use etw_constructs::regions::{AnomalyDetector, AnomalyThresholds, RegionsFile};
+use etw_constructs::schema_cache::SchemaCache;
+use etw_constructs::security_log::SecurityLog;
+use etw_constructs::sink::{open_output, CsvSink, EventSink, FilteredSinks, JsonLinesSink};
+use etw_constructs::stop_handle::StopHandle;
+use etw_constructs::system_config::{self, MachineProfile};
+use etw_constructs::taxonomy::Normalizer;
 use etw_constructs::tdh_wrapper;
-use etw_constructs::ETWSession;
-use windows::Win32::System::Diagnostics::Etw::KERNEL_LOGGER_NAMEA;
-use windows::Win32::System::Diagnostics::Etw::{
-    EVENT_HEADER_FLAG_32_BIT_HEADER, EVENT_HEADER_FLAG_64_BIT_HEADER, EVENT_RECORD,
-    TRACE_EVENT_INFO,
+use etw_constructs::time_series::{TimeSeriesFormat, TimeSeriesSink};
+use etw_constructs::win32k::{self, UiAnalyzer, Win32kEventIds};
+use etw_constructs::{ETWSession, EtwError, ParsedEvent, PropertyValue};
+use event_viewer::etw_constructs;
+use windows::Win32::Foundation::{
+    ERROR_INVALID_DATA, ERROR_INVALID_PARAMETER, STATUS_CONTROL_C_EXIT,
 };
+use windows::Win32::System::Diagnostics::Etw::EVENT_RECORD;
+use windows::Win32::System::Diagnostics::Etw::KERNEL_LOGGER_NAMEA;
+
+use tdh_wrapper::ProcessTypeGroup1;
 
-use tdh_wrapper::{ProcessTypeGroup1, Tdh};
+mod cli;
 
 // Use NT Kernel logger, so KERNEL_LOGGER_NAMEA
 static SESSION_NAME: LazyLock<CString> = LazyLock::new(|| unsafe {
     CString::from_vec_unchecked(KERNEL_LOGGER_NAMEA.as_bytes().to_vec())
 });
 
+static MACHINE_PROFILE: LazyLock<Mutex<MachineProfile>> =
+    LazyLock::new(|| Mutex::new(MachineProfile::default()));
+
+/// Rolling window the delivery latency objective is checked over
+const LATENCY_WINDOW: Duration = Duration::from_secs(10);
+
+/// Win32k input delays shorter than this are not reported
+const UI_DELAY_THRESHOLD_MS: u64 = 100;
+
+/// Failed writes in a row after which export gives up on its outputs
+const MAX_CONSECUTIVE_SINK_ERRORS: u32 = 100;
+
+/// How long the export loop waits for an event before it goes back to the events held for their environment
+const EXPORT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Used instead of the NT Kernel Logger when user-mode providers are enabled
+static PROVIDER_SESSION_NAME: &CStr = c"EtwRustTool";
+
+static RAW_WRITER: Mutex<Option<RawWriter>> = Mutex::new(None);
+
+/// Schemas of the events printed by [`on_process_creation`], so each kind of event is only looked up once
+static SCHEMA_CACHE: LazyLock<SchemaCache> = LazyLock::new(SchemaCache::new);
+
+/// Events [`on_process_creation`] could not decode and [`on_raw_event`] could not capture, summarized once the session
+/// stops
+static CALLBACK_PIPELINE_ERRORS: LazyLock<PipelineErrorCounters> =
+    LazyLock::new(PipelineErrorCounters::default);
+
 unsafe extern "system" fn on_process_creation(eventrecord: *mut EVENT_RECORD) {
     let record = unsafe { eventrecord.as_ref() }.expect("Expected trace, found nothing");
 
+    if let Some(bookmark) = Bookmark::from_record(record) {
+        println!("\n=== Bookmark: {} ===\n", bookmark.label);
+        return;
+    }
+
+    if let Some(audit) = AuditEvent::from_record(record) {
+        println!("=== {} {:?} ===", audit.action, audit.parameters);
+        return;
+    }
+
+    if record.UserDataLength == 0 {
+        return;
+    }
+
+    // The trace header and SystemConfig rundown events describe the machine rather than a process
+    if system_config::is_system_config_event(record) {
+        match ParsedEvent::from_record(record) {
+            Ok(event) => MACHINE_PROFILE
+                .lock()
+                .expect("Machine profile lock was poisoned")
+                .record(&event),
+            Err(err) => print_decode_error(record, err),
+        }
+        return;
+    }
+
     // example from https://learn.microsoft.com/en-us/windows/win32/etw/using-tdhformatproperty-to-consume-event-data
     // https://learn.microsoft.com/en-us/windows/win32/api/evntcons/ns-evntcons-event_header
     // https://learn.microsoft.com/en-us/windows/win32/api/evntprov/ns-evntprov-event_descriptor
-    if ![0x1].contains(&record.EventHeader.EventDescriptor.Opcode) || record.UserDataLength == 0 {
+    // Only process start events (op code 1) are printed for the process provider, other providers are printed in full
+    let is_process_event = record.EventHeader.ProviderId == PROCESS_GUID;
+    if is_process_event && ![0x1].contains(&record.EventHeader.EventDescriptor.Opcode) {
         return;
     }
 
@@ -39,82 +143,966 @@ unsafe extern "system" fn on_process_creation(eventrecord: *mut EVENT_RECORD) {
         record.EventHeader.EventDescriptor.Opcode
     );
 
-    let mut buffer = Tdh::get_event_information(record, None).expect("Could not get buffer information. Please consult https://learn.microsoft.com/en-us/windows/win32/debug/system-error-codes--0-499-. For what the error code means.");
+    match ParsedEvent::from_record_cached(
+        record,
+        consumer::trace_header_of(record).as_ref(),
+        &SCHEMA_CACHE,
+    ) {
+        Ok(event) if !is_process_event => {
+            println!();
+            println!("{:#?}", event);
+            println!();
+        }
+        Ok(event) => {
+            let process_info = ProcessTypeGroup1::from(&event);
 
-    if let Some(trace) = (buffer.as_mut_ptr() as *mut TRACE_EVENT_INFO).as_mut() {
-        // [EVENT_PROPERTY_INFO; 1] can be more than one element as given by PropertyCount
-        let property_infos = slice::from_raw_parts(
-            trace.EventPropertyInfoArray.as_ptr(),
-            trace.PropertyCount as usize,
-        );
+            // op code must be 1
+            println!();
+            println!("{:#?}", process_info);
+            println!();
+        }
+        Err(err) => print_decode_error(record, err),
+    }
+}
 
-        let pointer_size: u32 = dbg!(if record.EventHeader.Flags as u32
-            & EVENT_HEADER_FLAG_32_BIT_HEADER
-            != 0
-        {
-            4
-        } else if record.EventHeader.Flags as u32 & EVENT_HEADER_FLAG_64_BIT_HEADER != 0 {
-            8
-        } else {
-            mem::size_of::<*const u32>() as u32
+/// Prints a pipeline error event in place of an event that could not be decoded
+fn print_decode_error(record: &EVENT_RECORD, err: EtwError) {
+    let error = PipelineError::decode(record, 0, err);
+    CALLBACK_PIPELINE_ERRORS.record_error(&error);
+    println!();
+    println!("{:#?}", error.to_event());
+    println!();
+}
+
+/// Raw capture mode only copies each event to disk, decoding is left to the `decode` command
+unsafe extern "system" fn on_raw_event(eventrecord: *mut EVENT_RECORD) {
+    let Some(record) = (unsafe { eventrecord.as_ref() }) else {
+        return;
+    };
+
+    if let Some(writer) = RAW_WRITER
+        .lock()
+        .expect("Raw writer lock was poisoned")
+        .as_mut()
+    {
+        // The capture only holds event records, so the error is kept for the summary instead
+        if let Err(err) = writer.write(record) {
+            CALLBACK_PIPELINE_ERRORS.record_error(&PipelineError::capture(record, err));
+        }
+    }
+}
+
+/// Runs every event of a raw capture file through the same decoding as a live session
+fn decode(path: &Path) -> Result<(), EtwError> {
+    for raw_event in RawReader::open(path)? {
+        let raw_event = raw_event?;
+        let mut record = raw_event.as_record();
+        unsafe { on_process_creation(&mut record) };
+    }
+
+    print_machine_profile();
+    report_pipeline_errors(&CALLBACK_PIPELINE_ERRORS);
+    Ok(())
+}
+
+/// Runs the live captures coordinators that connect on `address` ask for, one after another, and streams their events
+/// back to them. Runs until killed
+fn run_agent(address: SocketAddr) -> Result<(), EtwError> {
+    let agent = Agent::bind(address)?;
+    eprintln!("Waiting for a coordinator on {}", agent.address()?);
+    loop {
+        let mut connection = agent.accept()?;
+        eprintln!("Capturing for {}", connection.coordinator());
+        // A failed capture is reported to the coordinator that asked for it, and the agent waits for the next one
+        if let Err(err) = agent_capture(&mut connection) {
+            eprintln!("{err}");
+            let _ = connection.send(&AgentMessage::Error {
+                message: err.to_string(),
+            });
+        }
+        let _ = connection.send(&AgentMessage::Finished);
+    }
+}
+
+/// Runs the capture the coordinator of `connection` asks for, with every event going back to it instead of to the
+/// outputs its options name
+fn agent_capture(connection: &mut AgentConnection) -> Result<(), EtwError> {
+    let Some(args) = connection.start_args()? else {
+        return Ok(());
+    };
+    let cli = Cli::parse_capture_args(&args).map_err(|context| EtwError::Win32 {
+        status: ERROR_INVALID_PARAMETER,
+        context,
+    })?;
+
+    let (session, header) = live_session(&cli, None)?;
+    if let Some(filter) = cli.filter() {
+        session.set_filter(filter);
+    }
+    if let Some(manifest_dir) = &cli.manifest_dir {
+        session.set_manifest_dir(manifest_dir.clone());
+    }
+    connection.stop_on_command(session.stop_handle())?;
+    stop_after(cli.duration, session.stop_handle());
+
+    let mut sink = FilteredSinks::new().sink(
+        connection.coordinator().to_string(),
+        Box::new(connection.sink()),
+        EventFilter::default(),
+    );
+    sink.write_header(&header.process_ids(cli.filter_pids.clone()))?;
+    let processes = process_tracker(&session)?;
+    export(session, processes, sink, None, None, &cli)
+}
+
+/// Runs a capture with the options `args` on every agent in `agents` at once, and writes the headers and events they
+/// send back to --out as JSON Lines, each with a `host` field naming the agent. Stops the agents after --duration or
+/// when Ctrl-C is pressed
+fn coordinate(agents: &[String], args: &[String], cli: &Cli) -> Result<(), EtwError> {
+    let coordinator = Coordinator::connect(agents)?;
+    let stopper = coordinator.stopper();
+    ctrlc::set_handler(move || {
+        eprintln!("\nCtrl-C pressed, stopping the agents\n");
+        stopper.stop();
+    })
+    .expect("Could not create ctrlc handler!");
+    if let Some(duration) = cli.duration {
+        let stopper = coordinator.stopper();
+        thread::spawn(move || {
+            thread::sleep(duration);
+            stopper.stop();
         });
+    }
 
-        let mut property_info_map: HashMap<String, String> = HashMap::new();
+    let mut out = open_output(&cli.out)?;
+    let stopper = coordinator.stopper();
+    coordinator.start(args)?;
+    for message in coordinator {
+        match &message.message {
+            AgentMessage::Error { message: error } => eprintln!("{}: {error}", message.host),
+            AgentMessage::Finished => eprintln!("{} finished", message.host),
+            AgentMessage::Header { .. } | AgentMessage::Event { .. } => {}
+        }
+        let Some(line) = message.tagged() else {
+            continue;
+        };
+        // The agents would otherwise keep capturing with nowhere for their events to go
+        if let Err(err) = serde_json::to_writer(&mut out, &line)
+            .map_err(io::Error::from)
+            .and_then(|()| out.write_all(b"\n"))
+        {
+            stopper.stop();
+            return Err(EtwError::from_io(&err, "Could not write the event"));
+        }
+    }
+    out.flush()
+        .map_err(|err| EtwError::from_io(&err, "Could not flush the events"))
+}
 
-        let mut userdata: &[u8] =
-            slice::from_raw_parts(record.UserData as *const u8, record.UserDataLength as usize);
+/// Reads a summary saved by `summarize`, or parses an .etl file into one on `decode_threads` threads
+fn summarize(path: &Path, decode_threads: usize) -> Result<CaptureSummary, EtwError> {
+    if path
+        .extension()
+        .is_some_and(|extension| extension == "json")
+    {
+        let read_error =
+            |err: io::Error| EtwError::from_io(&err, format!("Could not read {:?}", path));
+        let file = File::open(path).map_err(read_error)?;
+        return serde_json::from_reader(io::BufReader::new(file))
+            .map_err(|err| read_error(io::Error::from(err)));
+    }
 
-        for property_info in property_infos
-            .iter()
-            .take(trace.TopLevelPropertyCount as usize)
+    let mut summary = CaptureSummary::new();
+    let session = ETWSession::from_file(path, None)?;
+    session.set_decode_threads(decode_threads);
+    let mut stream = session.events()?;
+    for event in stream.by_ref() {
+        summary.add(&event);
+    }
+    stream.join()?;
+    Ok(summary)
+}
+
+fn list_providers(name: Option<&str>) -> Result<(), EtwError> {
+    let name = name.map(str::to_lowercase);
+    for provider in enumeration::list_providers()? {
+        if name
+            .as_ref()
+            .is_some_and(|name| !provider.name.to_lowercase().contains(name))
         {
-            let (property_data, consumed_bytes) = Tdh::format_property(trace, None, pointer_size, property_info, userdata).expect("Could not get buffer information. Please consult https://learn.microsoft.com/en-us/windows/win32/debug/system-error-codes--0-499-. For what the error code means.");
+            continue;
+        }
+        println!(
+            "{}  {:?}  {}",
+            PropertyValue::Guid(provider.guid),
+            provider.schema_source,
+            provider.name
+        );
+    }
+    Ok(())
+}
 
-            let property_name = {
-                let property_name: Vec<u16> = buffer[property_info.NameOffset as usize..]
-                    .chunks(2)
-                    .map(|x| u16::from_le_bytes([x[0], x[1]]))
-                    .take_while(|x| *x != 0)
-                    .collect();
+fn list_sessions() -> Result<(), EtwError> {
+    for session in enumeration::list_active_sessions()? {
+        println!(
+            "{}{}{}, flags {:#x}, {} KB of {} buffers, {} events lost",
+            session.name,
+            if session.is_real_time() {
+                " (real-time)"
+            } else {
+                ""
+            },
+            session
+                .log_file
+                .as_ref()
+                .map(|log_file| format!(" -> {log_file}"))
+                .unwrap_or_default(),
+            session.enable_flags.0,
+            session.stats.buffer_memory_bytes() / 1024,
+            if session.stats.paged_memory {
+                "paged"
+            } else {
+                "nonpaged"
+            },
+            session.stats.events_lost
+        );
+    }
+    Ok(())
+}
 
-                String::from_utf16_lossy(&property_name)
-            };
+/// Replays the .etl file written with --etl-out through the analyzers, and writes a WPA regions file next to it for the
+/// anomalies they find. Lets WPA highlight them. Nothing is written while a session kept alive is still logging to it
+fn write_regions(cli: &Cli) -> Result<(), EtwError> {
+    let Some(etl_out) = cli.etl_out.as_deref().filter(|_| !cli.keep_alive) else {
+        return Ok(());
+    };
 
-            // Get the property data as all the valid bytes in the property data buffer up until the first nul byte
-            let property_data = {
-                let valid_property_slice = &property_data[..property_data
-                    .iter()
-                    .position(|x| *x == 0)
-                    .unwrap_or(property_data.len())];
+    let session = ETWSession::from_file(etl_out, None)?;
+    session.set_decode_threads(cli.decode_threads());
+    let mut stream = session.events()?;
+    let mut detector = AnomalyDetector::new(AnomalyThresholds::default());
+    let processes = ProcessTracker::new();
+    let mut privileges = PrivilegeMonitor::new();
+    for event in stream.by_ref() {
+        processes.add(&event);
+        detector.add(&event);
+        detector.add_findings(&event, privileges.add(&event, &processes));
+    }
+    stream.join()?;
 
-                String::from_utf16_lossy(valid_property_slice)
+    let path = RegionsFile::path_for(etl_out);
+    RegionsFile::from_anomalies(&detector).write(&path)?;
+    eprintln!(
+        "Wrote the regions of {} anomalies to {}",
+        detector.anomalies().len(),
+        path.display()
+    );
+    Ok(())
+}
+
+fn print_machine_profile() {
+    // SystemConfig rundown events are only emitted when the session stops, so the profile is complete here
+    let machine_profile = MACHINE_PROFILE
+        .lock()
+        .expect("Machine profile lock was poisoned");
+    if !machine_profile.is_empty() {
+        println!("Machine profile:");
+        println!("{:#?}", machine_profile);
+    }
+}
+
+/// The first Ctrl-C stops the session and lets the events it already buffered drain to the output. A second one forces
+/// the session to stop and exits without waiting, so a stuck drain never leaves the session running
+fn handle_ctrlc(stop_handle: StopHandle) {
+    // The handler can only be set once, so an agent running one capture after another swaps the session it stops
+    static STOP_HANDLE: Mutex<Option<StopHandle>> = Mutex::new(None);
+    static PRESSES: AtomicU32 = AtomicU32::new(0);
+    static SET_HANDLER: Once = Once::new();
+
+    *STOP_HANDLE
+        .lock()
+        .expect("Ctrl-C stop handle lock was poisoned") = Some(stop_handle);
+    PRESSES.store(0, Ordering::Relaxed);
+    SET_HANDLER.call_once(|| {
+        ctrlc::set_handler(|| {
+            let Some(stop_handle) = STOP_HANDLE
+                .lock()
+                .expect("Ctrl-C stop handle lock was poisoned")
+                .clone()
+            else {
+                return;
             };
+            if PRESSES.fetch_add(1, Ordering::Relaxed) == 0 {
+                eprintln!(
+                    "\nCtrl-C pressed, stopping trace session. Press Ctrl-C again to force it\n"
+                );
+                stop_handle.stop();
+            } else {
+                eprintln!("\nForcing the trace session to stop\n");
+                stop_handle.force_stop();
+                // Nothing is dropped on exit, so mapped outputs would keep their zero padding
+                MappedFile::close_all();
+                std::process::exit(STATUS_CONTROL_C_EXIT.0);
+            }
+        })
+        .expect("Could not create ctrlc handler!");
+    });
+}
 
-            // map property name to its value
-            property_info_map.insert(property_name, property_data);
+/// Stops the session of `stop_handle` once `duration` has passed, if one was given
+fn stop_after(duration: Option<Duration>, stop_handle: StopHandle) {
+    if let Some(duration) = duration {
+        thread::spawn(move || {
+            thread::sleep(duration);
+            stop_handle.stop();
+        });
+    }
+}
+
+/// Tracks the processes of `session`. An attached session logged its rundown when it was started, so the processes
+/// running now are read instead
+fn process_tracker(session: &ETWSession) -> Result<ProcessTracker, EtwError> {
+    if session
+        .controller()
+        .is_some_and(|controller| controller.is_attached())
+    {
+        ProcessTracker::from_snapshot()
+    } else {
+        Ok(ProcessTracker::new())
+    }
+}
 
-            // move start of user data by consumed data bytes, since we already visited it
-            userdata = &userdata[consumed_bytes..];
+/// Opens the `format` output at `path`. Anything but csv is written as json
+fn open_sink(format: OutputFormat, path: &Path, cli: &Cli) -> Result<Box<dyn EventSink>, EtwError> {
+    if let Some(bucket) = cli.bucket {
+        let format = match format {
+            OutputFormat::Csv => TimeSeriesFormat::Csv,
+            _ => TimeSeriesFormat::JsonLines,
+        };
+        return Ok(Box::new(TimeSeriesSink::create(path, format, bucket)?));
+    }
+
+    Ok(match format {
+        OutputFormat::Csv => Box::new(CsvSink::create(path)?),
+        _ => match cli.mapped_file().filter(|_| path != Path::new("-")) {
+            Some(config) => Box::new(JsonLinesSink::create_mapped(path, config)?),
+            None => Box::new(JsonLinesSink::create(path)?),
+        },
+    })
+}
+
+/// Applies what the filter file sets to the outputs, the privilege rules and the session filter. A file that could
+/// not be used keeps all of them as they are. Returns what could not be applied, for writing into the output
+fn apply_filter_update(
+    update: Result<FilterUpdate, String>,
+    sink: &mut FilteredSinks,
+    privileges: &mut PrivilegeMonitor,
+    session_filter: Option<&SessionFilter>,
+) -> Vec<PipelineError> {
+    let filter_file_error = |context: String| {
+        PipelineError::filter_file(EtwError::Io {
+            status: ERROR_INVALID_DATA,
+            context,
+        })
+    };
+    let update = match update {
+        Ok(update) => update,
+        Err(err) => {
+            return vec![filter_file_error(format!(
+                "Kept the current filters, {err}"
+            ))]
         }
+    };
 
-        let process_info = ProcessTypeGroup1::from(property_info_map);
+    let mut errors = Vec::new();
+    let mut unknown_output = |name: &str| {
+        errors.push(filter_file_error(format!(
+            "The filter file names {name:?}, which is not an output"
+        )))
+    };
+    // Outputs the file no longer mentions go back to how they were started
+    let unlisted = |listed: &dyn Fn(&str) -> bool| -> Vec<String> {
+        sink.names()
+            .filter(|name| !listed(name))
+            .map(str::to_string)
+            .collect()
+    };
+    let unfiltered = unlisted(&|name| update.filters.contains_key(name));
+    let unprojected = unlisted(&|name| update.projections.contains_key(name));
+    for name in unfiltered {
+        sink.reset_filter(&name);
+    }
+    for name in unprojected {
+        sink.set_projection(&name, Vec::new());
+    }
+    for (name, filter) in update.filters {
+        if !sink.set_filter(&name, filter) {
+            unknown_output(&name);
+        }
+    }
+    for (name, properties) in update.projections {
+        if !sink.set_projection(&name, properties) {
+            unknown_output(&name);
+        }
+    }
+    if let Some(rules) = update.rules {
+        privileges.set_rules(rules);
+    }
+    if let (Some(process_ids), Some(session_filter)) = (update.process_ids, session_filter) {
+        session_filter.set((!process_ids.is_empty()).then(|| {
+            process_ids
+                .iter()
+                .fold(FilterSet::new(), |filter, pid| filter.process_id(*pid))
+        }));
+    }
+    errors
+}
 
-        // op code must be 1
-        println!();
-        println!("{:#?}", process_info);
-        println!();
+/// Summarizes the failures `pipeline_errors` counted, if any, once a capture is over
+fn report_pipeline_errors(pipeline_errors: &PipelineErrorCounters) {
+    if pipeline_errors.total() == 0 {
+        return;
+    }
+    eprintln!(
+        "Warning: the pipeline failed {} times, see the {} events in the output:",
+        pipeline_errors.total(),
+        PropertyValue::Guid(PIPELINE_ERROR_GUID)
+    );
+    for stage in PipelineStage::ALL {
+        let count = pipeline_errors.count(stage);
+        if count != 0 {
+            eprintln!("    {} {count}", stage.as_str());
+        }
+    }
+    if let Some(error) = pipeline_errors.last_error() {
+        eprintln!("    the last one: {}", error.error);
     }
 }
 
-fn main() {
-    let session = ETWSession::new(&SESSION_NAME, Some(on_process_creation));
+/// Starts the live session `cli` configures, or attaches to the one a run with --keep-alive left running
+fn live_session(
+    cli: &Cli,
+    handler: Option<unsafe extern "system" fn(*mut EVENT_RECORD)>,
+) -> Result<(ETWSession, CaptureHeader), EtwError> {
+    // Another tool's session of the same name is only stopped when asked to
+    let mut config = ControllerConfig {
+        enable_flags: cli.enable_flags(),
+        log_file: cli.log_file(),
+        existing_session: if cli.stop_existing {
+            ExistingSessionPolicy::StopAndRestart
+        } else {
+            ExistingSessionPolicy::Error
+        },
+        keep_alive: cli.keep_alive,
+        providers: cli.provider_configs(),
+        // A filter file can widen the processes kept while the session runs, which providers could not follow
+        provider_filter: cli
+            .filter()
+            .filter(|_| cli.filter_file.is_none())
+            .map(|filter| filter.provider_filter())
+            .unwrap_or_default(),
+        buffers: cli.buffers(),
+        stack_walk: cli.stacks.clone(),
+        mem_info: cli.mem_info,
+        sequence: cli.sequence,
+        secure: cli.secure,
+        buffer_memory: cli.buffer_memory(),
+        ..Default::default()
+    };
+    // The NT Kernel Logger cannot enable user-mode providers
+    let session_name: &'static CStr = if config.providers.is_empty() {
+        &SESSION_NAME
+    } else {
+        PROVIDER_SESSION_NAME
+    };
+    // A session that is reattached to is never stopped, whoever started it
+    if cli.reattach {
+        config.existing_session = ExistingSessionPolicy::AttachExisting;
+        match KeepAliveMarker::read(session_name)? {
+            Some(marker) => eprintln!(
+                "Reattaching to {:?}, left running by process {} since {}",
+                marker.session_name, marker.consumer_process_id, marker.attached_at
+            ),
+            None if Controller::query(session_name).is_ok() => eprintln!(
+                "Attaching to {:?}, which was not left running with --keep-alive",
+                session_name
+            ),
+            None => eprintln!("No session is running, starting a new one"),
+        }
+    }
+    let header = CaptureHeader::for_session(&session_name.to_string_lossy(), &config);
+    Ok((
+        ETWSession::with_config(session_name, config, handler)?,
+        header,
+    ))
+}
 
-    ctrlc::set_handler(move || {
-        if etw_constructs::consumer::SIGINT.set(()).is_ok() {
-            println!("\nCtrl-C pressed, stopping trace session\n");
+/// Streams every decoded event of `session` to `sink` until the session stops or Ctrl-C is pressed.
+/// Status messages go to stderr so stdout only holds events. Which analyses and enrichments run is read from `cli`
+fn export(
+    session: ETWSession,
+    processes: ProcessTracker,
+    mut sink: FilteredSinks,
+    filter_file: Option<FilterFileWatcher>,
+    mut environment: Option<EnvironmentCapture>,
+    cli: &Cli,
+) -> Result<(), EtwError> {
+    // The filter file is applied before the session starts, so its session filter holds from the first event
+    let session_filter = session.session_filter();
+    let mut privileges = PrivilegeMonitor::new();
+    // Its errors are written once the output is set up
+    let startup_errors = filter_file
+        .as_ref()
+        .and_then(FilterFileWatcher::take_update)
+        .map(|update| {
+            apply_filter_update(update, &mut sink, &mut privileges, session_filter.as_ref())
+        })
+        .unwrap_or_default();
+
+    let mut stream = session.events()?;
+    let security_log = cli
+        .security_log()
+        .then(SecurityLog::subscribe)
+        .transpose()?;
+
+    handle_ctrlc(stream.stop_handle());
+
+    // Managed processes get a GC and exception summary at the end, every process a memory summary and, with win32k
+    // enabled, UI threads their input delays. Privilege escalation findings are reported as they happen
+    let mut clr = ClrAnalyzer::new();
+    let mut ui = cli
+        .providers
+        .contains(&win32k::WIN32K_GUID)
+        .then(|| UiAnalyzer::new(UI_DELAY_THRESHOLD_MS, Win32kEventIds::default()));
+    let mut memory = MemoryAnalyzer::new();
+    let mut normalizer = Normalizer::new();
+    let pipeline_errors = stream.pipeline_errors();
+    let mut output = ExportOutput {
+        sink,
+        pipeline_errors: Arc::clone(&pipeline_errors),
+        // Replayed events were logged long before they are written, so only live sessions have a delivery latency
+        latency: cli.trace.is_none().then(|| {
+            Arc::new(Mutex::new(LatencyTracker::new(
+                cli.latency_slo,
+                LATENCY_WINDOW,
+            )))
+        }),
+        consecutive_errors: 0,
+    };
+    for error in startup_errors {
+        output.report(error);
+    }
+    // Windows are checked on their own thread, so they still end while the outputs are stuck on an event
+    let latency_finished = Arc::new(AtomicBool::new(false));
+    let latency_watcher = output
+        .latency
+        .as_ref()
+        .map(|latency| {
+            let latency = Arc::clone(latency);
+            let finished = Arc::clone(&latency_finished);
+            thread::Builder::new()
+                .name("etw-latency".to_string())
+                .spawn(move || {
+                    LatencyTracker::watch(&latency, &finished, |breach| {
+                        eprintln!("Warning: {breach}")
+                    })
+                })
+                .map_err(|err| EtwError::from_io(&err, "Could not spawn the latency thread"))
+        })
+        .transpose()?;
+    let _metrics = match (cli.metrics, &output.latency) {
+        (Some(address), Some(latency)) => {
+            let server = MetricsServer::bind(address, Arc::clone(latency))?;
+            eprintln!("Serving metrics on http://{}/metrics", server.address());
+            Some(server)
         }
-    })
-    .expect("Could not create ctrlc handler!");
+        _ => None,
+    };
+    // Set when the outputs stop taking events, which ends the export early
+    let mut write_error = None;
+    while write_error.is_none() {
+        // Events read from the Security log go through the same analyses as those of the session
+        let next = match security_log.as_ref().and_then(SecurityLog::try_next) {
+            Some(event) => Ok(event),
+            None => stream.next_timeout(EXPORT_POLL_INTERVAL),
+        };
+        let event = match next {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => {
+                write_error = output.write_ready(&mut environment).err();
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        output.received();
+        // Filters are swapped between two events, so each event is filtered by one set of filters throughout
+        if let Some(update) = filter_file
+            .as_ref()
+            .and_then(FilterFileWatcher::take_update)
+        {
+            for error in apply_filter_update(
+                update,
+                &mut output.sink,
+                &mut privileges,
+                session_filter.as_ref(),
+            ) {
+                output.report(error);
+            }
+        }
+        clr.add(&event);
+        if let Some(ui) = ui.as_mut() {
+            ui.add(&event);
+        }
+        memory.add(&event);
+        processes.add(&event);
+        for finding in privileges.add(&event, &processes) {
+            eprintln!("Privilege finding: {finding}");
+        }
+        // Analyzers see events as logged, enrichments and sinks their canonical form
+        let mut events = Vec::with_capacity(1);
+        if cli.normalize {
+            normalizer.normalize(event, |normalized| events.push(normalized));
+        } else {
+            events.push(event);
+        }
+        for mut event in events {
+            if let Some(levels) = cli.ancestry {
+                processes.attach_ancestry(&mut event, levels);
+            }
+            write_error = output.export(event, &mut environment).err();
+            if write_error.is_some() {
+                break;
+            }
+        }
+    }
+    // Canonical events still held for their copies
+    if write_error.is_none() {
+        write_error = normalizer.drain().into_iter().find_map(|mut event| {
+            if let Some(levels) = cli.ancestry {
+                processes.attach_ancestry(&mut event, levels);
+            }
+            output.export(event, &mut environment).err()
+        });
+    }
+    if write_error.is_none() {
+        write_error = environment
+            .as_mut()
+            .map(EnvironmentCapture::drain)
+            .unwrap_or_default()
+            .iter()
+            .find_map(|event| output.write(event).err());
+    }
+    // The reader of a pipe going away is the usual way for a piped export to end, rather than an error
+    let broken_pipe = write_error.as_ref().is_some_and(EtwError::is_broken_pipe);
+    let write_error = write_error.filter(|_| !broken_pipe);
+    if let Some(err) = &write_error {
+        eprintln!(
+            "Stopping, the outputs failed {MAX_CONSECUTIVE_SINK_ERRORS} times in a row: {err}"
+        );
+    }
+    stream.stop();
+    latency_finished.store(true, Ordering::Relaxed);
+    if let Some(watcher) = latency_watcher {
+        watcher.thread().unpark();
+        let _ = watcher.join();
+    }
+
+    let ExportOutput {
+        mut sink, latency, ..
+    } = output;
+    match sink.close() {
+        Err(err) if !broken_pipe => return Err(err),
+        _ => {}
+    }
+    if !clr.processes().is_empty() {
+        eprintln!("CLR summary:");
+        eprint!("{clr}");
+    }
+    if let Some(ui) = ui.filter(|ui| !ui.delays().is_empty()) {
+        eprintln!("UI delays:");
+        eprint!("{ui}");
+    }
+    if !memory.processes().is_empty() {
+        eprintln!("Memory summary:");
+        eprint!("{memory}");
+    }
+    if let Some(latency) = latency {
+        let latency = latency.lock().expect("Latency tracker lock was poisoned");
+        if latency.total().events != 0 {
+            eprintln!("Delivery latency:");
+            eprint!("{latency}");
+        }
+    }
+    if !privileges.findings().is_empty() {
+        eprintln!("Privilege findings:");
+        eprint!("{privileges}");
+    }
+    if cli.process_tree {
+        eprintln!("Process tree:");
+        eprint!("{processes}");
+    }
+    if let Some(config) = &cli.process_graph {
+        let mut graph = ProcessGraph::new(&processes);
+        for finding in privileges.findings() {
+            graph.annotate(
+                finding.event.process_id,
+                finding.event.timestamp,
+                finding.rule.name(),
+            );
+        }
+        graph.write(&config.path, config.format)?;
+        eprintln!("Wrote the process graph to {}", config.path.display());
+    }
+    let undecodable = pipeline_errors.undecodable();
+    if !undecodable.is_empty() {
+        eprintln!("Events without a schema on this machine, written undecoded:");
+        for (provider, count) in undecodable {
+            eprintln!("    {} {count}", PropertyValue::Guid(provider));
+        }
+    }
+    report_pipeline_errors(&pipeline_errors);
+    stream.join()?;
+    write_error.map_or(Ok(()), Err)
+}
+
+/// Where [`export`] writes events, along with what it keeps track of about writing them
+struct ExportOutput {
+    sink: FilteredSinks,
+    pipeline_errors: Arc<PipelineErrorCounters>,
+    latency: Option<Arc<Mutex<LatencyTracker>>>, // Shared with the thread checking its windows and the metrics server
+    consecutive_errors: u32,                     // Failed writes since the last one that succeeded
+}
+
+impl ExportOutput {
+    /// Notes that an event was taken off the stream, so time spent on it before it is written counts as a stall
+    fn received(&self) {
+        if let Some(latency) = &self.latency {
+            latency
+                .lock()
+                .expect("Latency tracker lock was poisoned")
+                .received();
+        }
+    }
+
+    /// Writes `event` and times its delivery. The capture carries on, with the error written in place of the event if
+    /// the sink can still take it, unless the reader of the output went away or [`MAX_CONSECUTIVE_SINK_ERRORS`]
+    /// writes failed in a row. The error is returned then, and the export should stop
+    fn write(&mut self, event: &ParsedEvent) -> Result<(), EtwError> {
+        match self.sink.write(event) {
+            Ok(()) => {
+                self.consecutive_errors = 0;
+                if let Some(latency) = &self.latency {
+                    latency
+                        .lock()
+                        .expect("Latency tracker lock was poisoned")
+                        .record(event, SystemTime::now());
+                }
+                Ok(())
+            }
+            Err(err) => {
+                self.consecutive_errors += 1;
+                if err.is_broken_pipe() || self.consecutive_errors >= MAX_CONSECUTIVE_SINK_ERRORS {
+                    self.pipeline_errors.record(PipelineStage::Sink);
+                    return Err(err);
+                }
+                self.report(PipelineError::sink(event, err));
+                Ok(())
+            }
+        }
+    }
+
+    /// Counts `error` and writes it into the output as an event, if the sink can still take it
+    fn report(&mut self, error: PipelineError) {
+        self.pipeline_errors.record_error(&error);
+        let _ = self.sink.write(&error.to_event());
+    }
+
+    /// Hands `event` to `environment` if there is one, since process starts wait on the environment thread and the
+    /// loop keeps draining the stream in the meantime. Otherwise writes it. Then writes the events it is done with
+    fn export(
+        &mut self,
+        event: ParsedEvent,
+        environment: &mut Option<EnvironmentCapture>,
+    ) -> Result<(), EtwError> {
+        match environment {
+            Some(environment) => environment.push(event),
+            None => self.write(&event)?,
+        }
+        self.write_ready(environment)
+    }
+
+    /// Writes the events `environment` is done with
+    fn write_ready(
+        &mut self,
+        environment: &mut Option<EnvironmentCapture>,
+    ) -> Result<(), EtwError> {
+        while let Some(event) = environment.as_mut().and_then(EnvironmentCapture::pop_ready) {
+            self.write(&event)?;
+        }
+        Ok(())
+    }
+}
+
+fn main() -> Result<(), EtwError> {
+    let cli = Cli::parse_checked();
+
+    match &cli.command {
+        Some(Command::Decode { input }) => return decode(input),
+        Some(Command::Providers { name }) => return list_providers(name.as_deref()),
+        Some(Command::Sessions) => return list_sessions(),
+        Some(Command::Agent { listen }) => return run_agent(*listen),
+        Some(Command::Coordinate { agents, args }) => return coordinate(agents, args, &cli),
+        Some(Command::Summarize { trace }) => {
+            let summary = summarize(trace, cli.decode_threads())?;
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&summary).expect("Summaries always serialize")
+            );
+            return Ok(());
+        }
+        Some(Command::Diff { before, after }) => {
+            let diff = summarize(before, cli.decode_threads())?
+                .diff(&summarize(after, cli.decode_threads())?);
+            if diff.is_empty() {
+                println!("No new processes, network destinations or autostart registry writes");
+            }
+            print!("{diff}");
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let handler: Option<unsafe extern "system" fn(*mut EVENT_RECORD)> =
+        match (&cli.command, cli.output) {
+            (Some(Command::Raw { out }), _) => {
+                *RAW_WRITER.lock().expect("Raw writer lock was poisoned") =
+                    Some(match cli.mapped_file() {
+                        Some(config) => RawWriter::create_mapped(out, config)?,
+                        None => RawWriter::create(out)?,
+                    });
+                Some(on_raw_event)
+            }
+            // Each --sink takes decoded events, so they come off the stream like exported ones
+            (_, OutputFormat::Pretty) if cli.sinks.is_empty() => Some(on_process_creation),
+            // Exported events are decoded once, on the stream
+            _ => None,
+        };
+
+    let (session, header) = match &cli.trace {
+        Some(path) => {
+            let session = ETWSession::from_file(path, handler)?;
+            session.set_decode_threads(cli.decode_threads());
+            let header = CaptureHeader::for_trace(path, session.trace_header().as_ref());
+            (session, header)
+        }
+        None => live_session(&cli, handler)?,
+    };
+    let header = header.process_ids(cli.filter_pids.clone());
+
+    if let Some(filter) = cli.filter() {
+        session.set_filter(filter);
+    }
+    if let Some(manifest_dir) = &cli.manifest_dir {
+        session.set_manifest_dir(manifest_dir.clone());
+    }
+
+    // The event stream may be sensitive, so show who besides us can read it
+    if let (true, Some(controller)) = (cli.secure, session.controller()) {
+        eprintln!("Accounts that can consume this session in real-time:");
+        for consumer in controller.real_time_consumers()? {
+            eprintln!(
+                "    {} {}{}",
+                if consumer.allowed { "allow" } else { "deny " },
+                consumer.account.as_deref().unwrap_or(&consumer.sid),
+                if consumer.account.is_some() {
+                    format!(" ({})", consumer.sid)
+                } else {
+                    String::new()
+                }
+            );
+        }
+    }
+
+    stop_after(cli.duration, session.stop_handle());
+
+    if handler.is_none() {
+        // A single output is a sink with an empty filter, so the filter file can still name it
+        let mut sink = FilteredSinks::new();
+        match cli.sinks.as_slice() {
+            [] => {
+                sink = sink.sink(
+                    cli.out.display().to_string(),
+                    open_sink(cli.output, &cli.out, &cli)?,
+                    EventFilter::default(),
+                );
+            }
+            sinks => {
+                for config in sinks {
+                    sink = sink.sink(
+                        config.path.display().to_string(),
+                        open_sink(config.format, &config.path, &cli)?,
+                        config.filter.clone(),
+                    );
+                }
+            }
+        }
+        sink.write_header(&header)?;
+        let filter_file = cli
+            .filter_file
+            .clone()
+            .map(|path| FilterFileWatcher::watch(path, Duration::from_secs(1), cli::compile_filter))
+            .transpose()?;
+        let environment = if cli.capture_env.is_empty() {
+            None
+        } else {
+            Some(EnvironmentCapture::new(cli.capture_env.clone())?)
+        };
+        let processes = process_tracker(&session)?;
+        export(session, processes, sink, filter_file, environment, &cli)?;
+        return write_regions(&cli);
+    }
+
+    handle_ctrlc(session.stop_handle());
+
+    // Pressing Enter drops a bookmark into the live session, labelled with whatever was typed before it
+    if let Some(bookmarker) = session.bookmarker() {
+        println!("Type a label and press Enter to bookmark the trace");
+        thread::spawn(move || {
+            for (count, line) in io::stdin().lines().map_while(Result::ok).enumerate() {
+                let label = match line.trim() {
+                    "" => format!("bookmark {}", count + 1),
+                    label => label.to_string(),
+                };
+                if let Err(err) = bookmarker.mark(&label) {
+                    eprintln!("{err}");
+                }
+            }
+        });
+    }
+
+    session.start_session()?; // This drops the consumer for somer reason.
+
+    for report in session.guardrail_reports() {
+        eprintln!("{report}");
+    }
+
+    let lost_notifications = session.lost_notifications();
+    if lost_notifications != 0 {
+        eprintln!("Warning: ETW reported lost events {lost_notifications} times, the capture is incomplete");
+    }
+
+    // Dropping the writer closes the capture file, which trims a memory-mapped one to its events
+    if let Some(mut writer) = RAW_WRITER
+        .lock()
+        .expect("Raw writer lock was poisoned")
+        .take()
+    {
+        writer.flush()?;
+    }
 
-    session.start_session(); // This drops the consumer for somer reason.
+    print_machine_profile();
+    report_pipeline_errors(&CALLBACK_PIPELINE_ERRORS);
+    // The .etl file is only complete once the session is stopped
+    drop(session);
+    write_regions(&cli)
 }